@@ -0,0 +1,193 @@
+use std::{path::Path, str::FromStr};
+
+use anyhow::{anyhow, bail, Context};
+use clap::Clap;
+use filetime::FileTime;
+
+// Permissions, ownership and modification times of the files the receiver
+// creates. Not a doc comment, like `NetOpts`, being flattened into the
+// top-level options.
+#[derive(Clap, Clone)]
+pub struct FileAttrs {
+    /// Permissions of the files created, in octal, e.g. 640. Directories
+    /// also get the execute bit wherever it allows reading
+    #[clap(long = "mode", value_name = "MODE", parse(try_from_str = parse_mode))]
+    mode: Option<u32>,
+    /// Give the files created to this user and group, by name or ID, which
+    /// usually takes root
+    #[clap(long = "owner", value_name = "USER[:GROUP]")]
+    owner: Option<Owner>,
+    /// Modification time of the files created: `preserve` the one recorded
+    /// by the sender if any, the time they were written (`now`), or a Unix
+    /// timestamp or RFC 3339 date
+    #[clap(long = "mtime", value_name = "WHEN", default_value = "preserve")]
+    mtime: Mtime,
+}
+
+fn parse_mode(s: &str) -> anyhow::Result<u32> {
+    let mode = u32::from_str_radix(s, 8).map_err(|_| anyhow!("invalid mode: {}", s))?;
+    if mode > 0o7777 {
+        bail!("invalid mode: {}", s);
+    }
+    Ok(mode)
+}
+
+/// A user and optionally a group, resolved when parsed.
+#[derive(Clone, Copy, Debug)]
+struct Owner {
+    uid: u32,
+    gid: Option<u32>,
+}
+
+#[cfg(unix)]
+impl FromStr for Owner {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        use nix::unistd::{Group, User};
+
+        let (user, group) = match s.find(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+
+        let uid = match user.parse() {
+            Ok(uid) => uid,
+            Err(_) => User::from_name(user)?
+                .ok_or_else(|| anyhow!("unknown user: {}", user))?
+                .uid
+                .as_raw(),
+        };
+        let gid = match group {
+            Some(group) => Some(match group.parse() {
+                Ok(gid) => gid,
+                Err(_) => Group::from_name(group)?
+                    .ok_or_else(|| anyhow!("unknown group: {}", group))?
+                    .gid
+                    .as_raw(),
+            }),
+            None => None,
+        };
+
+        Ok(Owner { uid, gid })
+    }
+}
+
+#[cfg(not(unix))]
+impl FromStr for Owner {
+    type Err = anyhow::Error;
+
+    fn from_str(_: &str) -> anyhow::Result<Self> {
+        bail!("--owner is not supported on this platform")
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Mtime {
+    Preserve,
+    Now,
+    At(FileTime),
+}
+
+impl FromStr for Mtime {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "preserve" => Mtime::Preserve,
+            "now" => Mtime::Now,
+            _ => match s.parse::<i64>() {
+                Ok(seconds) => Mtime::At(FileTime::from_unix_time(seconds, 0)),
+                Err(_) => {
+                    let date = chrono::DateTime::parse_from_rfc3339(s)
+                        .map_err(|_| anyhow!("invalid modification time: {}", s))?;
+                    Mtime::At(FileTime::from_unix_time(
+                        date.timestamp(),
+                        date.timestamp_subsec_nanos(),
+                    ))
+                }
+            },
+        })
+    }
+}
+
+#[cfg(unix)]
+pub(super) fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub(super) fn set_mode(_: &Path, _: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn chown(path: &Path, owner: Owner) -> anyhow::Result<()> {
+    use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+
+    fchownat(
+        None,
+        path,
+        Some(Uid::from_raw(owner.uid)),
+        owner.gid.map(Gid::from_raw),
+        FchownatFlags::NoFollowSymlink,
+    )?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn chown(_: &Path, _: Owner) -> anyhow::Result<()> {
+    Ok(())
+}
+
+impl FileAttrs {
+    /// Restricts a file just created, before anything is written to it.
+    pub fn created(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(mode) = self.mode {
+            set_mode(path, mode)
+                .with_context(|| format!("failed to set the mode of {}", path.display()))?;
+        }
+        self.chown(path)
+    }
+
+    /// The mode given for files, or `preserved` by the sender, adjusted for
+    /// directories.
+    pub fn mode(&self, preserved: Option<u32>, dir: bool) -> Option<u32> {
+        match self.mode {
+            // r-- becomes r-x
+            Some(mode) if dir => Some(mode | (mode & 0o444) >> 2),
+            Some(mode) => Some(mode),
+            None => preserved,
+        }
+    }
+
+    pub fn chown(&self, path: &Path) -> anyhow::Result<()> {
+        match self.owner {
+            Some(owner) => chown(path, owner)
+                .with_context(|| format!("failed to change the owner of {}", path.display())),
+            None => Ok(()),
+        }
+    }
+
+    /// The modification time to give a file once written, if not the
+    /// current one.
+    pub fn mtime(&self, preserved: Option<FileTime>) -> Option<FileTime> {
+        match self.mtime {
+            Mtime::Preserve => preserved,
+            Mtime::Now => None,
+            Mtime::At(mtime) => Some(mtime),
+        }
+    }
+
+    /// Sets the modification time given on the command line, once a file
+    /// has been fully written.
+    pub fn written(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(mtime) = self.mtime(None) {
+            filetime::set_file_mtime(path, mtime)
+                .with_context(|| format!("failed to set the times of {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
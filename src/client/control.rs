@@ -0,0 +1,197 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use clap::Clap;
+use log::{debug, info};
+use reqwest::StatusCode;
+
+use super::{net, sign};
+use crate::common::{headers, signature::Signer, Stats, Status};
+
+const WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clap)]
+pub struct ControlOpts {
+    endpoint: String,
+}
+
+#[derive(Clap)]
+pub struct WaitOpts {
+    /// Give up after this many seconds
+    #[clap(long = "timeout")]
+    timeout: Option<u64>,
+    endpoint: String,
+}
+
+#[derive(Clap)]
+pub struct ReserveOpts {
+    /// Release the channel if neither end uses it for this many seconds
+    #[clap(long = "timeout")]
+    timeout: Option<u64>,
+    endpoint: String,
+}
+
+/// Stops the server from accepting more data on a channel until it is
+/// resumed. The sender is held back while paused.
+pub async fn pause(opts: ControlOpts, secret: Option<String>, paused: bool) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+    let url = &net::endpoint(&opts.endpoint).await?;
+
+    set_paused(url, signer.as_ref(), paused).await
+}
+
+pub(super) async fn set_paused(
+    url: &str,
+    signer: Option<&Signer>,
+    paused: bool,
+) -> anyhow::Result<()> {
+    let resp = sign(net::client().get(url), signer, "GET", url, None)
+        .header(headers::PAUSE, if paused { 1 } else { 0 })
+        .send()
+        .await?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        bail!("server returned failure status: {:?}", status);
+    }
+
+    Ok(())
+}
+
+/// Tears down a channel on the server, failing the transfer on both ends.
+pub async fn abort(opts: ControlOpts, secret: Option<String>) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+    let url = &net::endpoint(&opts.endpoint).await?;
+
+    abort_channel(url, signer.as_ref(), "channel aborted by an operator").await
+}
+
+/// Tears down the channel at `url`, telling both ends `reason`.
+pub(super) async fn abort_channel(
+    url: &str,
+    signer: Option<&Signer>,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let resp = sign(net::client().delete(url), signer, "DELETE", url, None)
+        .header(headers::ABORT_REASON, reason)
+        .send()
+        .await?;
+
+    let status = resp.status();
+    if status == StatusCode::NOT_FOUND {
+        bail!("channel not found: {}", url);
+    }
+
+    if !status.is_success() {
+        bail!("server returned failure status: {:?}", status);
+    }
+
+    Ok(())
+}
+
+/// Sets a channel aside, printing the claims its sender and receiver then
+/// pass with --claim.
+pub async fn reserve(opts: ReserveOpts, secret: Option<String>) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+    let url = &net::endpoint(&opts.endpoint).await?;
+
+    let mut req = net::client().post(url);
+    if let Some(timeout) = opts.timeout {
+        req = req.query(&[("timeout", timeout)]);
+    }
+    let resp = sign(req, signer.as_ref(), "POST", url, None).send().await?;
+
+    let status = resp.status();
+    if status == StatusCode::CONFLICT {
+        bail!("cannot reserve {}: {}", url, resp.text().await?);
+    }
+
+    if !status.is_success() {
+        bail!("server returned failure status: {:?}", status);
+    }
+
+    println!("{}", resp.text().await?);
+    Ok(())
+}
+
+async fn status(
+    url: &str,
+    signer: Option<&Signer>,
+) -> anyhow::Result<(Status, Option<Stats>, String)> {
+    let resp = sign(net::client().get(url), signer, "GET", url, None)
+        .header(headers::STATUS, 1)
+        .send()
+        .await?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        bail!("server returned failure status: {:?}", status);
+    }
+
+    let channel = resp
+        .headers()
+        .get(headers::STATUS)
+        .ok_or_else(|| anyhow!("server did not report the channel status"))?
+        .to_str()?
+        .parse()?;
+
+    let stats = match resp.headers().get(headers::STATS) {
+        Some(stats) => Some(stats.to_str()?.parse()?),
+        None => None,
+    };
+
+    Ok((channel, stats, resp.text().await?))
+}
+
+/// Prints the status of a channel and how lossy its transfer has been as
+/// seen by the server.
+pub async fn stats(opts: ControlOpts, secret: Option<String>) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+
+    let url = &net::endpoint(&opts.endpoint).await?;
+
+    match status(url, signer.as_ref()).await? {
+        (status, Some(stats), _) => {
+            println!("{}: {}", status, stats);
+            if let Some(end) = stats.slow_end() {
+                println!("the {} is the slow end", end);
+            }
+        }
+        (status, None, _) => println!("{}", status),
+    }
+
+    Ok(())
+}
+
+/// Blocks until the receiver has taken everything sent to a channel,
+/// failing if the transfer is aborted or the channel disappears.
+pub async fn wait(opts: WaitOpts, secret: Option<String>) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+    let url = &net::endpoint(&opts.endpoint).await?;
+    let deadline = opts
+        .timeout
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let mut seen = false;
+
+    loop {
+        match status(url, signer.as_ref()).await {
+            Ok((Status::Complete, _, _)) => {
+                info!("transfer complete");
+                return Ok(());
+            }
+            Ok((Status::Aborted, _, reason)) => bail!("transfer aborted: {}", reason),
+            Ok((Status::Unknown, _, _)) if seen => bail!("channel expired before completing"),
+            Ok((Status::Unknown, _, _)) => {}
+            Ok(_) => seen = true,
+            Err(e) => debug!("failed to get the channel status: {}", e),
+        }
+
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline {
+                bail!("timed out waiting for the transfer to complete");
+            }
+        }
+
+        tokio::time::delay_for(WAIT_INTERVAL).await;
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use bytes::{BufMut, Bytes, BytesMut};
+use clap::Clap;
+use log::info;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::sync::mpsc::{self, Sender};
+
+use super::{receiver, sender};
+use crate::common::signature::Signer;
+
+const OP_COPY: u8 = 1;
+const OP_DATA: u8 = 2;
+const OP_END: u8 = 3;
+
+const MAX_LITERAL: usize = 1024 * 1024;
+const READ_SIZE: usize = 256 * 1024;
+
+#[derive(Clap)]
+pub struct SendOpts {
+    endpoint: String,
+    file: PathBuf,
+}
+
+#[derive(Clap)]
+pub struct ReceiveOpts {
+    #[clap(long = "block-size", default_value = "16384")]
+    block_size: usize,
+    endpoint: String,
+    file: PathBuf,
+}
+
+fn signature_url(endpoint: &str) -> String {
+    format!("{}/signature", endpoint.trim_end_matches('/'))
+}
+
+fn delta_url(endpoint: &str) -> String {
+    format!("{}/delta", endpoint.trim_end_matches('/'))
+}
+
+/// The weak checksum of rsync, which can be updated in constant time when
+/// the window slides by one byte.
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let len = data.len() as u32;
+        let mut rolling = Rolling { a: 0, b: 0, len };
+        for (i, &x) in data.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(x as u32);
+            rolling.b = rolling
+                .b
+                .wrapping_add((len - i as u32).wrapping_mul(x as u32));
+        }
+        rolling
+    }
+
+    fn roll(&mut self, out: u8, input: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(input as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(data: &[u8]) -> [u8; 16] {
+    let mut hash = [0; 16];
+    hash.copy_from_slice(&Sha256::digest(data)[..16]);
+    hash
+}
+
+/// Checksums of the blocks of the file already present on the receiving
+/// side, encoded as the block size and file length followed by the weak
+/// and strong checksum of every block.
+struct Signature {
+    block_size: usize,
+    len: u64,
+    blocks: Vec<[u8; 16]>,
+    index: HashMap<u32, Vec<usize>>,
+}
+
+impl Signature {
+    fn compute(path: &Path, block_size: usize) -> anyhow::Result<Vec<u8>> {
+        let mut encoded = BytesMut::new();
+        encoded.put_u32(block_size as u32);
+
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                encoded.put_u64(0);
+                return Ok(encoded.to_vec());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        encoded.put_u64(file.metadata()?.len());
+
+        let mut block = vec![0; block_size];
+        loop {
+            let n = read_full(&mut file, &mut block)?;
+            if n == 0 {
+                break;
+            }
+
+            encoded.put_u32(Rolling::new(&block[..n]).digest());
+            encoded.put_slice(&strong(&block[..n]));
+        }
+
+        Ok(encoded.to_vec())
+    }
+
+    fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 12 {
+            bail!("malformed block signature");
+        }
+
+        let block_size = u32::from_be_bytes(data[..4].try_into()?) as usize;
+        let len = u64::from_be_bytes(data[4..12].try_into()?);
+        let mut blocks = Vec::new();
+        let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+
+        let chunks = data[12..].chunks_exact(20);
+        if !chunks.remainder().is_empty() {
+            bail!("malformed block signature");
+        }
+
+        for (i, chunk) in chunks.enumerate() {
+            let weak = u32::from_be_bytes(chunk[..4].try_into()?);
+            blocks.push(chunk[4..].try_into()?);
+            index.entry(weak).or_default().push(i);
+        }
+
+        let count = blocks.len() as u64;
+        if block_size == 0
+            || count * (block_size as u64) < len
+            || (count > 0 && (count - 1) * (block_size as u64) >= len)
+        {
+            bail!("malformed block signature");
+        }
+
+        Ok(Signature {
+            block_size,
+            len,
+            blocks,
+            index,
+        })
+    }
+
+    fn block_len(&self, i: usize) -> usize {
+        if i + 1 == self.blocks.len() {
+            (self.len - (i * self.block_size) as u64) as usize
+        } else {
+            self.block_size
+        }
+    }
+
+    fn find(&self, weak: u32, data: &[u8]) -> Option<usize> {
+        let candidates = self.index.get(&weak)?;
+        let hash = strong(data);
+        candidates
+            .iter()
+            .copied()
+            .find(|&i| self.block_len(i) == data.len() && self.blocks[i] == hash)
+    }
+}
+
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+struct DeltaWriter {
+    tx: Sender<io::Result<Bytes>>,
+    literal_bytes: u64,
+    copied_blocks: u64,
+}
+
+impl DeltaWriter {
+    fn emit(&mut self, op: Bytes) -> anyhow::Result<()> {
+        futures::executor::block_on(self.tx.send(Ok(op)))
+            .map_err(|_| anyhow!("delta stream closed"))
+    }
+
+    fn literal(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        for chunk in data.chunks(MAX_LITERAL) {
+            let mut op = BytesMut::with_capacity(5 + chunk.len());
+            op.put_u8(OP_DATA);
+            op.put_u32(chunk.len() as u32);
+            op.put_slice(chunk);
+            self.emit(op.freeze())?;
+            self.literal_bytes += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, block: usize) -> anyhow::Result<()> {
+        let mut op = BytesMut::with_capacity(9);
+        op.put_u8(OP_COPY);
+        op.put_u64(block as u64);
+        self.emit(op.freeze())?;
+        self.copied_blocks += 1;
+        Ok(())
+    }
+}
+
+/// Slides a window over `path`, emitting references to blocks the
+/// receiver already has and literal data for everything else, followed by
+/// the SHA-256 of the whole file.
+fn compute_delta(path: &Path, signature: &Signature, out: &mut DeltaWriter) -> anyhow::Result<()> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let block_size = signature.block_size;
+    let mut hasher = Sha256::new();
+
+    let mut buf = Vec::new();
+    let mut pos = 0;
+    let mut literal = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+
+    loop {
+        while !eof && buf.len() - pos <= block_size {
+            let start = buf.len();
+            buf.resize(start + READ_SIZE, 0);
+            let n = file.read(&mut buf[start..])?;
+            buf.truncate(start + n);
+            hasher.update(&buf[start..]);
+            eof = n == 0;
+        }
+
+        let available = buf.len() - pos;
+        if available == 0 {
+            break;
+        }
+
+        if signature.blocks.is_empty() {
+            out.literal(&buf)?;
+            buf.clear();
+            pos = 0;
+            continue;
+        }
+
+        if available < block_size {
+            let tail = &buf[pos..];
+            match signature.find(Rolling::new(tail).digest(), tail) {
+                Some(block) => {
+                    out.literal(&buf[literal..pos])?;
+                    out.copy(block)?;
+                }
+                None => out.literal(&buf[literal..])?,
+            }
+            literal = buf.len();
+            break;
+        }
+
+        let window = &buf[pos..pos + block_size];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+
+        if let Some(block) = signature.find(weak, window) {
+            out.literal(&buf[literal..pos])?;
+            out.copy(block)?;
+            pos += block_size;
+            literal = pos;
+            rolling = None;
+        } else {
+            match (rolling.as_mut(), buf.get(pos + block_size)) {
+                (Some(rolling), Some(&input)) => rolling.roll(buf[pos], input),
+                _ => rolling = None,
+            }
+            pos += 1;
+
+            if pos - literal >= MAX_LITERAL {
+                out.literal(&buf[literal..pos])?;
+                literal = pos;
+            }
+        }
+
+        if literal >= MAX_LITERAL {
+            buf.drain(..literal);
+            pos -= literal;
+            literal = 0;
+        }
+    }
+
+    out.literal(&buf[literal..])?;
+
+    let mut end = BytesMut::with_capacity(33);
+    end.put_u8(OP_END);
+    end.put_slice(&hasher.finalize());
+    out.emit(end.freeze())
+}
+
+/// Rebuilds `path` from its current content and the delta stored in
+/// `delta`, replacing it only once the result matches the sender's hash.
+fn apply_delta(path: &Path, delta: &Path, block_size: usize) -> anyhow::Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let mut basis = File::open(path).ok();
+    let mut delta = BufReader::new(File::open(delta)?);
+    let output = NamedTempFile::new_in(dir)?;
+    // the updated file keeps the mode of the one it replaces
+    if let Some(basis) = &basis {
+        output
+            .as_file()
+            .set_permissions(basis.metadata()?.permissions())?;
+    }
+    let mut writer = BufWriter::new(output.as_file());
+    let mut hasher = Sha256::new();
+    let mut block = vec![0; block_size];
+    let mut data = vec![0; READ_SIZE];
+
+    loop {
+        let mut op = [0; 1];
+        delta
+            .read_exact(&mut op)
+            .context("truncated delta stream")?;
+
+        match op[0] {
+            OP_COPY => {
+                let mut index = [0; 8];
+                delta.read_exact(&mut index)?;
+                let offset = u64::from_be_bytes(index) * block_size as u64;
+
+                let basis = basis
+                    .as_mut()
+                    .ok_or_else(|| anyhow!("delta references a missing file"))?;
+                basis.seek(SeekFrom::Start(offset))?;
+                let n = read_full(basis, &mut block)?;
+
+                hasher.update(&block[..n]);
+                writer.write_all(&block[..n])?;
+            }
+            OP_DATA => {
+                let mut len = [0; 4];
+                delta.read_exact(&mut len)?;
+                // the length comes from the sender, so the data is not
+                // buffered whole
+                let mut left = u32::from_be_bytes(len) as usize;
+                while left > 0 {
+                    let n = left.min(data.len());
+                    delta.read_exact(&mut data[..n])?;
+                    hasher.update(&data[..n]);
+                    writer.write_all(&data[..n])?;
+                    left -= n;
+                }
+            }
+            OP_END => {
+                let mut expected = [0; 32];
+                delta.read_exact(&mut expected)?;
+                if hasher.finalize()[..] != expected[..] {
+                    bail!("checksum mismatch after applying delta");
+                }
+                break;
+            }
+            op => bail!("unknown delta operation: {}", op),
+        }
+    }
+
+    writer.flush()?;
+    drop(writer);
+    output.persist(path)?;
+
+    Ok(())
+}
+
+pub async fn send(opts: SendOpts, secret: Option<String>) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+
+    let mut signature = Vec::new();
+    receiver::receive(
+        &signature_url(&opts.endpoint),
+        signer.clone(),
+        &Default::default(),
+        &mut signature,
+    )
+    .await?;
+    let signature = Signature::decode(&signature)?;
+
+    let (tx, rx) = mpsc::channel(4);
+    let path = opts.file.clone();
+    let delta = tokio::task::spawn_blocking(move || {
+        let mut out = DeltaWriter {
+            tx,
+            literal_bytes: 0,
+            copied_blocks: 0,
+        };
+
+        if let Err(e) = compute_delta(&path, &signature, &mut out) {
+            // fail the upload instead of letting it end with a truncated delta
+            let error = io::Error::from(io::ErrorKind::Other);
+            let _ = futures::executor::block_on(out.tx.send(Err(error)));
+            return Err(e);
+        }
+
+        Ok((out.literal_bytes, out.copied_blocks))
+    });
+
+    let sent = sender::send(
+        &delta_url(&opts.endpoint),
+        signer,
+        &Default::default(),
+        tokio::io::stream_reader(rx),
+    )
+    .await;
+    let (literal_bytes, copied_blocks) = delta.await??;
+    sent?;
+
+    info!(
+        "sent {} bytes of literal data, reused {} blocks",
+        literal_bytes, copied_blocks
+    );
+
+    Ok(())
+}
+
+pub async fn receive(opts: ReceiveOpts, secret: Option<String>) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+    let block_size = opts.block_size.max(1);
+
+    let path = opts.file.clone();
+    let signature =
+        tokio::task::spawn_blocking(move || Signature::compute(&path, block_size)).await??;
+    sender::send(
+        &signature_url(&opts.endpoint),
+        signer.clone(),
+        &Default::default(),
+        &signature[..],
+    )
+    .await?;
+
+    let delta = NamedTempFile::new()?;
+    let writer = tokio::fs::File::from_std(delta.reopen()?);
+    receiver::receive(
+        &delta_url(&opts.endpoint),
+        signer,
+        &Default::default(),
+        writer,
+    )
+    .await?;
+
+    let path = opts.file.clone();
+    tokio::task::spawn_blocking(move || apply_delta(&path, delta.path(), block_size)).await??;
+
+    info!("updated {}", opts.file.display());
+
+    Ok(())
+}
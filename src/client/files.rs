@@ -0,0 +1,784 @@
+use std::{
+    fs::Metadata,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use atty::Stream;
+use clap::Clap;
+use filetime::FileTime;
+use futures::{
+    ready,
+    stream::{self, StreamExt, TryStreamExt},
+};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{info, log_enabled, warn, Level};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use walkdir::WalkDir;
+
+use super::{
+    attrs::{set_mode, FileAttrs},
+    progress::{Counted, Progress},
+    receiver, sender,
+};
+use crate::common::{
+    io::{HttpPipeReader, HttpPipeWriter},
+    signature::Signer,
+    Size,
+};
+
+#[derive(Clap)]
+#[clap(override_usage = "http-pipe send-files [FLAGS] [OPTIONS] <paths>... <endpoint>")]
+pub struct SendOpts {
+    #[clap(long = "parallel", default_value = "4")]
+    parallel: usize,
+    /// Preserve permissions and modification times
+    #[clap(short = 'p', long = "preserve")]
+    preserve: bool,
+    /// Skip paths matching this glob, may be given multiple times
+    #[clap(long = "exclude", number_of_values = 1)]
+    excludes: Vec<String>,
+    /// Stream everything over the channel itself, as an archive, rather
+    /// than each file over its own sub-channel
+    #[clap(long = "archive")]
+    archive: bool,
+    // a single list, as clap gives the first path to the endpoint when
+    // it follows an option with a value
+    /// Files and directories to send, then the endpoint
+    #[clap(required = true, min_values = 2, value_name = "PATH")]
+    args: Vec<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct SendFileOpts {
+    path: PathBuf,
+    endpoint: String,
+}
+
+#[derive(Clap)]
+pub struct ReceiveFileOpts {
+    /// Where to write the file, under the name it was sent with
+    #[clap(short = 'o', long = "output-dir", default_value = ".")]
+    output_dir: PathBuf,
+    #[clap(flatten)]
+    attrs: FileAttrs,
+    endpoint: String,
+}
+
+#[derive(Clap)]
+pub struct ReceiveOpts {
+    #[clap(short = 'o', long = "output-dir", default_value = ".")]
+    output_dir: PathBuf,
+    #[clap(long = "parallel", default_value = "4")]
+    parallel: usize,
+    /// Receive an archive sent with send-files --archive
+    #[clap(long = "archive")]
+    archive: bool,
+    #[clap(flatten)]
+    attrs: FileAttrs,
+    endpoint: String,
+}
+
+/// Describes the entries of a transfer. It is sent over the `manifest`
+/// sub-channel, while the content of the n-th entry, if it is a regular
+/// file, goes over the `files/n` sub-channel.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    files: Vec<Entry>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    name: String,
+    kind: Kind,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<(i64, u32)>,
+}
+
+/// The most a header frame may take, for a name and a few numbers.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// The header frame sent ahead of the content of a file, by send-file and
+/// for each entry of an archive: the entry describing it as JSON, preceded
+/// by its length as a big-endian 32-bit integer.
+fn header_frame(entry: &Entry) -> anyhow::Result<Vec<u8>> {
+    let json = serde_json::to_vec(entry)?;
+    let mut frame = (json.len() as u32).to_be_bytes().to_vec();
+    frame.extend(json);
+    Ok(frame)
+}
+
+fn manifest_url(endpoint: &str) -> String {
+    format!("{}/manifest", endpoint.trim_end_matches('/'))
+}
+
+fn file_url(endpoint: &str, index: usize) -> String {
+    format!("{}/files/{}", endpoint.trim_end_matches('/'), index)
+}
+
+fn entry_name(path: &Path, base: &Path) -> anyhow::Result<String> {
+    Ok(path
+        .strip_prefix(base)?
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()
+        .with_context(|| format!("non UTF-8 file name: {}", path.display()))?
+        .join("/"))
+}
+
+fn is_excluded(excludes: &GlobSet, name: &str) -> bool {
+    let file_name = name.rsplit('/').next().unwrap_or_default();
+    excludes.is_match(name) || excludes.is_match(file_name)
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_: &Metadata) -> Option<u32> {
+    None
+}
+
+/// Expands directories and names every entry relative to the directory
+/// containing the path it was found through, using `/` as separator.
+/// Symbolic links are recorded as such rather than followed.
+fn collect(
+    paths: &[PathBuf],
+    excludes: &GlobSet,
+    preserve: bool,
+) -> anyhow::Result<Vec<(PathBuf, Entry)>> {
+    let mut entries: Vec<(PathBuf, Entry)> = Vec::new();
+
+    for path in paths {
+        let base = match path.file_name() {
+            Some(_) => path.parent().unwrap_or_else(|| Path::new("")),
+            None => path,
+        };
+
+        let walker = WalkDir::new(path)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_entry(|e| match entry_name(e.path(), base) {
+                Ok(name) => name.is_empty() || !is_excluded(excludes, &name),
+                Err(_) => true,
+            });
+
+        for dir_entry in walker {
+            let dir_entry = dir_entry?;
+            let name = entry_name(dir_entry.path(), base)?;
+            if name.is_empty() {
+                continue;
+            }
+
+            if entries.iter().any(|(_, e)| e.name == name) {
+                bail!("duplicate file name: {}", name);
+            }
+
+            let file_type = dir_entry.file_type();
+            let kind = if file_type.is_dir() {
+                Kind::Directory
+            } else if file_type.is_symlink() {
+                Kind::Symlink
+            } else if file_type.is_file() {
+                Kind::File
+            } else {
+                warn!("skipping special file {}", dir_entry.path().display());
+                continue;
+            };
+
+            let target = if kind == Kind::Symlink {
+                let target = std::fs::read_link(dir_entry.path())?;
+                Some(target.to_str().map(str::to_owned).with_context(|| {
+                    format!("non UTF-8 link target: {}", dir_entry.path().display())
+                })?)
+            } else {
+                None
+            };
+
+            let (mode, mtime) = if preserve {
+                let metadata = dir_entry.metadata()?;
+                let mtime = FileTime::from_last_modification_time(&metadata);
+                (
+                    file_mode(&metadata).filter(|_| kind != Kind::Symlink),
+                    Some((mtime.unix_seconds(), mtime.nanoseconds())),
+                )
+            } else {
+                (None, None)
+            };
+
+            entries.push((
+                dir_entry.into_path(),
+                Entry {
+                    name,
+                    kind,
+                    size: 0,
+                    sha256: String::new(),
+                    target,
+                    mode,
+                    mtime,
+                },
+            ));
+        }
+    }
+
+    Ok(entries)
+}
+
+async fn hash_file(path: PathBuf) -> anyhow::Result<(u64, String)> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        let mut size = 0;
+
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..n]);
+            size += n as u64;
+        }
+
+        Ok((size, hex::encode(hasher.finalize())))
+    })
+    .await?
+}
+
+fn output_path(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let relative = Path::new(name);
+    if name.is_empty()
+        || relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        bail!("refusing to write outside the output directory: {}", name);
+    }
+
+    Ok(dir.join(relative))
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, path)
+}
+
+/// Applies the permissions, owner and modification time given on the
+/// command line, or else preserved by the sender.
+fn restore_metadata(path: &Path, entry: &Entry, attrs: &FileAttrs) -> anyhow::Result<()> {
+    if entry.kind != Kind::Symlink {
+        if let Some(mode) = attrs.mode(entry.mode, entry.kind == Kind::Directory) {
+            set_mode(path, mode)?;
+        }
+    }
+    attrs.chown(path)?;
+
+    let preserved = entry
+        .mtime
+        .map(|(seconds, nanos)| FileTime::from_unix_time(seconds, nanos));
+    if let Some(mtime) = attrs.mtime(preserved) {
+        if entry.kind == Kind::Symlink {
+            filetime::set_symlink_file_times(path, mtime, mtime)?;
+        } else {
+            filetime::set_file_mtime(path, mtime)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[pin_project]
+struct HashWriter<W> {
+    #[pin]
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W> HashWriter<W> {
+    fn new(inner: W) -> Self {
+        HashWriter {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    fn finish(self) -> (u64, String) {
+        (self.size, hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for HashWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = ready!(this.inner.poll_write(cx, buf))?;
+        this.hasher.update(&buf[..n]);
+        *this.size += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+/// Takes the header frame written ahead of a file, then writes the rest
+/// into the file it names, created in `dir`.
+struct FileReceiver<'a> {
+    dir: &'a Path,
+    attrs: &'a FileAttrs,
+    progress: &'a Progress,
+    header: Vec<u8>,
+    file: Option<(PathBuf, Entry, Counted<File>)>,
+}
+
+impl<'a> FileReceiver<'a> {
+    fn new(dir: &'a Path, attrs: &'a FileAttrs, progress: &'a Progress) -> Self {
+        FileReceiver {
+            dir,
+            attrs,
+            progress,
+            header: Vec::new(),
+            file: None,
+        }
+    }
+
+    /// Takes as much of `buf` as belongs to the header, and creates the
+    /// file once the header is complete.
+    fn take_header(&mut self, buf: &[u8]) -> anyhow::Result<usize> {
+        let wanted = match self.header.get(..4) {
+            Some(len) => 4 + u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize,
+            None => 4,
+        };
+        let n = (wanted - self.header.len()).min(buf.len());
+        self.header.extend_from_slice(&buf[..n]);
+        if self.header.len() < wanted {
+            return Ok(n);
+        }
+
+        if wanted == 4 {
+            let len = &self.header[..4];
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            if len == 0 || len > MAX_HEADER_SIZE {
+                bail!("invalid file header, was the file sent with send-file?");
+            }
+            return Ok(n);
+        }
+
+        let entry: Entry = serde_json::from_slice(&self.header[4..])
+            .context("invalid file header, was the file sent with send-file?")?;
+        if entry.kind != Kind::File {
+            bail!("not a file: {}", entry.name);
+        }
+
+        let path = output_path(self.dir, &entry.name)?;
+        let file = std::fs::File::create(&path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        self.attrs.created(&path)?;
+        self.progress.set_total(entry.size);
+        info!("receiving {} ({})", entry.name, Size(entry.size));
+
+        self.file = Some((path, entry, self.progress.count(File::from_std(file))));
+        Ok(n)
+    }
+}
+
+impl AsyncWrite for FileReceiver<'_> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match &mut this.file {
+            Some((_, _, file)) => Pin::new(file).poll_write(cx, buf),
+            None => Poll::Ready(
+                this.take_header(buf)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:#}", e))),
+            ),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().file {
+            Some((_, _, file)) => Pin::new(file).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().file {
+            Some((_, _, file)) => Pin::new(file).poll_shutdown(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+pub async fn send(opts: SendOpts, secret: Option<String>) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+
+    let mut excludes = GlobSetBuilder::new();
+    for pattern in &opts.excludes {
+        excludes.add(Glob::new(pattern)?);
+    }
+
+    let (endpoint, paths) = opts.args.split_last().unwrap();
+    let endpoint = endpoint
+        .to_str()
+        .ok_or_else(|| anyhow!("invalid endpoint: {}", endpoint.display()))?;
+
+    let mut entries = collect(paths, &excludes.build()?, opts.preserve)?;
+    if opts.archive {
+        return send_archive(endpoint, secret.as_deref(), entries).await;
+    }
+
+    for (path, entry) in &mut entries {
+        if entry.kind == Kind::File {
+            let (size, sha256) = hash_file(path.clone()).await?;
+            entry.size = size;
+            entry.sha256 = sha256;
+        }
+    }
+
+    let (paths, entries): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+    let files: Vec<_> = paths
+        .into_iter()
+        .zip(&entries)
+        .enumerate()
+        .filter(|(_, (_, entry))| entry.kind == Kind::File)
+        .map(|(i, (path, entry))| (i, path, entry.name.clone()))
+        .collect();
+
+    let manifest = serde_json::to_vec(&Manifest { files: entries })?;
+    sender::send(
+        &manifest_url(endpoint),
+        signer.clone(),
+        &Default::default(),
+        &manifest[..],
+    )
+    .await?;
+
+    stream::iter(files)
+        .map(|(i, path, name)| {
+            let signer = signer.clone();
+            async move {
+                let file = File::open(&path)
+                    .await
+                    .with_context(|| format!("failed to open {}", path.display()))?;
+                sender::send(&file_url(endpoint, i), signer, &Default::default(), file).await?;
+                info!("sent {}", name);
+                Ok(())
+            }
+        })
+        .buffer_unordered(opts.parallel.max(1))
+        .try_collect()
+        .await
+}
+
+pub async fn receive(opts: ReceiveOpts, secret: Option<String>) -> anyhow::Result<()> {
+    if opts.archive {
+        return receive_archive(&opts, secret.as_deref()).await;
+    }
+
+    let signer = secret.as_deref().map(Signer::new);
+
+    let mut manifest = Vec::new();
+    receiver::receive(
+        &manifest_url(&opts.endpoint),
+        signer.clone(),
+        &Default::default(),
+        &mut manifest,
+    )
+    .await?;
+    let manifest: Manifest = serde_json::from_slice(&manifest).context("invalid manifest")?;
+
+    for entry in &manifest.files {
+        let path = output_path(&opts.output_dir, &entry.name)?;
+        if entry.kind == Kind::Directory {
+            tokio::fs::create_dir_all(&path)
+                .await
+                .with_context(|| format!("failed to create {}", path.display()))?;
+        }
+    }
+
+    let (endpoint, output_dir, attrs) = (&opts.endpoint, &opts.output_dir, &opts.attrs);
+    stream::iter(
+        manifest
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.kind == Kind::File),
+    )
+    .map(|(i, entry)| {
+        let signer = signer.clone();
+        async move {
+            let path = output_path(output_dir, &entry.name)?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+
+            let file = File::create(&path)
+                .await
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            attrs.created(&path)?;
+            let mut writer = HashWriter::new(file);
+            receiver::receive(
+                &file_url(endpoint, i),
+                signer,
+                &Default::default(),
+                &mut writer,
+            )
+            .await?;
+
+            let (size, sha256) = writer.finish();
+            if size != entry.size || sha256 != entry.sha256 {
+                bail!("{}: size or checksum mismatch", entry.name);
+            }
+
+            restore_metadata(&path, entry, attrs)?;
+            info!("received {}", entry.name);
+            Ok(())
+        }
+    })
+    .buffer_unordered(opts.parallel.max(1))
+    .try_collect::<()>()
+    .await?;
+
+    finish(&manifest.files, output_dir, attrs)
+}
+
+/// Creates the links and restores the metadata of directories, once all
+/// files are written.
+fn finish(entries: &[Entry], output_dir: &Path, attrs: &FileAttrs) -> anyhow::Result<()> {
+    // links are only created once all files are written, so that no file
+    // can be written through a link received in the same transfer
+    for entry in entries {
+        if entry.kind == Kind::Symlink {
+            let path = output_path(output_dir, &entry.name)?;
+            let target = entry.target.as_deref().unwrap_or_default();
+            symlink(target, &path)
+                .with_context(|| format!("failed to create link {}", path.display()))?;
+            restore_metadata(&path, entry, attrs)?;
+        }
+    }
+
+    // children come after their parents, so walking the entries backwards
+    // restores directory times after their content is written
+    for entry in entries.iter().rev() {
+        if entry.kind == Kind::Directory {
+            restore_metadata(&output_path(output_dir, &entry.name)?, entry, attrs)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Streams the entries one after the other over the channel itself, each
+/// as a header frame followed by the content of files.
+async fn send_archive(
+    endpoint: &str,
+    secret: Option<&str>,
+    entries: Vec<(PathBuf, Entry)>,
+) -> anyhow::Result<()> {
+    let mut writer = HttpPipeWriter::new(endpoint, secret, Default::default());
+
+    for (path, mut entry) in entries {
+        let file = match entry.kind {
+            Kind::File => {
+                let file = File::open(&path)
+                    .await
+                    .with_context(|| format!("failed to open {}", path.display()))?;
+                entry.size = file.metadata().await?.len();
+                Some(file)
+            }
+            _ => None,
+        };
+
+        writer.write_all(&header_frame(&entry)?).await?;
+        if let Some(file) = file {
+            let sent = tokio::io::copy(&mut file.take(entry.size), &mut writer).await?;
+            if sent != entry.size {
+                bail!("{} was truncated while being sent", entry.name);
+            }
+            info!("sent {}", entry.name);
+        }
+    }
+
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Reads the header frame of the next entry of an archive, or `None` at
+/// its end.
+async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<Entry>> {
+    let mut len = [0; 4];
+    // the archive can only end between entries
+    if reader.read(&mut len[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[1..]).await?;
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_HEADER_SIZE {
+        bail!("invalid archive header, was it sent with send-files --archive?");
+    }
+    let mut header = vec![0; len];
+    reader.read_exact(&mut header).await?;
+
+    Ok(Some(
+        serde_json::from_slice(&header).context("invalid archive header")?,
+    ))
+}
+
+async fn receive_archive(opts: &ReceiveOpts, secret: Option<&str>) -> anyhow::Result<()> {
+    let (output_dir, attrs) = (&opts.output_dir, &opts.attrs);
+    let mut reader = HttpPipeReader::new(&opts.endpoint, secret, Default::default());
+    let mut entries = Vec::new();
+
+    while let Some(entry) = read_header(&mut reader).await? {
+        let path = output_path(output_dir, &entry.name)?;
+        match entry.kind {
+            Kind::Directory => tokio::fs::create_dir_all(&path)
+                .await
+                .with_context(|| format!("failed to create {}", path.display()))?,
+            Kind::File => {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+
+                let mut file = File::create(&path)
+                    .await
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                attrs.created(&path)?;
+                let received = tokio::io::copy(&mut (&mut reader).take(entry.size), &mut file)
+                    .await
+                    .with_context(|| format!("failed to receive {}", entry.name))?;
+                if received != entry.size {
+                    bail!("{}: archive ended early", entry.name);
+                }
+                file.flush().await?;
+
+                restore_metadata(&path, &entry, attrs)?;
+                info!("received {}", entry.name);
+            }
+            Kind::Symlink => {}
+        }
+        entries.push(entry);
+    }
+
+    finish(&entries, output_dir, attrs)
+}
+
+pub async fn send_file(opts: SendFileOpts, secret: Option<String>) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+
+    let path = &opts.path;
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    if !metadata.is_file() {
+        bail!("not a file: {}", path.display());
+    }
+
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("no UTF-8 file name: {}", path.display()))?;
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    let header = header_frame(&Entry {
+        name: name.to_owned(),
+        kind: Kind::File,
+        size: metadata.len(),
+        sha256: String::new(),
+        target: None,
+        mode: file_mode(&metadata),
+        mtime: Some((mtime.unix_seconds(), mtime.nanoseconds())),
+    })?;
+
+    let file = File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let progress = Progress::default();
+    progress.set_total(metadata.len());
+    let input = AsyncReadExt::chain(&header[..], progress.count(file));
+    let options = Default::default();
+    let sending = sender::send(&opts.endpoint, signer, &options, input);
+
+    if !log_enabled!(Level::Info) || !atty::is(Stream::Stderr) {
+        sending.await
+    } else {
+        progress.show(sending).await
+    }
+}
+
+pub async fn receive_file(opts: ReceiveFileOpts, secret: Option<String>) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+
+    let progress = Progress::default();
+    let mut output = FileReceiver::new(&opts.output_dir, &opts.attrs, &progress);
+    let options = Default::default();
+    let receiving = receiver::receive(&opts.endpoint, signer, &options, &mut output);
+    if !log_enabled!(Level::Info) || !atty::is(Stream::Stderr) {
+        receiving.await?;
+    } else {
+        progress.show(receiving).await?;
+    }
+
+    let (path, entry, mut file) = output
+        .file
+        .take()
+        .context("nothing was received, was the file sent with send-file?")?;
+    file.flush().await?;
+    drop(file);
+
+    let size = tokio::fs::metadata(&path).await?.len();
+    if size != entry.size {
+        bail!(
+            "{}: expected {} bytes, received {}",
+            entry.name,
+            entry.size,
+            size
+        );
+    }
+
+    restore_metadata(&path, &entry, &opts.attrs)?;
+    info!("received {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use std::{
+    fs::{File, Metadata},
+    io::{self, Read, Seek, SeekFrom},
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use log::{debug, info};
+use tokio::{
+    io::AsyncRead,
+    sync::mpsc::{self, Sender},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const BUFFER_SIZE: usize = 64 * 1024;
+
+#[cfg(unix)]
+fn identity(metadata: &Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn identity(_metadata: &Metadata) -> (u64, u64) {
+    (0, 0)
+}
+
+/// Reads `path` like `tail -F`: the current content is read first, then
+/// appended data as it arrives. A file that is replaced or truncated is
+/// reopened from the start, and the stream never ends.
+pub fn follow(path: PathBuf) -> impl AsyncRead {
+    let (tx, rx) = mpsc::channel(4);
+
+    thread::spawn(move || run(path, tx));
+
+    tokio::io::stream_reader(rx)
+}
+
+fn run(path: PathBuf, mut tx: Sender<io::Result<Bytes>>) {
+    let mut current: Option<(File, (u64, u64))> = None;
+    let mut position = 0;
+    let mut buffer = vec![0; BUFFER_SIZE];
+
+    loop {
+        let (file, id) = match current.as_mut() {
+            Some(current) => current,
+            None => match File::open(&path).and_then(|f| f.metadata().map(|m| (f, m))) {
+                Ok((file, metadata)) => {
+                    info!("following {}", path.display());
+                    position = 0;
+                    current.get_or_insert((file, identity(&metadata)))
+                }
+                Err(e) => {
+                    debug!("failed to open {}: {}", path.display(), e);
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            },
+        };
+
+        match file.read(&mut buffer) {
+            Ok(0) => {}
+            Ok(n) => {
+                position += n as u64;
+                let data = Bytes::copy_from_slice(&buffer[..n]);
+                if futures::executor::block_on(tx.send(Ok(data))).is_err() {
+                    return;
+                }
+                continue;
+            }
+            Err(e) => {
+                let _ = futures::executor::block_on(tx.send(Err(e)));
+                return;
+            }
+        }
+
+        match std::fs::metadata(&path) {
+            Ok(metadata) if identity(&metadata) != *id => {
+                info!("{} has been replaced, reopening", path.display());
+                current = None;
+                continue;
+            }
+            Ok(metadata) if metadata.len() < position => {
+                info!("{} has been truncated", path.display());
+                position = 0;
+                let _ = file.seek(SeekFrom::Start(0));
+                continue;
+            }
+            _ => {}
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
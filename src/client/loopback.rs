@@ -0,0 +1,214 @@
+use std::{
+    io,
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context as _};
+use atty::Stream;
+use clap::Clap;
+use futures::ready;
+use log::{info, log_enabled, Level};
+use pin_project::pin_project;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use sha2::{Digest, Sha256};
+use tokio::{io::AsyncRead, sync::oneshot};
+
+use super::{
+    progress::Progress, receiver, sender, verify::HashWriter, ReceiveOptions, SendOptions,
+    Transport,
+};
+use crate::common::{compression::Compression, signature::Signer, Size};
+use crate::server;
+
+/// How long the relay may take to start listening
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// The receiver usually asks for the channel before the sender has created
+/// it, which a relay this close is not worth waiting long for
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clap)]
+pub struct LoopbackOpts {
+    /// Send this file instead of random data
+    #[clap(long = "input", value_name = "PATH")]
+    input: Option<PathBuf>,
+    /// Amount of random data to send
+    #[clap(long = "size", default_value = "256M")]
+    size: Size,
+    /// Number of parallel connections of either end
+    #[clap(long = "workers")]
+    workers: Option<u64>,
+    #[clap(long = "packet-size")]
+    packet_size: Option<Size>,
+    #[clap(long = "compress")]
+    compress: Option<Compression>,
+    #[clap(long = "passphrase", env = "HTTP_PIPE_PASSPHRASE")]
+    passphrase: Option<String>,
+    #[clap(long = "transport")]
+    transport: Option<Transport>,
+    /// Settings of the relay
+    #[clap(flatten)]
+    server_opts: server::Opts,
+}
+
+/// Random bytes, as many as asked for.
+struct Random {
+    rng: StdRng,
+    remaining: u64,
+}
+
+impl AsyncRead for Random {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = buf.len().min(self.remaining as usize);
+        self.rng.fill_bytes(&mut buf[..n]);
+        self.remaining -= n as u64;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Hashes everything read through it.
+#[pin_project]
+struct HashReader<R> {
+    #[pin]
+    inner: R,
+    hash: Sha256,
+    len: u64,
+}
+
+impl<R: AsyncRead> AsyncRead for HashReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = ready!(this.inner.poll_read(cx, buf))?;
+        this.hash.update(&buf[..n]);
+        *this.len += n as u64;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Waits until the relay listens on `addr`, or has failed to start.
+async fn wait_listening(
+    addr: &str,
+    server: &mut oneshot::Receiver<anyhow::Result<()>>,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    while TcpStream::connect(addr).is_err() {
+        if let Ok(result) = server.try_recv() {
+            result?;
+            bail!("relay exited before the transfer");
+        }
+        if started.elapsed() > STARTUP_TIMEOUT {
+            bail!("relay did not start listening on {}", addr);
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+/// Pipes data from a sender to a receiver through a relay, all in this
+/// process, then reports the throughput and checks that the data made it
+/// through intact.
+pub async fn run(opts: LoopbackOpts, secret: Option<String>) -> anyhow::Result<()> {
+    let input: Box<dyn AsyncRead + Unpin + Send> = match &opts.input {
+        Some(path) => Box::new(
+            tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("failed to open {}", path.display()))?,
+        ),
+        None => Box::new(Random {
+            rng: StdRng::from_entropy(),
+            remaining: opts.size.0,
+        }),
+    };
+
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string();
+    let url = format!("http://{}/loopback", addr);
+
+    // the relay runs on its own thread, as it would in its own process
+    let (tx, mut server) = oneshot::channel();
+    let (server_addr, server_secret) = (addr.clone(), secret.clone());
+    let server_opts = opts.server_opts;
+    std::thread::spawn(move || {
+        let result = tokio::runtime::Runtime::new()
+            .map_err(anyhow::Error::from)
+            .and_then(|mut rt| {
+                rt.block_on(server::main(Some(server_addr), server_secret, server_opts))
+            });
+        let _ = tx.send(result);
+    });
+    wait_listening(&addr, &mut server).await?;
+
+    let send_options = SendOptions {
+        workers: opts.workers,
+        packet_size: opts.packet_size.map(|size| size.0 as usize),
+        compress: opts.compress,
+        passphrase: opts.passphrase.clone(),
+        transport: opts.transport,
+        retry_delay: Some(RETRY_DELAY),
+        ..Default::default()
+    };
+    let receive_options = ReceiveOptions {
+        workers: opts.workers,
+        passphrase: opts.passphrase,
+        transport: opts.transport,
+        retry_delay: Some(RETRY_DELAY),
+        ..Default::default()
+    };
+
+    let signer = secret.as_deref().map(Signer::new);
+    let mut input = HashReader {
+        inner: input,
+        hash: Sha256::new(),
+        len: 0,
+    };
+    let mut output = HashWriter(Sha256::new());
+
+    let progress = Progress::default();
+    let started = Instant::now();
+    let transfer = async {
+        futures::try_join!(
+            sender::send(&url, signer.clone(), &send_options, &mut input),
+            receiver::receive(
+                &url,
+                signer.clone(),
+                &receive_options,
+                progress.count(&mut output)
+            ),
+        )
+    };
+    if !log_enabled!(Level::Info) || !atty::is(Stream::Stderr) {
+        transfer.await?;
+    } else {
+        progress.show(transfer).await?;
+    }
+    let elapsed = started.elapsed();
+
+    let (sent, received) = (input.hash.finalize(), output.0.finalize());
+    if sent != received {
+        bail!(
+            "data corrupted: sent sha256 {}, received {}",
+            hex::encode(sent),
+            hex::encode(received)
+        );
+    }
+
+    let rate = input.len as f64 / elapsed.as_secs_f64().max(0.001);
+    info!(
+        "{} in {:.2}s, {}/s, sha256 {} intact",
+        Size(input.len),
+        elapsed.as_secs_f64(),
+        Size(rate as u64),
+        hex::encode(sent)
+    );
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use reqwest::Client;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Semaphore,
+};
+
+use super::{net, ReceiveOptions, SendOptions};
+use crate::common::RateLimit;
+
+/// Runs many transfers at once over a single pool of connections, e.g. in
+/// a program relaying dozens of pipes. Transfers beyond the limit wait for
+/// others to finish, and all of them share a bandwidth limit.
+#[derive(Clone)]
+pub struct TransferManager {
+    pool: Client,
+    slots: Arc<Semaphore>,
+    rate_limit: Option<Arc<RateLimit>>,
+}
+
+impl TransferManager {
+    /// Runs up to `max_transfers` at a time, within `rate_limit` bytes per
+    /// second over all of them if given.
+    pub fn new(max_transfers: usize, rate_limit: Option<u64>) -> Self {
+        TransferManager {
+            pool: net::builder().build().unwrap_or_default(),
+            slots: Arc::new(Semaphore::new(max_transfers.max(1))),
+            rate_limit: rate_limit.map(|rate| Arc::new(RateLimit::new(rate))),
+        }
+    }
+
+    /// The limit shared by all transfers, which can be changed as they run.
+    pub fn rate_limit(&self) -> Option<&Arc<RateLimit>> {
+        self.rate_limit.as_ref()
+    }
+
+    /// How many more transfers can start right away.
+    pub fn available(&self) -> usize {
+        self.slots.available_permits()
+    }
+
+    /// Sends `input` like [`send`](super::send) once a slot is free. The
+    /// shared bandwidth limit replaces any of the transfer's own.
+    pub async fn send<R>(
+        &self,
+        endpoint: &str,
+        secret: Option<&str>,
+        mut options: SendOptions,
+        input: R,
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let _slot = self.slots.acquire().await;
+        options.pool = Some(self.pool.clone());
+        if self.rate_limit.is_some() {
+            options.rate_limit = self.rate_limit.clone();
+        }
+        super::send(endpoint, secret, &options, input).await
+    }
+
+    /// Receives into `output` like [`receive`](super::receive) once a slot
+    /// is free. The shared bandwidth limit replaces any of the transfer's
+    /// own.
+    pub async fn receive<W>(
+        &self,
+        endpoint: &str,
+        secret: Option<&str>,
+        mut options: ReceiveOptions,
+        output: W,
+    ) -> anyhow::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let _slot = self.slots.acquire().await;
+        options.pool = Some(self.pool.clone());
+        if self.rate_limit.is_some() {
+            options.rate_limit = self.rate_limit.clone();
+        }
+        super::receive(endpoint, secret, &options, output).await
+    }
+}
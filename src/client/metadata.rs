@@ -0,0 +1,60 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use log::warn;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::mpsc::{self, UnboundedSender},
+    task::JoinHandle,
+};
+
+use crate::common::records::{Outbox, Record};
+
+/// Sends each line read from `path` along with the data, e.g. from a FIFO
+/// or a file descriptor like `/dev/fd/3` that an application writes to.
+pub(crate) fn read(path: PathBuf) -> Arc<Outbox> {
+    let outbox = Arc::new(Outbox::default());
+    let pushed = outbox.clone();
+
+    tokio::spawn(async move {
+        let result: anyhow::Result<()> = async {
+            let mut lines = BufReader::new(File::open(&path).await?).lines();
+            while let Some(line) = lines.next_line().await? {
+                pushed.push(line);
+            }
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            warn!("failed to read metadata from {}: {}", path.display(), e);
+        }
+    });
+
+    outbox
+}
+
+/// Writes the records passed to the returned sender to `path`, one JSON
+/// object per line, until the sender is dropped.
+pub(crate) fn write(path: PathBuf) -> (UnboundedSender<Record>, JoinHandle<anyhow::Result<()>>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Record>();
+
+    let task = tokio::spawn(async move {
+        let mut file = File::create(&path)
+            .await
+            .with_context(|| format!("failed to open {}", path.display()))?;
+
+        while let Some(record) = rx.recv().await {
+            let mut line = serde_json::to_vec(&record)?;
+            line.push(b'\n');
+            file.write_all(&line).await?;
+            // whoever reads them follows along
+            file.flush().await?;
+        }
+
+        Ok(())
+    });
+
+    (tx, task)
+}
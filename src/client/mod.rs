@@ -1,15 +1,826 @@
-use anyhow::bail;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
 use atty::Stream;
+use bytes::Bytes;
+use clap::Clap;
+use log::{debug, info, log_enabled, Level};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, RETRY_AFTER, USER_AGENT},
+    Client, IntoUrl, RequestBuilder, Response, StatusCode, Url,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::mpsc::UnboundedSender,
+};
+
+use self::attrs::FileAttrs;
+pub use self::manager::TransferManager;
+use self::outputs::{Output, Tee};
+pub use self::preset::Preset;
+pub use self::profile::with_profile;
+use self::progress::Progress;
+pub use self::receiver::Options as ReceiveOptions;
+pub use self::resume::ResumeToken;
+pub use self::sender::Options as SendOptions;
+pub use self::transport::Transport;
+use crate::common::{
+    checksum::{self, Algorithm},
+    compression::Compression,
+    encryption::{self, Cipher, Decryptor, Params},
+    headers,
+    records::{self, Record},
+    signature::{self, Signer},
+    Priority, RateLimit, Size, Stats, StreamHasher, TimeOfDay, Trailer,
+};
 
+mod attrs;
+pub mod control;
+pub mod delta;
+pub mod files;
+mod follow;
+pub mod loopback;
+mod manager;
+mod metadata;
+pub mod net;
+mod outputs;
+mod preset;
+mod profile;
+mod progress;
 mod receiver;
+pub mod record;
+mod resume;
+mod segments;
 mod sender;
+mod socket;
+mod spool;
+mod transport;
+pub mod verify;
+
+#[derive(Clap)]
+pub struct Opts {
+    #[clap(long = "send", conflicts_with_all = &["recv", "outputs"])]
+    send: bool,
+    #[clap(long = "recv", conflicts_with = "follow")]
+    recv: bool,
+    #[clap(long = "duplex", conflicts_with_all = &["send", "recv", "follow", "outputs"])]
+    duplex: bool,
+    #[clap(long = "listen", requires = "duplex")]
+    listen: bool,
+    #[clap(long = "follow")]
+    follow: Option<PathBuf>,
+    #[clap(long = "stream", value_name = "RETENTION")]
+    stream: Option<usize>,
+    #[clap(long = "stream-max-age", value_name = "SECONDS", requires = "stream")]
+    stream_max_age: Option<u64>,
+    #[clap(long = "stream-max-bytes", value_name = "SIZE", requires = "stream")]
+    stream_max_bytes: Option<Size>,
+    #[clap(long = "receivers", conflicts_with = "stream")]
+    receivers: Option<usize>,
+    #[clap(
+        long = "output",
+        value_name = "PATH|-|'|COMMAND'",
+        number_of_values = 1
+    )]
+    outputs: Vec<Output>,
+    #[clap(long = "atomic", requires = "outputs")]
+    atomic: bool,
+    #[clap(long = "append", requires = "outputs", conflicts_with = "atomic")]
+    append: bool,
+    #[clap(long = "input", conflicts_with_all = &["recv", "follow", "outputs"])]
+    input: Option<PathBuf>,
+    #[clap(flatten)]
+    attrs: FileAttrs,
+    #[clap(long = "checkpoint")]
+    checkpoint: Option<PathBuf>,
+    #[clap(long = "priority")]
+    priority: Option<Priority>,
+    #[clap(long = "start-at")]
+    start_at: Option<TimeOfDay>,
+    #[clap(long = "resume")]
+    resume: Option<ResumeToken>,
+    #[clap(long = "resume-token")]
+    resume_token: Option<PathBuf>,
+    #[clap(long = "segments")]
+    segments: Option<u64>,
+    #[clap(long = "workers")]
+    workers: Option<u64>,
+    #[clap(long = "packet-size")]
+    packet_size: Option<Size>,
+    #[clap(long = "fixed-packet-size")]
+    fixed_packet_size: bool,
+    #[clap(long = "checksum")]
+    checksum: Option<Algorithm>,
+    #[clap(long = "compress")]
+    compress: Option<Compression>,
+    #[clap(long = "rate-limit")]
+    rate_limit: Option<Size>,
+    #[clap(long = "passphrase", env = "HTTP_PIPE_PASSPHRASE")]
+    passphrase: Option<String>,
+    #[clap(long = "rekey-after", requires = "passphrase")]
+    rekey_after: Option<Size>,
+    #[clap(long = "cipher", requires = "passphrase")]
+    cipher: Option<Cipher>,
+    #[clap(
+        long = "kdf-rounds",
+        requires = "passphrase",
+        parse(try_from_str = encryption::parse_kdf_rounds)
+    )]
+    kdf_rounds: Option<u32>,
+    #[clap(long = "metadata", value_name = "PATH", conflicts_with = "duplex")]
+    metadata: Option<PathBuf>,
+    #[clap(long = "transport")]
+    transport: Option<Transport>,
+    #[clap(long = "batch")]
+    batch: Option<u64>,
+    #[clap(long = "flush-interval", value_name = "MS")]
+    flush_interval: Option<u64>,
+    #[clap(
+        long = "input-timeout",
+        value_name = "SECONDS",
+        conflicts_with = "recv"
+    )]
+    input_timeout: Option<u64>,
+    #[clap(long = "retry-delay", value_name = "SECONDS")]
+    retry_delay: Option<u64>,
+    #[clap(long = "max-retry-delay", value_name = "SECONDS")]
+    max_retry_delay: Option<u64>,
+    #[clap(long = "max-retries", value_name = "N")]
+    max_retries: Option<u32>,
+    #[clap(long = "max-retry-time", value_name = "SECONDS")]
+    max_retry_time: Option<u64>,
+    #[clap(long = "heartbeat", value_name = "SECONDS")]
+    heartbeat: Option<u64>,
+    #[clap(long = "preset")]
+    preset: Option<Preset>,
+    #[clap(
+        long = "multipath",
+        value_name = "URL",
+        number_of_values = 1,
+        conflicts_with_all = &["recv", "duplex", "outputs"]
+    )]
+    multipath: Vec<String>,
+    #[clap(long = "spool", value_name = "DIR")]
+    spool: Option<PathBuf>,
+    #[clap(long = "spool-limit", default_value = "1G")]
+    spool_limit: Size,
+    #[clap(long = "control", value_name = "PATH")]
+    control: Option<PathBuf>,
+    #[clap(long = "record", value_name = "FILE")]
+    record: Option<PathBuf>,
+    #[clap(long = "record-hashes-only", requires = "record")]
+    record_hashes_only: bool,
+}
+
+/// Sends everything read from `input` to the channel at `endpoint`, signing
+/// requests with `secret` if the relay requires it.
+pub async fn send<R>(
+    endpoint: &str,
+    secret: Option<&str>,
+    options: &SendOptions,
+    input: R,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    sender::send(endpoint, secret.map(Signer::new), options, input).await
+}
+
+/// Receives everything sent to the channel at `endpoint` into `output`.
+pub async fn receive<W>(
+    endpoint: &str,
+    secret: Option<&str>,
+    options: &ReceiveOptions,
+    output: W,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    receiver::receive(endpoint, secret.map(Signer::new), options, output).await
+}
+
+pub async fn main(endpoint: String, secret: Option<String>, opts: Opts) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+
+    let endpoint = net::absolute(&endpoint);
+
+    // a sender given only the relay picks a channel no one can guess
+    let given = endpoint.clone();
+    let endpoint = match Url::parse(&endpoint) {
+        Ok(base) if base.path() == "/" => {
+            let receiving = opts.recv || !opts.outputs.is_empty();
+            let sending = opts.send
+                || opts.input.is_some()
+                || opts.follow.is_some()
+                || opts.duplex
+                || !atty::is(Stream::Stdin);
+            if receiving || !sending {
+                bail!("no channel in {}, use the URL the sender printed", endpoint);
+            }
+
+            let url = new_channel(&base);
+            let command = match (opts.duplex, opts.listen) {
+                (true, false) => format!("http-pipe --duplex --listen {}", url),
+                (true, true) => format!("http-pipe --duplex {}", url),
+                (false, _) => format!("http-pipe {} > FILE", url),
+            };
+            eprintln!("On the other end, run:\n\n    {}\n", command);
+            url.to_string()
+        }
+        _ => endpoint,
+    };
+
+    if let Some(path) = &opts.record {
+        record::start(path, &given, &endpoint, opts.record_hashes_only)?;
+    }
+
+    if let Some(start_at) = opts.start_at {
+        info!("waiting until {} to start", start_at);
+        tokio::time::delay_for(start_at.until_next()).await;
+    }
+
+    let preset = opts.preset.unwrap_or_default();
+    let workers = opts.workers.or(preset.workers);
+    let flush_interval = opts.flush_interval.or(preset.flush_interval);
+    let retry_delay = opts.retry_delay.or(preset.retry_delay);
+
+    // a control socket may set a rate limit later, or lift it
+    let (rate, controlled) = (opts.rate_limit.map(|size| size.0), opts.control.is_some());
+    let rate_limit = || match (rate, controlled) {
+        (Some(rate), _) => Some(Arc::new(RateLimit::new(rate))),
+        (None, true) => Some(Arc::new(RateLimit::new(0))),
+        (None, false) => None,
+    };
+
+    let mut send_options = sender::Options {
+        stream: opts.stream,
+        stream_max_age: opts.stream_max_age.map(Duration::from_secs),
+        stream_max_bytes: opts.stream_max_bytes.map(|size| size.0),
+        receivers: opts.receivers,
+        priority: opts.priority,
+        resume: opts.resume,
+        resume_token: opts.resume_token,
+        workers,
+        packet_size: opts
+            .packet_size
+            .or(preset.packet_size)
+            .map(|size| size.0 as usize),
+        fixed_packet_size: opts.fixed_packet_size,
+        checksum: opts.checksum,
+        compress: opts.compress.or(preset.compress),
+        rate_limit: rate_limit(),
+        passphrase: opts.passphrase.clone(),
+        rekey_after: opts.rekey_after.map(|size| size.0),
+        cipher: opts.cipher,
+        kdf_rounds: opts.kdf_rounds,
+        metadata: None,
+        transport: opts.transport,
+        multipath: opts.multipath,
+        flush_interval: flush_interval.map(Duration::from_millis),
+        input_timeout: opts.input_timeout.map(Duration::from_secs),
+        retry_delay: retry_delay.map(Duration::from_secs),
+        max_retry_delay: opts.max_retry_delay.map(Duration::from_secs),
+        max_retries: opts.max_retries,
+        max_retry_time: opts.max_retry_time.map(Duration::from_secs),
+        heartbeat: opts.heartbeat.map(Duration::from_secs),
+        pool: None,
+    };
+    let mut receive_options = receiver::Options {
+        checkpoint: opts.checkpoint,
+        segments: opts.segments,
+        workers,
+        rate_limit: rate_limit(),
+        passphrase: opts.passphrase,
+        metadata: None,
+        transport: opts.transport,
+        batch: opts.batch,
+        retry_delay: retry_delay.map(Duration::from_secs),
+        max_retry_delay: opts.max_retry_delay.map(Duration::from_secs),
+        max_retries: opts.max_retries,
+        max_retry_time: opts.max_retry_time.map(Duration::from_secs),
+        heartbeat: opts.heartbeat.map(Duration::from_secs),
+        pool: None,
+    };
+
+    let _socket = match opts.control {
+        Some(path) => {
+            let channels = if opts.duplex {
+                vec![subchannel(&endpoint, "up"), subchannel(&endpoint, "down")]
+            } else {
+                vec![endpoint.clone()]
+            };
+            let rate_limits = send_options
+                .rate_limit
+                .iter()
+                .chain(&receive_options.rate_limit)
+                .cloned()
+                .collect();
+            let controls = socket::Controls::new(signer.clone(), channels, rate_limits);
+            Some(socket::listen(path, controls)?)
+        }
+        None => None,
+    };
+
+    let progress = Progress::default();
+    let counted = progress.clone();
+    let (follow, send, recv) = (opts.follow, opts.send, opts.recv);
+    let (outputs, atomic, append) = (opts.outputs, opts.atomic, opts.append);
+    let attrs = opts.attrs;
+    let (duplex, listen, metadata) = (opts.duplex, opts.listen, opts.metadata);
+    let input_given = opts.input.is_some();
+    let (input, spool, spool_limit) = (opts.input, opts.spool, opts.spool_limit.0);
+    let source = move || -> anyhow::Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let source: Box<dyn AsyncRead + Unpin + Send> = match input {
+            Some(path) => Box::new(tokio::fs::File::from_std(
+                std::fs::File::open(&path)
+                    .with_context(|| format!("failed to open {}", path.display()))?,
+            )),
+            None => Box::new(tokio::io::stdin()),
+        };
+        Ok(match spool {
+            Some(dir) => Box::new(spool::spool(source, &dir, spool_limit)?),
+            None => source,
+        })
+    };
+
+    let transfer = async move {
+        if let Some(path) = follow {
+            send_options.metadata = metadata.map(metadata::read);
+            let input = counted.count(follow::follow(path));
+            return sender::send(&endpoint, signer, &send_options, input).await;
+        }
+
+        // both ends send and receive at once, whatever stdin and stdout are
+        if duplex {
+            let (up, down) = (subchannel(&endpoint, "up"), subchannel(&endpoint, "down"));
+            let (outgoing, incoming) = if listen { (down, up) } else { (up, down) };
+            let input = counted.count(source()?);
+            let output = counted.count(tokio::io::stdout());
+
+            futures::try_join!(
+                sender::send(&outgoing, signer.clone(), &send_options, input),
+                receiver::receive(&incoming, signer, &receive_options, output),
+            )?;
+            return Ok(());
+        }
+
+        // writing elsewhere than stdout only makes sense when receiving
+        let recv = recv || !outputs.is_empty();
+        // and so does reading elsewhere than stdin when sending
+        let send = send || input_given;
+        let sending = match (
+            send,
+            recv,
+            atty::is(Stream::Stdin),
+            atty::is(Stream::Stdout),
+        ) {
+            (true, _, _, _) => true,
+            (_, true, _, _) => false,
+            (_, _, false, true) => true,
+            (_, _, true, _) => false,
+            _ => {
+                bail!("Invalid usage, please use this with a single pipe, or pass --send or --recv")
+            }
+        };
+
+        if sending {
+            send_options.metadata = metadata.map(metadata::read);
+            let input = counted.count(source()?);
+            return sender::send(&endpoint, signer, &send_options, input).await;
+        }
+
+        let writer = metadata.map(|path| {
+            let (tx, writer) = metadata::write(path);
+            receive_options.metadata = Some(tx);
+            writer
+        });
+
+        if outputs.is_empty() {
+            let output = counted.count(tokio::io::stdout());
+            receiver::receive(&endpoint, signer, &receive_options, output).await?;
+        } else {
+            let mut tee = Tee::new(outputs, atomic, append, attrs)?;
+            let output = counted.count(&mut tee);
+            receiver::receive(&endpoint, signer, &receive_options, output).await?;
+            tee.shutdown().await?;
+            tee.commit().await?;
+        }
+
+        // the writer finishes once it has been passed all records
+        drop(receive_options);
+        if let Some(writer) = writer {
+            writer.await??;
+        }
+        Ok(())
+    };
+
+    // only shown to people watching, not in redirected logs
+    if !log_enabled!(Level::Info) || !atty::is(Stream::Stderr) {
+        transfer.await
+    } else {
+        progress.show(transfer).await
+    }
+}
+
+/// A channel named with 128 random bits on the relay at `base`.
+fn new_channel(base: &Url) -> Url {
+    let mut url = base.clone();
+    url.set_path(&hex::encode(rand::random::<[u8; 16]>()));
+    url
+}
+
+/// The channel below `endpoint` named `name`, for modes using several.
+fn subchannel(endpoint: &str, name: &str) -> String {
+    format!("{}/{}", endpoint.trim_end_matches('/'), name)
+}
+
+/// Reports how lossy the path has been, unless it has not been at all.
+fn log_stats(transfer: &str, stats: &Stats) {
+    if !stats.is_empty() {
+        info!("transfer {} stats: {}", transfer, stats);
+    }
+}
+
+/// The data of the packets in a response, and the records sent along.
+#[derive(Clone)]
+pub(crate) struct Packets {
+    data: Bytes,
+    records: Vec<Record>,
+}
+
+impl Packets {
+    /// Only the empty packet ending the transfer carries nothing at all.
+    fn is_end(&self) -> bool {
+        self.data.is_empty() && self.records.is_empty()
+    }
+
+    /// Passes the records on, for receivers that asked for them.
+    fn forward_records(&mut self, metadata: Option<&UnboundedSender<Record>>) {
+        for record in self.records.drain(..) {
+            match metadata {
+                Some(tx) => {
+                    let _ = tx.send(record);
+                }
+                None => debug!("metadata at {}: {}", record.offset, record.value),
+            }
+        }
+    }
+}
+
+/// Reads the packets in a response, the first of which is packet `index`,
+/// checked against their checksum, decrypted, decompressed and split into
+/// records, or `None` if they have been corrupted on their way.
+pub(crate) async fn read_packets(
+    resp: Response,
+    index: u64,
+    decryptor: Option<&Decryptor>,
+) -> anyhow::Result<Option<Packets>> {
+    let expected = match resp.headers().get(headers::CHECKSUM) {
+        Some(expected) => Some(expected.to_str()?.to_owned()),
+        None => None,
+    };
+    let encoding = PacketEncoding::of(&resp)?;
+
+    let bytes = resp.bytes().await?;
+    encoding.decode(bytes, index, expected.as_deref(), decryptor)
+}
+
+/// How the sender has encoded the packets in a response.
+pub(crate) struct PacketEncoding {
+    compression: Option<Compression>,
+    encryption: Option<Params>,
+    framed: bool,
+}
+
+impl PacketEncoding {
+    pub(crate) fn of(resp: &Response) -> anyhow::Result<Self> {
+        let compression = match resp.headers().get(headers::COMPRESSION) {
+            Some(compression) => Some(compression.to_str()?.parse()?),
+            None => None,
+        };
+
+        Ok(PacketEncoding {
+            compression,
+            encryption: match resp.headers().get(headers::ENCRYPTION) {
+                Some(params) => Some(params.to_str()?.parse()?),
+                None => None,
+            },
+            framed: resp.headers().get(headers::RECORDS).is_some(),
+        })
+    }
+
+    /// Checks a packet against its checksum and decodes it, or `None` if it
+    /// has been corrupted on its way.
+    pub(crate) fn decode(
+        &self,
+        mut bytes: Bytes,
+        index: u64,
+        expected: Option<&str>,
+        decryptor: Option<&Decryptor>,
+    ) -> anyhow::Result<Option<Packets>> {
+        if let Some(expected) = expected {
+            if !checksum::verify(expected, &bytes)? {
+                return Ok(None);
+            }
+        }
+
+        // the end of the transfer is neither framed, compressed nor encrypted
+        if bytes.is_empty() {
+            return Ok(Some(Packets {
+                data: bytes,
+                records: Vec::new(),
+            }));
+        }
+
+        match (decryptor, self.encryption) {
+            (Some(decryptor), Some(params)) => match decryptor.decrypt(params, index, &bytes) {
+                Ok(decrypted) => bytes = decrypted.into(),
+                Err(e) => return Err(Undecryptable(e.to_string()).into()),
+            },
+            (None, Some(_)) => {
+                let reason = "the transfer is encrypted, pass --passphrase to decrypt it";
+                return Err(Undecryptable(reason.into()).into());
+            }
+            // a relay must not be able to pass off plaintext as the transfer
+            (Some(_), None) => {
+                return Err(Undecryptable("the transfer is not encrypted".into()).into())
+            }
+            (None, None) => {}
+        }
+
+        if let Some(compression) = self.compression {
+            bytes = compression.decompress(&bytes)?.into();
+        }
+
+        let (records, data) = if self.framed {
+            records::unframe(bytes)?
+        } else {
+            (Vec::new(), bytes)
+        };
+        Ok(Some(Packets { data, records }))
+    }
+}
+
+/// A client tagging its requests with the transfer and worker they belong
+/// to, so they can be correlated in the logs of the server and of proxies.
+#[derive(Clone)]
+pub(crate) struct HttpClient {
+    client: Client,
+    headers: HeaderMap,
+    /// The connections belong to other transfers as well
+    shared: bool,
+}
+
+impl HttpClient {
+    pub(crate) fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.get(url).headers(self.headers.clone())
+    }
+
+    pub(crate) fn put<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.put(url).headers(self.headers.clone())
+    }
+
+    /// Starts over with new connections, unless they are shared.
+    pub(crate) fn reconnect(&mut self) {
+        if !self.shared {
+            self.client = net::builder().build().unwrap_or_default();
+        }
+    }
+}
+
+/// Tags requests for `transfer`, sent over the connections of `pool` if
+/// given, or else over connections of their own.
+pub(crate) fn http_client(
+    pool: Option<&Client>,
+    transfer: &str,
+    worker: Option<u64>,
+) -> HttpClient {
+    let mut user_agent = format!("http-pipe/{}", env!("CARGO_PKG_VERSION"));
+    let mut tags = HeaderMap::new();
+
+    if let Ok(transfer) = HeaderValue::from_str(transfer) {
+        tags.insert(headers::TRANSFER, transfer);
+    }
+
+    if let Some(worker) = worker {
+        user_agent += &format!(" (worker {})", worker);
+        tags.insert(headers::WORKER, worker.into());
+    }
+
+    if let Ok(user_agent) = HeaderValue::from_str(&user_agent) {
+        tags.insert(USER_AGENT, user_agent);
+    }
+
+    HttpClient {
+        client: match pool {
+            Some(pool) => pool.clone(),
+            None => net::builder().build().unwrap_or_default(),
+        },
+        headers: tags,
+        shared: pool.is_some(),
+    }
+}
+
+/// The server has answered with a failure status, which the request may be
+/// retried after, no sooner than it asked if it did.
+#[derive(Debug, thiserror::Error)]
+#[error("server returned failure status: {status:?}")]
+pub(crate) struct FailureStatus {
+    pub status: StatusCode,
+    pub retry_after: Option<Duration>,
+}
 
-pub async fn main(endpoint: String) -> anyhow::Result<()> {
-    match (atty::is(Stream::Stdin), atty::is(Stream::Stdout)) {
-        (false, true) => sender::send(&endpoint).await?,
-        (true, _) => receiver::receive(&endpoint).await?,
-        _ => bail!("Invalid usage, please use this with a single pipe"),
+impl FailureStatus {
+    pub(crate) fn of(resp: &Response) -> Self {
+        let retry_after = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|secs| secs.to_str().ok()?.parse().ok())
+            .map(Duration::from_secs);
+        FailureStatus {
+            status: resp.status(),
+            retry_after,
+        }
     }
+}
+
+/// How long the relay asked to wait before retrying after `error`, if it
+/// did.
+pub(crate) fn retry_after(error: &anyhow::Error) -> Option<Duration> {
+    error.downcast_ref::<FailureStatus>()?.retry_after
+}
+
+/// The server has given up on the transfer, e.g. on a plugin's verdict.
+#[derive(Debug, thiserror::Error)]
+#[error("transfer aborted by the server: {0}")]
+pub struct Aborted(pub String);
+
+/// The input has produced nothing for longer than allowed, as if whatever
+/// writes to it had hung.
+#[derive(Debug, thiserror::Error)]
+#[error("no input for {} seconds", .0.as_secs())]
+pub struct InputTimeout(pub Duration);
+
+impl InputTimeout {
+    /// Tells a hung producer apart from other failures
+    pub const EXIT_CODE: i32 = 124;
+}
 
+/// The sender has missed its heartbeats on the relay, as if it had died,
+/// though it may yet come back.
+#[derive(Debug, thiserror::Error)]
+#[error("the sender has shown no sign of life for {} seconds", .0.as_secs())]
+pub struct SenderSilent(pub Duration);
+
+/// The receivers have missed their heartbeats on the relay, which turns the
+/// sender away rather than have it fill the channel for nobody.
+#[derive(Debug, thiserror::Error)]
+#[error("the receiver is gone, silent for {} seconds", .0.as_secs())]
+pub struct ReceiverGone(pub Duration);
+
+impl ReceiverGone {
+    fn check(resp: &Response) -> anyhow::Result<()> {
+        if resp.status() == StatusCode::GONE {
+            if let Some(silence) = resp.headers().get(headers::RECEIVER_SILENCE) {
+                let silence = Duration::from_secs(silence.to_str()?.parse()?);
+                return Err(ReceiverGone(silence).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The channel has been reset by another transfer of the same name since
+/// this one started, whose data is not to be mixed up with this one's.
+#[derive(Debug, thiserror::Error)]
+#[error("the channel has been reset by another transfer")]
+pub struct StaleSession;
+
+impl StaleSession {
+    fn check(resp: &Response) -> anyhow::Result<()> {
+        if resp.status() == StatusCode::CONFLICT && resp.headers().contains_key(headers::SESSION) {
+            return Err(StaleSession.into());
+        }
+        Ok(())
+    }
+}
+
+/// The relay takes no new channels as it is shutting down, so the transfer
+/// has to go through another one.
+#[derive(Debug, thiserror::Error)]
+#[error("the relay is shutting down")]
+pub struct ShuttingDown;
+
+impl ShuttingDown {
+    /// Whether the relay turned a request down for shutting down, rather
+    /// than e.g. a proxy in front of it being unavailable.
+    fn check(resp: &Response) -> anyhow::Result<()> {
+        if resp.status() == StatusCode::SERVICE_UNAVAILABLE
+            && resp.headers().contains_key(headers::SHUTDOWN)
+        {
+            return Err(ShuttingDown.into());
+        }
+        Ok(())
+    }
+}
+
+/// The server does not serve the request, e.g. downloads from a relay
+/// that only accepts uploads. Retrying won't help.
+#[derive(Debug, thiserror::Error)]
+#[error("forbidden by the server: {0}")]
+pub struct Forbidden(pub String);
+
+/// A packet did not match its checksum, e.g. after passing a misbehaving
+/// proxy.
+#[derive(Debug, thiserror::Error)]
+#[error("packet {0} was corrupted on its way from the server")]
+pub struct Corrupted(pub u64);
+
+/// What has been received differs from what the sender read, as told by
+/// its trailer.
+#[derive(Debug, thiserror::Error)]
+#[error("the received stream does not match what was sent: {0}")]
+pub struct Mismatch(pub String);
+
+/// Checks the whole stream received against the sender's trailer, if it
+/// sent one.
+pub(crate) fn check_trailer(
+    trailer: Option<&Trailer>,
+    received: &StreamHasher,
+) -> anyhow::Result<()> {
+    let (trailer, bytes) = match trailer.and_then(|t| Some((t, t.bytes?))) {
+        Some(trailer) => trailer,
+        None => {
+            debug!("the sender sent no trailer, the stream is not verified");
+            return Ok(());
+        }
+    };
+
+    let received = received.trailer(trailer.blake3.is_some());
+    if received.bytes != Some(bytes) {
+        let msg = format!(
+            "received {} bytes of {}",
+            received.bytes.unwrap_or(0),
+            bytes
+        );
+        return Err(Mismatch(msg).into());
+    }
+    if received.blake3 != trailer.blake3 {
+        return Err(Mismatch("the BLAKE3 hash differs".into()).into());
+    }
+
+    debug!("received {} bytes, as the sender's trailer says", bytes);
     Ok(())
 }
+
+/// Checks an encrypted stream ended at packet `end`, where the sender
+/// sealed it, and that the relay left the rest of the trailer alone.
+pub(crate) fn check_seal(
+    trailer: Option<&Trailer>,
+    decryptor: Option<&Decryptor>,
+    end: u64,
+) -> anyhow::Result<()> {
+    let decryptor = match decryptor {
+        Some(decryptor) => decryptor,
+        None => return Ok(()),
+    };
+
+    match trailer.and_then(|t| Some((t, t.seal.as_deref()?))) {
+        Some((trailer, seal)) => decryptor
+            .check_seal(seal, end, &trailer.sealed())
+            .map_err(|e| Mismatch(e.to_string()).into()),
+        None => Err(Mismatch("the sender did not seal the end of the stream".into()).into()),
+    }
+}
+
+/// Packets could not be decrypted, e.g. for a wrong passphrase.
+#[derive(Debug, thiserror::Error)]
+#[error("cannot decrypt the transfer: {0}")]
+pub struct Undecryptable(pub String);
+
+pub(crate) fn sign(
+    req: RequestBuilder,
+    signer: Option<&Signer>,
+    method: &str,
+    url: &str,
+    index: Option<u64>,
+) -> RequestBuilder {
+    let signer = match signer {
+        Some(signer) => signer,
+        None => return req,
+    };
+
+    let channel = Url::parse(url)
+        .map(|url| url.path().to_owned())
+        .unwrap_or_default();
+    let index = index.map(|i| i.to_string()).unwrap_or_default();
+    let timestamp = signature::timestamp().to_string();
+    let nonce = signature::nonce();
+    let signature = signer.sign(method, &channel, &index, &timestamp, &nonce);
+
+    req.header(headers::TIMESTAMP, timestamp)
+        .header(headers::NONCE, nonce)
+        .header(headers::SIGNATURE, signature)
+}
@@ -0,0 +1,366 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    path::PathBuf,
+    pin::Pin,
+    process::Stdio,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use futures::{ready, task::AtomicWaker};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt},
+    process::Command,
+    sync::mpsc,
+    task::JoinHandle,
+};
+use uuid::Uuid;
+
+use super::attrs::FileAttrs;
+
+/// Chunks buffered for each destination, so that a slow one does not hold
+/// the others back until it falls this far behind
+const BUFFERED_CHUNKS: usize = 64;
+
+/// Where received data is written: `-` for stdout, `|COMMAND` for the
+/// input of a shell command, or else a file.
+#[derive(Clone, Debug)]
+pub enum Output {
+    Stdout,
+    File(PathBuf),
+    Command(String),
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "" => bail!("output must not be empty"),
+            "-" => Output::Stdout,
+            _ if s.starts_with('|') => Output::Command(s[1..].trim().to_owned()),
+            _ => Output::File(s.into()),
+        })
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Output::Stdout => write!(f, "stdout"),
+            Output::File(path) => write!(f, "{}", path.display()),
+            Output::Command(command) => write!(f, "`{}`", command),
+        }
+    }
+}
+
+impl Output {
+    /// A file next to the output to write to first, so that it only ever
+    /// appears complete.
+    fn temporary(&self) -> anyhow::Result<Option<PathBuf>> {
+        let path = match self {
+            Output::File(path) => path,
+            _ => return Ok(None),
+        };
+
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("not a file: {}", path.display()))?;
+        let temporary = format!(".{}.{}.part", name.to_string_lossy(), Uuid::new_v4());
+        Ok(Some(path.with_file_name(temporary)))
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+/// Chunks written by a destination, for flushes to wait on.
+#[derive(Default)]
+struct Written {
+    chunks: AtomicU64,
+    waker: AtomicWaker,
+}
+
+async fn copy<W>(
+    mut rx: mpsc::Receiver<Bytes>,
+    mut writer: W,
+    written: &Written,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(chunk) = rx.recv().await {
+        writer.write_all(&chunk).await?;
+        writer.flush().await?;
+        written.chunks.fetch_add(1, Ordering::SeqCst);
+        written.waker.wake();
+    }
+
+    writer.shutdown().await?;
+    Ok(())
+}
+
+async fn write(
+    output: Output,
+    rx: mpsc::Receiver<Bytes>,
+    written: Arc<Written>,
+    append: bool,
+    attrs: FileAttrs,
+) -> anyhow::Result<()> {
+    let result: anyhow::Result<()> = async {
+        match &output {
+            Output::Stdout => copy(rx, tokio::io::stdout(), &written).await,
+            Output::File(path) => {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(append)
+                    .truncate(!append)
+                    .open(path)
+                    .await?;
+                attrs.created(path)?;
+                copy(rx, file, &written).await
+            }
+            Output::Command(command) => {
+                let mut child = shell(command).stdin(Stdio::piped()).spawn()?;
+                if let Some(stdin) = child.stdin.take() {
+                    copy(rx, stdin, &written).await?;
+                }
+
+                let status = child.await?;
+                if !status.success() {
+                    bail!("{}", status);
+                }
+                Ok(())
+            }
+        }
+    }
+    .await;
+
+    // flushes must not wait for a destination that is gone
+    written.chunks.store(u64::MAX, Ordering::SeqCst);
+    written.waker.wake();
+
+    result.map_err(|e| e.context(format!("failed to write to {}", output)))
+}
+
+/// A failed destination, as seen through the I/O traits.
+fn io_error<E>(e: E) -> io::Error
+where
+    E: fmt::Display,
+{
+    io::Error::new(io::ErrorKind::BrokenPipe, format!("{:#}", e))
+}
+
+struct Destination {
+    tx: Option<mpsc::Sender<Bytes>>,
+    written: Arc<Written>,
+    task: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+/// Writes everything to several outputs, each with its own buffer. Shutting
+/// it down waits for all of them to finish, failing if any did.
+pub struct Tee {
+    destinations: Vec<Destination>,
+    sent: u64,
+    /// Files written to a temporary file first, and where they go once
+    /// committed
+    renames: Vec<(PathBuf, PathBuf)>,
+    /// The files written, for their modification time to be set once
+    /// committed
+    files: Vec<PathBuf>,
+    attrs: FileAttrs,
+}
+
+impl Tee {
+    /// With `atomic`, files only appear under their name once committed,
+    /// with `append`, they are appended to rather than truncated.
+    pub fn new(
+        outputs: Vec<Output>,
+        atomic: bool,
+        append: bool,
+        attrs: FileAttrs,
+    ) -> anyhow::Result<Self> {
+        let mut renames = Vec::new();
+        let mut targets = Vec::new();
+        for output in outputs {
+            let temporary = if atomic { output.temporary()? } else { None };
+            match (temporary, output) {
+                (Some(temporary), Output::File(path)) => {
+                    renames.push((temporary.clone(), path));
+                    targets.push(Output::File(temporary));
+                }
+                (_, output) => targets.push(output),
+            }
+        }
+
+        let files = targets
+            .iter()
+            .filter_map(|output| match output {
+                Output::File(path) => Some(path.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let destinations = targets
+            .into_iter()
+            .map(|output| {
+                let (tx, rx) = mpsc::channel(BUFFERED_CHUNKS);
+                let written = Arc::new(Written::default());
+                let task = tokio::spawn(write(output, rx, written.clone(), append, attrs.clone()));
+
+                Destination {
+                    tx: Some(tx),
+                    written,
+                    task: Some(task),
+                }
+            })
+            .collect();
+
+        Ok(Tee {
+            destinations,
+            sent: 0,
+            renames,
+            files,
+            attrs,
+        })
+    }
+
+    /// Moves files written atomically into place, once the transfer has
+    /// succeeded and the tee has been shut down.
+    pub async fn commit(&mut self) -> anyhow::Result<()> {
+        for file in &self.files {
+            self.attrs.written(file)?;
+        }
+
+        for (temporary, path) in std::mem::take(&mut self.renames) {
+            tokio::fs::rename(&temporary, &path)
+                .await
+                .map_err(|e| anyhow!("failed to move {} into place: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// The error a destination failed with, once it has stopped taking data.
+    fn poll_failure(&mut self, cx: &mut Context) -> Poll<io::Error> {
+        for destination in &mut self.destinations {
+            if let Some(task) = &mut destination.task {
+                if let Poll::Ready(result) = Pin::new(task).poll(cx) {
+                    destination.task = None;
+                    let e = match result {
+                        Ok(Ok(())) => io::ErrorKind::BrokenPipe.into(),
+                        Ok(Err(e)) => io_error(e),
+                        Err(e) => io_error(e),
+                    };
+                    return Poll::Ready(e);
+                }
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Files not committed are left out, the transfer having failed.
+impl Drop for Tee {
+    fn drop(&mut self) {
+        for (temporary, _) in &self.renames {
+            let _ = std::fs::remove_file(temporary);
+        }
+    }
+}
+
+impl AsyncWrite for Tee {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pending = false;
+        for destination in &mut self.destinations {
+            let tx = match &mut destination.tx {
+                Some(tx) => tx,
+                None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            };
+
+            match tx.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(_)) => {
+                    destination.tx = None;
+                    return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+                }
+                Poll::Pending => pending = true,
+            }
+        }
+
+        // a destination that failed would never make room again
+        if pending {
+            return self.poll_failure(cx).map(Err);
+        }
+
+        let chunk = Bytes::copy_from_slice(buf);
+        for destination in &mut self.destinations {
+            if let Some(tx) = &mut destination.tx {
+                if tx.try_send(chunk.clone()).is_err() {
+                    return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+                }
+            }
+        }
+        self.sent += 1;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Waits for all destinations to have written what they have been sent.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        for destination in &self.destinations {
+            destination.written.waker.register(cx.waker());
+            if destination.written.chunks.load(Ordering::SeqCst) < self.sent {
+                return Poll::Pending;
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        for destination in &mut self.destinations {
+            destination.tx = None;
+        }
+
+        for destination in &mut self.destinations {
+            if let Some(task) = &mut destination.task {
+                let result = ready!(Pin::new(task).poll(cx));
+                destination.task = None;
+
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => return Poll::Ready(Err(io_error(e))),
+                    Err(e) => return Poll::Ready(Err(io_error(e))),
+                }
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::common::{compression::Compression, Size};
+
+/// The presets shipped with http-pipe, by name
+const PRESETS: &str = include_str!("presets.toml");
+
+/// Settings bundled for a kind of workload, each of which the matching
+/// command line flag overrides.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Preset {
+    #[serde(deserialize_with = "parsed")]
+    pub packet_size: Option<Size>,
+    pub workers: Option<u64>,
+    #[serde(deserialize_with = "parsed")]
+    pub compress: Option<Compression>,
+    /// In milliseconds
+    pub flush_interval: Option<u64>,
+    /// In seconds
+    pub retry_delay: Option<u64>,
+}
+
+/// Reads a value written as it would be on the command line.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => s.parse().map(Some).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+impl FromStr for Preset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut presets: BTreeMap<String, Preset> = toml::from_str(PRESETS)?;
+        let names = presets.keys().cloned().collect::<Vec<_>>().join(", ");
+
+        presets
+            .remove(s)
+            .ok_or_else(|| anyhow!("unknown preset: {} (available: {})", s, names))
+    }
+}
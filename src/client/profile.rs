@@ -0,0 +1,44 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context};
+use toml::Value;
+
+use crate::common::flags;
+
+/// Where profiles are read from, `~/.config/http-pipe/config.toml` unless
+/// XDG_CONFIG_HOME says otherwise.
+fn path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("http-pipe").join("config.toml"))
+}
+
+/// Adds the settings of the profile `name` to the command line `args`,
+/// each as the flag it is named after, e.g. `workers = 8` for
+/// `--workers 8`. Flags already on the command line take precedence.
+pub fn with_profile(name: &str, mut args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let path = path().ok_or_else(|| anyhow!("no home directory to read profiles from"))?;
+    let config =
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut profiles: BTreeMap<String, BTreeMap<String, Value>> =
+        toml::from_str(&config).with_context(|| format!("invalid config {}", path.display()))?;
+    let names = profiles.keys().cloned().collect::<Vec<_>>().join(", ");
+
+    let profile = profiles.remove(name).ok_or_else(|| {
+        anyhow!(
+            "unknown profile: {} (in {}: {})",
+            name,
+            path.display(),
+            names
+        )
+    })?;
+    flags::add_flags(&mut args, profile)
+        .with_context(|| format!("in profile {} of {}", name, path.display()))?;
+    Ok(args)
+}
@@ -0,0 +1,146 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{self, Either},
+    ready, Future,
+};
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::common::Size;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counts the bytes read from or written to the wrapped stream.
+#[pin_project]
+pub struct Counted<T> {
+    #[pin]
+    inner: T,
+    count: Arc<AtomicU64>,
+}
+
+impl<T: AsyncRead> AsyncRead for Counted<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = ready!(this.inner.poll_read(cx, buf))?;
+        this.count.fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for Counted<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = ready!(this.inner.poll_write(cx, buf))?;
+        this.count.fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+/// How much of the piped data has passed, shown on stderr like `pv` does.
+#[derive(Clone, Default)]
+pub struct Progress {
+    count: Arc<AtomicU64>,
+    /// How much is to pass, if known, or 0
+    total: Arc<AtomicU64>,
+}
+
+fn clock(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+impl Progress {
+    pub fn count<T>(&self, inner: T) -> Counted<T> {
+        Counted {
+            inner,
+            count: self.count.clone(),
+        }
+    }
+
+    /// Shows how far along the transfer is and when it should be done,
+    /// e.g. once the size of a file has been received.
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    fn print(&self, elapsed: Duration, rate: f64) {
+        let count = self.count.load(Ordering::Relaxed);
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 {
+            eprint!(
+                "\r{}  {}/s  {}\x1b[K",
+                Size(count),
+                Size(rate as u64),
+                clock(elapsed.as_secs())
+            );
+            return;
+        }
+
+        let eta = match total.saturating_sub(count) {
+            0 => clock(0),
+            _ if rate < 1.0 => "-:--:--".to_owned(),
+            left => clock((left as f64 / rate) as u64),
+        };
+        eprint!(
+            "\r{}/{} ({}%)  {}/s  {}  ETA {}\x1b[K",
+            Size(count),
+            Size(total),
+            count.min(total) * 100 / total,
+            Size(rate as u64),
+            clock(elapsed.as_secs()),
+            eta
+        );
+    }
+
+    /// Refreshes the display with the current throughput until `future`
+    /// completes, then leaves a line with the average.
+    pub async fn show<F: Future>(&self, future: F) -> F::Output {
+        let start = Instant::now();
+        let display = async {
+            let mut last = 0;
+            loop {
+                tokio::time::delay_for(REFRESH_INTERVAL).await;
+                let count = self.count.load(Ordering::Relaxed);
+                self.print(
+                    start.elapsed(),
+                    (count - last) as f64 / REFRESH_INTERVAL.as_secs_f64(),
+                );
+                last = count;
+            }
+        };
+
+        futures::pin_mut!(future, display);
+        let output = match future::select(future, display).await {
+            Either::Left((output, _)) => output,
+            Either::Right((_, future)) => future.await,
+        };
+
+        let elapsed = start.elapsed();
+        let average = self.count.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64().max(0.001);
+        self.print(elapsed, average);
+        eprintln!();
+
+        output
+    }
+}
@@ -1,28 +1,71 @@
-use std::time::Duration;
+use std::{
+    convert::Infallible,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::bail;
-use bytes::Bytes;
-use log::debug;
-use reqwest::Client;
+use bytes::BytesMut;
+use futures::future::{self, Either};
+use log::{debug, info, warn};
+use reqwest::{Client, StatusCode};
 use tokio::{
-    io::AsyncWriteExt,
-    sync::mpsc::{self, Receiver, Sender},
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc::{self, Receiver, Sender, UnboundedSender},
 };
+use uuid::Uuid;
 
-use crate::common::headers;
+use super::{
+    check_seal, check_trailer, http_client, log_stats, net, read_packets, record, retry_after,
+    segments, sign,
+    transport::{Connection, Transport},
+    Aborted, Corrupted, FailureStatus, Forbidden, PacketEncoding, Packets, SenderSilent,
+    ShuttingDown, StaleSession, Undecryptable,
+};
+use crate::common::{
+    encryption::Decryptor, headers, log_retry, records::Record, signature::Signer, unframe_packet,
+    Backoff, RateLimit, Stats, Status, StreamHasher, Trailer,
+};
 
-const WORKER_NUM: u64 = 4;
+const DEFAULT_WORKER_NUM: u64 = 4;
+const DEFAULT_BATCH: u64 = 16;
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(10);
 
 struct Worker {
-    tx: Sender<Bytes>,
+    tx: Sender<anyhow::Result<(u64, Packets)>>,
     index: u64,
     worker_num: u64,
     url: String,
-    client: Client,
+    conn: Connection,
+    signer: Option<Signer>,
+    committed: Arc<AtomicU64>,
+    stats: Arc<Stats>,
+    rate_limit: Option<Arc<RateLimit>>,
+    decryptor: Option<Arc<Decryptor>>,
+    batch: u64,
+    backoff: Backoff,
+    /// Where the sender's trailer goes, for whichever worker gets it
+    trailer: Arc<Mutex<Option<Trailer>>>,
+    /// Of the channel, as first answered by the relay
+    session: Arc<Mutex<Option<String>>>,
+    ack: Option<u64>,
 }
 
 impl Worker {
-    fn new(url: &str, index: u64, worker_num: u64) -> (Receiver<Bytes>, Worker) {
+    fn new(
+        url: &str,
+        conn: Connection,
+        index: u64,
+        worker_num: u64,
+        signer: Option<Signer>,
+        committed: Arc<AtomicU64>,
+        stats: Arc<Stats>,
+    ) -> (Receiver<anyhow::Result<(u64, Packets)>>, Worker) {
         let (tx, rx) = mpsc::channel(1);
 
         (
@@ -32,93 +75,586 @@ impl Worker {
                 index,
                 worker_num,
                 url: url.into(),
-                client: Client::new(),
+                conn,
+                signer,
+                committed,
+                stats,
+                rate_limit: None,
+                decryptor: None,
+                batch: 1,
+                backoff: Backoff::new(None, None),
+                trailer: Arc::new(Mutex::new(None)),
+                session: Arc::new(Mutex::new(None)),
+                ack: None,
             },
         )
     }
 
     async fn run(mut self) {
-        let mut ack = None;
-        'l: loop {
-            loop {
-                match self.receive(ack).await {
-                    Ok(bytes) => {
-                        if bytes.len() == 0 {
-                            break 'l;
-                        }
+        loop {
+            match self.receive().await {
+                Ok(true) => break,
+                Ok(false) => self.backoff.reset(),
+
+                Err(e)
+                    if e.is::<Aborted>()
+                        || e.is::<Corrupted>()
+                        || e.is::<Forbidden>()
+                        || e.is::<ShuttingDown>()
+                        || e.is::<StaleSession>()
+                        || e.is::<Undecryptable>() =>
+                {
+                    let _ = self.tx.send(Err(e)).await;
+                    break;
+                }
 
-                        ack = Some(self.index);
-                        self.index += self.worker_num;
+                Err(e) => {
+                    log_retry("GET", &e);
+                    self.stats.retry();
+                    // reconnect, resolving the relay's name again
+                    net::race(&self.url).await;
+                    self.conn.reconnect();
+                    if let Err(e) = self.backoff.wait(retry_after(&e)).await {
+                        let _ = self.tx.send(Err(e.into())).await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Passes on the packet at the worker's index, returning whether it is
+    /// the end of the transfer.
+    async fn deliver(&mut self, packets: Packets) -> bool {
+        if packets.is_end() {
+            return true;
+        }
 
-                        if let Err(_) = self.tx.send(bytes).await {
-                            panic!("receiver closed before sender");
+        if let Some(limit) = &self.rate_limit {
+            limit.take(packets.data.len() as u64).await;
+        }
+
+        let index = self.index;
+        self.ack = Some(index);
+        self.index += self.worker_num;
+
+        if let Err(_) = self.tx.send(Ok((index, packets))).await {
+            panic!("receiver closed before sender");
+        }
+
+        false
+    }
+
+    /// Receives the packets of a single response, returning whether the
+    /// end of the transfer has been reached.
+    async fn receive(&mut self) -> anyhow::Result<bool> {
+        loop {
+            let mut r = sign(
+                self.conn.client().get(&self.url),
+                self.signer.as_ref(),
+                "GET",
+                &self.url,
+                Some(self.index),
+            );
+
+            if let Some(ack) = self.ack {
+                r = r.header(headers::ACK, ack);
+            }
+
+            if let Some(session) = self.session.lock().unwrap().clone() {
+                r = r.header(headers::SESSION, session);
+            }
+
+            // lets the sender slow down instead of filling the server
+            let rate = self.rate_limit.as_ref().map_or(0, |limit| limit.rate());
+            if rate > 0 {
+                r = r.header(headers::MAX_RATE, rate);
+            }
+
+            // the packets this worker takes next can follow in the response
+            if self.batch > 1 {
+                r = r
+                    .header(headers::PACKETS, self.batch)
+                    .header(headers::STRIDE, self.worker_num);
+            }
+
+            let r = r
+                .header(headers::INDEX, self.index)
+                .header(headers::COMMITTED, self.committed.load(Ordering::SeqCst));
+            let mut resp = self.conn.send(r).await?;
+            ShuttingDown::check(&resp)?;
+            StaleSession::check(&resp)?;
+
+            let status = resp.status();
+            if status == StatusCode::PRECONDITION_FAILED {
+                self.stats.precondition_failed();
+            }
+
+            if status == StatusCode::GONE {
+                self.stats.gone();
+                // workers that skipped dropped packets may be past the end
+                if let Some(end) = resp.headers().get(headers::COMPLETE) {
+                    if self.index > end.to_str()?.parse()? {
+                        return Ok(true);
+                    }
+                }
+                if let Some(oldest) = resp.headers().get(headers::OLDEST) {
+                    let oldest = oldest.to_str()?.parse()?;
+                    if oldest > self.index {
+                        warn!(
+                            "packets before {} have been dropped from the stream",
+                            oldest
+                        );
+                        while self.index < oldest {
+                            self.index += self.worker_num;
                         }
+                        continue;
+                    }
+                }
+            }
 
-                        break;
+            if status == StatusCode::UNPROCESSABLE_ENTITY {
+                return Err(Aborted(resp.text().await?).into());
+            }
+
+            // retried, in case the sender comes back
+            if let (StatusCode::GATEWAY_TIMEOUT, Some(silence)) =
+                (status, resp.headers().get(headers::SENDER_SILENCE))
+            {
+                let silence = Duration::from_secs(silence.to_str()?.parse()?);
+                return Err(SenderSilent(silence).into());
+            }
+
+            // e.g. a broadcast channel that already has all its receivers
+            if status == StatusCode::FORBIDDEN
+                || status == StatusCode::UNAUTHORIZED
+                || status == StatusCode::CONFLICT
+            {
+                return Err(Forbidden(resp.text().await?).into());
+            }
+
+            if !status.is_success() {
+                return Err(FailureStatus::of(&resp).into());
+            }
+
+            // a channel re-created by another transfer is told apart from
+            // this one by its session
+            if let Some(session) = resp.headers().get(headers::SESSION) {
+                let mut pinned = self.session.lock().unwrap();
+                if pinned.is_none() {
+                    *pinned = Some(session.to_str()?.to_owned());
+                }
+            }
+
+            // sent along with the end of the stream
+            if let Some(trailer) = resp.headers().get(headers::TRAILER) {
+                *self.trailer.lock().unwrap() = Some(trailer.to_str()?.parse()?);
+            }
+
+            // a relay not streaming packets sends a single one as the body
+            if resp.headers().get(headers::PACKETS).is_none() {
+                return match read_packets(resp, self.index, self.decryptor.as_deref()).await? {
+                    Some(packets) => Ok(self.deliver(packets).await),
+                    None => Err(Corrupted(self.index).into()),
+                };
+            }
+
+            let encoding = PacketEncoding::of(&resp)?;
+            let mut buf = BytesMut::new();
+            loop {
+                while let Some((index, checksum, data)) = unframe_packet(&mut buf)? {
+                    if index != self.index {
+                        bail!("expected packet {}, got {}", self.index, index);
                     }
 
-                    Err(e) => {
-                        debug!("http error: {}", e);
-                        tokio::time::delay_for(Duration::from_secs(3)).await;
+                    let decryptor = self.decryptor.as_deref();
+                    let packets = match encoding.decode(data, index, Some(&checksum), decryptor)? {
+                        Some(packets) => packets,
+                        None => return Err(Corrupted(self.index).into()),
+                    };
+                    if self.deliver(packets).await {
+                        return Ok(true);
                     }
                 }
+
+                match resp.chunk().await? {
+                    Some(chunk) => buf.extend_from_slice(&chunk),
+                    None if buf.is_empty() => return Ok(false),
+                    None => bail!("response ended in the middle of packet {}", self.index),
+                }
             }
         }
     }
+}
 
-    async fn receive(&mut self, ack: Option<u64>) -> anyhow::Result<Bytes> {
-        let mut r = self.client.get(&self.url);
+#[derive(Default)]
+pub struct Options {
+    /// Periodically save the index to continue from after a restart
+    pub checkpoint: Option<PathBuf>,
+    /// Download a finished channel in this many parallel segments
+    pub segments: Option<u64>,
+    /// Number of parallel connections, 4 by default
+    pub workers: Option<u64>,
+    /// Limit downloads to a number of bytes per second over all workers
+    pub rate_limit: Option<Arc<RateLimit>>,
+    /// Decrypt packets encrypted with this passphrase
+    pub passphrase: Option<String>,
+    /// Pass the metadata records sent along with the data to this
+    pub metadata: Option<UnboundedSender<Record>>,
+    /// How workers receive packets, a request for each by default
+    pub transport: Option<Transport>,
+    /// Packets a worker asks for at once, streamed in a single response
+    pub batch: Option<u64>,
+    /// Wait this long before retrying a failed request, twice as long after
+    /// each failure in a row, 500ms by default
+    pub retry_delay: Option<Duration>,
+    /// Wait at most this long between retries, 30s by default
+    pub max_retry_delay: Option<Duration>,
+    /// Fail once a request has been retried this many times in a row
+    pub max_retries: Option<u32>,
+    /// Fail once a request has kept failing for this long
+    pub max_retry_time: Option<Duration>,
+    /// Tell the relay the receiver is alive every this long, for the sender
+    /// to give up once it is gone, 10s by default, never if zero
+    pub heartbeat: Option<Duration>,
+    /// Make requests over the connections of this client, shared with
+    /// other transfers, rather than over connections of its own
+    pub pool: Option<Client>,
+}
 
-        if let Some(ack) = ack {
-            r = r.header(headers::ACK, ack);
+impl Options {
+    /// How requests are retried.
+    pub(crate) fn backoff(&self) -> Backoff {
+        Backoff::new(self.retry_delay, self.max_retry_delay)
+            .budget(self.max_retries, self.max_retry_time)
+    }
+}
+
+async fn save_checkpoint(path: &Path, index: u64) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    tokio::fs::write(&tmp, index.to_string()).await?;
+    tokio::fs::rename(&tmp, path).await?;
+
+    Ok(())
+}
+
+/// Asks the relay how many workers the sender uses, waiting for it to
+/// create the channel, or `None` from relays that do not tell.
+async fn sender_workers(
+    url: &str,
+    transfer: &str,
+    signer: &Option<Signer>,
+    pool: Option<&Client>,
+    mut backoff: Backoff,
+) -> anyhow::Result<Option<u64>> {
+    loop {
+        let resp = record::send(
+            sign(
+                http_client(pool, transfer, None).get(url),
+                signer.as_ref(),
+                "GET",
+                url,
+                None,
+            )
+            .header(headers::STATUS, 1),
+        )
+        .await;
+
+        match resp {
+            Ok(resp) if resp.status().is_success() => {
+                if let Some(workers) = resp.headers().get(headers::WORKERS) {
+                    return Ok(Some(workers.to_str()?.parse()?));
+                }
+                let status: Status = match resp.headers().get(headers::STATUS) {
+                    Some(status) => status.to_str()?.parse()?,
+                    None => return Ok(None),
+                };
+                if status != Status::Unknown {
+                    return Ok(None);
+                }
+            }
+            // the workers find out what is wrong
+            Ok(_) => return Ok(None),
+            Err(e) => {
+                log_retry("GET", e);
+                backoff.wait(None).await?;
+                continue;
+            }
         }
 
-        let resp = r.header(headers::INDEX, self.index).send().await?;
+        // waiting for the sender is no failure
+        tokio::time::delay_for(backoff.delay()).await;
+        backoff.reset();
+    }
+}
+
+/// Tells the relay the receiver is alive, even while it is busy writing
+/// out what it has taken, if it is to.
+async fn heartbeats(
+    url: &str,
+    transfer: &str,
+    signer: &Option<Signer>,
+    options: &Options,
+    session: &Mutex<Option<String>>,
+) -> Infallible {
+    let interval = Some(options.heartbeat.unwrap_or(DEFAULT_HEARTBEAT))
+        .filter(|heartbeat| heartbeat.as_secs() > 0);
+    let interval = match interval {
+        Some(interval) => interval,
+        None => return future::pending().await,
+    };
 
-        let status = resp.status();
-        if !status.is_success() {
-            // prevent poisoned connection from being reused
-            self.client = Client::new();
-            bail!("server returned failure status: {:?}", status);
+    let client = http_client(options.pool.as_ref(), transfer, None);
+    loop {
+        let mut req = sign(client.get(url), signer.as_ref(), "GET", url, None)
+            .header(headers::HEARTBEAT, interval.as_secs());
+        if let Some(session) = session.lock().unwrap().clone() {
+            req = req.header(headers::SESSION, session);
+        }
+        // missing one is harmless, and the workers report a relay that is
+        // gone
+        match record::send(req).await {
+            Ok(resp) if !resp.status().is_success() => {
+                debug!("heartbeat failed: {}", resp.status())
+            }
+            Err(e) => debug!("heartbeat failed: {}", e),
+            Ok(_) => {}
         }
 
-        Ok(resp.bytes().await?)
+        tokio::time::delay_for(interval).await;
     }
 }
 
-pub async fn receive(url: &str) -> anyhow::Result<()> {
-    let mut receivers: Vec<_> = (0..WORKER_NUM)
+/// Receives packets from `next` on until the end of the stream, returning
+/// the index after the last one, and the session of the channel.
+async fn receive_packets<W>(
+    url: &str,
+    transfer: &str,
+    signer: &Option<Signer>,
+    options: &Options,
+    mut next: u64,
+    stats: &Arc<Stats>,
+    output: &mut W,
+) -> anyhow::Result<(u64, Option<String>)>
+where
+    W: AsyncWrite + Unpin,
+{
+    let backoff = options.backoff();
+    let workers = match (
+        options.workers,
+        sender_workers(
+            url,
+            transfer,
+            signer,
+            options.pool.as_ref(),
+            backoff.clone(),
+        )
+        .await?,
+    ) {
+        (Some(workers), Some(sender)) if workers != sender => {
+            warn!(
+                "the sender uses {} workers and this receiver {}, leave out --workers to match",
+                sender, workers
+            );
+            workers
+        }
+        (Some(workers), _) | (None, Some(workers)) => workers,
+        (None, None) => DEFAULT_WORKER_NUM,
+    };
+    let committed = Arc::new(AtomicU64::new(next));
+    let trailer = Arc::new(Mutex::new(None));
+    let session = Arc::new(Mutex::new(None));
+    let rate_limit = options.rate_limit.clone();
+    let decryptor = options
+        .passphrase
+        .as_deref()
+        .map(|passphrase| Arc::new(Decryptor::new(passphrase)));
+    let mut round = next / workers;
+    let mut receivers: Vec<_> = (0..workers)
         .map(|i| {
-            let (rx, worker) = Worker::new(url, i, WORKER_NUM);
+            let mut index = round * workers + i;
+            if index < next {
+                index += workers;
+            }
+
+            let conn = Connection::new(
+                options.transport,
+                options.pool.as_ref(),
+                transfer,
+                i,
+                signer.clone(),
+            );
+            let (rx, mut worker) = Worker::new(
+                url,
+                conn,
+                index,
+                workers,
+                signer.clone(),
+                committed.clone(),
+                stats.clone(),
+            );
+            worker.rate_limit = rate_limit.clone();
+            worker.decryptor = decryptor.clone();
+            worker.batch = options.batch.unwrap_or(DEFAULT_BATCH);
+            worker.backoff = backoff.clone();
+            worker.trailer = trailer.clone();
+            worker.session = session.clone();
 
             tokio::spawn(worker.run());
             rx
         })
         .collect();
 
-    let mut stdout = tokio::io::stdout();
+    // only a stream received from its start can be checked as a whole
+    let mut hasher = match next {
+        0 => Some(StreamHasher::default()),
+        _ => None,
+    };
+    let mut pending = vec![None; receivers.len()];
+    let mut last_checkpoint = Instant::now();
+
+    let receiving = async {
+        'l: loop {
+            for (r, p) in receivers.iter_mut().zip(pending.iter_mut()) {
+                if p.is_none() {
+                    match r.recv().await {
+                        Some(packet) => *p = Some(packet?),
+                        None => break 'l,
+                    }
+                }
+
+                // workers that skipped dropped packets wait for their round
+                if let Some((index, _)) = p {
+                    if *index / workers > round {
+                        continue;
+                    }
+                }
 
-    'l: loop {
-        for r in &mut receivers {
-            if let Some(bytes) = r.recv().await {
-                stdout.write_all(&bytes).await?;
-            } else {
-                break 'l;
+                if let Some((index, mut packets)) = p.take() {
+                    output.write_all(&packets.data).await?;
+                    if let Some(hasher) = &mut hasher {
+                        hasher.update(&packets.data);
+                    }
+                    packets.forward_records(options.metadata.as_ref());
+                    next = index + 1;
+                    committed.store(next, Ordering::SeqCst);
+                }
             }
+
+            round += 1;
+
+            if let Some(path) = &options.checkpoint {
+                if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                    output.flush().await?;
+                    save_checkpoint(path, next).await?;
+                    last_checkpoint = Instant::now();
+                }
+            }
+        }
+
+        let trailer = trailer.lock().unwrap();
+        if let Some(hasher) = &hasher {
+            check_trailer(trailer.as_ref(), hasher)?;
+        }
+        check_seal(trailer.as_ref(), decryptor.as_deref(), next)?;
+        Ok::<_, anyhow::Error>(next)
+    };
+
+    let heartbeats = heartbeats(url, transfer, signer, options, &session);
+    futures::pin_mut!(receiving, heartbeats);
+    let next = match future::select(receiving, heartbeats).await {
+        Either::Left((result, _)) => result?,
+        Either::Right((never, _)) => match never {},
+    };
+
+    let session = session.lock().unwrap().clone();
+    Ok((next, session))
+}
+
+pub async fn receive<W>(
+    url: &str,
+    signer: Option<Signer>,
+    options: &Options,
+    mut output: W,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if options.workers == Some(0) {
+        bail!("at least one worker is required");
+    }
+
+    let mut next = match &options.checkpoint {
+        Some(path) => match tokio::fs::read_to_string(path).await {
+            Ok(index) => index.trim().parse()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        },
+        None => 0,
+    };
+
+    let url = &net::endpoint(url).await?;
+    let transfer = Uuid::new_v4().to_string();
+    debug!("transfer {} receiving from {}", transfer, url);
+    net::race(url).await;
+
+    let mut downloaded = None;
+    if let Some(n) = options.segments {
+        downloaded = segments::receive(
+            url,
+            &transfer,
+            signer.as_ref(),
+            options,
+            next,
+            n,
+            &mut output,
+        )
+        .await?;
+        if downloaded.is_none() {
+            info!("channel is not complete, receiving packets as they arrive");
         }
     }
 
+    let (end, session) = match downloaded {
+        Some(end) => (end, None),
+        None => {
+            let stats = Arc::new(Stats::default());
+            let result =
+                receive_packets(url, &transfer, &signer, options, next, &stats, &mut output).await;
+            log_stats(&transfer, &stats);
+            result?
+        }
+    };
+    next = end;
+
+    output.flush().await?;
+
+    if let Some(path) = &options.checkpoint {
+        save_checkpoint(path, next).await?;
+    }
+
+    // a late retry must not finish a channel re-created by another transfer
+    let mut backoff = options.backoff();
     loop {
-        match Client::new()
-            .get(url)
-            .header(headers::RESET, 0)
-            .send()
-            .await
-        {
+        let mut req = sign(
+            http_client(options.pool.as_ref(), &transfer, None).get(url),
+            signer.as_ref(),
+            "GET",
+            url,
+            None,
+        )
+        .header(headers::RESET, 0);
+        if let Some(session) = &session {
+            req = req.header(headers::SESSION, session);
+        }
+
+        match record::send(req).await {
             Ok(_) => break,
             Err(e) => {
-                debug!("http error: {}", e);
-                tokio::time::delay_for(Duration::from_secs(3)).await;
+                log_retry("GET", e);
+                backoff.wait(None).await?;
             }
         }
     }
@@ -0,0 +1,470 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+
+use actix_web::{http::StatusCode, web, App, HttpRequest, HttpResponse, HttpServer};
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use clap::Clap;
+use futures::StreamExt;
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use reqwest::{header::HeaderMap, Request, RequestBuilder, Response, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio_tungstenite::tungstenite::http;
+
+use crate::common::headers;
+
+/// Headers telling apart requests for the same URL, e.g. for different
+/// packets, which replayed requests are matched on
+const KEY_HEADERS: &[&str] = &[
+    headers::INDEX,
+    headers::RANGE,
+    headers::WORKER,
+    headers::RESET,
+    headers::STATUS,
+    headers::COMPLETE,
+];
+
+/// Options left out of recordings, along with their values
+const OMITTED: &[&str] = &[
+    "--record",
+    "--secret",
+    "--passphrase",
+    "--token",
+    "--claim",
+    "--channel-secret",
+];
+
+static RECORDER: OnceCell<Recorder> = OnceCell::new();
+
+struct Recorder {
+    file: Mutex<File>,
+    started: Instant,
+    hashes_only: bool,
+}
+
+/// The first line of a recording, telling how the client was run.
+#[derive(Serialize, Deserialize)]
+struct Preamble {
+    version: String,
+    /// URL of the channel, which the arguments name
+    endpoint: String,
+    args: Vec<String>,
+}
+
+/// The body of a request or response.
+#[derive(Serialize, Deserialize)]
+struct Payload {
+    size: usize,
+    sha256: String,
+    /// Base64, unless only hashes are recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+}
+
+impl Payload {
+    fn new(data: &[u8], hashes_only: bool) -> Self {
+        Payload {
+            size: data.len(),
+            sha256: hex::encode(Sha256::digest(data)),
+            data: if hashes_only || data.is_empty() {
+                None
+            } else {
+                Some(base64::encode(data))
+            },
+        }
+    }
+
+    /// The recorded data, or as many zeros if only its hash was recorded.
+    fn bytes(&self) -> Bytes {
+        self.data
+            .as_deref()
+            .and_then(|data| base64::decode(data).ok())
+            .unwrap_or_else(|| vec![0; self.size])
+            .into()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Response {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Payload,
+    },
+    /// The request failed before a response came
+    Error(String),
+}
+
+/// A request and what came of it.
+#[derive(Serialize, Deserialize)]
+struct Exchange {
+    /// Milliseconds since the recording started
+    at: u64,
+    /// Milliseconds until the whole response had been read
+    took: u64,
+    method: String,
+    /// Path and query, the relay being another one on replay
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Payload,
+    outcome: Outcome,
+}
+
+impl Exchange {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+        .collect()
+}
+
+fn path_and_query(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
+    }
+}
+
+/// The URL of a proxy, less any user and password in it.
+fn without_credentials(proxy: &str) -> String {
+    match Url::parse(proxy) {
+        Ok(mut url) => {
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url.to_string()
+        }
+        Err(_) => proxy.to_owned(),
+    }
+}
+
+/// Records every request made from then on, and its response, to `path`,
+/// along with the arguments the client was run with. `given` is the
+/// endpoint as given on the command line, and `endpoint` the URL of the
+/// channel it has come to.
+pub(crate) fn start(
+    path: &Path,
+    given: &str,
+    endpoint: &str,
+    hashes_only: bool,
+) -> anyhow::Result<()> {
+    let mut args = Vec::new();
+    let mut given_args = std::env::args().skip(1);
+    while let Some(arg) = given_args.next() {
+        let name = arg.split('=').next().unwrap_or_default();
+        if OMITTED.contains(&name) {
+            if !arg.contains('=') {
+                given_args.next();
+            }
+        } else if name == "--proxy" {
+            let proxy = match arg.find('=') {
+                Some(i) => arg[i + 1..].to_owned(),
+                None => given_args.next().unwrap_or_default(),
+            };
+            args.push(format!("--proxy={}", without_credentials(&proxy)));
+        } else if arg == given {
+            args.push(endpoint.to_owned());
+        } else if arg != "--record-hashes-only" {
+            args.push(arg);
+        }
+    }
+
+    let mut file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let preamble = Preamble {
+        version: env!("CARGO_PKG_VERSION").into(),
+        endpoint: endpoint.into(),
+        args,
+    };
+    writeln!(file, "{}", serde_json::to_string(&preamble)?)?;
+
+    let _ = RECORDER.set(Recorder {
+        file: Mutex::new(file),
+        started: Instant::now(),
+        hashes_only,
+    });
+    Ok(())
+}
+
+/// Writes down `request` and its outcome, if recording.
+pub(crate) fn note(
+    request: &Request,
+    started: Instant,
+    outcome: Result<&http::Response<Bytes>, String>,
+) {
+    let recorder = match RECORDER.get() {
+        Some(recorder) => recorder,
+        None => return,
+    };
+
+    let body = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .unwrap_or_default();
+    let exchange = Exchange {
+        at: started.duration_since(recorder.started).as_millis() as u64,
+        took: started.elapsed().as_millis() as u64,
+        method: request.method().to_string(),
+        url: path_and_query(request.url()),
+        headers: header_pairs(request.headers()),
+        body: Payload::new(body, recorder.hashes_only),
+        outcome: match outcome {
+            Ok(resp) => Outcome::Response {
+                status: resp.status().as_u16(),
+                headers: header_pairs(resp.headers()),
+                body: Payload::new(resp.body(), recorder.hashes_only),
+            },
+            Err(e) => Outcome::Error(e),
+        },
+    };
+
+    let line = match serde_json::to_string(&exchange) {
+        Ok(line) => line,
+        Err(e) => return warn!("cannot record request: {}", e),
+    };
+    if let Err(e) = writeln!(recorder.file.lock().unwrap(), "{}", line) {
+        warn!("cannot record request: {}", e);
+    }
+}
+
+/// Sends `req`, recording it if asked to. Responses being recorded are
+/// read whole before they are returned.
+pub(crate) async fn send(req: RequestBuilder) -> reqwest::Result<Response> {
+    if RECORDER.get().is_none() {
+        return req.send().await;
+    }
+
+    let request = req.try_clone().and_then(|req| req.build().ok());
+    let started = Instant::now();
+    let result = async {
+        let resp = req.send().await?;
+        let mut buffered = http::Response::builder().status(resp.status());
+        if let Some(headers) = buffered.headers_mut() {
+            *headers = resp.headers().clone();
+        }
+        let body = resp.bytes().await?;
+        Ok::<_, reqwest::Error>(buffered.body(body).expect("status and headers are valid"))
+    }
+    .await;
+
+    if let Some(request) = &request {
+        note(
+            request,
+            started,
+            result.as_ref().map_err(|e: &reqwest::Error| e.to_string()),
+        );
+    }
+    result.map(Response::from)
+}
+
+#[derive(Clap)]
+pub struct ReplayOpts {
+    /// Recording made with --record
+    log: PathBuf,
+    /// Answer at once rather than taking as long as the relay did
+    #[clap(long = "fast")]
+    fast: bool,
+}
+
+/// Answers requests as the relay did in a recording.
+struct Mock {
+    exchanges: Vec<Exchange>,
+    replayed: Mutex<Vec<bool>>,
+    /// Requests that went another way than recorded
+    diverged: AtomicUsize,
+    fast: bool,
+}
+
+impl Mock {
+    /// The recorded exchange to answer a request with: the first not yet
+    /// replayed of those for the same URL and key headers, or else the last
+    /// one again.
+    fn find(&self, method: &str, url: &str, key: &[Option<&str>]) -> Option<&Exchange> {
+        let matches = |e: &Exchange| {
+            e.method == method
+                && e.url == url
+                && KEY_HEADERS.iter().zip(key).all(|(h, v)| e.header(h) == *v)
+        };
+
+        let mut replayed = self.replayed.lock().unwrap();
+        let unreplayed =
+            (0..self.exchanges.len()).find(|&i| !replayed[i] && matches(&self.exchanges[i]));
+        if let Some(i) = unreplayed {
+            replayed[i] = true;
+            return Some(&self.exchanges[i]);
+        }
+        // retried more often than when recorded
+        if let Some(exchange) = self.exchanges.iter().rev().find(|e| matches(e)) {
+            return Some(exchange);
+        }
+
+        self.diverged.fetch_add(1, Ordering::Relaxed);
+        let key: Vec<_> = KEY_HEADERS
+            .iter()
+            .zip(key)
+            .filter_map(|(h, v)| Some(format!("{}: {}", h, (*v)?)))
+            .collect();
+        warn!(
+            "{} {} ({}) diverges from the recording",
+            method,
+            url,
+            key.join(", ")
+        );
+        None
+    }
+}
+
+async fn answer(req: HttpRequest, mut body: web::Payload, mock: web::Data<Mock>) -> HttpResponse {
+    // bodies are recorded, not checked, but are read for the client to
+    // have sent them whole
+    while let Some(Ok(_)) = body.next().await {}
+
+    let url = req
+        .uri()
+        .path_and_query()
+        .map(|url| url.as_str())
+        .unwrap_or_else(|| req.path());
+    let key: Vec<_> = KEY_HEADERS
+        .iter()
+        .map(|h| req.headers().get(*h).and_then(|v| v.to_str().ok()))
+        .collect();
+
+    let exchange = match mock.find(req.method().as_str(), url, &key) {
+        Some(exchange) => exchange,
+        None => return HttpResponse::NotFound().body("no such request in the recording"),
+    };
+    if !mock.fast {
+        tokio::time::delay_for(Duration::from_millis(exchange.took)).await;
+    }
+
+    match &exchange.outcome {
+        Outcome::Response {
+            status,
+            headers,
+            body,
+        } => {
+            let status = StatusCode::from_u16(*status).unwrap_or(StatusCode::OK);
+            let mut resp = HttpResponse::build(status);
+            for (name, value) in headers {
+                if !name.eq_ignore_ascii_case("content-length")
+                    && !name.eq_ignore_ascii_case("transfer-encoding")
+                {
+                    resp.header(name.as_str(), value.as_str());
+                }
+            }
+            resp.body(body.bytes())
+        }
+        // a failed connection has the client retry much like a bad gateway
+        Outcome::Error(e) => HttpResponse::BadGateway().body(e.clone()),
+    }
+}
+
+/// A recording being played back.
+pub struct Replay {
+    args: Vec<String>,
+    mock: Arc<Mock>,
+}
+
+impl Replay {
+    /// Starts answering the requests of a recording on a local port. The
+    /// client is then to be run with `args()`, which point it there.
+    pub fn start(opts: &ReplayOpts) -> anyhow::Result<Self> {
+        let file = File::open(&opts.log)
+            .with_context(|| format!("failed to open {}", opts.log.display()))?;
+        let mut lines = BufReader::new(file).lines();
+        let preamble: Preamble = match lines.next() {
+            Some(line) => serde_json::from_str(&line?).context("not a recording")?,
+            None => bail!("{} is empty", opts.log.display()),
+        };
+        if preamble.version != env!("CARGO_PKG_VERSION") {
+            warn!(
+                "recorded with version {}, replaying with {}",
+                preamble.version,
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+        let exchanges = lines
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<anyhow::Result<Vec<Exchange>>>()?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!(
+            "http://{}{}",
+            listener.local_addr()?,
+            path_and_query(&Url::parse(&preamble.endpoint)?)
+        );
+
+        let recorded = preamble.endpoint;
+        let mut args = vec![env!("CARGO_PKG_NAME").to_owned()];
+        args.extend(preamble.args.into_iter().map(|arg| {
+            if arg == recorded {
+                endpoint.clone()
+            } else {
+                arg
+            }
+        }));
+
+        let mock = Arc::new(Mock {
+            replayed: Mutex::new(vec![false; exchanges.len()]),
+            exchanges,
+            diverged: AtomicUsize::new(0),
+            fast: opts.fast,
+        });
+        let data = web::Data::from(mock.clone());
+        std::thread::spawn(move || {
+            let result = actix_rt::System::new("replay").block_on(async move {
+                HttpServer::new(move || {
+                    App::new()
+                        .app_data(data.clone())
+                        .default_service(web::route().to(answer))
+                })
+                .workers(1)
+                .disable_signals()
+                .listen(listener)?
+                .run()
+                .await
+            });
+            if let Err(e) = result {
+                warn!("mock relay failed: {}", e);
+            }
+        });
+
+        Ok(Replay { args, mock })
+    }
+
+    /// The arguments the recorded client was run with, pointed at the mock.
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Reports how closely the client has followed the recording.
+    pub fn finish(&self) {
+        let replayed = self.mock.replayed.lock().unwrap();
+        info!(
+            "replayed {} of {} recorded requests",
+            replayed.iter().filter(|&&r| r).count(),
+            replayed.len()
+        );
+        let diverged = self.mock.diverged.load(Ordering::Relaxed);
+        if diverged > 0 {
+            warn!("{} requests diverged from the recording", diverged);
+        }
+    }
+}
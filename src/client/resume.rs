@@ -0,0 +1,107 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Where an interrupted sender left off, so that another sender reading
+/// the same data can continue the transfer.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ResumeToken {
+    pub channel: String,
+    /// The first packet the receiver has not taken yet
+    pub index: usize,
+    /// Offset of that packet in the input
+    pub offset: u64,
+    /// SHA-256 of the input before `offset`
+    pub checksum: String,
+    /// Offsets of the packets after `index` the receiver has not taken
+    /// yet, which the server may already have
+    #[serde(default)]
+    pub offsets: Vec<u64>,
+    /// ID of the transfer, kept by the senders continuing it
+    #[serde(default)]
+    pub transfer: Option<String>,
+}
+
+impl ResumeToken {
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        tokio::fs::write(&tmp, self.to_string()).await?;
+        tokio::fs::rename(&tmp, path).await?;
+
+        Ok(())
+    }
+
+    /// Reads the input up to the token's offset, checking that it is the
+    /// data the token was created from.
+    pub async fn skip<R>(&self, input: &mut R) -> anyhow::Result<Sha256>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut hasher = Sha256::new();
+        consume(input, &mut hasher, self.offset).await?;
+
+        if hex::encode(hasher.clone().finalize()) != self.checksum {
+            bail!("input does not match the data of the resume token");
+        }
+
+        Ok(hasher)
+    }
+
+    /// Offset of a packet in the input, if the token knows it.
+    pub fn offset_of(&self, index: usize) -> Option<u64> {
+        match index.checked_sub(self.index)? {
+            0 => Some(self.offset),
+            n => self.offsets.get(n - 1).cloned(),
+        }
+    }
+}
+
+/// Reads `len` bytes of the input into `hasher`.
+pub async fn consume<R>(input: &mut R, hasher: &mut Sha256, len: u64) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = vec![0; 64 * 1024];
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let n = remaining.min(buffer.len() as u64) as usize;
+        let n = input.read(&mut buffer[..n]).await?;
+        if n == 0 {
+            bail!("input ended before the offset to resume from");
+        }
+
+        hasher.update(&buffer[..n]);
+        remaining -= n as u64;
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let json = serde_json::to_vec(self).map_err(|_| fmt::Error)?;
+        f.write_str(&base64::encode_config(json, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+impl FromStr for ResumeToken {
+    type Err = anyhow::Error;
+
+    /// Parses a token, or reads it from the file of that name, e.g. one
+    /// saved with `--resume-token`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if Path::new(s).is_file() {
+            return std::fs::read_to_string(s)?.parse();
+        }
+
+        let json = base64::decode_config(s.trim(), base64::URL_SAFE_NO_PAD)
+            .context("invalid resume token")?;
+        serde_json::from_slice(&json).context("invalid resume token")
+    }
+}
@@ -0,0 +1,185 @@
+use std::{ops::Range, sync::Arc};
+
+use anyhow::bail;
+use log::info;
+use reqwest::StatusCode;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::{
+    check_seal, check_trailer, http_client, net, read_packets, record, sign, Aborted,
+    FailureStatus, Forbidden, HttpClient, Packets, ReceiveOptions, ShuttingDown,
+};
+use crate::common::{
+    encryption::Decryptor, headers, log_retry, signature::Signer, Backoff, RateLimit, StreamHasher,
+    Trailer,
+};
+
+async fn download(
+    url: &str,
+    mut client: HttpClient,
+    signer: Option<&Signer>,
+    rate_limit: Option<&RateLimit>,
+    decryptor: Option<&Decryptor>,
+    packets: Range<u64>,
+    mut backoff: Backoff,
+) -> anyhow::Result<Packets> {
+    let (start, end) = (packets.start, packets.end);
+    let mut retry_after = None;
+    loop {
+        let resp = record::send(
+            sign(client.get(url), signer, "GET", url, None)
+                .header(headers::RANGE, format!("{}-{}", start, end)),
+        )
+        .await;
+
+        match resp {
+            Ok(resp) if resp.status().is_success() => {
+                match read_packets(resp, start, decryptor).await? {
+                    Some(packets) => {
+                        if let Some(limit) = rate_limit {
+                            limit.take(packets.data.len() as u64).await;
+                        }
+                        return Ok(packets);
+                    }
+                    None => bail!("packets {} to {} were corrupted on their way", start, end),
+                }
+            }
+            Ok(resp) if resp.status() == StatusCode::GONE => {
+                bail!("packets {} to {} are no longer available", start, end)
+            }
+            Ok(resp) if ShuttingDown::check(&resp).is_err() => return Err(ShuttingDown.into()),
+            Ok(resp) if resp.status() == StatusCode::UNPROCESSABLE_ENTITY => {
+                return Err(Aborted(resp.text().await?).into())
+            }
+            Ok(resp)
+                if resp.status() == StatusCode::FORBIDDEN
+                    || resp.status() == StatusCode::UNAUTHORIZED =>
+            {
+                return Err(Forbidden(resp.text().await?).into())
+            }
+            Ok(resp) => {
+                let failure = FailureStatus::of(&resp);
+                log_retry("GET", &failure);
+                retry_after = failure.retry_after;
+            }
+            Err(e) => log_retry("GET", e),
+        }
+
+        // reconnect, resolving the relay's name again
+        net::race(url).await;
+        client.reconnect();
+
+        backoff.wait(retry_after.take()).await?;
+    }
+}
+
+/// Downloads a channel the sender has finished writing in parallel
+/// segments, starting from packet `start`. Returns the index of the
+/// end-of-stream packet, or `None` if the channel is not complete.
+pub async fn receive<W>(
+    url: &str,
+    transfer: &str,
+    signer: Option<&Signer>,
+    options: &ReceiveOptions,
+    start: u64,
+    segments: u64,
+    output: &mut W,
+) -> anyhow::Result<Option<u64>>
+where
+    W: AsyncWrite + Unpin,
+{
+    let resp = record::send(
+        sign(
+            http_client(options.pool.as_ref(), transfer, None).get(url),
+            signer,
+            "GET",
+            url,
+            None,
+        )
+        .header(headers::COMPLETE, 1),
+    )
+    .await?;
+
+    ShuttingDown::check(&resp)?;
+    // the sender may not have even started yet
+    if resp.status() == StatusCode::CONFLICT || resp.status() == StatusCode::PRECONDITION_FAILED {
+        return Ok(None);
+    }
+
+    if !resp.status().is_success() {
+        bail!("server returned failure status: {:?}", resp.status());
+    }
+
+    let (first, end): (u64, u64) = match (
+        resp.headers().get(headers::OLDEST),
+        resp.headers().get(headers::COMPLETE),
+    ) {
+        (Some(first), Some(end)) => (first.to_str()?.parse()?, end.to_str()?.parse()?),
+        _ => bail!("server did not return the range of the channel"),
+    };
+
+    if start < first {
+        return Ok(None);
+    }
+
+    let trailer: Option<Trailer> = match resp.headers().get(headers::TRAILER) {
+        Some(trailer) => Some(trailer.to_str()?.parse()?),
+        None => None,
+    };
+    // only a stream downloaded from its start can be checked as a whole
+    let mut hasher = match start {
+        0 => Some(StreamHasher::default()),
+        _ => None,
+    };
+
+    let packets = end.saturating_sub(start);
+    let segments = segments.min(packets).max(1);
+    info!("downloading {} packets in {} segments", packets, segments);
+
+    let rate_limit = options.rate_limit.clone();
+    let decryptor = options
+        .passphrase
+        .as_deref()
+        .map(|passphrase| Arc::new(Decryptor::new(passphrase)));
+    let tasks: Vec<_> = (0..segments)
+        .map(|i| {
+            let url = url.to_owned();
+            let client = http_client(options.pool.as_ref(), transfer, Some(i));
+            let backoff = options.backoff();
+            let signer = signer.cloned();
+            let rate_limit = rate_limit.clone();
+            let decryptor = decryptor.clone();
+            let from = start + packets * i / segments;
+            let to = start + packets * (i + 1) / segments;
+
+            tokio::spawn(async move {
+                download(
+                    &url,
+                    client,
+                    signer.as_ref(),
+                    rate_limit.as_deref(),
+                    decryptor.as_deref(),
+                    from..to,
+                    backoff,
+                )
+                .await
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        let mut packets = task.await??;
+        output.write_all(&packets.data).await?;
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&packets.data);
+        }
+        packets.forward_records(options.metadata.as_ref());
+    }
+
+    if let Some(hasher) = &hasher {
+        check_trailer(trailer.as_ref(), hasher)?;
+    }
+    check_seal(trailer.as_ref(), decryptor.as_deref(), end)?;
+
+    Ok(Some(end))
+}
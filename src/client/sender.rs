@@ -1,29 +1,471 @@
-use std::time::Duration;
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::bail;
-use bytes::BytesMut;
-use log::debug;
-use reqwest::Client;
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, Either};
+use log::{debug, info, warn};
+use reqwest::{Client, Response, StatusCode, Url};
+use sha2::{Digest, Sha256};
 use tokio::{
-    io::AsyncReadExt,
-    sync::mpsc::{self, Receiver, Sender},
+    io::{AsyncRead, AsyncReadExt},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        RwLock,
+    },
 };
+use uuid::Uuid;
 
-use crate::common::{headers, Packet};
+use super::{
+    control, http_client, log_stats, net, record,
+    resume::{self, ResumeToken},
+    retry_after, sign,
+    transport::{Connection, Transport},
+    Aborted, FailureStatus, HttpClient, InputTimeout, ReceiverGone, ShuttingDown, StaleSession,
+};
+use crate::common::{
+    checksum::Algorithm,
+    compression::Compression,
+    encryption::{Cipher, Encryptor, Params},
+    headers, log_retry, pow,
+    records::{self, Outbox, Record},
+    signature::Signer,
+    Backoff, Limits, Packet, Priority, RateLimit, Size, Stats, StreamHasher, TokenBucket, Trailer,
+};
 
-const WORKER_NUM: u64 = 4;
-const PACKET_SIZE: usize = 1 * 1024 * 1024;
+const DEFAULT_WORKER_NUM: u64 = 4;
+const DEFAULT_PACKET_SIZE: usize = 1 * 1024 * 1024;
+const MIN_PACKET_SIZE: usize = 4 * 1024;
+/// Packets start at this size and grow from there, as long as the link
+/// keeps up
+const INITIAL_PACKET_SIZE: usize = 64 * 1024;
+/// Failed requests shrink packets down to this size at most
+const MIN_ADAPTIVE_PACKET_SIZE: usize = 16 * 1024;
+/// Packets sent faster than this grow
+const PACKET_LATENCY_TARGET: Duration = Duration::from_secs(1);
 const BUFFER_SIZE: usize = 64 * 1024;
+/// Room for what compression, encryption and records add to a packet, on
+/// top of 1/128 of it for incompressible data
+const ENCODING_OVERHEAD: usize = 4 * 1024;
+const DEFAULT_REKEY_AFTER: u64 = 64 * 1024 * 1024;
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const RESUME_TOKEN_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(10);
 
-struct Worker {
-    rx: Receiver<Packet>,
+#[derive(Debug, thiserror::Error)]
+#[error("channel is not available on the server")]
+struct ChannelLost;
+
+#[derive(Debug, thiserror::Error)]
+#[error("packet is too large for the server")]
+struct TooLarge;
+
+/// Where a packet starts in the input
+struct Boundary {
+    index: usize,
+    offset: u64,
+    /// Hash of the input before `offset`
+    hasher: Sha256,
+}
+
+/// State shared by all workers, used to re-create the channel when the
+/// server has lost it, e.g. after a restart.
+struct Channel {
     url: String,
+    /// URLs packets are sent to, the channel's and any reaching the relay
+    /// over other networks
+    paths: Vec<String>,
+    transfer: String,
+    signer: Option<Signer>,
+    stream: Option<usize>,
+    stream_max_age: Option<Duration>,
+    stream_max_bytes: Option<u64>,
+    receivers: Option<usize>,
+    priority: Option<Priority>,
+    workers: u64,
+    /// Largest size packets grow to, halved whenever the server or a proxy
+    /// rejects a packet as too large
+    max_packet_size: AtomicUsize,
+    /// Told to the relay, which rejects larger packets, with room for them
+    /// to grow when encoded. Kept when packets shrink, as those read before
+    /// are still sent again in parts
+    declared_packet_size: usize,
+    /// Size packets are read at, adapted to how the link copes with them
+    packet_size: AtomicUsize,
+    /// Packets are always read at the largest size
+    fixed_packet_size: bool,
+    checksum: Algorithm,
+    compress: Option<Compression>,
+    encrypt: Option<Encryptor>,
+    /// Packets are framed into records carrying metadata along
+    records: bool,
+    transport: Option<Transport>,
+    rate_limit: Option<Arc<RateLimit>>,
+    /// How long to wait before retrying a failed request
+    backoff: Backoff,
+    /// How often the relay hears from the sender when it has nothing to send
+    heartbeat: Option<Duration>,
+    pool: Option<Client>,
+    /// Packets before this index have been taken by the receiver
+    committed: Mutex<usize>,
+    /// Free slots advertised by the server, if it does so
+    credit: Mutex<Option<usize>>,
+    /// Bytes per second the receivers asked the sender to keep to, or 0
+    max_rate: AtomicU64,
+    max_rate_bucket: TokenBucket,
+    /// Given by the relay on creating the channel, and kept when
+    /// re-creating it
+    session: Mutex<Option<String>>,
+    /// Sent with the end of the stream, once the input has all been read
+    trailer: Mutex<Option<Trailer>>,
+    /// Packets not yet taken by the receiver, for resume tokens
+    boundaries: Mutex<VecDeque<Boundary>>,
+    /// Incremented every time the channel is re-created
+    epoch: AtomicUsize,
+    stats: Stats,
+    /// Held for reading while sending packets, and for writing while
+    /// re-creating the channel
+    lock: RwLock<()>,
+}
+
+impl Channel {
+    async fn reset(&self, index: Option<usize>) -> anyhow::Result<usize> {
+        let mut resp = self.request_reset(index, None).await?;
+
+        // anonymous relays may ask for some work in exchange for a channel
+        if resp.status() == StatusCode::PRECONDITION_REQUIRED {
+            if let Some(challenge) = resp.headers().get(headers::POW) {
+                let (challenge, url) = (challenge.to_str()?.to_owned(), self.url.clone());
+                let channel = Url::parse(&url)?.path().to_owned();
+                debug!("solving proof-of-work challenge {}", challenge);
+
+                let solution =
+                    tokio::task::spawn_blocking(move || pow::solve(&challenge, &channel)).await??;
+                resp = self.request_reset(index, Some(&solution)).await?;
+            }
+        }
+
+        ShuttingDown::check(&resp)?;
+        StaleSession::check(&resp)?;
+        // e.g. settings the server does not support, which retrying won't fix
+        if resp.status().is_client_error() {
+            bail!("server rejected the channel: {}", resp.text().await?);
+        }
+        self.update_flow_control(&resp)?;
+
+        if let Some(session) = resp.headers().get(headers::SESSION) {
+            *self.session.lock().unwrap() = Some(session.to_str()?.to_owned());
+        }
+
+        // a stream continues from the index the server expects next
+        Ok(match resp.headers().get(headers::INDEX) {
+            Some(index) => index.to_str()?.parse()?,
+            None => index.unwrap_or(0),
+        })
+    }
+
+    async fn request_reset(
+        &self,
+        index: Option<usize>,
+        pow: Option<&str>,
+    ) -> anyhow::Result<Response> {
+        let mut req = sign(
+            http_client(self.pool.as_ref(), &self.transfer, None).put(&self.url),
+            self.signer.as_ref(),
+            "PUT",
+            &self.url,
+            index.map(|index| index as u64),
+        )
+        .header(headers::RESET, self.workers)
+        .header(headers::PACKET_SIZE, self.declared_packet_size)
+        .header(headers::CHECKSUM_ALGORITHM, self.checksum.to_string());
+
+        if let Some(retention) = self.stream {
+            req = req.header(headers::STREAM, retention);
+        }
+
+        if let Some(max_age) = self.stream_max_age {
+            req = req.header(headers::STREAM_MAX_AGE, max_age.as_secs());
+        }
+
+        if let Some(max_bytes) = self.stream_max_bytes {
+            req = req.header(headers::STREAM_MAX_BYTES, max_bytes);
+        }
+
+        if let Some(receivers) = self.receivers {
+            req = req.header(headers::RECEIVERS, receivers);
+        }
+
+        if let Some(priority) = self.priority {
+            req = req.header(headers::PRIORITY, priority.to_string());
+        }
+
+        if let Some(compression) = self.compress {
+            req = req.header(headers::COMPRESSION, compression.to_string());
+        }
+
+        if let Some(encryptor) = &self.encrypt {
+            req = req.header(headers::ENCRYPTION, encryptor.params().to_string());
+        }
+
+        if self.records {
+            req = req.header(headers::RECORDS, 1);
+        }
+
+        if let Some(index) = index {
+            req = req.header(headers::INDEX, index);
+        }
+
+        if let Some(solution) = pow {
+            req = req.header(headers::POW, solution);
+        }
+
+        if let Some(heartbeat) = self.heartbeat {
+            req = req.header(headers::HEARTBEAT, heartbeat.as_secs());
+        }
+
+        if let Some(session) = self.session() {
+            req = req.header(headers::SESSION, session);
+        }
+
+        Ok(record::send(req).await?)
+    }
+
+    async fn reattach(&self, epoch: usize) -> anyhow::Result<()> {
+        let _guard = self.lock.write().await;
+
+        // another worker got here first
+        if self.epoch.load(Ordering::SeqCst) != epoch {
+            return Ok(());
+        }
+
+        let committed = self.committed();
+        warn!(
+            "channel lost on the server, resending from packet {}",
+            committed
+        );
+
+        self.reset(Some(committed)).await?;
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn update_flow_control(&self, resp: &Response) -> anyhow::Result<()> {
+        let credit = match resp.headers().get(headers::CREDIT) {
+            Some(credit) => Some(credit.to_str()?.parse()?),
+            None => None,
+        };
+
+        *self.credit.lock().unwrap() = credit;
+
+        let max_rate = match resp.headers().get(headers::MAX_RATE) {
+            Some(rate) => rate.to_str()?.parse()?,
+            None => 0,
+        };
+
+        if self.max_rate.swap(max_rate, Ordering::SeqCst) != max_rate {
+            match max_rate {
+                0 => info!("receivers no longer limit their rate"),
+                rate => info!("receivers consume at most {}/s, slowing down", Size(rate)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the server has room for another packet.
+    async fn acquire_credit(&self, client: &HttpClient) -> anyhow::Result<()> {
+        let mut backoff = self.backoff.clone();
+        loop {
+            match self.credit.lock().unwrap().as_mut() {
+                Some(credit) if *credit > 0 => {
+                    *credit -= 1;
+                    return Ok(());
+                }
+                Some(_) => {}
+                None => return Ok(()),
+            }
+
+            let mut req = sign(
+                client.put(&self.url),
+                self.signer.as_ref(),
+                "PUT",
+                &self.url,
+                None,
+            )
+            .header(headers::CREDIT, 1);
+            if let Some(session) = self.session() {
+                req = req.header(headers::SESSION, session);
+            }
+
+            let resp = record::send(req).await;
+            if let Ok(resp) = &resp {
+                ReceiverGone::check(resp)?;
+                StaleSession::check(resp)?;
+            }
+
+            match resp {
+                // leave re-creating or aborting the channel to the worker
+                Ok(resp) if resp.status() == StatusCode::PRECONDITION_FAILED => {
+                    self.stats.precondition_failed();
+                    return Ok(());
+                }
+                Ok(resp) if resp.status() == StatusCode::UNPROCESSABLE_ENTITY => return Ok(()),
+                Ok(resp) if ShuttingDown::check(&resp).is_err() => return Ok(()),
+                Ok(resp) if resp.status().is_success() => {
+                    if let Err(e) = self.update_flow_control(&resp) {
+                        debug!("invalid credit: {}", e);
+                        return Ok(());
+                    }
+                }
+                Ok(resp) => {
+                    let failure = FailureStatus::of(&resp);
+                    log_retry("PUT", &failure);
+                    backoff.wait(failure.retry_after).await?;
+                }
+                Err(e) => {
+                    log_retry("PUT", e);
+                    backoff.wait(None).await?;
+                }
+            }
+        }
+    }
+
+    fn session(&self) -> Option<String> {
+        self.session.lock().unwrap().clone()
+    }
+
+    fn packet_size(&self) -> usize {
+        self.packet_size.load(Ordering::SeqCst)
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size.load(Ordering::SeqCst)
+    }
+
+    /// Doubles the packet size, up to the largest, after a full packet of
+    /// `len` bytes has been sent within `elapsed`.
+    fn grow(&self, len: usize, elapsed: Duration) {
+        let size = self.packet_size();
+        if self.fixed_packet_size || len < size || elapsed > PACKET_LATENCY_TARGET {
+            return;
+        }
+
+        let larger = (size * 2).min(self.max_packet_size());
+        if larger > size
+            && self
+                .packet_size
+                .compare_exchange(size, larger, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        {
+            debug!("sending packets of {} bytes", larger);
+        }
+    }
+
+    /// Halves the packet size after a failed request, for a lost packet to
+    /// cost less to send again. Slow requests leave it as it is, as they
+    /// may well be waiting for the receiver rather than for the link.
+    fn slow_down(&self) {
+        if self.fixed_packet_size {
+            return;
+        }
+
+        let size = self.packet_size();
+        let smaller = (size / 2).max(MIN_ADAPTIVE_PACKET_SIZE).min(size);
+        if smaller < size
+            && self
+                .packet_size
+                .compare_exchange(size, smaller, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        {
+            debug!("sending packets of {} bytes", smaller);
+        }
+    }
+
+    /// Halves the largest packet size after packets of `size` have been
+    /// rejected, unless another worker already has.
+    fn shrink(&self, size: usize) -> anyhow::Result<()> {
+        if size <= MIN_PACKET_SIZE {
+            bail!("the server rejects packets of {} bytes as too large", size);
+        }
+
+        let smaller = (size / 2).max(MIN_PACKET_SIZE);
+        if self
+            .max_packet_size
+            .compare_exchange(size, smaller, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            self.packet_size.fetch_min(smaller, Ordering::SeqCst);
+            warn!(
+                "packets are too large for the server, sending at most {} bytes",
+                smaller
+            );
+        }
+
+        Ok(())
+    }
+
+    fn committed(&self) -> usize {
+        *self.committed.lock().unwrap()
+    }
+
+    fn commit(&self, index: usize) {
+        let mut committed = self.committed.lock().unwrap();
+        *committed = (*committed).max(index);
+    }
+
+    fn record(&self, boundary: Boundary) {
+        let committed = self.committed();
+        let mut boundaries = self.boundaries.lock().unwrap();
+        boundaries.push_back(boundary);
+
+        while boundaries.len() > 1 && boundaries[1].index <= committed {
+            boundaries.pop_front();
+        }
+    }
+
+    fn resume_token(&self) -> Option<ResumeToken> {
+        let committed = self.committed();
+        let mut boundaries = self.boundaries.lock().unwrap();
+
+        while boundaries.len() > 1 && boundaries[1].index <= committed {
+            boundaries.pop_front();
+        }
+
+        boundaries.front().map(|b| ResumeToken {
+            channel: self.url.clone(),
+            index: b.index,
+            offset: b.offset,
+            checksum: hex::encode(b.hasher.clone().finalize()),
+            offsets: boundaries.iter().skip(1).map(|b| b.offset).collect(),
+            transfer: Some(self.transfer.clone()),
+        })
+    }
+}
+
+struct Worker {
+    /// Packets along with how much input each holds
+    rx: Receiver<(Packet, usize)>,
     index: u64,
-    client: Client,
+    conn: Connection,
+    channel: Arc<Channel>,
+    /// Which of the channel's paths packets are sent over
+    path: usize,
+    /// Packets sent but not yet taken by the receiver
+    window: VecDeque<Packet>,
+    epoch: usize,
 }
 
 impl Worker {
-    fn new(url: &str, index: u64) -> (Sender<Packet>, Self) {
+    fn new(index: u64, channel: Arc<Channel>) -> (Sender<(Packet, usize)>, Self) {
         let (tx, rx) = mpsc::channel(1);
 
         (
@@ -31,106 +473,746 @@ impl Worker {
             Worker {
                 rx,
                 index,
-                client: Client::new(),
-                url: url.into(),
+                conn: Connection::new(
+                    channel.transport,
+                    channel.pool.as_ref(),
+                    &channel.transfer,
+                    index,
+                    channel.signer.clone(),
+                ),
+                path: index as usize % channel.paths.len(),
+                channel,
+                window: VecDeque::new(),
+                epoch: 0,
             },
         )
     }
 
-    async fn run(mut self) {
-        while let Some(packet) = self.rx.recv().await {
+    async fn run(mut self) -> anyhow::Result<()> {
+        let mut backoff = self.channel.backoff.clone();
+        while let Some((packet, len)) = self.rx.recv().await {
+            self.channel.acquire_credit(self.conn.client()).await?;
+
             loop {
-                if let Err(e) = self.send(&packet).await {
-                    debug!("http error: {}", e);
-                    tokio::time::delay_for(Duration::from_secs(3)).await;
+                if let Err(e) = self.send_synced(&packet, len).await {
+                    if e.is::<Aborted>()
+                        || e.is::<ShuttingDown>()
+                        || e.is::<ReceiverGone>()
+                        || e.is::<StaleSession>()
+                    {
+                        return Err(e);
+                    }
+
+                    log_retry("PUT", &e);
+                    self.channel.stats.retry();
+                    self.channel.slow_down();
+                    // another path to the relay may still work
+                    self.path = (self.path + 1) % self.channel.paths.len();
+                    // reconnect, resolving the relay's name again
+                    net::race(&self.channel.paths[self.path]).await;
+                    self.conn.reconnect();
+                    backoff.wait(retry_after(&e)).await?;
                     continue;
                 }
 
+                backoff.reset();
+                break;
+            }
+
+            self.window.push_back(packet);
+            self.prune();
+        }
+
+        Ok(())
+    }
+
+    fn prune(&mut self) {
+        let committed = self.channel.committed();
+        while let Some(packet) = self.window.front() {
+            if packet.index >= committed {
                 break;
             }
+            self.window.pop_front();
+        }
+    }
+
+    async fn send_synced(&mut self, packet: &Packet, len: usize) -> anyhow::Result<()> {
+        let channel = self.channel.clone();
+        let guard = channel.lock.read().await;
+        let epoch = channel.epoch.load(Ordering::SeqCst);
+
+        let mut result = Ok(());
+        if self.epoch != epoch {
+            // the channel has been re-created, send what the server has lost first
+            self.prune();
+            for p in self.window.clone() {
+                channel.stats.duplicate();
+                result = self.send(&p, 0).await;
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+
+        if result.is_ok() {
+            self.epoch = epoch;
+            result = self.send(packet, len).await;
+        }
+
+        drop(guard);
+
+        if let Err(e) = &result {
+            if e.is::<ChannelLost>() {
+                channel.reattach(epoch).await?;
+            }
+        }
+
+        result
+    }
+
+    /// Sends a packet holding `len` bytes of input, in parts if it is larger
+    /// than the server accepts.
+    async fn send(&mut self, packet: &Packet, len: usize) -> anyhow::Result<()> {
+        loop {
+            let size = self.channel.max_packet_size();
+            let encoded = packet.data.len();
+
+            let mut result = Ok(());
+            if encoded <= size {
+                result = self
+                    .put(packet.index, packet.data.clone(), None)
+                    .await
+                    .map(|elapsed| self.channel.grow(len, elapsed));
+            } else {
+                let starts: Vec<usize> = (0..encoded).step_by(size).collect();
+                for (part, &start) in starts.iter().enumerate() {
+                    let data = packet.data.slice(start..(start + size).min(encoded));
+                    result = self
+                        .put(packet.index, data, Some((part, starts.len())))
+                        .await
+                        .map(|_| ());
+                    if result.is_err() {
+                        break;
+                    }
+                }
+            }
+
+            match result {
+                Err(e) if e.is::<TooLarge>() => self.channel.shrink(size)?,
+                result => return result,
+            }
         }
     }
 
-    async fn send(&mut self, packet: &Packet) -> anyhow::Result<()> {
-        let resp = self
-            .client
-            .put(&self.url)
-            .header(headers::INDEX, packet.index)
+    async fn put(
+        &mut self,
+        index: usize,
+        data: Bytes,
+        part: Option<(usize, usize)>,
+    ) -> anyhow::Result<Duration> {
+        let url = &self.channel.paths[self.path];
+        let mut req = sign(
+            self.conn.client().put(url),
+            self.channel.signer.as_ref(),
+            "PUT",
+            url,
+            Some(index as u64),
+        );
+
+        if let Some((part, parts)) = part {
+            req = req.header(headers::PART, format!("{}/{}", part, parts));
+        }
+
+        if let Some(session) = self.channel.session() {
+            req = req.header(headers::SESSION, session);
+        }
+
+        if data.is_empty() {
+            if let Some(trailer) = &*self.channel.trailer.lock().unwrap() {
+                req = req.header(headers::TRAILER, trailer.to_string());
+            }
+        }
+
+        if let Some(limit) = &self.channel.rate_limit {
+            limit.take(data.len() as u64).await;
+        }
+
+        let max_rate = self.channel.max_rate.load(Ordering::SeqCst);
+        if max_rate > 0 {
+            let n = data.len() as u64;
+            self.channel.max_rate_bucket.take(n, max_rate).await;
+        }
+
+        let req = req
+            .header(headers::INDEX, index)
             .header(headers::WORKER, self.index)
-            .body(packet.data.clone())
-            .send()
-            .await?;
+            .header(headers::CHECKSUM, self.channel.checksum.checksum(&data))
+            .body(data);
+        let started = Instant::now();
+        let resp = self.conn.send(req).await?;
+        let elapsed = started.elapsed();
+        ShuttingDown::check(&resp)?;
+        ReceiverGone::check(&resp)?;
+        StaleSession::check(&resp)?;
 
         let status = resp.status();
+        if status == StatusCode::PAYLOAD_TOO_LARGE {
+            return Err(TooLarge.into());
+        }
+
+        if status == StatusCode::PRECONDITION_FAILED {
+            self.channel.stats.precondition_failed();
+            return Err(ChannelLost.into());
+        }
+
+        if status == StatusCode::UNPROCESSABLE_ENTITY {
+            return Err(Aborted(resp.text().await?).into());
+        }
+
         if !status.is_success() {
-            // prevent poisoned connection from being reused
-            self.client = Client::new();
-            bail!("server returned failure status: {:?}", status);
+            return Err(FailureStatus::of(&resp).into());
         }
 
-        Ok(())
+        if let Some(committed) = resp.headers().get(headers::COMMITTED) {
+            self.channel.commit(committed.to_str()?.parse()?);
+        }
+
+        self.channel.update_flow_control(&resp)?;
+
+        Ok(elapsed)
+    }
+}
+
+#[derive(Default)]
+pub struct Options {
+    /// Open the channel as a stream retaining this many packets
+    pub stream: Option<usize>,
+    /// Have the stream drop packets older than this
+    pub stream_max_age: Option<Duration>,
+    /// Have the stream drop its oldest packets beyond this many bytes
+    pub stream_max_bytes: Option<u64>,
+    /// Hold each packet until this many receivers have taken it
+    pub receivers: Option<usize>,
+    pub priority: Option<Priority>,
+    /// Continue a transfer started by another sender
+    pub resume: Option<ResumeToken>,
+    /// Periodically save a resume token to this file
+    pub resume_token: Option<PathBuf>,
+    /// Number of parallel connections, 4 by default
+    pub workers: Option<u64>,
+    /// Maximum size of a packet in bytes, 1 MiB by default
+    pub packet_size: Option<usize>,
+    /// Read every packet at the maximum size, rather than starting small
+    /// and growing packets as long as the link keeps up
+    pub fixed_packet_size: bool,
+    /// How packets are checked for corruption, BLAKE3 by default
+    pub checksum: Option<Algorithm>,
+    /// Compress each packet before sending it
+    pub compress: Option<Compression>,
+    /// Encrypt each packet end to end with a key derived from this passphrase
+    pub passphrase: Option<String>,
+    /// Switch to a new encryption key every this many bytes of input, 64 MiB
+    /// by default
+    pub rekey_after: Option<u64>,
+    /// Encrypt with this cipher, ChaCha20-Poly1305 by default
+    pub cipher: Option<Cipher>,
+    /// Stretch the passphrase with this many PBKDF2 rounds, 100000 by default
+    pub kdf_rounds: Option<u32>,
+    /// Limit uploads to a number of bytes per second over all workers
+    pub rate_limit: Option<Arc<RateLimit>>,
+    /// Frame packets into records, sending whatever is pushed to this along
+    /// with the data
+    pub metadata: Option<Arc<Outbox>>,
+    /// How workers send packets, a request for each by default
+    pub transport: Option<Transport>,
+    /// Other URLs of the channel, reaching the same relay over other
+    /// networks, to spread packets over
+    pub multipath: Vec<String>,
+    /// Send what has been read of a slow input after this long, 1s by default
+    pub flush_interval: Option<Duration>,
+    /// Abort the transfer if the input produces nothing for this long
+    pub input_timeout: Option<Duration>,
+    /// Wait this long before retrying a failed request, twice as long after
+    /// each failure in a row, 500ms by default
+    pub retry_delay: Option<Duration>,
+    /// Wait at most this long between retries, 30s by default
+    pub max_retry_delay: Option<Duration>,
+    /// Fail once a request has been retried this many times in a row
+    pub max_retries: Option<u32>,
+    /// Fail once a request has kept failing for this long
+    pub max_retry_time: Option<Duration>,
+    /// Tell the relay the sender is alive every this long, for receivers to
+    /// tell a slow sender from a dead one, 10s by default, never if zero
+    pub heartbeat: Option<Duration>,
+    /// Make requests over the connections of this client, shared with
+    /// other transfers, rather than over connections of its own
+    pub pool: Option<Client>,
+}
+
+impl Options {
+    /// How requests are retried.
+    pub(crate) fn backoff(&self) -> Backoff {
+        Backoff::new(self.retry_delay, self.max_retry_delay)
+            .budget(self.max_retries, self.max_retry_time)
+    }
+}
+
+/// Reads from `input`, failing with `InputTimeout` if nothing has come by
+/// `deadline`.
+async fn read_until<R>(
+    input: &mut R,
+    buffer: &mut [u8],
+    deadline: Option<(Instant, Duration)>,
+) -> anyhow::Result<usize>
+where
+    R: AsyncRead + Unpin,
+{
+    match deadline {
+        Some((deadline, timeout)) => {
+            let deadline = tokio::time::Instant::from_std(deadline);
+            match tokio::time::timeout_at(deadline, input.read(buffer)).await {
+                Ok(n) => Ok(n?),
+                Err(_) => Err(InputTimeout(timeout).into()),
+            }
+        }
+        None => Ok(input.read(buffer).await?),
     }
 }
 
-pub async fn send(url: &str) -> anyhow::Result<()> {
+async fn save_resume_tokens(channel: &Channel, path: &Path) -> anyhow::Result<()> {
+    loop {
+        tokio::time::delay_for(RESUME_TOKEN_INTERVAL).await;
+
+        if let Some(token) = channel.resume_token() {
+            token.save(path).await?;
+        }
+    }
+}
+
+/// Tells the relay the sender is alive, if it is to, until dropped.
+async fn heartbeats(channel: &Channel) -> anyhow::Result<()> {
+    let interval = match channel.heartbeat {
+        Some(interval) => interval,
+        None => return future::pending().await,
+    };
+
+    let client = http_client(channel.pool.as_ref(), &channel.transfer, None);
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        let mut req = sign(
+            client.put(&channel.url),
+            channel.signer.as_ref(),
+            "PUT",
+            &channel.url,
+            None,
+        )
+        .header(headers::HEARTBEAT, 1);
+        if let Some(session) = channel.session() {
+            req = req.header(headers::SESSION, session);
+        }
+        // missing one is harmless, and the workers report a relay that is
+        // gone
+        match record::send(req).await {
+            Ok(resp) if !resp.status().is_success() => {
+                ReceiverGone::check(&resp)?;
+                StaleSession::check(&resp)?;
+                debug!("heartbeat failed: {}", resp.status())
+            }
+            Err(e) => debug!("heartbeat failed: {}", e),
+            Ok(_) => {}
+        }
+    }
+}
+
+/// The limits advertised by the relay, `None` for relays that do not.
+async fn limits(url: &str, signer: Option<&Signer>) -> anyhow::Result<Option<Limits>> {
+    let resp = record::send(
+        sign(net::client().get(url), signer, "GET", url, None).header(headers::STATUS, 1),
+    )
+    .await?;
+
+    match resp.headers().get(headers::LIMITS) {
+        Some(limits) if resp.status().is_success() => Ok(Some(limits.to_str()?.parse()?)),
+        _ => Ok(None),
+    }
+}
+
+/// Fails on settings the relay would reject, instead of finding out
+/// halfway through the transfer.
+fn check_limits(
+    limits: &Limits,
+    options: &Options,
+    workers: u64,
+    packet_size: usize,
+) -> anyhow::Result<()> {
+    if let Some(max) = limits.max_workers.filter(|&max| workers > max) {
+        bail!(
+            "the relay accepts at most {} workers per sender, use --workers {} or fewer",
+            max,
+            max
+        );
+    }
+
+    if let Some(max) = limits
+        .max_packet_size
+        .filter(|&max| packet_size as u64 > max)
+    {
+        bail!(
+            "the relay accepts packets of at most {}, use --packet-size {} or smaller",
+            Size(max),
+            max
+        );
+    }
+
+    if let (Some(retention), Some(max)) = (options.stream, limits.max_retention) {
+        if retention as u64 > max {
+            bail!(
+                "the relay retains at most {} packets of a stream, use --stream {} or fewer",
+                max,
+                max
+            );
+        }
+    }
+
+    // still accepted one at a time, but never buffered alongside others
+    if let Some(limit) = limits
+        .memory_limit
+        .filter(|&limit| packet_size as u64 > limit)
+    {
+        warn!(
+            "packets of {} exceed the memory limit of the relay, {}",
+            Size(packet_size as u64),
+            Size(limit)
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn send<R>(
+    url: &str,
+    signer: Option<Signer>,
+    options: &Options,
+    mut input: R,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let workers = options.workers.unwrap_or(DEFAULT_WORKER_NUM);
+    if workers == 0 {
+        bail!("at least one worker is required");
+    }
+
+    let packet_size = options.packet_size.unwrap_or(DEFAULT_PACKET_SIZE);
+    if packet_size == 0 {
+        bail!("the packet size must not be zero");
+    }
+
+    let url = &net::endpoint(url).await?;
+    let transfer = match options
+        .resume
+        .as_ref()
+        .and_then(|token| token.transfer.clone())
+    {
+        Some(transfer) => transfer,
+        None => Uuid::new_v4().to_string(),
+    };
+    debug!("transfer {} sending to {}", transfer, url);
+    net::race(url).await;
+
+    // the relay merges packets by channel, whichever way they came
+    let channel_path = Url::parse(url)?.path().to_owned();
+    let mut paths = vec![url.clone()];
+    for path in &options.multipath {
+        let path = net::endpoint(path).await?;
+        if Url::parse(&path)?.path() != channel_path {
+            bail!("{} is not the same channel as {}", path, url);
+        }
+        net::race(&path).await;
+        paths.push(path);
+    }
+
+    match limits(url, signer.as_ref()).await {
+        Ok(Some(limits)) => check_limits(&limits, options, workers, packet_size)?,
+        Ok(None) => {}
+        // the channel requests retry until the relay is reachable
+        Err(e) => debug!("failed to get the limits of the relay: {}", e),
+    }
+
+    let channel = Arc::new(Channel {
+        url: url.into(),
+        paths,
+        transfer,
+        signer,
+        stream: options.stream,
+        stream_max_age: options.stream_max_age,
+        stream_max_bytes: options.stream_max_bytes,
+        receivers: options.receivers,
+        priority: options.priority,
+        workers,
+        max_packet_size: AtomicUsize::new(packet_size),
+        declared_packet_size: packet_size + packet_size / 128 + ENCODING_OVERHEAD,
+        packet_size: AtomicUsize::new(match options.fixed_packet_size {
+            true => packet_size,
+            false => packet_size.min(INITIAL_PACKET_SIZE),
+        }),
+        fixed_packet_size: options.fixed_packet_size,
+        checksum: options.checksum.unwrap_or_default(),
+        compress: options.compress,
+        encrypt: options.passphrase.as_deref().map(|passphrase| {
+            let defaults = Params::default();
+            let params = Params {
+                cipher: options.cipher.unwrap_or(defaults.cipher),
+                kdf_rounds: options.kdf_rounds.unwrap_or(defaults.kdf_rounds),
+            };
+            Encryptor::new(
+                passphrase,
+                params,
+                options.rekey_after.unwrap_or(DEFAULT_REKEY_AFTER),
+            )
+        }),
+        records: options.metadata.is_some(),
+        transport: options.transport,
+        rate_limit: options.rate_limit.clone(),
+        backoff: options.backoff(),
+        heartbeat: Some(options.heartbeat.unwrap_or(DEFAULT_HEARTBEAT))
+            .filter(|heartbeat| heartbeat.as_secs() > 0),
+        pool: options.pool.clone(),
+        committed: Mutex::new(0),
+        credit: Mutex::new(None),
+        max_rate: AtomicU64::new(0),
+        max_rate_bucket: TokenBucket::new(),
+        session: Mutex::new(None),
+        trailer: Mutex::new(None),
+        boundaries: Mutex::new(VecDeque::new()),
+        epoch: AtomicUsize::new(0),
+        stats: Stats::default(),
+        lock: RwLock::new(()),
+    });
+
     let mut senders = Vec::new();
     let mut futures = Vec::new();
 
-    for i in 0..WORKER_NUM {
-        let (tx, worker) = Worker::new(url, i);
+    for i in 0..workers {
+        let (tx, worker) = Worker::new(i, channel.clone());
 
         futures.push(tokio::spawn(worker.run()));
         senders.push(tx);
     }
 
-    Client::new()
-        .put(url)
-        .header(headers::RESET, WORKER_NUM)
-        .send()
-        .await?;
+    let (mut index, mut offset, resumed) = match &options.resume {
+        Some(token) => {
+            let path = |url: &str| Url::parse(url).map(|url| url.path().to_owned()).ok();
+            if path(&token.channel) != path(url) {
+                bail!("the resume token is for another channel: {}", token.channel);
+            }
+
+            let mut hasher = token.skip(&mut input).await?;
+            channel.commit(token.index);
+
+            // the server skips packets it already has, and tells how far it
+            // has got, which may be beyond what the receiver has taken
+            let received = channel.reset(Some(token.index)).await?;
+            match token.offset_of(received) {
+                Some(offset) if received > token.index => {
+                    debug!("server already has packets up to {}", received);
+                    resume::consume(&mut input, &mut hasher, offset - token.offset).await?;
+                    (received, offset, Some(hasher))
+                }
+                _ => (token.index, token.offset, Some(hasher)),
+            }
+        }
+        None => {
+            let index = channel.reset(None).await?;
+            channel.commit(index);
+            (index, 0, None)
+        }
+    };
+    senders.rotate_left(index % workers as usize);
+
+    // a resumed sender has not read the input before the token, and the
+    // receivers of a stream may have joined halfway through
+    let mut stream_hasher = match (resumed.is_some(), options.stream) {
+        (false, None) => Some(StreamHasher::default()),
+        _ => None,
+    };
+
+    // the input is only hashed when resume tokens are saved
+    let mut hasher = options
+        .resume_token
+        .as_ref()
+        .map(|_| resumed.unwrap_or_default());
 
-    let mut stdin = tokio::io::stdin();
     let mut buffer = vec![0; BUFFER_SIZE];
-    let mut index = 0;
     let mut is_eof = false;
+    let flush_interval = options.flush_interval.unwrap_or(DEFAULT_FLUSH_INTERVAL);
+    let mut last_input = Instant::now();
 
-    'l: loop {
-        for s in &mut senders {
-            let mut bytes = BytesMut::new();
+    let input = async {
+        'l: loop {
+            for s in &mut senders {
+                let mut bytes = BytesMut::new();
 
-            if is_eof {
-                s.send(Packet {
-                    index,
-                    data: bytes.freeze(),
-                })
-                .await?;
-                break 'l;
-            }
+                let packet_size = channel.packet_size();
+                while !is_eof && bytes.len() < packet_size {
+                    let buffer = &mut buffer[..(packet_size - bytes.len()).min(BUFFER_SIZE)];
+                    let deadline = options
+                        .input_timeout
+                        .map(|timeout| (last_input + timeout, timeout));
+                    let read = read_until(&mut input, buffer, deadline);
+                    let n = if bytes.is_empty() {
+                        read.await?
+                    } else {
+                        // don't hold back data from a slow producer for too long
+                        match tokio::time::timeout(flush_interval, read).await {
+                            Ok(n) => n?,
+                            Err(_) => break,
+                        }
+                    };
 
-            while bytes.len() < PACKET_SIZE {
-                let n = stdin.read(&mut buffer).await?;
-                if n == 0 {
-                    is_eof = true;
-                    break;
+                    if n == 0 {
+                        is_eof = true;
+                        break;
+                    }
+                    last_input = Instant::now();
+
+                    bytes.extend_from_slice(&buffer[..n]);
                 }
 
-                bytes.extend_from_slice(&buffer[..n]);
+                // records pushed while reading the packet follow its data
+                let end = offset + bytes.len() as u64;
+                let records: Vec<_> = match &options.metadata {
+                    Some(outbox) => outbox
+                        .take()
+                        .into_iter()
+                        .map(|value| Record { offset: end, value })
+                        .collect(),
+                    None => Vec::new(),
+                };
+
+                // an empty packet marks the end of the stream, once the
+                // records pushed before it have been sent
+                let is_last = bytes.is_empty() && records.is_empty();
+
+                if let Some(hasher) = &mut hasher {
+                    channel.record(Boundary {
+                        index,
+                        offset,
+                        hasher: hasher.clone(),
+                    });
+                    hasher.update(&bytes);
+                }
+                if let Some(stream_hasher) = &mut stream_hasher {
+                    stream_hasher.update(&bytes);
+                }
+                if is_last {
+                    let hashed = channel.encrypt.is_none();
+                    let mut trailer = stream_hasher.as_ref().map(|h| h.trailer(hashed));
+                    // the relay could otherwise end an encrypted stream early
+                    if let Some(encryptor) = &channel.encrypt {
+                        let mut sealed = trailer.unwrap_or(Trailer {
+                            bytes: None,
+                            blake3: None,
+                            seal: None,
+                        });
+                        sealed.seal = Some(encryptor.seal(index as u64, &sealed.sealed()));
+                        trailer = Some(sealed);
+                    }
+                    *channel.trailer.lock().unwrap() = trailer;
+                }
+                let len = bytes.len() as u64;
+
+                let mut data = if channel.records && !is_last {
+                    records::frame(&records, &bytes)?.into()
+                } else {
+                    bytes.freeze()
+                };
+                if let (Some(compression), false) = (channel.compress, is_last) {
+                    data = compression.compress(&data)?.into();
+                }
+                if let (Some(encryptor), false) = (&channel.encrypt, is_last) {
+                    data = encryptor.encrypt(index as u64, offset, &data)?.into();
+                }
+                offset += len;
+
+                // a worker only stops early on failure, reported below
+                if s.send((Packet { index, data }, len as usize))
+                    .await
+                    .is_err()
+                {
+                    break 'l;
+                }
+                index += 1;
+
+                if is_last {
+                    break 'l;
+                }
             }
+        }
 
-            s.send(Packet {
-                index,
-                data: bytes.freeze(),
-            })
-            .await?;
-            index += 1;
+        drop(senders);
+        Ok::<_, anyhow::Error>(())
+    };
+
+    // a worker giving up fails the transfer without waiting for more input
+    let workers = future::try_join_all(futures.into_iter().map(|f| async { f.await? }));
+    let transfer = async {
+        let (sending, heartbeats) = (future::try_join(input, workers), heartbeats(&channel));
+        futures::pin_mut!(sending, heartbeats);
+        match future::select(sending, heartbeats).await {
+            Either::Left((result, _)) => result.map(|_| ()),
+            Either::Right((result, _)) => result,
         }
-    }
+    };
+
+    let result = match &options.resume_token {
+        Some(path) => {
+            let saver = save_resume_tokens(&channel, path);
+            futures::pin_mut!(transfer, saver);
+
+            match future::select(transfer, saver).await {
+                Either::Left((result, _)) | Either::Right((result, _)) => result,
+            }
+        }
+        None => transfer.await,
+    };
 
-    drop(senders);
+    log_stats(&channel.transfer, &channel.stats);
 
-    for f in futures {
-        f.await?;
+    // the receiver would otherwise wait for the rest forever, or come back
+    // to a channel the sender has given up on
+    let reason = match result.as_ref().err() {
+        Some(e) => match e.downcast_ref() {
+            Some(InputTimeout(timeout)) => Some(format!(
+                "the sender had no input for {} seconds",
+                timeout.as_secs()
+            )),
+            None if e.is::<ReceiverGone>() => Some(format!("the sender gave up: {}", e)),
+            None => None,
+        },
+        None => None,
+    };
+    if let Some(reason) = reason {
+        if let Err(e) = control::abort_channel(url, channel.signer.as_ref(), &reason).await {
+            warn!("failed to abort the channel: {}", e);
+        }
     }
 
-    Ok(())
+    if let Some(path) = &options.resume_token {
+        match &result {
+            // the token is of no use once the transfer has finished
+            Ok(()) => match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+            Err(_) => {
+                if let Some(token) = channel.resume_token() {
+                    token.save(path).await?;
+                }
+            }
+        }
+    }
+
+    result
 }
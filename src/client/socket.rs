@@ -0,0 +1,138 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{anyhow, bail};
+use log::{info, warn, LevelFilter};
+
+use super::{control, net};
+use crate::common::{set_log_level, signature::Signer, RateLimit, Size};
+
+/// What of a running transfer can be changed through its control socket.
+pub struct Controls {
+    signer: Option<Signer>,
+    /// The channels of the transfer, paused and resumed together
+    channels: Vec<String>,
+    rate_limits: Vec<Arc<RateLimit>>,
+}
+
+impl Controls {
+    pub fn new(
+        signer: Option<Signer>,
+        channels: Vec<String>,
+        rate_limits: Vec<Arc<RateLimit>>,
+    ) -> Self {
+        Controls {
+            signer,
+            channels,
+            rate_limits,
+        }
+    }
+
+    /// Runs a command read from the socket, e.g. `rate 10M`, `rate off`,
+    /// `pause`, `resume` or `verbosity debug`.
+    async fn run(&self, command: &str) -> anyhow::Result<()> {
+        let mut words = command.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("rate"), Some(rate), None) => {
+                let rate = match rate {
+                    "off" => 0,
+                    _ => rate.parse::<Size>()?.0,
+                };
+                for limit in &self.rate_limits {
+                    limit.set_rate(rate);
+                }
+                match rate {
+                    0 => info!("rate limit lifted"),
+                    _ => info!("rate limited to {}/s", Size(rate)),
+                }
+            }
+            (Some(command @ "pause"), None, None) | (Some(command @ "resume"), None, None) => {
+                let paused = command == "pause";
+                for url in &self.channels {
+                    let url = &net::endpoint(url).await?;
+                    control::set_paused(url, self.signer.as_ref(), paused).await?;
+                }
+                info!("transfer {}d", command);
+            }
+            (Some("verbosity"), Some(level), None) => {
+                let level = level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| anyhow!("invalid verbosity: {}", level))?;
+                set_log_level(level);
+            }
+            _ => bail!("unknown command: {}", command),
+        }
+        Ok(())
+    }
+}
+
+/// The control socket, removed once the transfer is over.
+pub struct Socket {
+    path: PathBuf,
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Listens on a Unix socket at `path` for commands changing the running
+/// transfer, one per line, each answered with `ok` or `error: ...`.
+#[cfg(unix)]
+pub fn listen(path: PathBuf, controls: Controls) -> anyhow::Result<Socket> {
+    use std::os::unix::fs::FileTypeExt;
+
+    use anyhow::Context;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::UnixListener,
+    };
+
+    // left behind by a previous run that did not exit cleanly
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+
+    let mut listener = UnixListener::bind(&path)
+        .with_context(|| format!("failed to listen on {}", path.display()))?;
+    let controls = Arc::new(controls);
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("control socket failed: {}", e);
+                    return;
+                }
+            };
+
+            let controls = controls.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = tokio::io::split(stream);
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let reply = match controls.run(&line).await {
+                        Ok(()) => "ok\n".to_owned(),
+                        Err(e) => format!("error: {}\n", e),
+                    };
+                    if writer.write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(Socket { path })
+}
+
+#[cfg(not(unix))]
+pub fn listen(_: PathBuf, _: Controls) -> anyhow::Result<Socket> {
+    bail!("--control is not supported on this platform")
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::VecDeque,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use log::debug;
+use tempfile::TempDir;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, Notify},
+};
+
+use crate::common::Size;
+
+/// Size of the files the spool is made of, which are deleted once read
+const SEGMENT_SIZE: u64 = 4 << 20;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+struct Segment {
+    path: PathBuf,
+    len: u64,
+    /// Nothing will be appended to it anymore
+    sealed: bool,
+}
+
+#[derive(Default)]
+struct State {
+    segments: VecDeque<Segment>,
+    unread: u64,
+    eof: bool,
+    error: Option<io::Error>,
+}
+
+struct Spool {
+    dir: TempDir,
+    limit: u64,
+    state: Mutex<State>,
+    readable: Notify,
+    writable: Notify,
+}
+
+impl Spool {
+    fn seal(&self) {
+        if let Some(segment) = self.state.lock().unwrap().segments.back_mut() {
+            segment.sealed = true;
+        }
+    }
+
+    /// Copies `input` to the segment files for as long as there is room.
+    async fn fill<R>(&self, mut input: R) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut file: Option<(File, u64)> = None;
+        let mut segments = 0;
+        let mut full = false;
+
+        loop {
+            while self.state.lock().unwrap().unread >= self.limit {
+                if !full {
+                    debug!("spool is full, no longer reading the input");
+                    full = true;
+                }
+                self.writable.notified().await;
+            }
+            full = false;
+
+            let n = input.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+
+            let rotate = match &file {
+                Some((_, len)) => *len >= SEGMENT_SIZE,
+                None => true,
+            };
+            if rotate {
+                self.seal();
+
+                let path = self.dir.path().join(segments.to_string());
+                segments += 1;
+                let f = OpenOptions::new()
+                    .create_new(true)
+                    .write(true)
+                    .open(&path)
+                    .await?;
+                file = Some((f, 0));
+
+                self.state.lock().unwrap().segments.push_back(Segment {
+                    path,
+                    len: 0,
+                    sealed: false,
+                });
+            }
+
+            if let Some((f, len)) = &mut file {
+                f.write_all(&buf[..n]).await?;
+                // the reader must find what it is told is there
+                f.flush().await?;
+                *len += n as u64;
+            }
+
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(segment) = state.segments.back_mut() {
+                    segment.len += n as u64;
+                }
+                state.unread += n as u64;
+            }
+            self.readable.notify();
+        }
+    }
+
+    /// Passes the spooled data on in order, deleting segment files once
+    /// they have been read.
+    async fn drain(&self, mut tx: mpsc::Sender<io::Result<Bytes>>) -> io::Result<()> {
+        let mut file: Option<File> = None;
+        let mut pos = 0;
+
+        loop {
+            let front = {
+                let mut state = self.state.lock().unwrap();
+                match state.segments.front() {
+                    Some(segment) => {
+                        Some((segment.path.clone(), segment.len - pos, segment.sealed))
+                    }
+                    None => match state.error.take() {
+                        Some(e) => return Err(e),
+                        None if state.eof => return Ok(()),
+                        None => None,
+                    },
+                }
+            };
+
+            let (path, available, sealed) = match front {
+                Some(front) => front,
+                None => {
+                    self.readable.notified().await;
+                    continue;
+                }
+            };
+
+            if available > 0 {
+                if file.is_none() {
+                    file = Some(File::open(&path).await?);
+                }
+
+                let mut buf = vec![0; available.min(CHUNK_SIZE as u64) as usize];
+                if let Some(f) = &mut file {
+                    f.read_exact(&mut buf).await?;
+                }
+                pos += buf.len() as u64;
+
+                self.state.lock().unwrap().unread -= buf.len() as u64;
+                self.writable.notify();
+
+                if tx.send(Ok(buf.into())).await.is_err() {
+                    return Ok(());
+                }
+            } else if sealed {
+                self.state.lock().unwrap().segments.pop_front();
+                file = None;
+                pos = 0;
+                if let Err(e) = fs::remove_file(&path).await {
+                    debug!("failed to remove {}: {}", path.display(), e);
+                }
+            } else {
+                self.readable.notified().await;
+            }
+        }
+    }
+}
+
+/// Reads `input` into a spool of files in `dir` as fast as it can, up to
+/// `limit` bytes ahead of what has been read from the returned reader, so
+/// that whatever produces the input does not have to wait for the network.
+pub(crate) fn spool<R>(input: R, dir: &Path, limit: u64) -> anyhow::Result<impl AsyncRead>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let dir = tempfile::Builder::new()
+        .prefix("http-pipe-")
+        .tempdir_in(dir)?;
+    debug!(
+        "spooling up to {} of input in {}",
+        Size(limit),
+        dir.path().display()
+    );
+
+    let spool = Arc::new(Spool {
+        dir,
+        limit: limit.max(1),
+        state: Mutex::new(State::default()),
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    let (tx, rx) = mpsc::channel(1);
+
+    let filler = spool.clone();
+    tokio::spawn(async move {
+        let result = filler.fill(input).await;
+
+        filler.seal();
+        {
+            let mut state = filler.state.lock().unwrap();
+            match result {
+                Ok(()) => state.eof = true,
+                Err(e) => state.error = Some(e),
+            }
+        }
+        filler.readable.notify();
+    });
+
+    tokio::spawn(async move {
+        let mut error_tx = tx.clone();
+        if let Err(e) = spool.drain(tx).await {
+            let _ = error_tx.send(Err(e)).await;
+        }
+    });
+
+    Ok(tokio::io::stream_reader(rx))
+}
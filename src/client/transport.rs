@@ -0,0 +1,204 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use reqwest::{Client, Request, RequestBuilder, Response, Url};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{
+    tungstenite::{http, protocol::WebSocketConfig, Message},
+    WebSocketStream,
+};
+
+use super::{http_client, net, record, sign, HttpClient};
+use crate::common::{
+    signature::Signer,
+    tunnel::{self, RequestHead, ResponseHead},
+};
+
+/// Largest message accepted, a packet along with its headers
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// How workers exchange packets with the relay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// A request for each packet
+    Http,
+    /// All requests of a worker over a single WebSocket
+    WebSocket,
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "http" => Transport::Http,
+            "websocket" | "ws" => Transport::WebSocket,
+            _ => bail!("unknown transport: {}", s),
+        })
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Http => "http",
+            Transport::WebSocket => "websocket",
+        })
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Io for T {}
+
+type Socket = WebSocketStream<Box<dyn Io>>;
+
+/// The connection a worker sends its packets over. Requests for anything
+/// else than packets are made over HTTP whatever the transport.
+pub(crate) struct Connection {
+    transport: Transport,
+    signer: Option<Signer>,
+    client: HttpClient,
+    socket: Option<Socket>,
+}
+
+impl Connection {
+    pub(crate) fn new(
+        transport: Option<Transport>,
+        pool: Option<&Client>,
+        transfer: &str,
+        worker: u64,
+        signer: Option<Signer>,
+    ) -> Self {
+        Connection {
+            transport: transport.unwrap_or(Transport::Http),
+            signer,
+            client: http_client(pool, transfer, Some(worker)),
+            socket: None,
+        }
+    }
+
+    /// The client requests are built with.
+    pub(crate) fn client(&self) -> &HttpClient {
+        &self.client
+    }
+
+    /// Starts over with new connections, e.g. once the relay is reachable
+    /// at another address.
+    pub(crate) fn reconnect(&mut self) {
+        self.client.reconnect();
+        self.socket = None;
+    }
+
+    /// Sends a packet request and waits for its response.
+    pub(crate) async fn send(&mut self, req: RequestBuilder) -> anyhow::Result<Response> {
+        if self.transport == Transport::Http {
+            return Ok(record::send(req).await?);
+        }
+
+        let req = req.build()?;
+        let started = Instant::now();
+        let result = match net::request_timeout() {
+            Some(timeout) => match tokio::time::timeout(timeout, self.tunnel(&req)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("request timed out")),
+            },
+            None => self.tunnel(&req).await,
+        };
+        record::note(&req, started, result.as_ref().map_err(ToString::to_string));
+        Ok(result?.into())
+    }
+
+    /// Exchanges `req` for its response over the WebSocket.
+    async fn tunnel(&mut self, req: &Request) -> anyhow::Result<http::Response<Bytes>> {
+        let mut headers = Vec::new();
+        for (name, value) in req.headers() {
+            if let Ok(value) = value.to_str() {
+                headers.push((name.to_string(), value.to_owned()));
+            }
+        }
+        let head = RequestHead {
+            method: req.method().to_string(),
+            headers,
+        };
+        let body = req
+            .body()
+            .and_then(|body| body.as_bytes())
+            .unwrap_or_default();
+        let message = tunnel::encode(&head, body)?;
+
+        // a socket failing halfway through is not used again
+        let mut socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => self.open(req.url()).await?,
+        };
+        socket.send(Message::Binary(message)).await?;
+        let message = loop {
+            match socket.next().await {
+                Some(Ok(Message::Binary(message))) => break message,
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                Some(Ok(_)) | None => bail!("the relay closed the WebSocket"),
+                Some(Err(e)) => return Err(e.into()),
+            }
+        };
+        self.socket = Some(socket);
+
+        let (head, body): (ResponseHead, _) = tunnel::decode(message.into())?;
+        let mut resp = http::Response::builder().status(head.status);
+        for (name, value) in &head.headers {
+            resp = resp.header(name.as_str(), value.as_str());
+        }
+        Ok(resp.body(body)?)
+    }
+
+    /// Opens the WebSocket of the channel at `url`, authenticated like any
+    /// request for the channel.
+    async fn open(&self, url: &Url) -> anyhow::Result<Socket> {
+        let mut http_url = url.clone();
+        http_url.set_path(&format!("{}/ws", url.path().trim_end_matches('/')));
+        let mut ws_url = http_url.clone();
+        let tls = url.scheme() == "https";
+        let _ = ws_url.set_scheme(if tls { "wss" } else { "ws" });
+
+        let signed = sign(
+            self.client.get(http_url.as_str()),
+            self.signer.as_ref(),
+            "GET",
+            http_url.as_str(),
+            None,
+        )
+        .build()?;
+
+        let mut request = http::Request::builder().uri(ws_url.as_str());
+        for (name, value) in signed.headers() {
+            request = request.header(name, value);
+        }
+        for (name, value) in &net::default_headers() {
+            request = request.header(name, value);
+        }
+
+        let stream = net::connect(&http_url).await?;
+        let stream: Box<dyn Io> = if tls {
+            let connector = tokio_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+            let domain = url.host_str().unwrap_or_default();
+            Box::new(connector.connect(domain, stream).await?)
+        } else {
+            Box::new(stream)
+        };
+
+        let config = WebSocketConfig {
+            max_send_queue: None,
+            max_message_size: Some(MAX_MESSAGE_SIZE),
+            max_frame_size: Some(MAX_MESSAGE_SIZE),
+        };
+        let (socket, _) =
+            tokio_tungstenite::client_async_with_config(request.body(())?, stream, Some(config))
+                .await?;
+
+        Ok(socket)
+    }
+}
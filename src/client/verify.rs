@@ -0,0 +1,135 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::bail;
+use clap::Clap;
+use log::info;
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWrite;
+use uuid::Uuid;
+
+use super::{net, segments, sign, ReceiveOptions};
+use crate::common::{headers, signature::Signer};
+
+#[derive(Clap)]
+pub struct VerifyOpts {
+    /// The SHA-256 of the data sent over the channel, in hex
+    #[clap(long = "expect", value_name = "SHA256")]
+    expect: String,
+    /// Download and hash the data here rather than on the relay, which
+    /// cannot hash compressed or encrypted channels
+    #[clap(long = "local")]
+    local: bool,
+    /// Decrypt the data with this passphrase before hashing it, implies --local
+    #[clap(long = "passphrase", env = "HTTP_PIPE_PASSPHRASE")]
+    passphrase: Option<String>,
+    /// Download in this many parallel segments with --local
+    #[clap(long = "segments", default_value = "4")]
+    segments: u64,
+    endpoint: String,
+}
+
+/// Hashes everything written to it.
+pub(super) struct HashWriter(pub(super) Sha256);
+
+impl AsyncWrite for HashWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.update(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Has the relay hash the data of a completed channel.
+async fn relay_digest(url: &str, signer: Option<&Signer>) -> anyhow::Result<String> {
+    let resp = sign(net::client().get(url), signer, "GET", url, None)
+        .header(headers::DIGEST, "sha256")
+        .send()
+        .await?;
+
+    let status = resp.status();
+    if status == StatusCode::PRECONDITION_FAILED {
+        bail!("channel not found: {}", url);
+    }
+    if status == StatusCode::CONFLICT || status == StatusCode::GONE {
+        bail!("{}", resp.text().await?);
+    }
+    if !status.is_success() {
+        bail!("server returned failure status: {:?}", status);
+    }
+
+    match resp.headers().get(headers::DIGEST) {
+        Some(digest) => Ok(digest.to_str()?.to_owned()),
+        None => bail!("server did not return the digest of the channel"),
+    }
+}
+
+/// Downloads a completed channel without taking it from the relay and
+/// hashes its data.
+async fn local_digest(
+    url: &str,
+    signer: Option<&Signer>,
+    opts: &VerifyOpts,
+) -> anyhow::Result<String> {
+    let options = ReceiveOptions {
+        passphrase: opts.passphrase.clone(),
+        ..Default::default()
+    };
+    let transfer = Uuid::new_v4().to_string();
+
+    let mut hash = HashWriter(Sha256::new());
+    let end = segments::receive(
+        url,
+        &transfer,
+        signer,
+        &options,
+        0,
+        opts.segments.max(1),
+        &mut hash,
+    )
+    .await?;
+    if end.is_none() {
+        bail!("channel is not complete, or has already been partly received");
+    }
+
+    Ok(hex::encode(hash.0.finalize()))
+}
+
+/// Checks that the data of a channel the sender has finished hashes to the
+/// expected SHA-256, leaving the channel to its receiver.
+pub async fn verify(opts: VerifyOpts, secret: Option<String>) -> anyhow::Result<()> {
+    let signer = secret.as_deref().map(Signer::new);
+    let url = &net::endpoint(&opts.endpoint).await?;
+
+    let digest = if opts.local || opts.passphrase.is_some() {
+        local_digest(url, signer.as_ref(), &opts).await?
+    } else {
+        relay_digest(url, signer.as_ref()).await?
+    };
+
+    if !digest.eq_ignore_ascii_case(opts.expect.trim()) {
+        bail!(
+            "checksum mismatch: expected {}, got {}",
+            opts.expect.trim(),
+            digest
+        );
+    }
+
+    info!("sha256 {} matches", digest);
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+/// Wait before the first retry, unless told otherwise
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait between retries, unless told otherwise
+pub const DEFAULT_MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How long to wait before retrying a request that keeps failing: twice as
+/// long after each failure up to a cap, less a random part so that workers
+/// failing together do not all retry together.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    max_retries: Option<u32>,
+    max_time: Option<Duration>,
+    failures: u32,
+    /// When the request first failed, since it last went through
+    since: Option<Instant>,
+}
+
+/// A request has failed more often, or for longer, than allowed.
+#[derive(Debug, thiserror::Error)]
+#[error("giving up after {retries} retries over {}s", .elapsed.as_secs())]
+pub struct RetriesExhausted {
+    pub retries: u32,
+    pub elapsed: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Option<Duration>, max: Option<Duration>) -> Self {
+        let initial = initial.unwrap_or(DEFAULT_RETRY_DELAY);
+        Backoff {
+            initial,
+            max: max.unwrap_or(DEFAULT_MAX_RETRY_DELAY).max(initial),
+            max_retries: None,
+            max_time: None,
+            failures: 0,
+            since: None,
+        }
+    }
+
+    /// Gives up on a request once it has been retried `retries` times in a
+    /// row, or has kept failing for `time`, instead of retrying forever.
+    pub fn budget(mut self, retries: Option<u32>, time: Option<Duration>) -> Self {
+        self.max_retries = retries;
+        self.max_time = time;
+        self
+    }
+
+    /// The wait before the next retry, between half and all of the current
+    /// step.
+    pub fn delay(&mut self) -> Duration {
+        let step = self
+            .initial
+            .checked_mul(1 << self.failures.min(16))
+            .map_or(self.max, |step| step.min(self.max));
+        self.failures = self.failures.saturating_add(1);
+
+        let half = step / 2;
+        half + half.mul_f64(rand::thread_rng().gen())
+    }
+
+    /// Waits before a retry, at least as long as the relay asked if it did,
+    /// or fails if the retry is over budget.
+    pub async fn wait(&mut self, retry_after: Option<Duration>) -> Result<(), RetriesExhausted> {
+        let elapsed = self.since.get_or_insert_with(Instant::now).elapsed();
+        let exhausted = match (self.max_retries, self.max_time) {
+            (Some(retries), _) if self.failures >= retries => true,
+            (_, Some(time)) => elapsed >= time,
+            _ => false,
+        };
+        if exhausted {
+            return Err(RetriesExhausted {
+                retries: self.failures,
+                elapsed,
+            });
+        }
+
+        let delay = self.delay();
+        let mut delay = match retry_after {
+            Some(retry_after) => retry_after.max(delay),
+            None => delay,
+        };
+        // the last retry comes as the time is up
+        if let Some(time) = self.max_time {
+            delay = delay.min(time - elapsed);
+        }
+        tokio::time::delay_for(delay).await;
+        Ok(())
+    }
+
+    /// Starts over from the initial wait, once a request has gone through.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.since = None;
+    }
+}
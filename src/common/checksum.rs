@@ -0,0 +1,77 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+use sha2::{Digest, Sha256};
+
+/// How packets are checked for corruption, from fastest to strongest
+/// against deliberate tampering. The sender picks one per transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Crc32c,
+    Xxh3,
+    Sha256,
+    Blake3,
+}
+
+impl Default for Algorithm {
+    /// Fast and cryptographically strong.
+    fn default() -> Self {
+        Algorithm::Blake3
+    }
+}
+
+impl Algorithm {
+    fn digest(self, data: &[u8]) -> String {
+        match self {
+            Algorithm::Crc32c => format!("{:08x}", crc32c::crc32c(data)),
+            Algorithm::Xxh3 => format!("{:016x}", twox_hash::xxh3::hash64(data)),
+            Algorithm::Sha256 => hex::encode(Sha256::digest(data)),
+            Algorithm::Blake3 => blake3::hash(data).to_hex().to_string(),
+        }
+    }
+
+    /// Checksum of a packet in the form sent in the checksum header,
+    /// `ALGORITHM=HEX`.
+    pub fn checksum(self, data: &[u8]) -> String {
+        format!("{}={}", self, self.digest(data))
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "crc32c" => Algorithm::Crc32c,
+            "xxh3" => Algorithm::Xxh3,
+            "sha256" => Algorithm::Sha256,
+            "blake3" => Algorithm::Blake3,
+            _ => bail!("unknown checksum algorithm: {}", s),
+        })
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Algorithm::Crc32c => "crc32c",
+            Algorithm::Xxh3 => "xxh3",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Blake3 => "blake3",
+        })
+    }
+}
+
+/// Whether data matches a checksum header, whichever algorithm it uses.
+/// Fails on checksums that cannot be checked at all.
+pub fn verify(expected: &str, data: &[u8]) -> anyhow::Result<bool> {
+    let mut parts = expected.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(algorithm), Some(value)) => {
+            let algorithm: Algorithm = algorithm.parse()?;
+            Ok(value.eq_ignore_ascii_case(&algorithm.digest(data)))
+        }
+        _ => bail!("invalid checksum: {}", expected),
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+
+/// A way of compressing packets, each on its own, streamed from the packet
+/// to the output. Concatenated packets should decompress as a whole, as
+/// zstd, gzip and lz4 frames do.
+pub trait Codec: Send + Sync {
+    /// What the codec is picked by, and announced to receivers as
+    fn name(&self) -> &str;
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()>;
+    fn decompress(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()>;
+}
+
+#[cfg(feature = "zstd")]
+pub struct Zstd;
+
+#[cfg(feature = "zstd")]
+impl Codec for Zstd {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        zstd::stream::copy_encode(input, output, 0)
+    }
+
+    fn decompress(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        zstd::stream::copy_decode(input, output)
+    }
+}
+
+#[cfg(feature = "gzip")]
+pub struct Gzip;
+
+#[cfg(feature = "gzip")]
+impl Codec for Gzip {
+    fn name(&self) -> &str {
+        "gzip"
+    }
+
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+        io::copy(input, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn decompress(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        io::copy(&mut flate2::read::MultiGzDecoder::new(input), output)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "lz4")]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4 {
+    fn name(&self) -> &str {
+        "lz4"
+    }
+
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let mut encoder = lz4_flex::frame::FrameEncoder::new(output);
+        io::copy(input, &mut encoder)?;
+        encoder.finish().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn decompress(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        io::copy(&mut lz4_flex::frame::FrameDecoder::new(input), output)?;
+        Ok(())
+    }
+}
+
+/// The codecs packets can be compressed with, built in or registered.
+static CODECS: Lazy<RwLock<Vec<&'static dyn Codec>>> = Lazy::new(|| {
+    RwLock::new(vec![
+        #[cfg(feature = "zstd")]
+        &Zstd,
+        #[cfg(feature = "gzip")]
+        &Gzip,
+        #[cfg(feature = "lz4")]
+        &Lz4,
+    ])
+});
+
+/// Makes `codec` available by its name, to `--compress` and to receivers of
+/// packets it has compressed, in place of any codec of the same name.
+pub fn register(codec: Box<dyn Codec>) -> Compression {
+    let codec: &'static dyn Codec = Box::leak(codec);
+    let mut codecs = CODECS.write().unwrap();
+    codecs.retain(|c| !c.name().eq_ignore_ascii_case(codec.name()));
+    codecs.push(codec);
+    Compression(codec)
+}
+
+/// How the sender compresses packets, undone by receivers.
+#[derive(Clone, Copy)]
+pub struct Compression(&'static dyn Codec);
+
+impl Compression {
+    pub fn codec(self) -> &'static dyn Codec {
+        self.0
+    }
+
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut compressed = Vec::new();
+        self.0.compress(&mut &data[..], &mut compressed)?;
+        Ok(compressed)
+    }
+
+    pub fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        self.0.decompress(&mut &data[..], &mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+impl PartialEq for Compression {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.name().eq_ignore_ascii_case(other.0.name())
+    }
+}
+
+impl Eq for Compression {}
+
+impl fmt::Debug for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Compression").field(&self.0.name()).finish()
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let codecs = CODECS.read().unwrap();
+        match codecs.iter().find(|c| c.name().eq_ignore_ascii_case(s)) {
+            Some(&codec) => Ok(Compression(codec)),
+            None => {
+                let names: Vec<_> = codecs.iter().map(|c| c.name()).collect();
+                Err(anyhow!(
+                    "unknown compression: {} (available: {})",
+                    s,
+                    names.join(", ")
+                ))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0.name())
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::bail;
+use chacha20poly1305::{
+    aead::{Aead, Error, NewAead, Payload},
+    ChaCha20Poly1305, Key, Nonce, XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::Sha256;
+
+/// Version 1 left the packet index out of what is authenticated
+const VERSION: u8 = 2;
+const SALT_LEN: usize = 16;
+/// Version, salt, epoch, and ciphertext length, besides the nonce
+const HEADER_LEN: usize = 1 + SALT_LEN + 8 + 4;
+const KDF: &str = "pbkdf2-sha256";
+const DEFAULT_KDF_ROUNDS: u32 = 100_000;
+/// Stretching the passphrase more would stall receivers, whoever set it
+const MAX_KDF_ROUNDS: u32 = 10_000_000;
+
+type Salt = [u8; SALT_LEN];
+
+/// How packets are encrypted end to end, so the relay only ever sees
+/// ciphertext.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cipher {
+    ChaCha20Poly1305,
+    /// With random nonces long enough to never repeat, however much is sent
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn nonce_len(self) -> usize {
+        match self {
+            Cipher::ChaCha20Poly1305 => 12,
+            Cipher::XChaCha20Poly1305 => 24,
+        }
+    }
+
+    fn encrypt(self, key: &[u8; 32], nonce: &[u8], payload: Payload) -> Result<Vec<u8>, Error> {
+        let key = Key::from(*key);
+        match self {
+            Cipher::ChaCha20Poly1305 => {
+                let nonce: [u8; 12] = nonce.try_into().map_err(|_| Error)?;
+                ChaCha20Poly1305::new(&key).encrypt(&Nonce::from(nonce), payload)
+            }
+            Cipher::XChaCha20Poly1305 => {
+                let nonce: [u8; 24] = nonce.try_into().map_err(|_| Error)?;
+                XChaCha20Poly1305::new(&key).encrypt(&XNonce::from(nonce), payload)
+            }
+        }
+    }
+
+    fn decrypt(self, key: &[u8; 32], nonce: &[u8], payload: Payload) -> Result<Vec<u8>, Error> {
+        let key = Key::from(*key);
+        match self {
+            Cipher::ChaCha20Poly1305 => {
+                let nonce: [u8; 12] = nonce.try_into().map_err(|_| Error)?;
+                ChaCha20Poly1305::new(&key).decrypt(&Nonce::from(nonce), payload)
+            }
+            Cipher::XChaCha20Poly1305 => {
+                let nonce: [u8; 24] = nonce.try_into().map_err(|_| Error)?;
+                XChaCha20Poly1305::new(&key).decrypt(&XNonce::from(nonce), payload)
+            }
+        }
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "chacha20poly1305" => Cipher::ChaCha20Poly1305,
+            "xchacha20poly1305" => Cipher::XChaCha20Poly1305,
+            _ => bail!("unknown cipher: {}", s),
+        })
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Cipher::ChaCha20Poly1305 => "chacha20poly1305",
+            Cipher::XChaCha20Poly1305 => "xchacha20poly1305",
+        })
+    }
+}
+
+/// Everything but the passphrase that it takes to decrypt a transfer,
+/// announced by the sender along with the channel so that receivers need
+/// not be told. Written like `chacha20poly1305; kdf=pbkdf2-sha256;
+/// rounds=100000`, where older senders only give the cipher.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Params {
+    pub cipher: Cipher,
+    /// PBKDF2-SHA256 iterations stretching the passphrase
+    pub kdf_rounds: u32,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            cipher: Cipher::ChaCha20Poly1305,
+            kdf_rounds: DEFAULT_KDF_ROUNDS,
+        }
+    }
+}
+
+impl FromStr for Params {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parts = s.split(';').map(str::trim);
+        let mut params = Params {
+            cipher: parts.next().unwrap_or_default().parse()?,
+            ..Default::default()
+        };
+
+        for part in parts {
+            match part.find('=').map(|i| (&part[..i], &part[i + 1..])) {
+                Some(("kdf", KDF)) => {}
+                Some(("kdf", kdf)) => bail!("unknown key derivation: {}", kdf),
+                Some(("rounds", rounds)) => params.kdf_rounds = parse_kdf_rounds(rounds)?,
+                _ => bail!("invalid encryption parameter: {}", part),
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+pub fn parse_kdf_rounds(s: &str) -> anyhow::Result<u32> {
+    match s.parse() {
+        Ok(rounds) if rounds > 0 && rounds <= MAX_KDF_ROUNDS => Ok(rounds),
+        _ => bail!("invalid key derivation rounds: {}", s),
+    }
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}; kdf={}; rounds={}",
+            self.cipher, KDF, self.kdf_rounds
+        )
+    }
+}
+
+/// The key a transfer's epoch keys are derived from, stretched from the
+/// passphrase as it is the only secret both ends share.
+fn master_key(passphrase: &str, salt: &Salt, rounds: u32) -> [u8; 32] {
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, &mut key);
+    key
+}
+
+/// The key of one epoch. Epoch keys cannot be derived from one another, so
+/// a leaked one only exposes the data sent during its epoch.
+fn epoch_key(master: &[u8; 32], salt: &Salt, epoch: u64) -> [u8; 32] {
+    let mut info = b"http-pipe epoch ".to_vec();
+    info.extend_from_slice(&epoch.to_be_bytes());
+
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(Some(salt), master)
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid length for HKDF-SHA256");
+    key
+}
+
+/// The key sealing the end of a transfer, kept apart from the epoch keys.
+fn seal_key(master: &[u8; 32], salt: &Salt) -> [u8; 32] {
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(Some(salt), master)
+        .expand(b"http-pipe seal", &mut key)
+        .expect("32 bytes is a valid length for HKDF-SHA256");
+    key
+}
+
+fn seal_mac(master: &[u8; 32], salt: &Salt, end: u64, sealed: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(&seal_key(master, salt)).expect("HMAC accepts keys of any size");
+    mac.update(&end.to_be_bytes());
+    mac.update(sealed.as_bytes());
+    mac
+}
+
+/// The packet index is authenticated along with the frame header without
+/// being sent, so the relay cannot reorder, repeat or drop packets.
+fn aad(header: &[u8], index: u64) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad
+}
+
+/// Encrypts packets, switching to a new key every `rekey_after` bytes of
+/// input. Each packet carries the salt and epoch it was encrypted with, so
+/// receivers follow the rotation without being told about it.
+pub struct Encryptor {
+    params: Params,
+    master: [u8; 32],
+    salt: Salt,
+    rekey_after: u64,
+}
+
+impl Encryptor {
+    pub fn new(passphrase: &str, mut params: Params, rekey_after: u64) -> Self {
+        params.kdf_rounds = params.kdf_rounds.clamp(1, MAX_KDF_ROUNDS);
+        let mut salt = [0; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        Encryptor {
+            params,
+            master: master_key(passphrase, &salt, params.kdf_rounds),
+            salt,
+            rekey_after: rekey_after.max(1),
+        }
+    }
+
+    /// What receivers need to be told to decrypt the packets.
+    pub fn params(&self) -> Params {
+        self.params
+    }
+
+    /// Encrypts packet `index`, whose data is found at `offset` in the
+    /// input.
+    pub fn encrypt(&self, index: u64, offset: u64, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let epoch = offset / self.rekey_after;
+        let cipher = self.params.cipher;
+        let mut nonce = vec![0; cipher.nonce_len()];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut frame = Vec::with_capacity(HEADER_LEN + nonce.len() + data.len() + 16);
+        frame.push(VERSION);
+        frame.extend_from_slice(&self.salt);
+        frame.extend_from_slice(&epoch.to_be_bytes());
+        frame.extend_from_slice(&nonce);
+        frame.extend_from_slice(&(data.len() as u32 + 16).to_be_bytes());
+
+        let key = epoch_key(&self.master, &self.salt, epoch);
+        let aad = aad(&frame, index);
+        let payload = Payload {
+            msg: data,
+            aad: &aad,
+        };
+        let ciphertext = match cipher.encrypt(&key, &nonce, payload) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => bail!("failed to encrypt packet"),
+        };
+        frame.extend_from_slice(&ciphertext);
+
+        Ok(frame)
+    }
+
+    /// Seals the end of the stream at packet `end`, along with whatever
+    /// else the sender says about the transfer there. Packets on their own
+    /// cannot tell receivers that the relay ended the stream early.
+    pub fn seal(&self, end: u64, sealed: &str) -> String {
+        let mac = seal_mac(&self.master, &self.salt, end, sealed);
+        format!(
+            "{}:{}:{}",
+            self.params.kdf_rounds,
+            hex::encode(self.salt),
+            hex::encode(mac.finalize().into_bytes())
+        )
+    }
+}
+
+/// Decrypts packets from any sender that used the same passphrase.
+pub struct Decryptor {
+    passphrase: String,
+    masters: Mutex<HashMap<(Salt, u32), [u8; 32]>>,
+}
+
+impl Decryptor {
+    pub fn new(passphrase: &str) -> Self {
+        Decryptor {
+            passphrase: passphrase.to_owned(),
+            masters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Every sender picks its own salt, and stretching it is slow.
+    fn master(&self, salt: Salt, rounds: u32) -> [u8; 32] {
+        *self
+            .masters
+            .lock()
+            .unwrap()
+            .entry((salt, rounds))
+            .or_insert_with(|| master_key(&self.passphrase, &salt, rounds))
+    }
+
+    /// Decrypts one or more concatenated packets starting at `index`,
+    /// encrypted as `params` describe.
+    pub fn decrypt(
+        &self,
+        params: Params,
+        mut index: u64,
+        mut data: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut plaintext = Vec::with_capacity(data.len());
+        let nonce_len = params.cipher.nonce_len();
+        let header_len = HEADER_LEN + nonce_len;
+
+        while !data.is_empty() {
+            if data.first() == Some(&1) {
+                bail!("the sender's version of http-pipe is too old");
+            }
+            if data.len() < header_len || data[0] != VERSION {
+                bail!("not an encrypted packet");
+            }
+
+            let salt: Salt = data[1..1 + SALT_LEN].try_into()?;
+            let mut pos = 1 + SALT_LEN;
+            let epoch = u64::from_be_bytes(data[pos..pos + 8].try_into()?);
+            pos += 8;
+            let nonce = &data[pos..pos + nonce_len];
+            pos += nonce_len;
+            let len = u32::from_be_bytes(data[pos..pos + 4].try_into()?) as usize;
+
+            if data.len() < header_len + len {
+                bail!("truncated encrypted packet");
+            }
+
+            let master = self.master(salt, params.kdf_rounds);
+            let aad = aad(&data[..header_len], index);
+            let payload = Payload {
+                msg: &data[header_len..header_len + len],
+                aad: &aad,
+            };
+            let key = epoch_key(&master, &salt, epoch);
+            match params.cipher.decrypt(&key, nonce, payload) {
+                Ok(decrypted) => plaintext.extend_from_slice(&decrypted),
+                Err(_) => bail!("wrong passphrase, or the packet was tampered with"),
+            }
+
+            data = &data[header_len + len..];
+            index += 1;
+        }
+
+        Ok(plaintext)
+    }
+
+    /// Checks the sender sealed the end of the stream at packet `end`,
+    /// along with `sealed`.
+    pub fn check_seal(&self, seal: &str, end: u64, sealed: &str) -> anyhow::Result<()> {
+        let fields: Vec<&str> = seal.split(':').collect();
+        let (rounds, salt, mac) = match fields.as_slice() {
+            [rounds, salt, mac] => (
+                parse_kdf_rounds(rounds)?,
+                hex::decode(salt)?,
+                hex::decode(mac)?,
+            ),
+            _ => bail!("invalid seal: {}", seal),
+        };
+        let salt: Salt = match salt.as_slice().try_into() {
+            Ok(salt) => salt,
+            Err(_) => bail!("invalid seal: {}", seal),
+        };
+
+        let master = self.master(salt, rounds);
+        if seal_mac(&master, &salt, end, sealed).verify(&mac).is_err() {
+            bail!("the end of the stream was tampered with");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+
+use anyhow::bail;
+use toml::Value;
+
+/// Adds settings read from a config file to the command line `args`, as
+/// the flags they are named after, e.g. `max-retention = 512` for
+/// `--max-retention 512`. Flags already on the command line take
+/// precedence.
+pub fn add_flags(
+    args: &mut Vec<OsString>,
+    settings: BTreeMap<String, Value>,
+) -> anyhow::Result<()> {
+    let given = |flag: &str| {
+        args.iter().any(|arg| match arg.to_str() {
+            Some(arg) => arg == flag || arg.starts_with(&format!("{}=", flag)),
+            None => false,
+        })
+    };
+
+    let mut added = Vec::new();
+    for (key, value) in settings {
+        if key.is_empty() || key.starts_with('-') {
+            bail!("invalid setting: {}", key);
+        }
+
+        let flag = format!("--{}", key);
+        if given(&flag) {
+            continue;
+        }
+        match &value {
+            Value::Boolean(true) => added.push(flag),
+            Value::Boolean(false) => {}
+            Value::Array(values) => {
+                for value in values {
+                    added.push(format!("{}={}", flag, scalar(&key, value)?));
+                }
+            }
+            value => added.push(format!("{}={}", flag, scalar(&key, value)?)),
+        }
+    }
+
+    // ahead of any subcommand, which would take them as its own
+    let at = args.len().min(1);
+    args.splice(at..at, added.into_iter().map(OsString::from));
+    Ok(())
+}
+
+/// A value as it would be written on the command line.
+pub fn scalar(key: &str, value: &Value) -> anyhow::Result<String> {
+    Ok(match value {
+        Value::String(s) => s.clone(),
+        Value::Integer(n) => n.to_string(),
+        Value::Float(n) => n.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Datetime(d) => d.to_string(),
+        Value::Array(_) | Value::Table(_) => bail!("{} must be a single value", key),
+    })
+}
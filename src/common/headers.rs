@@ -1,4 +1,47 @@
 pub const ACK: &str = "X-Http-Pipe-Ack";
 pub const INDEX: &str = "X-Http-Pipe-Index";
+pub const NONCE: &str = "X-Http-Pipe-Nonce";
 pub const RESET: &str = "X-Http-Pipe-Reset";
 pub const WORKER: &str = "X-Http-Pipe-Worker";
+pub const WORKERS: &str = "X-Http-Pipe-Workers";
+pub const SIGNATURE: &str = "X-Http-Pipe-Signature";
+pub const TIMESTAMP: &str = "X-Http-Pipe-Timestamp";
+pub const OLDEST: &str = "X-Http-Pipe-Oldest";
+pub const STREAM: &str = "X-Http-Pipe-Stream";
+pub const STREAM_MAX_AGE: &str = "X-Http-Pipe-Stream-Max-Age";
+pub const STREAM_MAX_BYTES: &str = "X-Http-Pipe-Stream-Max-Bytes";
+pub const COMMITTED: &str = "X-Http-Pipe-Committed";
+pub const CREDIT: &str = "X-Http-Pipe-Credit";
+pub const PAUSE: &str = "X-Http-Pipe-Pause";
+pub const PRIORITY: &str = "X-Http-Pipe-Priority";
+pub const COMPLETE: &str = "X-Http-Pipe-Complete";
+pub const RANGE: &str = "X-Http-Pipe-Range";
+pub const MIRROR: &str = "X-Http-Pipe-Mirror";
+pub const STATUS: &str = "X-Http-Pipe-Status";
+pub const STATS: &str = "X-Http-Pipe-Stats";
+pub const TRANSFER: &str = "X-Http-Pipe-Transfer";
+pub const PACKET_SIZE: &str = "X-Http-Pipe-Packet-Size";
+pub const CHECKSUM: &str = "X-Http-Pipe-Checksum";
+pub const CHECKSUM_ALGORITHM: &str = "X-Http-Pipe-Checksum-Algorithm";
+pub const COMPRESSION: &str = "X-Http-Pipe-Compression";
+pub const POW: &str = "X-Http-Pipe-Pow";
+pub const PART: &str = "X-Http-Pipe-Part";
+pub const LIMITS: &str = "X-Http-Pipe-Limits";
+pub const RECEIVERS: &str = "X-Http-Pipe-Receivers";
+pub const ENCRYPTION: &str = "X-Http-Pipe-Encryption";
+pub const RECORDS: &str = "X-Http-Pipe-Records";
+pub const MAX_RATE: &str = "X-Http-Pipe-Max-Rate";
+pub const PACKETS: &str = "X-Http-Pipe-Packets";
+pub const STRIDE: &str = "X-Http-Pipe-Stride";
+pub const DIGEST: &str = "X-Http-Pipe-Digest";
+pub const CLAIM: &str = "X-Http-Pipe-Claim";
+pub const CHANNEL_SECRET: &str = "X-Http-Pipe-Channel-Secret";
+pub const CHANNEL_SECRET_DIGEST: &str = "X-Http-Pipe-Channel-Secret-Digest";
+pub const SHUTDOWN: &str = "X-Http-Pipe-Shutdown";
+pub const ABORT_REASON: &str = "X-Http-Pipe-Abort-Reason";
+pub const HEARTBEAT: &str = "X-Http-Pipe-Heartbeat";
+pub const SENDER_SILENCE: &str = "X-Http-Pipe-Sender-Silence";
+pub const RECEIVER_SILENCE: &str = "X-Http-Pipe-Receiver-Silence";
+pub const TRAILER: &str = "X-Http-Pipe-Trailer";
+pub const SESSION: &str = "X-Http-Pipe-Session";
+pub const FORWARDED: &str = "X-Http-Pipe-Forwarded";
@@ -0,0 +1,158 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::ready;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, StreamReader},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::client::{self, ReceiveOptions, SendOptions};
+
+type Chunk = io::Result<Bytes>;
+
+/// A failed transfer, as seen through the I/O traits.
+fn io_error<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::ConnectionAborted, e)
+}
+
+/// Hands written data over to the task running a transfer.
+struct ChannelWriter {
+    tx: Option<mpsc::Sender<Chunk>>,
+}
+
+impl AsyncWrite for ChannelWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let tx = match &mut self.tx {
+            Some(tx) => tx,
+            None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        };
+
+        if ready!(tx.poll_ready(cx)).is_err() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        match tx.try_send(Ok(Bytes::copy_from_slice(buf))) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        self.tx = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Sends everything written to it to a channel. Shutting it down marks
+/// the end of the data and waits for the transfer to finish, reporting
+/// its failure if any.
+pub struct HttpPipeWriter {
+    inner: ChannelWriter,
+    task: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl HttpPipeWriter {
+    pub fn new(endpoint: &str, secret: Option<&str>, options: SendOptions) -> Self {
+        let (tx, rx) = mpsc::channel(4);
+        let endpoint = endpoint.to_owned();
+        let secret = secret.map(str::to_owned);
+
+        let task = tokio::spawn(async move {
+            let input = tokio::io::stream_reader(rx);
+            client::send(&endpoint, secret.as_deref(), &options, input).await
+        });
+
+        HttpPipeWriter {
+            inner: ChannelWriter { tx: Some(tx) },
+            task: Some(task),
+        }
+    }
+}
+
+impl AsyncWrite for HttpPipeWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+
+        let task = match &mut self.task {
+            Some(task) => task,
+            None => return Poll::Ready(Ok(())),
+        };
+
+        let result = ready!(Pin::new(task).poll(cx));
+        self.task = None;
+
+        Poll::Ready(match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(io_error(e)),
+            Err(e) => Err(io_error(e)),
+        })
+    }
+}
+
+/// Reads everything sent to a channel. A failed transfer surfaces as a
+/// read error.
+pub struct HttpPipeReader {
+    inner: StreamReader<mpsc::Receiver<Chunk>, Bytes>,
+}
+
+impl HttpPipeReader {
+    pub fn new(endpoint: &str, secret: Option<&str>, options: ReceiveOptions) -> Self {
+        let (mut tx, rx) = mpsc::channel(4);
+        let endpoint = endpoint.to_owned();
+        let secret = secret.map(str::to_owned);
+
+        tokio::spawn(async move {
+            let output = ChannelWriter {
+                tx: Some(tx.clone()),
+            };
+
+            if let Err(e) = client::receive(&endpoint, secret.as_deref(), &options, output).await {
+                let _ = tx.send(Err(io_error(e))).await;
+            }
+        });
+
+        HttpPipeReader {
+            inner: tokio::io::stream_reader(rx),
+        }
+    }
+}
+
+impl AsyncRead for HttpPipeReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
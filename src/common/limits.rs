@@ -0,0 +1,71 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+
+/// Limits of a relay, sent with RESET and status responses so that
+/// clients can check their settings against them before sending.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Limits {
+    pub max_workers: Option<u64>,
+    pub max_packet_size: Option<u64>,
+    /// Packets buffered for a channel at most
+    pub queue_depth: Option<u64>,
+    /// Bytes buffered for a channel, in however many packets they fit
+    pub channel_bytes: Option<u64>,
+    /// Packets retained for a stream at most
+    pub max_retention: Option<u64>,
+    /// Bytes buffered across all channels
+    pub memory_limit: Option<u64>,
+}
+
+impl Limits {
+    fn fields(&self) -> [(&'static str, Option<u64>); 6] {
+        [
+            ("max-workers", self.max_workers),
+            ("max-packet-size", self.max_packet_size),
+            ("queue-depth", self.queue_depth),
+            ("channel-bytes", self.channel_bytes),
+            ("max-retention", self.max_retention),
+            ("memory-limit", self.memory_limit),
+        ]
+    }
+
+    /// The form sent in the limits header, parsed back by `from_str`.
+    pub fn to_header(&self) -> String {
+        self.fields()
+            .iter()
+            .filter_map(|(key, value)| value.map(|value| format!("{}={}", key, value)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl FromStr for Limits {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut limits = Limits::default();
+
+        for pair in s.split(',').filter(|pair| !pair.is_empty()) {
+            let mut kv = pair.splitn(2, '=');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => (key, value.parse()?),
+                _ => bail!("invalid limits: {}", s),
+            };
+
+            let limit = match key {
+                "max-workers" => &mut limits.max_workers,
+                "max-packet-size" => &mut limits.max_packet_size,
+                "queue-depth" => &mut limits.queue_depth,
+                "channel-bytes" => &mut limits.channel_bytes,
+                "max-retention" => &mut limits.max_retention,
+                "memory-limit" => &mut limits.memory_limit,
+                // limits added by newer servers
+                _ => continue,
+            };
+            *limit = Some(value);
+        }
+
+        Ok(limits)
+    }
+}
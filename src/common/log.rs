@@ -1,14 +1,293 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt::{self, Display},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use atty::Stream;
+use chrono::SecondsFormat;
+use log::{debug, warn, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+use serde_json::{Map, Value};
 use simplelog::*;
 
-pub fn init_log(debug: bool) {
-    CombinedLogger::init(vec![TermLogger::new(
-        if debug {
-            LevelFilter::Debug
-        } else {
-            LevelFilter::Info
-        },
-        Config::default(),
-        TerminalMode::Stderr,
-    )])
-    .unwrap();
+/// How often failures repeating in a row are summarized
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Failures counted since the last summary, by request and error
+struct Failures {
+    since: Option<Instant>,
+    counts: BTreeMap<(&'static str, String), u64>,
+}
+
+static FAILURES: Lazy<Mutex<Failures>> = Lazy::new(|| {
+    Mutex::new(Failures {
+        since: None,
+        counts: BTreeMap::new(),
+    })
+});
+
+/// Log files are rotated once they reach this size
+const LOG_FILE_SIZE: u64 = 10 << 20;
+/// Rotated log files kept besides the current one
+const LOG_FILES_KEPT: usize = 5;
+
+thread_local! {
+    /// The fields of the record being logged by log_with, for JSON loggers
+    static FIELDS: RefCell<Map<String, Value>> = RefCell::new(Map::new());
+}
+
+/// How log lines are written: as text for people, or as JSON objects, one
+/// per line, for log collectors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            _ => bail!("unknown log format: {} (expected text or json)", s),
+        })
+    }
+}
+
+/// What a log line is about, given as fields of their own in JSON logs
+/// rather than only within the message.
+#[derive(Default)]
+pub struct Fields<'a> {
+    pub channel: Option<&'a str>,
+    pub transfer: Option<&'a str>,
+    pub worker: Option<&'a str>,
+    pub packet: Option<&'a str>,
+}
+
+impl Fields<'_> {
+    fn add_to(&self, line: &mut Map<String, Value>) {
+        let fields = [
+            ("channel", self.channel),
+            ("transfer", self.transfer),
+            ("worker", self.worker),
+            ("packet", self.packet),
+        ];
+        for (key, value) in fields.iter() {
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+            // indexes are numbers, for collectors to filter on
+            let value = match (*key, value.parse::<u64>()) {
+                ("worker", Ok(n)) | ("packet", Ok(n)) => Value::from(n),
+                _ => Value::from(*value),
+            };
+            line.insert((*key).to_owned(), value);
+        }
+    }
+}
+
+/// Writes each record as a JSON object on a line of its own.
+struct JsonLogger<W> {
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLogger<W> {
+    fn new(out: W) -> Box<Self> {
+        Box::new(JsonLogger {
+            out: Mutex::new(out),
+        })
+    }
+}
+
+impl<W: Write + Send> Log for JsonLogger<W> {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let mut line = Map::new();
+        line.insert(
+            "time".to_owned(),
+            Value::from(chrono::Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+        );
+        line.insert("level".to_owned(), Value::from(record.level().as_str()));
+        line.insert("target".to_owned(), Value::from(record.target()));
+        line.insert("message".to_owned(), Value::from(record.args().to_string()));
+        FIELDS.with(|fields| {
+            for (key, value) in fields.borrow().iter() {
+                line.insert(key.clone(), value.clone());
+            }
+        });
+
+        let _ = writeln!(self.out.lock().unwrap(), "{}", Value::Object(line));
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().unwrap().flush();
+    }
+}
+
+impl<W: Write + Send + 'static> SharedLogger for JsonLogger<W> {
+    fn level(&self) -> LevelFilter {
+        LevelFilter::Trace
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
+
+/// A log file moved aside as `PATH.1` once it reaches LOG_FILE_SIZE, the
+/// older ones becoming `PATH.2` and so on, up to LOG_FILES_KEPT.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// Whether the last write ended a line, so that lines are not split
+    /// across files
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RotatingFile {
+            path: path.to_owned(),
+            size: file.metadata()?.len(),
+            file,
+            at_line_start: true,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..LOG_FILES_KEPT).rev() {
+            let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        *self = RotatingFile::open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size >= LOG_FILE_SIZE && self.at_line_start {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        self.at_line_start = buf[..n].ends_with(b"\n");
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// All diagnostics go to stderr, leaving stdout to the piped data, and to
+/// `log_file` as well if given. Colors are only used when stderr is a
+/// terminal.
+pub fn init_log(
+    level: LevelFilter,
+    format: LogFormat,
+    log_file: Option<&Path>,
+) -> anyhow::Result<()> {
+    // the loggers let everything through, for the level to be changed later
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![match format {
+        LogFormat::Json => JsonLogger::new(io::stderr()),
+        LogFormat::Text if atty::is(Stream::Stderr) => {
+            TermLogger::new(LevelFilter::Trace, Config::default(), TerminalMode::Stderr)
+        }
+        LogFormat::Text => WriteLogger::new(LevelFilter::Trace, Config::default(), io::stderr()),
+    }];
+
+    if let Some(path) = log_file {
+        let file = RotatingFile::open(path)
+            .with_context(|| format!("failed to open log file {}", path.display()))?;
+        loggers.push(match format {
+            LogFormat::Json => JsonLogger::new(file),
+            LogFormat::Text => WriteLogger::new(LevelFilter::Trace, Config::default(), file),
+        });
+    }
+
+    CombinedLogger::init(loggers)?;
+    set_log_level(level);
+    Ok(())
+}
+
+/// Logs `args` from `target` along with `fields`, which are only written
+/// apart from the message in JSON logs.
+pub fn log_with(level: Level, target: &str, fields: &Fields, args: fmt::Arguments) {
+    if level > log::max_level() {
+        return;
+    }
+
+    let record = Record::builder()
+        .level(level)
+        .target(target)
+        .args(args)
+        .build();
+    FIELDS.with(|line| fields.add_to(&mut line.borrow_mut()));
+    log::logger().log(&record);
+    FIELDS.with(|line| line.borrow_mut().clear());
+}
+
+/// Changes how much is logged from now on.
+pub fn set_log_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Logs the failure of a request that is going to be retried. The first
+/// failure in a while is logged right away, while those following it are
+/// counted and summarized once a minute, e.g. `PUT failed 120× in last 60s:
+/// connection refused`.
+pub fn log_retry(what: &'static str, error: impl Display) {
+    let error = error.to_string();
+    let mut failures = FAILURES.lock().unwrap();
+
+    let summarized = match failures.since {
+        Some(since) if since.elapsed() < SUMMARY_INTERVAL => {
+            debug!("{} failed: {}", what, error);
+            *failures.counts.entry((what, error)).or_insert(0) += 1;
+            return;
+        }
+        Some(since) => {
+            let secs = since.elapsed().as_secs();
+            let counts = std::mem::take(&mut failures.counts);
+            for ((what, error), count) in &counts {
+                warn!("{} failed {}× in last {}s: {}", what, count, secs, error);
+            }
+            !counts.is_empty()
+        }
+        None => false,
+    };
+
+    failures.since = Some(Instant::now());
+    if summarized {
+        *failures.counts.entry((what, error)).or_insert(0) += 1;
+    } else {
+        warn!("{} failed: {}, retrying", what, error);
+    }
 }
@@ -1,7 +1,34 @@
+mod backoff;
+mod limits;
 mod log;
 mod packet;
+mod priority;
+mod rate;
+mod schedule;
+mod size;
+mod stats;
+mod status;
+mod trailer;
 
+pub mod checksum;
+pub mod compression;
+pub mod encryption;
+pub mod flags;
 pub mod headers;
+pub mod io;
+pub mod pow;
+pub mod records;
+pub mod signature;
+pub mod tunnel;
 
 pub use self::log::*;
+pub use backoff::*;
+pub use limits::*;
 pub use packet::*;
+pub use priority::*;
+pub use rate::*;
+pub use schedule::*;
+pub use size::*;
+pub use stats::*;
+pub use status::*;
+pub use trailer::*;
@@ -1,7 +1,49 @@
-use bytes::Bytes;
+use std::convert::TryInto;
+
+use anyhow::bail;
+use bytes::{Buf, Bytes, BytesMut};
 
 #[derive(Clone, Debug)]
 pub struct Packet {
     pub index: usize,
     pub data: Bytes,
 }
+
+/// A packet in a response carrying several: its index, the length of its
+/// checksum and the checksum, the length of its data and the data.
+pub fn frame_packet(index: usize, checksum: &str, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(14 + checksum.len() + data.len());
+    frame.extend_from_slice(&(index as u64).to_be_bytes());
+    frame.extend_from_slice(&(checksum.len() as u16).to_be_bytes());
+    frame.extend_from_slice(checksum.as_bytes());
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Splits the first frame off `buf` with its index and checksum, once it
+/// has been received completely.
+pub fn unframe_packet(buf: &mut BytesMut) -> anyhow::Result<Option<(u64, String, Bytes)>> {
+    if buf.len() < 10 {
+        return Ok(None);
+    }
+    let checksum_len = u16::from_be_bytes(buf[8..10].try_into()?) as usize;
+
+    let len_at = 10 + checksum_len;
+    if buf.len() < len_at + 4 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(buf[len_at..len_at + 4].try_into()?) as usize;
+    if buf.len() < len_at + 4 + len {
+        return Ok(None);
+    }
+
+    let index = u64::from_be_bytes(buf[..8].try_into()?);
+    let checksum = match std::str::from_utf8(&buf[10..len_at]) {
+        Ok(checksum) => checksum.to_owned(),
+        Err(_) => bail!("invalid checksum in packet {}", index),
+    };
+    buf.advance(len_at + 4);
+
+    Ok(Some((index, checksum, buf.split_to(len).freeze())))
+}
@@ -0,0 +1,43 @@
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+
+/// Beyond this, solving takes longer than anyone would wait
+pub const MAX_BITS: u32 = 32;
+
+/// Number of leading zero bits of the hash proving work on a challenge for
+/// a channel.
+pub fn work(challenge: &str, channel: &str, nonce: u64) -> u32 {
+    let hash = Sha256::new()
+        .chain(challenge)
+        .chain(":")
+        .chain(channel)
+        .chain(":")
+        .chain(nonce.to_string())
+        .finalize();
+
+    let mut bits = 0;
+    for byte in hash.iter() {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Finds a nonce for a challenge of the form `BITS:...`, returning the
+/// challenge with the nonce appended.
+pub fn solve(challenge: &str, channel: &str) -> anyhow::Result<String> {
+    let bits: u32 = challenge
+        .split(':')
+        .next()
+        .and_then(|bits| bits.parse().ok())
+        .filter(|&bits| bits <= MAX_BITS)
+        .ok_or_else(|| anyhow!("invalid proof-of-work challenge: {}", challenge))?;
+
+    let nonce = (0..)
+        .find(|&nonce| work(challenge, channel, nonce) >= bits)
+        .expect("a nonce is found long before running out");
+
+    Ok(format!("{}:{}", challenge, nonce))
+}
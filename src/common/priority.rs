@@ -0,0 +1,36 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+
+/// Service class of a channel. Under memory pressure on the server,
+/// higher classes are served first and bulk channels are held back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Bulk,
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "bulk" => Priority::Bulk,
+            "normal" => Priority::Normal,
+            "high" => Priority::High,
+            _ => bail!("unknown priority: {}", s),
+        })
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Priority::Bulk => "bulk",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        })
+    }
+}
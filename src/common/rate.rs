@@ -0,0 +1,103 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::future;
+use tokio::sync::broadcast;
+
+struct State {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Limits throughput to a rate given on every call, so that the rate can
+/// change over time. Callers going over the rate are delayed until the
+/// debt they incurred is paid off.
+pub struct TokenBucket {
+    state: Mutex<State>,
+}
+
+impl TokenBucket {
+    pub fn new() -> Self {
+        TokenBucket {
+            state: Mutex::new(State {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Lets `n` bytes through at `rate` bytes per second, allowing bursts
+    /// of up to one second worth of data.
+    pub async fn take(&self, n: u64, rate: u64) {
+        let rate = rate.max(1) as f64;
+
+        let delay = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last).as_secs_f64();
+            state.last = now;
+            state.tokens = (state.tokens + elapsed * rate).min(rate) - n as f64;
+
+            if state.tokens < 0.0 {
+                Duration::from_secs_f64(-state.tokens / rate)
+            } else {
+                Duration::from_secs(0)
+            }
+        };
+
+        if delay > Duration::from_secs(0) {
+            tokio::time::delay_for(delay).await;
+        }
+    }
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A rate shared by all connections of a transfer, which may be changed
+/// while it runs. A rate of 0 lets everything through.
+pub struct RateLimit {
+    rate: AtomicU64,
+    bucket: TokenBucket,
+    changed: broadcast::Sender<()>,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64) -> Self {
+        RateLimit {
+            rate: AtomicU64::new(rate),
+            bucket: TokenBucket::new(),
+            changed: broadcast::channel(1).0,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+        let _ = self.changed.send(());
+    }
+
+    pub async fn take(&self, n: u64) {
+        let rate = self.rate();
+        if rate > 0 {
+            // waits at the previous rate are cut short by a new one
+            let mut changed = self.changed.subscribe();
+            future::select(
+                Box::pin(self.bucket.take(n, rate)),
+                Box::pin(changed.recv()),
+            )
+            .await;
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::convert::TryInto;
+use std::sync::Mutex;
+
+use anyhow::bail;
+use bytes::{Buf, Bytes};
+use serde::{Deserialize, Serialize};
+
+/// Out-of-band metadata sent along with the data, e.g. a progress marker
+/// or an application tag, placed before the byte at `offset`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub offset: u64,
+    pub value: String,
+}
+
+/// Metadata waiting to be sent with the next packet.
+#[derive(Default)]
+pub struct Outbox {
+    values: Mutex<Vec<String>>,
+}
+
+impl Outbox {
+    pub fn push<S: Into<String>>(&self, value: S) {
+        self.values.lock().unwrap().push(value.into());
+    }
+
+    pub(crate) fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.values.lock().unwrap())
+    }
+}
+
+/// Frames the data of a packet together with the records following it:
+/// the length of the records, the records as JSON, then the length of the
+/// data and the data itself. Frames can be concatenated like packets.
+pub fn frame(records: &[Record], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let records = serde_json::to_vec(records)?;
+
+    let mut frame = Vec::with_capacity(8 + records.len() + data.len());
+    frame.extend_from_slice(&(records.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&records);
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+
+    Ok(frame)
+}
+
+fn split(bytes: &mut Bytes) -> anyhow::Result<Bytes> {
+    if bytes.len() < 4 {
+        bail!("truncated record frame");
+    }
+    let len = u32::from_be_bytes(bytes[..4].try_into()?) as usize;
+    bytes.advance(4);
+
+    if bytes.len() < len {
+        bail!("truncated record frame");
+    }
+    Ok(bytes.split_to(len))
+}
+
+/// Splits one or more concatenated frames into their records and data.
+pub fn unframe(mut bytes: Bytes) -> anyhow::Result<(Vec<Record>, Bytes)> {
+    let mut records = Vec::new();
+    let mut data = Vec::with_capacity(bytes.len());
+
+    while !bytes.is_empty() {
+        let json = split(&mut bytes)?;
+        records.extend(serde_json::from_slice::<Vec<Record>>(&json)?);
+        data.extend_from_slice(&split(&mut bytes)?);
+    }
+
+    Ok((records, data.into()))
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use chrono::{Local, Timelike};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A wall-clock time in the local time zone, written as `HH:MM`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeOfDay(u32);
+
+impl TimeOfDay {
+    pub fn now() -> Self {
+        let now = Local::now();
+        TimeOfDay(now.hour() * 60 + now.minute())
+    }
+
+    /// Whether `self` falls in the window from `start` (inclusive) to `end`
+    /// (exclusive), which may wrap around midnight.
+    pub fn is_between(self, start: TimeOfDay, end: TimeOfDay) -> bool {
+        if start.0 <= end.0 {
+            start.0 <= self.0 && self.0 < end.0
+        } else {
+            self.0 >= start.0 || self.0 < end.0
+        }
+    }
+
+    /// Time left until this time of day is reached next.
+    pub fn until_next(self) -> Duration {
+        let now = Local::now();
+        let elapsed = now.num_seconds_from_midnight() as u64;
+        let target = self.0 as u64 * 60;
+
+        let day = MINUTES_PER_DAY as u64 * 60;
+        Duration::from_secs((target + day - elapsed) % day)
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parts = s.splitn(2, ':');
+        let (hour, minute) = match (parts.next(), parts.next()) {
+            (Some(hour), Some(minute)) => (hour, minute),
+            _ => bail!("expected a time as HH:MM: {}", s),
+        };
+
+        let hour: u32 = hour.parse().map_err(|_| anyhow!("invalid hour: {}", s))?;
+        let minute: u32 = minute
+            .parse()
+            .map_err(|_| anyhow!("invalid minute: {}", s))?;
+        if hour > 24 || minute >= 60 || hour * 60 + minute > MINUTES_PER_DAY {
+            bail!("time out of range: {}", s);
+        }
+
+        Ok(TimeOfDay((hour * 60 + minute) % MINUTES_PER_DAY))
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Computes and checks HMAC-SHA256 signatures over the fields identifying
+/// a request: method, channel, packet index, timestamp and nonce.
+#[derive(Clone)]
+pub struct Signer {
+    secret: Vec<u8>,
+}
+
+impl Signer {
+    pub fn new(secret: &str) -> Self {
+        Signer {
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    fn mac(&self, fields: &[&str]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(&self.secret).expect("HMAC accepts keys of any size");
+        for field in fields {
+            mac.update(field.as_bytes());
+            mac.update(b"\n");
+        }
+        mac
+    }
+
+    pub fn sign(
+        &self,
+        method: &str,
+        channel: &str,
+        index: &str,
+        timestamp: &str,
+        nonce: &str,
+    ) -> String {
+        hex::encode(
+            self.mac(&[method, channel, index, timestamp, nonce])
+                .finalize()
+                .into_bytes(),
+        )
+    }
+
+    pub fn verify(
+        &self,
+        method: &str,
+        channel: &str,
+        index: &str,
+        timestamp: &str,
+        nonce: &str,
+        signature: &str,
+    ) -> bool {
+        match hex::decode(signature) {
+            Ok(signature) => self
+                .mac(&[method, channel, index, timestamp, nonce])
+                .verify(&signature)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+pub fn nonce() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the unix epoch")
+        .as_secs()
+}
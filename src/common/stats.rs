@@ -0,0 +1,203 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::bail;
+
+/// Packets queued this long on average point at a slow receiver
+const SLOW_RECEIVER: Duration = Duration::from_secs(1);
+/// Packets taken this quickly are awaited by the receiver
+const FAST_RECEIVER: Duration = Duration::from_millis(100);
+
+/// How lossy the path of a transfer is: packets sent more than once,
+/// requests for a channel the server did not have (412) or for packets it
+/// no longer had (410), and requests retried after a failure. Along with
+/// that, where packets waited on the server, which tells which end is the
+/// slow one.
+#[derive(Debug, Default)]
+pub struct Stats {
+    duplicates: AtomicU64,
+    precondition_failed: AtomicU64,
+    gone: AtomicU64,
+    retries: AtomicU64,
+    /// Packets fetched by receivers, and how long they were queued for in
+    /// total
+    fetched: AtomicU64,
+    queued_ms: AtomicU64,
+    /// How long the sender waited for room in the queue in total
+    stalled_ms: AtomicU64,
+    /// How long the oldest packet not fetched yet has been queued for, as of
+    /// the last status request
+    oldest_ms: AtomicU64,
+}
+
+impl Stats {
+    pub fn duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn precondition_failed(&self) {
+        self.precondition_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn gone(&self) {
+        self.gone.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A packet has been fetched after `wait` in the queue.
+    pub fn queued(&self, wait: Duration) {
+        self.fetched.fetch_add(1, Ordering::Relaxed);
+        self.queued_ms
+            .fetch_add(wait.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// A packet of the sender has waited for room in the queue.
+    pub fn stalled(&self, wait: Duration) {
+        self.stalled_ms
+            .fetch_add(wait.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_oldest(&self, wait: Option<Duration>) {
+        let wait = wait.unwrap_or_default().as_millis() as u64;
+        self.oldest_ms.store(wait, Ordering::Relaxed);
+    }
+
+    fn oldest(&self) -> Duration {
+        Duration::from_millis(self.oldest_ms.load(Ordering::Relaxed))
+    }
+
+    /// How long packets were queued for on average.
+    pub fn average_queued(&self) -> Option<Duration> {
+        let fetched = self.fetched.load(Ordering::Relaxed);
+        if fetched == 0 {
+            return None;
+        }
+
+        Some(Duration::from_millis(
+            self.queued_ms.load(Ordering::Relaxed) / fetched,
+        ))
+    }
+
+    pub fn total_stalled(&self) -> Duration {
+        Duration::from_millis(self.stalled_ms.load(Ordering::Relaxed))
+    }
+
+    /// Which end held the transfer back: packets waiting in the queue
+    /// and the sender waiting for room mean a slow receiver, receivers
+    /// taking packets right away a slow sender.
+    pub fn slow_end(&self) -> Option<&'static str> {
+        if self.oldest() >= SLOW_RECEIVER {
+            return Some("receiver");
+        }
+
+        let queued = self.average_queued()?;
+        if queued >= SLOW_RECEIVER || self.total_stalled() >= SLOW_RECEIVER {
+            Some("receiver")
+        } else if queued < FAST_RECEIVER {
+            Some("sender")
+        } else {
+            None
+        }
+    }
+
+    fn counters(&self) -> [u64; 4] {
+        [
+            self.duplicates.load(Ordering::Relaxed),
+            self.precondition_failed.load(Ordering::Relaxed),
+            self.gone.load(Ordering::Relaxed),
+            self.retries.load(Ordering::Relaxed),
+        ]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters().iter().all(|&n| n == 0)
+    }
+
+    /// The form sent in the stats header, parsed back by `from_str`.
+    pub fn to_header(&self) -> String {
+        self.named()
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Each counter by its name in the stats header.
+    pub fn named(&self) -> [(&'static str, u64); 8] {
+        let [duplicates, precondition_failed, gone, retries] = self.counters();
+        [
+            ("duplicates", duplicates),
+            ("precondition-failed", precondition_failed),
+            ("gone", gone),
+            ("retries", retries),
+            ("fetched", self.fetched.load(Ordering::Relaxed)),
+            ("queued-ms", self.queued_ms.load(Ordering::Relaxed)),
+            ("stalled-ms", self.stalled_ms.load(Ordering::Relaxed)),
+            ("oldest-ms", self.oldest_ms.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+impl FromStr for Stats {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let stats = Stats::default();
+
+        for pair in s.split(',') {
+            let mut kv = pair.splitn(2, '=');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => (key, value.parse()?),
+                _ => bail!("invalid stats: {}", s),
+            };
+
+            let counter = match key {
+                "duplicates" => &stats.duplicates,
+                "precondition-failed" => &stats.precondition_failed,
+                "gone" => &stats.gone,
+                "retries" => &stats.retries,
+                "fetched" => &stats.fetched,
+                "queued-ms" => &stats.queued_ms,
+                "stalled-ms" => &stats.stalled_ms,
+                "oldest-ms" => &stats.oldest_ms,
+                // counters added by newer servers
+                _ => continue,
+            };
+            counter.store(value, Ordering::Relaxed);
+        }
+
+        Ok(stats)
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [duplicates, precondition_failed, gone, retries] = self.counters();
+        write!(
+            f,
+            "{} duplicate packets, {} channel not available (412), {} data not available (410), {} retries",
+            duplicates, precondition_failed, gone, retries
+        )?;
+        if let Some(queued) = self.average_queued() {
+            write!(
+                f,
+                ", packets queued {:.1}s on average, sender waited {:.1}s for room",
+                queued.as_secs_f64(),
+                self.total_stalled().as_secs_f64()
+            )?;
+        }
+        if self.oldest() > Duration::default() {
+            write!(
+                f,
+                ", oldest packet queued for {:.1}s",
+                self.oldest().as_secs_f64()
+            )?;
+        }
+        Ok(())
+    }
+}
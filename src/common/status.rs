@@ -0,0 +1,44 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+
+/// Progress of a channel as reported by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Not known to the server, either not created yet or long gone
+    Unknown,
+    Active,
+    /// The sender has finished, the receiver has not yet
+    Sent,
+    /// The receiver has taken everything
+    Complete,
+    Aborted,
+}
+
+impl FromStr for Status {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "unknown" => Status::Unknown,
+            "active" => Status::Active,
+            "sent" => Status::Sent,
+            "complete" => Status::Complete,
+            "aborted" => Status::Aborted,
+            _ => bail!("unknown status: {}", s),
+        })
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Status::Unknown => "unknown",
+            Status::Active => "active",
+            Status::Sent => "sent",
+            Status::Complete => "complete",
+            Status::Aborted => "aborted",
+        })
+    }
+}
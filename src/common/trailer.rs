@@ -0,0 +1,99 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+
+/// What the sender read in all, sent along with the end of the stream for
+/// receivers to check they have got all of it, intact.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trailer {
+    /// Left out by senders that have not read the whole stream, i.e. resumed
+    /// ones and those of streams receivers may join halfway through
+    pub bytes: Option<u64>,
+    /// BLAKE3 hash of the whole stream, left out of encrypted transfers as
+    /// the relay would see it
+    pub blake3: Option<String>,
+    /// Seal of encrypted transfers over the index of the end of the stream
+    /// and the rest of the trailer, see `Encryptor::seal`
+    pub seal: Option<String>,
+}
+
+impl Trailer {
+    /// The fields the seal covers.
+    pub fn sealed(&self) -> String {
+        Trailer {
+            seal: None,
+            ..self.clone()
+        }
+        .to_string()
+    }
+}
+
+impl FromStr for Trailer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (mut bytes, mut blake3, mut seal) = (None, None, None);
+
+        for pair in s.split(',').filter(|pair| !pair.is_empty()) {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("bytes"), Some(value)) => bytes = Some(value.parse()?),
+                (Some("blake3"), Some(value)) => blake3 = Some(value.to_ascii_lowercase()),
+                (Some("seal"), Some(value)) => seal = Some(value.to_owned()),
+                // fields added later
+                (Some(_), Some(_)) => {}
+                _ => bail!("invalid trailer: {}", s),
+            }
+        }
+
+        if bytes.is_none() && seal.is_none() {
+            bail!("invalid trailer: {}", s);
+        }
+        Ok(Trailer {
+            bytes,
+            blake3,
+            seal,
+        })
+    }
+}
+
+impl fmt::Display for Trailer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields = [
+            self.bytes.map(|bytes| format!("bytes={}", bytes)),
+            self.blake3
+                .as_ref()
+                .map(|blake3| format!("blake3={}", blake3)),
+            self.seal.as_ref().map(|seal| format!("seal={}", seal)),
+        ];
+        let fields: Vec<_> = fields.iter().flatten().map(String::as_str).collect();
+        f.write_str(&fields.join(","))
+    }
+}
+
+/// Works out the trailer of the data passed through it.
+#[derive(Clone, Default)]
+pub struct StreamHasher {
+    bytes: u64,
+    hasher: blake3::Hasher,
+}
+
+impl StreamHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.bytes += data.len() as u64;
+        self.hasher.update(data);
+    }
+
+    pub fn trailer(&self, hashed: bool) -> Trailer {
+        Trailer {
+            bytes: Some(self.bytes),
+            blake3: if hashed {
+                Some(self.hasher.finalize().to_hex().to_string())
+            } else {
+                None
+            },
+            seal: None,
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::convert::TryInto;
+
+use anyhow::bail;
+use bytes::{Buf, Bytes};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The method and headers of a request sent over a WebSocket.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestHead {
+    pub method: String,
+    pub headers: Vec<(String, String)>,
+}
+
+/// The status and headers of the reply to a tunneled request.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseHead {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+/// A request or reply as one binary message: the length of the head, the
+/// head as JSON, then the body.
+pub fn encode<T: Serialize>(head: &T, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let head = serde_json::to_vec(head)?;
+
+    let mut message = Vec::with_capacity(4 + head.len() + body.len());
+    message.extend_from_slice(&(head.len() as u32).to_be_bytes());
+    message.extend_from_slice(&head);
+    message.extend_from_slice(body);
+
+    Ok(message)
+}
+
+pub fn decode<T: DeserializeOwned>(mut message: Bytes) -> anyhow::Result<(T, Bytes)> {
+    if message.len() < 4 {
+        bail!("truncated message");
+    }
+    let len = u32::from_be_bytes(message[..4].try_into()?) as usize;
+    message.advance(4);
+
+    if message.len() < len {
+        bail!("truncated message");
+    }
+    let head = serde_json::from_slice(&message.split_to(len))?;
+
+    Ok((head, message))
+}
@@ -0,0 +1,8 @@
+//! Piping data from one host to another using a relay server.
+//!
+//! Transfers can be embedded in other programs with [`client::send`] and
+//! [`client::receive`].
+
+pub mod client;
+pub mod common;
+pub mod server;
@@ -1,28 +1,167 @@
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use anyhow::bail;
 use clap::{crate_version, Clap};
+use log::LevelFilter;
 
-mod client;
-mod common;
-mod server;
+use http_pipe::{client, common, server};
 
 #[derive(Clap)]
 #[clap(version = crate_version ! ())]
 struct Opts {
-    #[clap(long = "debug")]
+    #[clap(short = 'v', long = "verbose", parse(from_occurrences), global = true)]
+    verbose: u64,
+    #[clap(short = 'q', long = "quiet", conflicts_with = "verbose", global = true)]
+    quiet: bool,
+    // the same as -v, from before there were levels
+    #[clap(long = "debug", hidden = true, global = true)]
     debug: bool,
+    #[clap(long = "log-format", default_value = "text", global = true)]
+    log_format: common::LogFormat,
+    #[clap(long = "log-file", value_name = "PATH", global = true)]
+    log_file: Option<PathBuf>,
     #[clap(long = "server")]
     server: bool,
-    endpoint: String,
+    #[clap(long = "config", requires = "server")]
+    config: Option<PathBuf>,
+    #[clap(
+        short = 'p',
+        long = "profile",
+        env = "HTTP_PIPE_PROFILE",
+        conflicts_with = "server"
+    )]
+    profile: Option<String>,
+    #[clap(long = "secret", env = "HTTP_PIPE_SECRET", global = true)]
+    secret: Option<String>,
+    #[clap(flatten)]
+    server_opts: server::Opts,
+    #[clap(flatten)]
+    client_opts: client::Opts,
+    #[clap(flatten)]
+    net_opts: client::net::NetOpts,
+    endpoint: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Send files and directories, each over its own sub-channel
+    SendFiles(client::files::SendOpts),
+    /// Receive files sent with send-files
+    ReceiveFiles(client::files::ReceiveOpts),
+    /// Send a file along with its name, size, mode and modification time
+    SendFile(client::files::SendFileOpts),
+    /// Receive a file sent with send-file
+    ReceiveFile(client::files::ReceiveFileOpts),
+    /// Update a remote copy of a file by sending only the changed blocks
+    DeltaSend(client::delta::SendOpts),
+    /// Update a local file with the changes sent by delta-send
+    DeltaReceive(client::delta::ReceiveOpts),
+    /// Hold back the sender of a channel until it is resumed
+    Pause(client::control::ControlOpts),
+    /// Resume a paused channel
+    Resume(client::control::ControlOpts),
+    /// Tear down a channel, failing its transfer on both ends
+    Abort(client::control::ControlOpts),
+    /// Wait until the receiver of a channel has taken everything
+    Wait(client::control::WaitOpts),
+    /// Show how lossy the transfer over a channel has been
+    Stats(client::control::ControlOpts),
+    /// Check the SHA-256 of a channel the sender has finished
+    Verify(client::verify::VerifyOpts),
+    /// Set a channel aside for the holders of the claims printed
+    Reserve(client::control::ReserveOpts),
+    /// Pipe data through a relay in this process, reporting the throughput
+    Loopback(Box<client::loopback::LoopbackOpts>),
+    /// Run a client again against the relay's answers in a --record file
+    Replay(client::record::ReplayOpts),
+}
+
+/// Parses the command line along with the server config or client profile
+/// it names.
+fn parse(args: Vec<OsString>) -> anyhow::Result<Opts> {
+    let opts = Opts::parse_from(&args);
+
+    if let Some(config) = &opts.config {
+        let args = server::with_config(config, args, opts.endpoint.is_some())?;
+        return Ok(Opts::parse_from(args));
+    }
+    if let Some(profile) = &opts.profile {
+        return Ok(Opts::parse_from(client::with_profile(profile, args)?));
+    }
+    Ok(opts)
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let opts: Opts = Opts::parse();
+    let mut opts = parse(env::args_os().collect())?;
 
-    common::init_log(opts.debug);
+    let level = match (opts.quiet, opts.verbose + opts.debug as u64) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    common::init_log(level, opts.log_format, opts.log_file.as_deref())?;
+
+    // the recorded client runs as it was run, only against a mock relay
+    let replay = match &opts.command {
+        Some(Command::Replay(cmd)) => Some(client::record::Replay::start(cmd)?),
+        _ => None,
+    };
+    if let Some(replay) = &replay {
+        opts = parse(replay.args().iter().map(OsString::from).collect())?;
+    }
+
+    client::net::init(opts.net_opts.clone());
+
+    match opts.command {
+        Some(Command::SendFiles(cmd)) => return client::files::send(cmd, opts.secret).await,
+        Some(Command::ReceiveFiles(cmd)) => return client::files::receive(cmd, opts.secret).await,
+        Some(Command::SendFile(cmd)) => return client::files::send_file(cmd, opts.secret).await,
+        Some(Command::ReceiveFile(cmd)) => {
+            return client::files::receive_file(cmd, opts.secret).await
+        }
+        Some(Command::DeltaSend(cmd)) => return client::delta::send(cmd, opts.secret).await,
+        Some(Command::DeltaReceive(cmd)) => return client::delta::receive(cmd, opts.secret).await,
+        Some(Command::Pause(cmd)) => return client::control::pause(cmd, opts.secret, true).await,
+        Some(Command::Resume(cmd)) => return client::control::pause(cmd, opts.secret, false).await,
+        Some(Command::Abort(cmd)) => return client::control::abort(cmd, opts.secret).await,
+        Some(Command::Wait(cmd)) => return client::control::wait(cmd, opts.secret).await,
+        Some(Command::Stats(cmd)) => return client::control::stats(cmd, opts.secret).await,
+        Some(Command::Verify(cmd)) => return client::verify::verify(cmd, opts.secret).await,
+        Some(Command::Reserve(cmd)) => return client::control::reserve(cmd, opts.secret).await,
+        Some(Command::Loopback(cmd)) => return client::loopback::run(*cmd, opts.secret).await,
+        Some(Command::Replay(_)) => bail!("only clients run without a command can be replayed"),
+        None => {}
+    }
 
     if opts.server {
-        server::main(opts.endpoint).await
-    } else {
-        client::main(opts.endpoint).await
+        return server::main(opts.endpoint, opts.secret, opts.server_opts).await;
+    }
+
+    // a profile's relay, for a sender to pick a new channel on
+    let endpoint = match (opts.endpoint, client::net::base_url()) {
+        (Some(endpoint), _) => endpoint,
+        (None, Some(base)) => base.to_owned(),
+        (None, None) => bail!("missing endpoint"),
+    };
+
+    let result = client::main(endpoint, opts.secret, opts.client_opts).await;
+    if let Some(replay) = &replay {
+        replay.finish();
+    }
+    // for orchestrators to tell a hung producer from other failures
+    if let Some(e) = result
+        .as_ref()
+        .err()
+        .filter(|e| e.is::<client::InputTimeout>())
+    {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(client::InputTimeout::EXIT_CODE);
     }
+    result
 }
@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix_web::dev::RequestHead;
+use actix_web::error::ErrorNotFound;
+use actix_web::http::header;
+use actix_web::{delete, get, web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::stream;
+use serde::{Deserialize, Serialize};
+
+use super::{close, AppState, ChannelKey, Conn, ControllerResult};
+use crate::common::Status;
+
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A channel as listed by the admin API.
+#[derive(Serialize)]
+struct ChannelInfo {
+    host: String,
+    id: String,
+    status: String,
+    /// Why the transfer has been aborted, if it has
+    reason: Option<String>,
+    /// Packets buffered
+    depth: usize,
+    received_bytes: u64,
+    sent_bytes: u64,
+    /// Seconds since the channel was last used
+    idle_seconds: f64,
+    stats: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Deserialize)]
+struct ChannelQuery {
+    /// The namespace of the channel with virtual hosts
+    #[serde(default)]
+    host: String,
+}
+
+#[get("/channels")]
+pub async fn channels(data: web::Data<AppState>) -> HttpResponse {
+    let mut channels = data
+        .endpoints
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, conn)| {
+            let (status, reason) = conn.status();
+            let progress = Progress::of(conn);
+
+            ChannelInfo {
+                host: key.host.clone(),
+                id: key.id.clone(),
+                status: status.to_string(),
+                reason,
+                depth: progress.depth,
+                received_bytes: progress.received_bytes,
+                sent_bytes: progress.sent_bytes,
+                idle_seconds: conn.queue.idle().as_secs_f64(),
+                stats: progress.stats,
+            }
+        })
+        .collect::<Vec<_>>();
+    channels.sort_by(|a, b| (&a.host, &a.id).cmp(&(&b.host, &b.id)));
+
+    HttpResponse::Ok().json(channels)
+}
+
+/// Force-closes a stuck channel.
+#[delete("/channels/{id:.+}")]
+pub async fn close_channel(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<ChannelQuery>,
+) -> ControllerResult<HttpResponse> {
+    let key = ChannelKey {
+        host: query.into_inner().host,
+        id: path.into_inner(),
+    };
+
+    if !close(&data, key, "channel closed by an administrator") {
+        return Err(ErrorNotFound("channel not found").into());
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Where the transfer over a channel is at, sent in `status` events.
+#[derive(Serialize, PartialEq)]
+struct StatusEvent {
+    status: String,
+    reason: Option<String>,
+}
+
+/// How far the transfer over a channel has come, sent in `progress` events.
+#[derive(Serialize, PartialEq)]
+struct Progress {
+    depth: usize,
+    received_bytes: u64,
+    sent_bytes: u64,
+    stats: BTreeMap<&'static str, u64>,
+}
+
+impl Progress {
+    fn of(conn: &Conn) -> Self {
+        let (received_bytes, sent_bytes) = conn.queue.traffic();
+        conn.stats.set_oldest(conn.queue.oldest_pending());
+
+        Progress {
+            depth: conn.queue.depth(),
+            received_bytes,
+            sent_bytes,
+            stats: conn.stats.named().iter().copied().collect(),
+        }
+    }
+}
+
+/// What a client watching the events of a channel has been told.
+struct Watch {
+    data: web::Data<AppState>,
+    key: ChannelKey,
+    status: Option<StatusEvent>,
+    progress: Option<Progress>,
+    /// For how long nothing has been sent
+    quiet: Duration,
+    done: bool,
+}
+
+fn event(name: &str, data: &impl Serialize) -> String {
+    let data = serde_json::to_string(data).unwrap_or_default();
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+impl Watch {
+    /// The events since the last call, if any.
+    fn events(&mut self) -> String {
+        let mut events = String::new();
+
+        let progress = self
+            .data
+            .endpoints
+            .lock()
+            .unwrap()
+            .get(&self.key)
+            .map(Progress::of);
+        if progress.is_some() && progress != self.progress {
+            events += &event("progress", &progress);
+            self.progress = progress;
+        }
+
+        let (status, reason, _) = self.data.status(&self.key);
+        let current = StatusEvent {
+            status: status.to_string(),
+            reason,
+        };
+        if self.status.as_ref() != Some(&current) {
+            events += &event("status", &current);
+            self.status = Some(current);
+            self.done = matches!(status, Status::Complete | Status::Aborted);
+        }
+
+        events
+    }
+}
+
+/// Only requests for an event stream are taken from the channel's own.
+pub fn accepts_events(req: &RequestHead) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("text/event-stream"))
+        .unwrap_or(false)
+}
+
+/// Streams the lifecycle and progress of a channel as server-sent events,
+/// until its transfer is over.
+pub async fn events(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    req: HttpRequest,
+) -> ControllerResult<HttpResponse> {
+    let watch = Watch {
+        key: data.channel_key(&req, path.into_inner())?,
+        data,
+        status: None,
+        progress: None,
+        quiet: Duration::default(),
+        done: false,
+    };
+
+    let events = stream::unfold(watch, |mut watch| async move {
+        if watch.done {
+            return None;
+        }
+
+        loop {
+            let mut events = watch.events();
+            if events.is_empty() {
+                tokio::time::delay_for(EVENT_INTERVAL).await;
+                watch.quiet += EVENT_INTERVAL;
+                // for proxies that close idle connections
+                if watch.quiet < KEEPALIVE_INTERVAL {
+                    continue;
+                }
+                events = ": keepalive\n\n".to_owned();
+            }
+
+            watch.quiet = Duration::default();
+            return Some((Ok::<_, actix_web::Error>(Bytes::from(events)), watch));
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .streaming(Box::pin(events)))
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use crate::common::{pow, signature};
+
+/// How long a proof-of-work challenge may be used
+const CHALLENGE_TTL: u64 = 300;
+
+/// Limits how many channels each client address may create per minute,
+/// allowing bursts of as many.
+pub struct ChannelRate {
+    per_minute: u32,
+    clients: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl ChannelRate {
+    pub fn new(per_minute: u32) -> Self {
+        ChannelRate {
+            per_minute,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `addr` may create another channel now, counting it if so.
+    pub fn allow(&self, addr: IpAddr) -> bool {
+        let burst = f64::from(self.per_minute);
+        let mut clients = self.clients.lock().unwrap();
+
+        // buckets refill completely within a minute
+        if !clients.contains_key(&addr) {
+            clients.retain(|_, (_, last)| last.elapsed() < Duration::from_secs(60));
+        }
+
+        let (tokens, last) = clients.entry(addr).or_insert((burst, Instant::now()));
+        *tokens = (*tokens + last.elapsed().as_secs_f64() * burst / 60.0).min(burst);
+        *last = Instant::now();
+
+        if *tokens < 1.0 {
+            return false;
+        }
+
+        *tokens -= 1.0;
+        true
+    }
+}
+
+/// Issues and checks proof-of-work challenges for creating channels. They
+/// are authenticated rather than remembered, and a solution only applies
+/// to the channel it was computed for.
+pub struct Challenges {
+    bits: u32,
+    key: [u8; 32],
+}
+
+impl Challenges {
+    pub fn new(bits: u32) -> Self {
+        Challenges {
+            bits,
+            key: rand::random(),
+        }
+    }
+
+    fn mac(&self, bits: &str, timestamp: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(bits.as_bytes());
+        mac.update(b":");
+        mac.update(timestamp.as_bytes());
+        mac
+    }
+
+    /// A fresh challenge, `BITS:TIMESTAMP:MAC`.
+    pub fn challenge(&self) -> String {
+        let (bits, timestamp) = (self.bits.to_string(), signature::timestamp().to_string());
+        let mac = hex::encode(self.mac(&bits, &timestamp).finalize().into_bytes());
+        format!("{}:{}:{}", bits, timestamp, mac)
+    }
+
+    /// Whether `solution`, a challenge with a nonce appended, proves enough
+    /// work for `channel`.
+    pub fn verify(&self, solution: &str, channel: &str) -> bool {
+        let mut parts = solution.rsplitn(2, ':');
+        let (nonce, challenge) = match (parts.next(), parts.next()) {
+            (Some(nonce), Some(challenge)) => match nonce.parse() {
+                Ok(nonce) => (nonce, challenge),
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+
+        let fields: Vec<&str> = challenge.split(':').collect();
+        let (bits, timestamp, mac) = match fields.as_slice() {
+            [bits, timestamp, mac] => (*bits, *timestamp, *mac),
+            _ => return false,
+        };
+
+        let fresh = match timestamp.parse::<u64>() {
+            Ok(timestamp) => signature::timestamp().saturating_sub(timestamp) < CHALLENGE_TTL,
+            Err(_) => false,
+        };
+
+        fresh
+            && bits == self.bits.to_string()
+            && matches!(hex::decode(mac), Ok(mac) if self.mac(bits, timestamp).verify(&mac).is_ok())
+            && pow::work(challenge, channel, nonce) >= self.bits
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{Error, ErrorUnauthorized};
+use actix_web::http::header::AUTHORIZATION;
+use futures::future::{self, Either, Ready};
+
+/// Compares in constant time, so that the token cannot be guessed byte by
+/// byte from how long the comparison takes.
+pub fn equals(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware rejecting requests without the bearer token shared by the
+/// server and its clients.
+pub struct RequireToken {
+    token: Arc<String>,
+}
+
+impl RequireToken {
+    pub fn new(token: &str) -> Self {
+        RequireToken {
+            token: Arc::new(token.to_owned()),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RequireToken
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireTokenMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(RequireTokenMiddleware {
+            service,
+            token: self.token.clone(),
+        })
+    }
+}
+
+pub struct RequireTokenMiddleware<S> {
+    service: S,
+    token: Arc<String>,
+}
+
+impl<S> RequireTokenMiddleware<S> {
+    fn verify(&self, req: &ServiceRequest) -> bool {
+        const BEARER: &[u8] = b"Bearer ";
+
+        let header = match req.headers().get(AUTHORIZATION) {
+            Some(header) => header.as_bytes(),
+            None => return false,
+        };
+
+        header.len() > BEARER.len()
+            && header[..BEARER.len()].eq_ignore_ascii_case(BEARER)
+            && equals(&header[BEARER.len()..], self.token.as_bytes())
+    }
+}
+
+impl<S, B> Service for RequireTokenMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if self.verify(&req) {
+            Either::Left(self.service.call(req))
+        } else {
+            Either::Right(future::err(ErrorUnauthorized("missing or invalid token")))
+        }
+    }
+}
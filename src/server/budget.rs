@@ -0,0 +1,78 @@
+use std::{
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+};
+
+use futures::future;
+
+use crate::common::Priority;
+
+/// Bytes buffered across all channels of the server. Each priority class
+/// may only fill its share of the limit, so that lower classes are held
+/// back first when memory runs short.
+pub struct MemoryBudget {
+    limit: Option<u64>,
+    used: Mutex<u64>,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<u64>) -> Self {
+        MemoryBudget {
+            limit,
+            used: Mutex::new(0),
+            wakers: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn share(&self, priority: Priority) -> Option<u64> {
+        let limit = self.limit?;
+        Some(match priority {
+            Priority::High => limit,
+            Priority::Normal => limit / 4 * 3,
+            Priority::Bulk => limit / 2,
+        })
+    }
+
+    fn poll_reserve(&self, cx: &mut Context, size: u64, priority: Priority) -> Poll<()> {
+        let mut used = self.used.lock().unwrap();
+
+        match self.share(priority) {
+            // a packet larger than the share still passes on an idle server
+            Some(share) if *used > 0 && *used + size > share => {
+                self.wakers.lock().unwrap().push(cx.waker().clone());
+                Poll::Pending
+            }
+            _ => {
+                *used += size;
+                Poll::Ready(())
+            }
+        }
+    }
+
+    /// Whether `size` bytes could be reserved right away.
+    pub fn has_room(&self, size: u64, priority: Priority) -> bool {
+        let used = self.used.lock().unwrap();
+        match self.share(priority) {
+            Some(share) => *used == 0 || *used + size <= share,
+            None => true,
+        }
+    }
+
+    pub async fn reserve(&self, size: u64, priority: Priority) {
+        future::poll_fn(|cx| self.poll_reserve(cx, size, priority)).await
+    }
+
+    pub fn release(&self, size: u64) {
+        if size == 0 {
+            return;
+        }
+
+        let mut used = self.used.lock().unwrap();
+        *used = used.saturating_sub(size);
+
+        for w in self.wakers.lock().unwrap().drain(..) {
+            w.wake();
+        }
+    }
+}
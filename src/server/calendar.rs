@@ -0,0 +1,72 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+use crate::common::{Size, TimeOfDay, TokenBucket};
+
+/// A limit on the rate at which the server takes in data during a daily
+/// time window, written as `HH:MM-HH:MM=RATE`, e.g. `09:00-18:00=10M`.
+#[derive(Clone, Debug)]
+pub struct BandwidthWindow {
+    start: TimeOfDay,
+    end: TimeOfDay,
+    rate: Size,
+}
+
+impl FromStr for BandwidthWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parts = s.splitn(2, '=');
+        let (window, rate) = match (parts.next(), parts.next()) {
+            (Some(window), Some(rate)) => (window, rate),
+            _ => return Err(anyhow!("expected HH:MM-HH:MM=RATE: {}", s)),
+        };
+
+        let mut times = window.splitn(2, '-');
+        let (start, end) = match (times.next(), times.next()) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return Err(anyhow!("expected HH:MM-HH:MM=RATE: {}", s)),
+        };
+
+        Ok(BandwidthWindow {
+            start: start.parse()?,
+            end: end.parse()?,
+            rate: rate.parse()?,
+        })
+    }
+}
+
+/// Server-wide bandwidth limits following a daily calendar. Outside of
+/// all windows, data is taken in at full speed.
+pub struct Bandwidth {
+    windows: Vec<BandwidthWindow>,
+    bucket: TokenBucket,
+}
+
+impl Bandwidth {
+    pub fn new(windows: Vec<BandwidthWindow>) -> Self {
+        Bandwidth {
+            windows,
+            bucket: TokenBucket::new(),
+        }
+    }
+
+    fn current_rate(&self) -> Option<u64> {
+        if self.windows.is_empty() {
+            return None;
+        }
+
+        let now = TimeOfDay::now();
+        self.windows
+            .iter()
+            .find(|w| now.is_between(w.start, w.end))
+            .map(|w| w.rate.0)
+    }
+
+    pub async fn throttle(&self, n: u64) {
+        if let Some(rate) = self.current_rate() {
+            self.bucket.take(n, rate).await;
+        }
+    }
+}
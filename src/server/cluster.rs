@@ -0,0 +1,349 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::ErrorBadGateway;
+use actix_web::http::{header, HeaderMap, Method, StatusCode};
+use actix_web::{error::Error, web, HttpMessage, HttpResponse};
+use futures::{
+    channel::mpsc,
+    future::{self, Either, Ready},
+    stream, Future, SinkExt, StreamExt,
+};
+use log::{debug, info, warn};
+use reqwest::{Body, Client, Response};
+use sha2::{Digest, Sha256};
+
+use super::{vhost::ChannelKey, AppState};
+use crate::client::net;
+use crate::common::{headers, signature, signature::Signer};
+
+/// How often peers are checked to still be up
+const PROBE_INTERVAL: Duration = Duration::from_secs(2);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Probes a peer fails in a row before its channels are taken over, so that
+/// they do not move back and forth on a blip
+const MISSED_PROBES: u32 = 3;
+/// How long a request forwarded by another node may take to arrive, clocks
+/// being off by as much either way
+const FORWARD_TTL: u64 = 60;
+
+struct Peer {
+    url: String,
+    /// Probes failed since the last answer
+    missed: AtomicU32,
+}
+
+impl Peer {
+    fn is_alive(&self) -> bool {
+        self.missed.load(Ordering::Relaxed) < MISSED_PROBES
+    }
+}
+
+/// Relays sharing their channels between them, each channel belonging to
+/// one of those up, picked by rendezvous hashing so that all of them agree
+/// on it without talking it over, and only the channels of a node going
+/// down or coming back move. Requests for a channel that belongs to
+/// another node are forwarded to it.
+#[derive(Clone)]
+pub struct Cluster {
+    /// The URL of this node, as the others know it
+    this: String,
+    peers: Arc<Vec<Peer>>,
+    client: Client,
+    /// Signs the requests nodes forward to one another, which only they
+    /// may make
+    signer: Signer,
+}
+
+impl Cluster {
+    pub fn new(this: &str, peers: &[String], secret: &str) -> Self {
+        let this = this.trim_end_matches('/').to_owned();
+        let peers = peers
+            .iter()
+            .map(|url| url.trim_end_matches('/').to_owned())
+            .filter(|url| *url != this)
+            .map(|url| Peer {
+                url,
+                // alive until shown otherwise, so that a node starting does
+                // not take channels that belong to others
+                missed: AtomicU32::new(0),
+            })
+            .collect();
+
+        Cluster {
+            this,
+            peers: Arc::new(peers),
+            client: net::client(),
+            signer: Signer::new(secret),
+        }
+    }
+
+    /// Whether another node forwarded the request, which is then served
+    /// here whoever the channel belongs to.
+    pub fn is_forwarded(&self, req: &ServiceRequest) -> bool {
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        let forwarded = match req.headers().get(headers::FORWARDED) {
+            Some(forwarded) => forwarded.to_str().unwrap_or_default(),
+            None => return false,
+        };
+        let fields: Vec<&str> = forwarded.split(':').collect();
+        let (timestamp, nonce, signature) = match fields.as_slice() {
+            [timestamp, nonce, signature] => (*timestamp, *nonce, *signature),
+            _ => return false,
+        };
+
+        let fresh = match timestamp.parse::<u64>() {
+            Ok(timestamp) => {
+                let now = signature::timestamp();
+                now.max(timestamp) - now.min(timestamp) < FORWARD_TTL
+            }
+            Err(_) => false,
+        };
+        fresh
+            && self
+                .signer
+                .verify(req.method().as_str(), path, "", timestamp, nonce, signature)
+    }
+
+    /// The URL of the node `key` belongs to, unless it is this one.
+    pub fn owner(&self, key: &ChannelKey) -> Option<&str> {
+        let weight = |node: &str| {
+            Sha256::new()
+                .chain(node)
+                .chain("\n")
+                .chain(&key.host)
+                .chain("/")
+                .chain(&key.id)
+                .finalize()
+        };
+
+        let mut owner = None;
+        let mut highest = weight(&self.this);
+        for peer in self.peers.iter() {
+            if !peer.is_alive() {
+                continue;
+            }
+            let weight = weight(&peer.url);
+            if weight > highest {
+                owner = Some(peer.url.as_str());
+                highest = weight;
+            }
+        }
+        owner
+    }
+
+    /// Checks the peers over and over, any answer at all showing one is up.
+    pub async fn watch(self) {
+        let client = &self.client;
+        loop {
+            future::join_all(self.peers.iter().map(|peer| async move {
+                let probe = client.get(&format!("{}/", peer.url)).send();
+                let answered =
+                    matches!(tokio::time::timeout(PROBE_TIMEOUT, probe).await, Ok(Ok(_)));
+                let was_alive = peer.is_alive();
+                if answered {
+                    peer.missed.store(0, Ordering::Relaxed);
+                } else {
+                    peer.missed.fetch_add(1, Ordering::Relaxed);
+                }
+                match (was_alive, peer.is_alive()) {
+                    (false, true) => info!("Cluster node {} is up", peer.url),
+                    (true, false) => {
+                        warn!(
+                            "Cluster node {} is down, taking over its channels",
+                            peer.url
+                        )
+                    }
+                    _ => {}
+                }
+            }))
+            .await;
+            tokio::time::delay_for(PROBE_INTERVAL).await;
+        }
+    }
+
+    /// Makes the request on the node at `owner`, which serves it itself.
+    pub async fn forward(
+        &self,
+        owner: &str,
+        method: &Method,
+        path: &str,
+        req_headers: &HeaderMap,
+        body: impl Into<Body>,
+    ) -> reqwest::Result<Response> {
+        let mut req = self
+            .client
+            .request(method.clone(), &format!("{}{}", owner, path));
+        for (name, value) in req_headers {
+            // the body is passed on as it is, so its length still holds
+            if (!is_hop_by_hop(name) || name == header::CONTENT_LENGTH)
+                && name != headers::FORWARDED
+            {
+                req = req.header(name.clone(), value.clone());
+            }
+        }
+
+        let (timestamp, nonce) = (signature::timestamp().to_string(), signature::nonce());
+        let signature = self
+            .signer
+            .sign(method.as_str(), path, "", &timestamp, &nonce);
+        req.header(
+            headers::FORWARDED,
+            format!("{}:{}:{}", timestamp, nonce, signature),
+        )
+        .body(body)
+        .send()
+        .await
+    }
+}
+
+fn is_hop_by_hop(name: &header::HeaderName) -> bool {
+    [
+        header::CONNECTION,
+        header::CONTENT_LENGTH,
+        header::TRANSFER_ENCODING,
+        header::TE,
+        header::UPGRADE,
+    ]
+    .contains(name)
+}
+
+/// The reply of another node, as given to the client.
+pub fn response_builder(resp: &Response) -> actix_web::dev::HttpResponseBuilder {
+    let status = StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut builder = HttpResponse::build(status);
+    for (name, value) in resp.headers() {
+        if !is_hop_by_hop(name) {
+            builder.header(name.clone(), value.clone());
+        }
+    }
+    builder
+}
+
+/// Middleware sending requests on to the node their channel belongs to,
+/// if in a cluster. WebSockets stay on the node they are opened on, which
+/// forwards each request sent over them instead.
+#[derive(Clone)]
+pub struct Forward {
+    cluster: Option<Cluster>,
+}
+
+impl Forward {
+    pub fn new(cluster: Option<Cluster>) -> Self {
+        Forward { cluster }
+    }
+}
+
+impl<S> Transform<S> for Forward
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ForwardMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(ForwardMiddleware {
+            service,
+            cluster: self.cluster.clone(),
+        })
+    }
+}
+
+pub struct ForwardMiddleware<S> {
+    service: S,
+    cluster: Option<Cluster>,
+}
+
+impl<S> ForwardMiddleware<S> {
+    /// The node the channel of `req` belongs to, if it is another one.
+    fn owner(&self, req: &ServiceRequest) -> Option<(Cluster, String)> {
+        let cluster = self.cluster.as_ref()?;
+        if req.head().upgrade() || cluster.is_forwarded(req) {
+            return None;
+        }
+        let id = req.path().trim_start_matches('/');
+        if id.is_empty() {
+            return None;
+        }
+
+        let data = req.app_data::<web::Data<AppState>>()?;
+        let key = data.channel_key_of(req.head(), id.to_owned()).ok()?;
+        let owner = cluster.owner(&key)?.to_owned();
+        Some((cluster.clone(), owner))
+    }
+}
+
+impl<S> Service for ForwardMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future =
+        Either<Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let (cluster, owner) = match self.owner(&req) {
+            Some(owner) => owner,
+            None => return Either::Right(self.service.call(req)),
+        };
+
+        // the body streams through to the owner as it arrives, passed on
+        // from the payload, which cannot leave this thread
+        let mut payload = req.take_payload();
+        let (mut tx, rx) = mpsc::channel(1);
+        actix_rt::spawn(async move {
+            while let Some(chunk) = payload.next().await {
+                let failed = chunk.is_err();
+                if tx.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Either::Left(Box::pin(async move {
+            let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+            debug!("Forwarding {} {} to {}", req.method(), path, owner);
+            let resp = cluster
+                .forward(
+                    &owner,
+                    req.method(),
+                    path,
+                    req.headers(),
+                    Body::wrap_stream(rx),
+                )
+                .await
+                .map_err(ErrorBadGateway)?;
+
+            let mut builder = response_builder(&resp);
+            let body = Box::pin(stream::unfold(Some(resp), |resp| async move {
+                let mut resp = resp?;
+                match resp.chunk().await {
+                    Ok(Some(chunk)) => Some((Ok(chunk), Some(resp))),
+                    Ok(None) => None,
+                    Err(e) => Some((Err(ErrorBadGateway(e)), None)),
+                }
+            }));
+            Ok(req.into_response(builder.streaming(body)))
+        }))
+    }
+}
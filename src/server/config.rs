@@ -0,0 +1,41 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use toml::Value;
+
+use crate::common::flags;
+
+/// The key standing for the address to listen on
+const LISTEN: &str = "listen";
+
+/// Adds the settings of the server config file at `path` to the command
+/// line `args`, each as the flag it is named after. Flags already on the
+/// command line take precedence, and so does the address to listen on
+/// when `listening`.
+pub fn with_config(
+    path: &Path,
+    mut args: Vec<OsString>,
+    listening: bool,
+) -> anyhow::Result<Vec<OsString>> {
+    let config =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut config: BTreeMap<String, Value> =
+        toml::from_str(&config).with_context(|| format!("invalid config {}", path.display()))?;
+
+    let listen = match config.remove(LISTEN) {
+        Some(value) => Some(flags::scalar(LISTEN, &value)?),
+        None => None,
+    };
+    if config.contains_key("config") {
+        bail!("{} cannot include another config", path.display());
+    }
+
+    flags::add_flags(&mut args, config).with_context(|| format!("in {}", path.display()))?;
+    if let (Some(listen), false) = (listen, listening) {
+        args.push(listen.into());
+    }
+    Ok(args)
+}
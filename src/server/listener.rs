@@ -0,0 +1,106 @@
+use std::env;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+use anyhow::bail;
+
+/// Addresses of this form are Unix sockets, e.g. `unix:/run/http-pipe.sock`
+const UNIX_PREFIX: &str = "unix:";
+
+/// Where the server takes connections from.
+pub enum Listener {
+    /// An address for the server to bind, e.g. `0.0.0.0:8080`
+    Addr(String),
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// The socket systemd passed if the server is socket-activated, or else
+    /// the one at `addr`, bound right away if a Unix socket.
+    pub fn new(addr: Option<String>) -> anyhow::Result<Self> {
+        if let Some(listener) = inherited()? {
+            return Ok(listener);
+        }
+
+        match addr {
+            Some(addr) if addr.starts_with(UNIX_PREFIX) => bind_unix(&addr[UNIX_PREFIX.len()..]),
+            Some(addr) => Ok(Listener::Addr(addr)),
+            None => bail!("missing address to listen on"),
+        }
+    }
+}
+
+/// Binds the Unix socket at `path`, in place of any left over by a server
+/// that did not get to remove it.
+#[cfg(unix)]
+fn bind_unix(path: &str) -> anyhow::Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    use anyhow::Context;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path);
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("failed to bind {}", path))?;
+    Ok(Listener::Unix(listener))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_: &str) -> anyhow::Result<Listener> {
+    bail!("Unix sockets are not supported on this platform")
+}
+
+/// The listening socket systemd passed to a socket-activated server, per
+/// sd_listen_fds(3), so that the port stays open while the server restarts.
+#[cfg(unix)]
+fn inherited() -> anyhow::Result<Option<Listener>> {
+    use std::os::unix::io::{FromRawFd, RawFd};
+
+    use anyhow::Context;
+    use log::warn;
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+    use nix::sys::socket::{getsockname, SockAddr};
+
+    /// The first socket passed, the ones after it following in order
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    let fds = match env::var("LISTEN_FDS") {
+        Ok(fds) => fds,
+        Err(_) => return Ok(None),
+    };
+    // set for another process, which left them to its children
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    if pid != Some(std::process::id()) {
+        return Ok(None);
+    }
+    for name in &["LISTEN_FDS", "LISTEN_PID", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    let fds: u32 = fds.parse().context("invalid LISTEN_FDS")?;
+    match fds {
+        0 => return Ok(None),
+        1 => {}
+        _ => warn!("listening on the first of the {} sockets passed", fds),
+    }
+
+    let fd = SD_LISTEN_FDS_START;
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).context("invalid socket passed")?;
+    Ok(Some(
+        match getsockname(fd).context("invalid socket passed")? {
+            SockAddr::Unix(_) => Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) }),
+            _ => Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) }),
+        },
+    ))
+}
+
+#[cfg(not(unix))]
+fn inherited() -> anyhow::Result<Option<Listener>> {
+    Ok(None)
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::{get, web, HttpResponse};
+
+use super::AppState;
+
+/// Upper bounds of the request latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+
+/// Server-wide counters, exposed on `/metrics` along with the state of the
+/// channels.
+#[derive(Default)]
+pub struct Metrics {
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+    retries: AtomicU64,
+    precondition_failed: AtomicU64,
+    gone: AtomicU64,
+    /// Request latencies by method
+    latencies: Mutex<BTreeMap<String, Histogram>>,
+}
+
+struct ChannelState {
+    name: String,
+    /// Packets buffered
+    depth: usize,
+    oldest: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Escapes a label value.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    /// Data received from senders.
+    pub fn received(&self, len: usize) {
+        self.received_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Data sent to receivers.
+    pub fn sent(&self, len: usize) {
+        self.sent_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn precondition_failed(&self) {
+        self.precondition_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn gone(&self) {
+        self.gone.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A request has been answered after `latency`.
+    pub fn observe(&self, method: &str, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        let mut latencies = self.latencies.lock().unwrap();
+        let histogram = latencies.entry(method.to_owned()).or_default();
+
+        for (bucket, &bound) in histogram.buckets.iter_mut().zip(&LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += secs;
+    }
+
+    /// Writes the metrics in the Prometheus text format, with the state of
+    /// each channel.
+    fn render(&self, out: &mut String, channels: &[ChannelState]) -> fmt::Result {
+        writeln!(
+            out,
+            "# HELP http_pipe_channels Channels open on the server."
+        )?;
+        writeln!(out, "# TYPE http_pipe_channels gauge")?;
+        writeln!(out, "http_pipe_channels {}", channels.len())?;
+
+        writeln!(
+            out,
+            "# HELP http_pipe_queue_depth Packets buffered in a channel."
+        )?;
+        writeln!(out, "# TYPE http_pipe_queue_depth gauge")?;
+        for channel in channels {
+            writeln!(
+                out,
+                "http_pipe_queue_depth{{channel=\"{}\"}} {}",
+                label(&channel.name),
+                channel.depth
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP http_pipe_queue_oldest_seconds Time the oldest packet not fetched yet has been buffered."
+        )?;
+        writeln!(out, "# TYPE http_pipe_queue_oldest_seconds gauge")?;
+        for channel in channels {
+            writeln!(
+                out,
+                "http_pipe_queue_oldest_seconds{{channel=\"{}\"}} {}",
+                label(&channel.name),
+                channel.oldest.unwrap_or_default().as_secs_f64()
+            )?;
+        }
+
+        let counters = [
+            (
+                "http_pipe_received_bytes_total",
+                "Bytes received from senders.",
+                &self.received_bytes,
+            ),
+            (
+                "http_pipe_sent_bytes_total",
+                "Bytes sent to receivers.",
+                &self.sent_bytes,
+            ),
+            (
+                "http_pipe_retries_total",
+                "Channels re-created or resumed by their sender.",
+                &self.retries,
+            ),
+            (
+                "http_pipe_precondition_failed_total",
+                "Requests for channels the server did not have (412).",
+                &self.precondition_failed,
+            ),
+            (
+                "http_pipe_gone_total",
+                "Requests for packets the server no longer had (410).",
+                &self.gone,
+            ),
+        ];
+        for (name, help, counter) in &counters {
+            writeln!(out, "# HELP {} {}", name, help)?;
+            writeln!(out, "# TYPE {} counter", name)?;
+            writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed))?;
+        }
+
+        let name = "http_pipe_request_duration_seconds";
+        writeln!(out, "# HELP {} Time until a request is answered.", name)?;
+        writeln!(out, "# TYPE {} histogram", name)?;
+        for (method, histogram) in self.latencies.lock().unwrap().iter() {
+            for (count, bound) in histogram.buckets.iter().zip(&LATENCY_BUCKETS) {
+                writeln!(
+                    out,
+                    "{}_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                    name, method, bound, count
+                )?;
+            }
+            writeln!(
+                out,
+                "{}_bucket{{method=\"{}\",le=\"+Inf\"}} {}",
+                name, method, histogram.count
+            )?;
+            writeln!(
+                out,
+                "{}_sum{{method=\"{}\"}} {}",
+                name, method, histogram.sum
+            )?;
+            writeln!(
+                out,
+                "{}_count{{method=\"{}\"}} {}",
+                name, method, histogram.count
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[get("/metrics")]
+pub async fn metrics(data: web::Data<AppState>) -> HttpResponse {
+    let mut channels = data
+        .endpoints
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, conn)| ChannelState {
+            name: key.to_string(),
+            depth: conn.queue.depth(),
+            oldest: conn.queue.oldest_pending(),
+        })
+        .collect::<Vec<_>>();
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut out = String::new();
+    data.metrics
+        .render(&mut out, &channels)
+        .expect("writing to a String cannot fail");
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(out)
+}
@@ -0,0 +1,312 @@
+use std::{sync::Arc, time::Duration};
+
+use actix_web::http::HeaderMap;
+use anyhow::bail;
+use log::{debug, warn};
+use reqwest::{header, Client, Method, RequestBuilder, Response, StatusCode};
+
+use super::queue::Queue;
+use super::Encoding;
+use crate::client::{net, sign};
+use crate::common::{headers, log_retry, signature, signature::Signer, Packet, Priority};
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a replica's signature of its requests stays valid, in seconds
+const MIRROR_TTL: u64 = 60;
+
+#[derive(Debug, thiserror::Error)]
+#[error("channel is not available on the mirror")]
+struct ChannelLost;
+
+/// A secondary relay every channel is replicated to.
+#[derive(Clone)]
+pub struct Mirror {
+    url: String,
+    signer: Option<Signer>,
+    /// Signs the requests of replicas with the secret shared with the mirror
+    primary: Signer,
+}
+
+impl Mirror {
+    pub fn new(url: &str, signer: Option<Signer>, secret: &str) -> Self {
+        Mirror {
+            url: url.trim_end_matches('/').to_owned(),
+            signer,
+            primary: Signer::new(secret),
+        }
+    }
+
+    /// Replicates the packets of a channel. On the mirror, it is a stream
+    /// retaining up to `retention` packets, which must be more than this
+    /// relay may hold so that none is dropped before being taken. Channels
+    /// of virtual hosts are replicated under the same `host`.
+    pub fn replica(
+        &self,
+        id: &str,
+        host: Option<String>,
+        queue: Arc<Queue>,
+        retention: usize,
+        priority: Priority,
+        encoding: Encoding,
+    ) -> Replica {
+        Replica {
+            url: format!("{}/{}", self.url, id),
+            id: id.to_owned(),
+            host,
+            secret: None,
+            signer: self.signer.clone(),
+            primary: self.primary.clone(),
+            client: net::client(),
+            queue,
+            retention,
+            priority,
+            encoding,
+        }
+    }
+}
+
+/// Tells the requests of a primary relay's replicas, signed with the secret
+/// it shares with this mirror, apart from those of anyone else.
+pub struct Primary {
+    signer: Signer,
+}
+
+impl Primary {
+    pub fn new(secret: &str) -> Self {
+        Primary {
+            signer: Signer::new(secret),
+        }
+    }
+
+    /// Whether the request for channel `id` comes from a replica.
+    pub fn verify(&self, id: &str, headers: &HeaderMap) -> bool {
+        let mirror = match headers.get(headers::MIRROR) {
+            Some(mirror) => mirror.to_str().unwrap_or_default(),
+            None => return false,
+        };
+        let fields: Vec<_> = mirror.splitn(3, ':').collect();
+        let (timestamp, nonce, signature) = match fields.as_slice() {
+            [timestamp, nonce, signature] => (*timestamp, *nonce, *signature),
+            _ => return false,
+        };
+
+        let fresh = match timestamp.parse::<u64>() {
+            Ok(timestamp) => {
+                let now = signature::timestamp();
+                now.max(timestamp) - now.min(timestamp) < MIRROR_TTL
+            }
+            Err(_) => false,
+        };
+
+        fresh
+            && self
+                .signer
+                .verify("MIRROR", id, "", timestamp, nonce, signature)
+    }
+}
+
+pub struct Replica {
+    url: String,
+    id: String,
+    host: Option<String>,
+    /// Digest of the channel secret, which the channel has on the mirror too
+    secret: Option<Vec<u8>>,
+    signer: Option<Signer>,
+    primary: Signer,
+    client: Client,
+    queue: Arc<Queue>,
+    retention: usize,
+    priority: Priority,
+    encoding: Encoding,
+}
+
+impl Replica {
+    pub fn with_secret(mut self, secret: Option<Vec<u8>>) -> Self {
+        self.secret = secret;
+        self
+    }
+
+    /// A request for the channel on the mirror, marked as coming from this
+    /// relay's replica of it.
+    fn request(&self, method: Method, index: Option<u64>) -> RequestBuilder {
+        let mut req = sign(
+            self.client.request(method.clone(), &self.url),
+            self.signer.as_ref(),
+            method.as_str(),
+            &self.url,
+            index,
+        );
+        if let Some(host) = &self.host {
+            req = req.header(header::HOST, host.as_str());
+        }
+        if let Some(secret) = &self.secret {
+            req = req.header(headers::CHANNEL_SECRET_DIGEST, hex::encode(secret));
+        }
+
+        let (timestamp, nonce) = (signature::timestamp().to_string(), signature::nonce());
+        let signature = self
+            .primary
+            .sign("MIRROR", &self.id, "", &timestamp, &nonce);
+        req.header(
+            headers::MIRROR,
+            format!("{}:{}:{}", timestamp, nonce, signature),
+        )
+    }
+
+    async fn reset(&self, index: usize) -> anyhow::Result<()> {
+        let mut req = self
+            .request(Method::PUT, Some(index as u64))
+            .header(headers::RESET, 1)
+            .header(headers::STREAM, self.retention)
+            .header(headers::PRIORITY, self.priority.to_string())
+            .header(headers::INDEX, index)
+            .header(headers::SESSION, self.queue.session())
+            .header(
+                headers::CHECKSUM_ALGORITHM,
+                self.encoding.checksum.to_string(),
+            );
+
+        if let Some(compression) = &self.encoding.compression {
+            req = req.header(headers::COMPRESSION, compression.as_str());
+        }
+
+        if let Some(params) = self.encoding.encryption {
+            req = req.header(headers::ENCRYPTION, params.to_string());
+        }
+
+        if self.encoding.records {
+            req = req.header(headers::RECORDS, 1);
+        }
+
+        let resp = req.send().await?;
+
+        if !resp.status().is_success() {
+            bail!("mirror returned failure status: {:?}", resp.status());
+        }
+
+        Ok(())
+    }
+
+    async fn put(&self, packet: &Packet) -> anyhow::Result<()> {
+        let resp = self
+            .request(Method::PUT, Some(packet.index as u64))
+            .header(headers::INDEX, packet.index)
+            .header(headers::WORKER, 0)
+            .header(
+                headers::CHECKSUM,
+                self.encoding.checksum.checksum(&packet.data),
+            )
+            .header(headers::COMMITTED, self.queue.first_index())
+            .body(packet.data.clone())
+            .send()
+            .await?;
+
+        self.reconcile(resp)
+    }
+
+    async fn sync(&self) -> anyhow::Result<()> {
+        let resp = self
+            .request(Method::PUT, None)
+            .header(headers::CREDIT, 1)
+            .header(headers::COMMITTED, self.queue.first_index())
+            .send()
+            .await?;
+
+        self.reconcile(resp)
+    }
+
+    /// Packets taken by receivers on the mirror are dropped here as well.
+    fn reconcile(&self, resp: Response) -> anyhow::Result<()> {
+        let status = resp.status();
+        if status == StatusCode::PRECONDITION_FAILED {
+            return Err(ChannelLost.into());
+        }
+
+        if !status.is_success() {
+            bail!("mirror returned failure status: {:?}", status);
+        }
+
+        if let Some(committed) = resp.headers().get(headers::COMMITTED) {
+            self.queue.remove_before(committed.to_str()?.parse()?);
+        }
+
+        Ok(())
+    }
+
+    async fn fin(&self) {
+        let resp = self
+            .request(Method::GET, None)
+            .header(headers::RESET, 0)
+            .send()
+            .await;
+
+        if let Err(e) = resp {
+            debug!("http error: {}", e);
+        }
+    }
+
+    /// Replicates packets until the channel is gone. `exists` tells whether
+    /// the channel still uses this queue, or `None` once it has been removed.
+    pub async fn run<F>(self, exists: F)
+    where
+        F: Fn() -> Option<bool>,
+    {
+        let mut index = 0;
+        let mut lost = true;
+
+        loop {
+            if lost {
+                // resend everything the receiver has not taken yet
+                index = self.queue.first_index();
+                if let Err(e) = self.reset(index).await {
+                    log_retry("creating the channel on the mirror", &e);
+                    tokio::time::delay_for(Duration::from_secs(3)).await;
+                    if exists() != Some(true) {
+                        return;
+                    }
+                    continue;
+                }
+                lost = false;
+            }
+
+            // packets taken by receivers here need not be replicated
+            index = index.max(self.queue.first_index());
+
+            let packet = match tokio::time::timeout(SYNC_INTERVAL, self.queue.get(index)).await {
+                Ok(Some(packet)) => packet,
+                // taken out of order, wait until the receiver has caught up
+                Ok(None) => {
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                    continue;
+                }
+                Err(_) => {
+                    match exists() {
+                        Some(true) => {}
+                        // replaced by a new channel, which has its own replica
+                        Some(false) => return,
+                        None => return self.fin().await,
+                    }
+
+                    if let Err(e) = self.sync().await {
+                        log_retry("syncing with the mirror", &e);
+                        lost = e.is::<ChannelLost>();
+                    }
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.put(&packet).await {
+                if e.is::<ChannelLost>() {
+                    warn!("channel lost on the mirror, replicating it again");
+                    lost = true;
+                } else {
+                    log_retry("replicating to the mirror", &e);
+                    tokio::time::delay_for(Duration::from_secs(3)).await;
+                }
+                continue;
+            }
+
+            index += 1;
+        }
+    }
+}
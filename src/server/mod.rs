@@ -1,24 +1,184 @@
 use std::str::FromStr;
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    fmt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
+use actix_web::dev::{HttpResponseBuilder, RequestHead, Server, Service};
 use actix_web::error::{
-    ErrorBadRequest, ErrorGone, ErrorInternalServerError, ErrorPreconditionFailed,
+    ErrorBadRequest, ErrorConflict, ErrorForbidden, ErrorGone, ErrorInternalServerError,
+    ErrorNotFound, ErrorPayloadTooLarge, ErrorPreconditionFailed, ErrorTooManyRequests,
+    ErrorUnprocessableEntity,
 };
-use actix_web::{get, put, web, App, HttpRequest, HttpResponse, HttpServer};
-use anyhow::anyhow;
-use bytes::BytesMut;
-use futures::stream::StreamExt;
-use log::debug;
+use actix_web::http::{header::CONTENT_LENGTH, HeaderMap, StatusCode};
+use actix_web::middleware::Condition;
+use actix_web::{
+    delete, get, guard, post, put, web, App, HttpRequest, HttpResponse, HttpServer, ResponseError,
+};
+use anyhow::{anyhow, bail};
+use bytes::{Bytes, BytesMut};
+use clap::Clap;
+use futures::future::{self, FutureExt};
+use futures::stream::{self, Stream, StreamExt};
+use log::{debug, info, trace, warn, Level};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use tokio::sync::mpsc::{self, Sender};
 
-use queue::Queue;
+use admission::{Challenges, ChannelRate};
+use auth::{equals, RequireToken};
+use budget::MemoryBudget;
+use calendar::{Bandwidth, BandwidthWindow};
+use cluster::{Cluster, Forward};
+pub use config::with_config;
+use listener::Listener;
+use metrics::Metrics;
+use mirror::{Mirror, Primary};
+use parts::Parts;
+use plugin::{Filter, PluginSpec};
+use policy::{NamePattern, NamePolicy};
+use queue::{Queue, Retention};
+use reservation::{Holder, Reservations};
+use signature::{NonceCache, VerifySignature};
+use spool::Spool;
+use store::{Manifest, ObjectStore};
+use throttle::{Limit, Throttle};
+use vhost::{Access, ChannelKey, VirtualHost, VirtualHosts};
 
-use crate::common::{headers, Packet};
+use crate::common::{
+    checksum::{self, Algorithm},
+    encryption::Params,
+    frame_packet, headers, log_with, pow,
+    signature::Signer,
+    Fields, Limits, Packet, Priority, Size, Stats, Status,
+};
 
+mod admin;
+mod admission;
+mod auth;
+mod budget;
+mod calendar;
+mod cluster;
+mod config;
+mod listener;
+mod metrics;
+mod mirror;
+mod parts;
+pub mod plugin;
+mod policy;
 mod queue;
+mod reservation;
+mod signature;
+mod spool;
+mod store;
+mod throttle;
+mod tls;
+mod tunnel;
+mod vhost;
+
+const CREDIT_WAIT: Duration = Duration::from_secs(10);
+/// How long a streaming response waits for the next packet before ending
+const BATCH_WAIT: Duration = Duration::from_secs(1);
+const QUEUE_CAPACITY: usize = 16;
+/// Data buffered per channel, whatever the sender's packet size
+const QUEUE_BYTES: usize = QUEUE_CAPACITY << 20;
+const MAX_QUEUE_CAPACITY: usize = 1024;
+const MAX_WORKERS: usize = 64;
+const MAX_RECEIVERS: usize = 64;
+/// How long an aborted channel is kept around for its peers to notice
+const ABORT_GRACE: Duration = Duration::from_secs(60);
+/// How long the outcome of a removed channel is remembered
+const RETIRED_TTL: Duration = Duration::from_secs(600);
+/// How often idle channels are looked for
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a reservation lasts without either end using it, unless the
+/// request asks otherwise
+const RESERVATION_TIMEOUT: Duration = Duration::from_secs(300);
+/// How often a server shutting down checks whether its channels have drained
+const DRAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clap)]
+pub struct Opts {
+    #[clap(long = "signature-window", default_value = "300")]
+    signature_window: u64,
+    #[clap(long = "vhost", number_of_values = 1)]
+    vhosts: Vec<VirtualHost>,
+    #[clap(long = "trust-proxy")]
+    trust_proxy: bool,
+    #[clap(long = "max-retention", default_value = "256")]
+    max_retention: usize,
+    #[clap(long = "max-packet-size", value_name = "SIZE")]
+    max_packet_size: Option<Size>,
+    #[clap(long = "idle-timeout", value_name = "SECONDS", default_value = "3600")]
+    idle_timeout: u64,
+    #[clap(long = "drain-timeout", value_name = "SECONDS", default_value = "60")]
+    drain_timeout: u64,
+    #[clap(long = "memory-limit")]
+    memory_limit: Option<Size>,
+    #[clap(long = "spool-dir")]
+    spool_dir: Option<PathBuf>,
+    #[clap(long = "spool-dir-limit", default_value = "10G")]
+    spool_dir_limit: Size,
+    #[clap(long = "object-store", value_name = "URL")]
+    object_store: Option<String>,
+    #[clap(long = "object-store-region", default_value = "us-east-1")]
+    object_store_region: String,
+    #[clap(long = "bandwidth", number_of_values = 1)]
+    bandwidth: Vec<BandwidthWindow>,
+    #[clap(long = "mirror", requires = "mirror-secret")]
+    mirror: Option<String>,
+    #[clap(long = "mirror-secret", env = "HTTP_PIPE_MIRROR_SECRET")]
+    mirror_secret: Option<String>,
+    #[clap(long = "cluster", value_name = "URL", requires = "cluster-secret")]
+    cluster: Option<String>,
+    #[clap(
+        long = "cluster-peer",
+        value_name = "URL",
+        number_of_values = 1,
+        requires = "cluster"
+    )]
+    cluster_peers: Vec<String>,
+    #[clap(long = "cluster-secret", env = "HTTP_PIPE_CLUSTER_SECRET")]
+    cluster_secret: Option<String>,
+    #[clap(long = "metrics")]
+    metrics: bool,
+    #[clap(long = "plugin", number_of_values = 1)]
+    plugins: Vec<PluginSpec>,
+    #[clap(long = "channel-rate", value_name = "PER_MINUTE")]
+    channel_rate: Option<u32>,
+    #[clap(long = "ip-requests", value_name = "PER_SECOND")]
+    ip_requests: Option<u32>,
+    #[clap(long = "ip-bandwidth", value_name = "RATE")]
+    ip_bandwidth: Option<Size>,
+    #[clap(long = "channel-requests", value_name = "PER_SECOND")]
+    channel_requests: Option<u32>,
+    #[clap(long = "channel-bandwidth", value_name = "RATE")]
+    channel_bandwidth: Option<Size>,
+    #[clap(long = "channel-pattern", value_name = "REGEX", number_of_values = 1)]
+    channel_patterns: Vec<NamePattern>,
+    #[clap(long = "min-channel-length")]
+    min_channel_length: Option<usize>,
+    #[clap(long = "min-channel-entropy", value_name = "BITS")]
+    min_channel_entropy: Option<u32>,
+    #[clap(long = "require-channel-secret")]
+    require_channel_secret: bool,
+    #[clap(long = "proof-of-work", value_name = "BITS")]
+    proof_of_work: Option<u32>,
+    #[clap(long = "require-token", env = "HTTP_PIPE_REQUIRE_TOKEN")]
+    require_token: Option<String>,
+    #[clap(long = "admin-token", env = "HTTP_PIPE_ADMIN_TOKEN")]
+    admin_token: Option<String>,
+    #[clap(long = "tls-cert", requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    #[clap(long = "tls-key", requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+}
 
 #[derive(Debug, thiserror::Error)]
 enum ControllerError {
@@ -36,6 +196,30 @@ enum ControllerError {
 
 type ControllerResult<T> = Result<T, ControllerError>;
 
+/// Turns down requests that would need a new channel while the server
+/// drains the ones it has, so that clients go elsewhere rather than retry.
+#[derive(Debug, thiserror::Error)]
+#[error("server is shutting down")]
+struct ShuttingDown;
+
+impl ResponseError for ShuttingDown {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::ServiceUnavailable()
+            .header(headers::SHUTDOWN, "1")
+            .body(self.to_string())
+    }
+}
+
+impl From<ShuttingDown> for ControllerError {
+    fn from(e: ShuttingDown) -> Self {
+        ControllerError::Actix(e.into())
+    }
+}
+
 impl From<ControllerError> for actix_web::error::Error {
     fn from(e: ControllerError) -> actix_web::error::Error {
         match e {
@@ -48,17 +232,422 @@ impl From<ControllerError> for actix_web::error::Error {
     }
 }
 
+struct Retired {
+    status: Status,
+    reason: Option<String>,
+    stats: Arc<Stats>,
+    at: Instant,
+}
+
 struct AppState {
-    endpoints: Mutex<HashMap<String, Conn>>,
+    endpoints: Mutex<HashMap<ChannelKey, Conn>>,
+    retired: Mutex<HashMap<ChannelKey, Retired>>,
+    /// Stats of channels requested before they were created
+    pending: Mutex<HashMap<ChannelKey, (Arc<Stats>, Instant)>>,
+    reservations: Reservations,
+    vhosts: VirtualHosts,
+    /// Whether the forwarding headers of requests are set by a proxy in
+    /// front of the relay, rather than by whoever sent them
+    trust_proxy: bool,
+    max_retention: usize,
+    /// Larger PUT bodies are turned down before being buffered
+    max_packet_size: Option<usize>,
+    budget: Arc<MemoryBudget>,
+    /// Where packets go once channels run out of memory
+    spool: Option<Arc<Spool>>,
+    /// Where completed packets are persisted to outlive the server
+    store: Option<Arc<ObjectStore>>,
+    bandwidth: Bandwidth,
+    mirror: Option<Mirror>,
+    /// Tells the replicas of the primary this relay mirrors
+    primary: Option<Primary>,
+    /// The other relays channels are shared with
+    cluster: Option<Cluster>,
+    plugins: Vec<PluginSpec>,
+    channel_rate: Option<ChannelRate>,
+    challenges: Option<Challenges>,
+    /// Advertised to clients
+    limits: Limits,
+    metrics: Arc<Metrics>,
+    /// Set once the server has been told to shut down, after which it
+    /// takes no new channels
+    draining: AtomicBool,
+    names: NamePolicy,
+    /// Whether senders must set a secret for the channels they create
+    require_channel_secret: bool,
+}
+
+impl AppState {
+    fn channel_key(&self, req: &HttpRequest, id: String) -> ControllerResult<ChannelKey> {
+        self.channel_key_of(req.head(), id)
+    }
+
+    /// Without any configured virtual host, all requests share one namespace.
+    fn channel_key_of(&self, head: &RequestHead, id: String) -> ControllerResult<ChannelKey> {
+        if self.vhosts.is_empty() {
+            return Ok(ChannelKey {
+                host: String::new(),
+                id,
+            });
+        }
+
+        let host = vhost::host_of(head, self.trust_proxy);
+        let vhost = match self.vhosts.lookup(&host) {
+            Some(vhost) => vhost,
+            None => return Err(ErrorNotFound("unknown virtual host").into()),
+        };
+
+        Ok(ChannelKey {
+            host: vhost.namespace.clone().unwrap_or(host),
+            id,
+        })
+    }
+
+    /// The virtual host `req` is for, if any matches.
+    fn vhost(&self, req: &HttpRequest) -> Option<&VirtualHost> {
+        self.vhosts
+            .lookup(&vhost::host_of(req.head(), self.trust_proxy))
+    }
+
+    /// Rejects uploads or downloads the host does not serve.
+    fn check_access(&self, req: &HttpRequest, upload: bool) -> ControllerResult<()> {
+        let access = match self.vhost(req) {
+            Some(vhost) => vhost.access,
+            None => return Ok(()),
+        };
+
+        match (upload, access.allows_upload(), access.allows_download()) {
+            (true, false, _) => Err(ErrorForbidden("this host does not accept uploads").into()),
+            (false, _, false) => Err(ErrorForbidden("this host does not serve downloads").into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects requests acting on channels as a whole, aborting or
+    /// reserving them, on hosts restricted to one direction.
+    fn check_full_access(&self, req: &HttpRequest) -> ControllerResult<()> {
+        match self.vhost(req) {
+            Some(vhost) if vhost.access != Access::ReadWrite => {
+                Err(ErrorForbidden("this host does not abort or reserve channels").into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects requests for a reserved channel without the claim of the
+    /// end they come from, and those for a channel with a secret without it.
+    fn check_claim(
+        &self,
+        key: &ChannelKey,
+        headers: &HeaderMap,
+        holder: Holder,
+    ) -> ControllerResult<()> {
+        let (open, secret) = match self.endpoints.lock().unwrap().get(key) {
+            Some(conn) => (true, conn.secret.clone()),
+            None => (false, None),
+        };
+        if !self.reservations.check(key, headers, holder, open) {
+            return Err(ErrorForbidden("channel is reserved").into());
+        }
+        if let Some(secret) = secret {
+            match self.presented_secret(key, headers) {
+                Some(digest) if equals(&digest, &secret) => {}
+                Some(_) => return Err(ErrorForbidden("wrong channel secret").into()),
+                None => return Err(ErrorForbidden("channel secret required").into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// The digest of the secret a request presents for channel `key`, which
+    /// the primary passes on to the mirror as is.
+    fn presented_secret(&self, key: &ChannelKey, headers: &HeaderMap) -> Option<Vec<u8>> {
+        match &self.primary {
+            Some(primary) if primary.verify(&key.id, headers) => headers
+                .get(headers::CHANNEL_SECRET_DIGEST)
+                .and_then(|digest| hex::decode(digest.as_bytes()).ok()),
+            _ => channel_secret(headers),
+        }
+    }
+
+    /// Remembers for a while how a removed channel has ended.
+    fn retire(&self, key: ChannelKey, conn: &Conn) {
+        let reason = conn.queue.aborted();
+        let status = if reason.is_some() {
+            Status::Aborted
+        } else {
+            Status::Complete
+        };
+
+        conn.stats.set_oldest(None);
+        if !conn.stats.is_empty() {
+            info!("channel {} finished: {}", key, conn.stats);
+        }
+
+        self.reservations.release(&key);
+        let mut retired = self.retired.lock().unwrap();
+        retired.retain(|_, r| r.at.elapsed() < RETIRED_TTL);
+        retired.insert(
+            key,
+            Retired {
+                status,
+                reason,
+                stats: conn.stats.clone(),
+                at: Instant::now(),
+            },
+        );
+    }
+
+    /// Serves a channel the sender had finished again from the object store,
+    /// if it has been persisted there before the server restarted.
+    async fn restore(
+        &self,
+        key: &ChannelKey,
+    ) -> ControllerResult<Option<(Arc<Queue>, bool, Arc<Stats>, Encoding)>> {
+        let store = match &self.store {
+            Some(store) if !self.is_draining() => store,
+            _ => return Ok(None),
+        };
+
+        let channel = store.channel(key, Manifest::default());
+        if !channel.load_manifest().await? {
+            return Ok(None);
+        }
+        let manifest = channel.manifest();
+        let end = match manifest.end {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let encoding = Encoding::from_manifest(&manifest)?;
+
+        let queue = Arc::new(
+            Queue::restored(QUEUE_CAPACITY, channel, end)
+                .with_budget(self.budget.clone(), Priority::Normal),
+        );
+        let mut endpoints = self.endpoints.lock().unwrap();
+        // another receiver may have restored it meanwhile
+        let conn = endpoints.entry(key.clone()).or_insert_with(|| {
+            info!("channel {} restored from the object store", key);
+            let mut conn = Conn::attach(
+                1,
+                queue,
+                Filters::default(),
+                false,
+                Arc::default(),
+                encoding,
+            );
+            conn.secret = manifest.secret.as_deref().and_then(|s| hex::decode(s).ok());
+            conn
+        });
+
+        Ok(Some((
+            conn.queue.clone(),
+            conn.stream,
+            conn.stats.clone(),
+            conn.encoding.clone(),
+        )))
+    }
+
+    /// Tears down channels no sender or receiver has used for `timeout`, e.g.
+    /// after the sender died without finishing, failing any request still
+    /// waiting on them.
+    fn reap(&self, timeout: Duration) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let idle = endpoints
+            .iter()
+            .filter(|(_, conn)| conn.queue.idle() >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in idle {
+            if let Some(conn) = endpoints.remove(&key) {
+                info!("channel {} closed after being idle", key);
+                conn.queue.abort("channel closed after being idle".into());
+                self.retire(key, &conn);
+            }
+        }
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Counts a request for a channel that does not exist (yet).
+    fn precondition_failed(&self, key: &ChannelKey, reason: &'static str) -> ControllerError {
+        // it won't be created on this server anymore
+        if self.is_draining() {
+            return ShuttingDown.into();
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        if !pending.contains_key(key) {
+            pending.retain(|_, (_, at)| at.elapsed() < RETIRED_TTL);
+        }
+
+        let (stats, at) = pending
+            .entry(key.clone())
+            .or_insert_with(|| (Arc::default(), Instant::now()));
+        stats.precondition_failed();
+        self.metrics.precondition_failed();
+        *at = Instant::now();
+
+        ErrorPreconditionFailed(reason).into()
+    }
+
+    fn status(&self, key: &ChannelKey) -> (Status, Option<String>, Option<Arc<Stats>>) {
+        if let Some(conn) = self.endpoints.lock().unwrap().get(key) {
+            let (status, reason) = conn.status();
+            conn.stats.set_oldest(conn.queue.oldest_pending());
+            return (status, reason, Some(conn.stats.clone()));
+        }
+
+        match self.retired.lock().unwrap().get(key) {
+            Some(r) if r.at.elapsed() < RETIRED_TTL => {
+                (r.status, r.reason.clone(), Some(r.stats.clone()))
+            }
+            _ => {
+                let pending = self.pending.lock().unwrap();
+                let stats = pending.get(key).map(|(stats, _)| stats.clone());
+                (Status::Unknown, None, stats)
+            }
+        }
+    }
+}
+
+/// The name of a codec, checked to be one rather than anything else passed
+/// on to receivers.
+fn codec_name(name: &str) -> ControllerResult<String> {
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    if name.is_empty() || name.len() > 32 || !valid {
+        return Err(ErrorBadRequest("invalid compression").into());
+    }
+    Ok(name.to_ascii_lowercase())
+}
+
+type Filters = Arc<Mutex<Vec<Box<dyn Filter>>>>;
+
+/// How the sender of a channel encodes its packets, passed on to receivers.
+#[derive(Clone, PartialEq, Eq)]
+struct Encoding {
+    checksum: Algorithm,
+    /// Only receivers need to know the codec, which may be one of their own
+    compression: Option<String>,
+    /// Announced to receivers, which need nothing but the passphrase
+    encryption: Option<Params>,
+    /// Packets are framed into records carrying metadata along
+    records: bool,
+}
+
+impl Encoding {
+    fn from_request(req: &HttpRequest) -> ControllerResult<Self> {
+        let checksum = match req.headers().get(headers::CHECKSUM_ALGORITHM) {
+            Some(algorithm) => algorithm.to_str()?.parse().map_err(ErrorBadRequest)?,
+            None => Algorithm::default(),
+        };
+        let compression = match req.headers().get(headers::COMPRESSION) {
+            Some(compression) => Some(codec_name(compression.to_str()?)?),
+            None => None,
+        };
+        let encryption = match req.headers().get(headers::ENCRYPTION) {
+            Some(params) => Some(params.to_str()?.parse().map_err(ErrorBadRequest)?),
+            None => None,
+        };
+
+        Ok(Encoding {
+            checksum,
+            compression,
+            encryption,
+            records: req.headers().get(headers::RECORDS).is_some(),
+        })
+    }
+
+    fn manifest(&self) -> Manifest {
+        Manifest {
+            checksum: self.checksum.to_string(),
+            compression: self.compression.clone(),
+            encryption: self.encryption.map(|params| params.to_string()),
+            records: self.records,
+            end: None,
+            secret: None,
+        }
+    }
+
+    fn from_manifest(manifest: &Manifest) -> anyhow::Result<Self> {
+        Ok(Encoding {
+            checksum: manifest.checksum.parse()?,
+            compression: manifest.compression.clone(),
+            encryption: match &manifest.encryption {
+                Some(params) => Some(params.parse()?),
+                None => None,
+            },
+            records: manifest.records,
+        })
+    }
+
+    /// Plugins could not make sense of packets encoded this way.
+    fn is_opaque(&self) -> bool {
+        self.compression.is_some() || self.encryption.is_some() || self.records
+    }
+
+    /// Adds the headers receivers need to check and decode `data`.
+    fn describe(&self, resp: &mut HttpResponseBuilder, data: &[u8]) {
+        resp.header(headers::CHECKSUM, self.checksum.checksum(data));
+        self.describe_stream(resp);
+    }
+
+    /// Adds the headers receivers need to decode packets streamed one after
+    /// the other, each framed with its own checksum.
+    fn describe_stream(&self, resp: &mut HttpResponseBuilder) {
+        if let Some(compression) = &self.compression {
+            resp.header(headers::COMPRESSION, compression.as_str());
+        }
+        if let Some(params) = self.encryption {
+            resp.header(headers::ENCRYPTION, params.to_string());
+        }
+        if self.records {
+            resp.header(headers::RECORDS, "1");
+        }
+    }
 }
 
 struct Conn {
     senders: Vec<Sender<Packet>>,
     queue: Arc<Queue>,
+    filters: Filters,
+    stream: bool,
+    stats: Arc<Stats>,
+    encoding: Encoding,
+    parts: Arc<Parts>,
+    /// Largest packet the sender said it sends, which the queue is sized for
+    packet_size: Option<usize>,
+    /// Digest of the secret every request for the channel must carry
+    secret: Option<Vec<u8>>,
 }
 
 impl Conn {
-    fn new(worker_num: usize) -> Self {
+    fn status(&self) -> (Status, Option<String>) {
+        let reason = self.queue.aborted();
+        let status = if reason.is_some() {
+            Status::Aborted
+        } else if self.queue.is_finished() {
+            Status::Sent
+        } else {
+            Status::Active
+        };
+        (status, reason)
+    }
+
+    /// Starts accepting packets from the queue's next index on.
+    fn attach(
+        worker_num: usize,
+        queue: Arc<Queue>,
+        filters: Filters,
+        stream: bool,
+        stats: Arc<Stats>,
+        encoding: Encoding,
+    ) -> Self {
         let mut senders: Vec<Sender<Packet>> = Vec::new();
         let mut receivers = Vec::new();
 
@@ -68,163 +657,1357 @@ impl Conn {
             receivers.push(rx);
         }
 
-        let queue = Arc::new(Queue::new(16));
-
-        let q = queue.clone();
+        let (q, f, st) = (queue.clone(), filters.clone(), stats.clone());
         tokio::spawn(async move {
-            let mut index = 0;
+            let mut index = q.next_index();
             'l: loop {
-                for rx in &mut receivers {
-                    loop {
-                        if let Some(packet) = rx.recv().await {
-                            if packet.index < index {
-                                continue;
-                            }
-
-                            debug_assert!(packet.index == index);
-
-                            let is_eof = packet.data.is_empty();
-                            q.push(packet).await;
-                            index += 1;
+                let rx = &mut receivers[index % worker_num];
+                loop {
+                    if let Some(mut packet) = rx.recv().await {
+                        if packet.index < index {
+                            st.duplicate();
+                            continue;
+                        }
 
-                            if is_eof {
+                        let result = plugin::apply(&mut f.lock().unwrap(), packet.data);
+                        packet.data = match result {
+                            Ok(data) => data,
+                            Err(e) => {
+                                warn!("transfer aborted: {}", e);
+                                q.abort(e.to_string());
                                 break 'l;
                             }
+                        };
+
+                        // a mirror skips packets already taken on the primary
+                        debug_assert!(packet.index == index || worker_num == 1);
 
-                            break;
-                        } else {
+                        let is_eof = packet.data.is_empty();
+                        index = packet.index + 1;
+                        st.stalled(q.push(packet).await);
+
+                        if is_eof {
                             break 'l;
                         }
+
+                        break;
+                    } else {
+                        break 'l;
                     }
                 }
             }
         });
 
-        Conn { senders, queue }
+        let parts = Arc::new(Parts::new(queue.clone(), worker_num));
+        Conn {
+            senders,
+            queue,
+            filters,
+            stream,
+            stats,
+            encoding,
+            parts,
+            packet_size: None,
+            secret: None,
+        }
+    }
+}
+
+/// Number of packets of the given size buffered for a channel.
+fn queue_capacity(packet_size: usize) -> usize {
+    match QUEUE_BYTES / packet_size.max(1) {
+        // large packets are still double-buffered
+        0..=1 => 2,
+        n => n.min(MAX_QUEUE_CAPACITY),
     }
 }
 
-fn parse_from_header<T>(req: &HttpRequest, name: &str) -> ControllerResult<T>
+/// Digest of the channel secret a request carries, if any.
+fn channel_secret(headers: &HeaderMap) -> Option<Vec<u8>> {
+    headers
+        .get(headers::CHANNEL_SECRET)
+        .map(|secret| Sha256::digest(secret.as_bytes()).to_vec())
+}
+
+fn check_aborted(queue: &Queue) -> ControllerResult<()> {
+    match queue.aborted() {
+        Some(reason) => Err(ErrorUnprocessableEntity(reason).into()),
+        None => Ok(()),
+    }
+}
+
+/// How often a client said it would send heartbeats, if it did.
+fn heartbeat(req_headers: &HeaderMap) -> ControllerResult<Option<Duration>> {
+    Ok(match req_headers.get(headers::HEARTBEAT) {
+        Some(secs) => match secs.to_str()?.parse()? {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        None => None,
+    })
+}
+
+/// Turns the sender away once the receivers have missed their heartbeats,
+/// as nothing would take what it sends.
+fn receiver_gone(queue: &Queue) -> Option<HttpResponse> {
+    let silence = queue.receiver_silence()?;
+    Some(
+        HttpResponse::Gone()
+            .header(headers::RECEIVER_SILENCE, silence.as_secs())
+            .body("the receiver has gone silent"),
+    )
+}
+
+/// Turns away requests of another session than the channel's, e.g. of a
+/// worker of an earlier transfer under the same name still retrying.
+fn stale_session(queue: &Queue, req_headers: &HeaderMap) -> Option<HttpResponse> {
+    let session = req_headers.get(headers::SESSION)?;
+    if session.as_bytes() == queue.session().as_bytes() {
+        return None;
+    }
+    Some(
+        HttpResponse::Conflict()
+            .header(headers::SESSION, queue.session())
+            .body("stale session"),
+    )
+}
+
+/// Logs a request, with the channel, transfer, worker and packet it is for
+/// as fields of their own in JSON logs.
+fn log_request(level: Level, method: &str, path: &ChannelKey, req_headers: &HeaderMap) {
+    if level > log::max_level() {
+        return;
+    }
+
+    let header = |name| req_headers.get(name).and_then(|v| v.to_str().ok());
+    let channel = path.to_string();
+    let fields = Fields {
+        channel: Some(&channel),
+        transfer: header(headers::TRANSFER),
+        worker: header(headers::WORKER),
+        packet: header(headers::INDEX),
+    };
+    log_with(
+        level,
+        module_path!(),
+        &fields,
+        format_args!("{} {:?} ({})", method, path, origin(req_headers)),
+    );
+}
+
+/// The transfer and worker a request belongs to, for correlating it with
+/// the logs of clients and proxies.
+fn origin(headers: &HeaderMap) -> String {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("-")
+    };
+
+    format!(
+        "transfer {}, worker {}",
+        header(headers::TRANSFER),
+        header(headers::WORKER)
+    )
+}
+
+fn parse_from_header<T>(headers: &HeaderMap, name: &str) -> ControllerResult<T>
 where
     T: FromStr,
     ControllerError: From<T::Err>,
 {
-    Ok(req
-        .headers()
+    Ok(headers
         .get(name)
         .ok_or_else(|| ControllerError::MissingRequiredFields(name.into()))?
         .to_str()?
         .parse()?)
 }
 
-#[put("/{id}")]
+#[put("/{id:.+}")]
 async fn recv(
     data: web::Data<AppState>,
     path: web::Path<String>,
     req: HttpRequest,
-    mut body: web::Payload,
+    body: web::Payload,
 ) -> ControllerResult<HttpResponse> {
-    let path = path.into_inner();
+    let path = data.channel_key(&req, path.into_inner())?;
+    data.check_access(&req, true)?;
+    data.check_claim(&path, req.headers(), Holder::Sender)?;
 
     if let Some(worker_num) = req.headers().get(headers::RESET) {
-        debug!("RESET {:?}", path);
+        log_request(Level::Debug, "RESET", &path, req.headers());
+
+        let worker_num = worker_num.to_str()?.parse()?;
+        if worker_num == 0 || worker_num > MAX_WORKERS {
+            return Err(ErrorBadRequest("invalid number of workers").into());
+        }
+
+        let retention = match req.headers().get(headers::STREAM) {
+            Some(retention) => Some(retention.to_str()?.parse::<usize>()?),
+            None => None,
+        };
+        let max_age = match req.headers().get(headers::STREAM_MAX_AGE) {
+            Some(max_age) => Some(Duration::from_secs(max_age.to_str()?.parse()?)),
+            None => None,
+        };
+        let max_bytes = match req.headers().get(headers::STREAM_MAX_BYTES) {
+            Some(max_bytes) => Some(max_bytes.to_str()?.parse()?),
+            None => None,
+        };
+        if (max_age.is_some() || max_bytes.is_some()) && retention.is_none() {
+            return Err(ErrorBadRequest("only streams are retained by age or size").into());
+        }
+        let receivers = match req.headers().get(headers::RECEIVERS) {
+            Some(receivers) => Some(receivers.to_str()?.parse::<usize>()?),
+            None => None,
+        };
+        if receivers == Some(0) || receivers > Some(MAX_RECEIVERS) {
+            return Err(ErrorBadRequest("invalid number of receivers").into());
+        }
+        // streams drop packets whether they have been taken or not
+        if receivers.is_some() && retention.is_some() {
+            return Err(ErrorBadRequest("streams cannot have several receivers").into());
+        }
+        // a sender re-creating a channel lost by a restart continues from
+        // the last packet the receiver has taken
+        let start = match req.headers().get(headers::INDEX) {
+            Some(index) => Some(index.to_str()?.parse()?),
+            None => None,
+        };
+        let priority: Priority = match req.headers().get(headers::PRIORITY) {
+            Some(priority) => priority.to_str()?.parse().map_err(ErrorBadRequest)?,
+            None => Priority::Normal,
+        };
+        // anyone else claiming to be the primary is an ordinary sender
+        let mirrored = data
+            .primary
+            .as_ref()
+            .is_some_and(|primary| primary.verify(&path.id, req.headers()));
+        let heartbeat = heartbeat(req.headers())?;
+        // a sender re-creating the channel continues its session
+        let session = match req.headers().get(headers::SESSION) {
+            Some(session) => Some(session.to_str()?.to_owned()),
+            None => None,
+        };
+        let packet_size = match req.headers().get(headers::PACKET_SIZE) {
+            Some(size) => Some(size.to_str()?.parse()?),
+            None => None,
+        };
+        let capacity = packet_size.map_or(QUEUE_CAPACITY, queue_capacity);
+        let encoding = Encoding::from_request(&req)?;
+
+        if encoding.is_opaque() && !mirrored && !plugin::filters(&data.plugins, &path.id).is_empty()
+        {
+            return Err(ErrorBadRequest("encoded transfers cannot pass plugins").into());
+        }
+
+        let mut endpoints = data.endpoints.lock().unwrap();
 
-        data.endpoints
-            .lock()
-            .unwrap()
-            .insert(path, Conn::new(worker_num.to_str()?.parse()?));
+        let vhost = data.vhost(&req);
+        if let Some(max_channels) = vhost.and_then(|vhost| vhost.max_channels) {
+            // `*` keys channels by each host it matches, which all count
+            // against it
+            let name = vhost.map(|vhost| &vhost.name);
+            let channels = endpoints
+                .keys()
+                .filter(|k| {
+                    k.host == path.host || data.vhosts.owner(&k.host).map(|v| &v.name) == name
+                })
+                .count();
+            if !endpoints.contains_key(&path) && channels >= max_channels {
+                return Err(ErrorTooManyRequests("too many channels on this host").into());
+            }
+        }
+
+        let existing = endpoints
+            .get(&path)
+            .filter(|conn| conn.queue.aborted().is_none());
+        // the primary replaces the replica of an earlier channel
+        if let (Some(conn), false) = (existing, mirrored) {
+            if let Some(resp) = stale_session(&conn.queue, req.headers()) {
+                return Ok(resp);
+            }
+        }
+        let (queue, created) = match (retention, existing) {
+            // a restarted sender continues a stream where it left off
+            (Some(_), Some(conn)) if conn.stream && !mirrored => (conn.queue.clone(), false),
+            // a resumed sender may send packets the channel already has,
+            // which are skipped
+            (None, Some(conn)) if start.is_some() && !conn.stream => (conn.queue.clone(), false),
+            // a stream keeps at most `retention` packets for receivers, and
+            // possibly only as many as are recent or small enough, and
+            // drops the oldest ones beyond that
+            (Some(retention), _) => (
+                Arc::new(
+                    Queue::dropping_oldest(retention.min(data.max_retention), start.unwrap_or(0))
+                        .with_retention(Retention { max_age, max_bytes })
+                        .with_budget(data.budget.clone(), priority)
+                        .with_session(session.clone()),
+                ),
+                true,
+            ),
+            (None, _) => {
+                let mut queue = Queue::new(capacity, start.unwrap_or(0))
+                    .with_budget(data.budget.clone(), priority)
+                    .with_session(session.clone());
+                if let Some(spool) = &data.spool {
+                    queue = queue.with_spool(spool.clone());
+                }
+                if let Some(store) = &data.store {
+                    let manifest = Manifest {
+                        secret: data.presented_secret(&path, req.headers()).map(hex::encode),
+                        ..encoding.manifest()
+                    };
+                    queue = queue.with_store(store.channel(&path, manifest));
+                }
+                if let Some(receivers) = receivers {
+                    queue = queue.with_receivers(receivers);
+                }
+                (Arc::new(queue), true)
+            }
+        };
+
+        // senders of channels being drained may still re-attach
+        if created && data.is_draining() {
+            return Err(ShuttingDown.into());
+        }
+
+        // the primary has already checked the name
+        if created && !mirrored {
+            data.names.check(&path.id).map_err(ErrorForbidden)?;
+        }
+        if created
+            && data.require_channel_secret
+            && data.presented_secret(&path, req.headers()).is_none()
+        {
+            return Err(ErrorForbidden("channels need a secret on this relay").into());
+        }
+
+        // receivers could not decode packets encoded differently
+        if let (false, Some(conn)) = (created, existing) {
+            if conn.encoding.compression != encoding.compression {
+                return Err(ErrorConflict("channel uses a different compression").into());
+            }
+            if conn.encoding.encryption != encoding.encryption {
+                return Err(ErrorConflict("channel uses a different encryption").into());
+            }
+            if conn.encoding.records != encoding.records {
+                return Err(ErrorConflict("channel uses a different framing").into());
+            }
+            if conn.queue.receivers() != receivers {
+                return Err(ErrorConflict("channel has a different number of receivers").into());
+            }
+        }
+
+        if let (true, false, Some(challenges)) = (created, mirrored, &data.challenges) {
+            let solved = match req.headers().get(headers::POW) {
+                Some(solution) => challenges.verify(solution.to_str()?, req.path()),
+                None => false,
+            };
+            if !solved {
+                return Ok(HttpResponse::build(StatusCode::PRECONDITION_REQUIRED)
+                    .header(headers::POW, challenges.challenge())
+                    .body("proof of work required"));
+            }
+        }
 
+        if let (true, false, Some(rate)) = (created, mirrored, &data.channel_rate) {
+            if let Some(addr) = req.peer_addr() {
+                if !rate.allow(addr.ip()) {
+                    warn!("{} is creating too many channels", addr.ip());
+                    return Err(ErrorTooManyRequests("too many new channels").into());
+                }
+            }
+        }
+
+        if created {
+            info!("channel {} opened by {}", path, origin(req.headers()));
+        }
+
+        if let (true, false, Some(mirror)) = (created, mirrored, &data.mirror) {
+            // the mirror tells the channels of virtual hosts apart by host too
+            let host = if path.host.is_empty() {
+                None
+            } else {
+                Some(vhost::host_of(req.head(), data.trust_proxy))
+            };
+            let replica = mirror
+                .replica(
+                    &path.id,
+                    host,
+                    queue.clone(),
+                    2 * queue.capacity(),
+                    priority,
+                    encoding.clone(),
+                )
+                .with_secret(channel_secret(req.headers()));
+            let (data, key, queue) = (data.clone(), path.clone(), queue.clone());
+
+            tokio::spawn(replica.run(move || {
+                let endpoints = data.endpoints.lock().unwrap();
+                endpoints
+                    .get(&key)
+                    .map(|conn| Arc::ptr_eq(&conn.queue, &queue))
+            }));
+        }
+
+        let filters = match existing {
+            Some(conn) if !created => conn.filters.clone(),
+            // packets have already passed the plugins on the primary
+            _ if mirrored => Filters::default(),
+            _ => Arc::new(Mutex::new(plugin::filters(&data.plugins, &path.id))),
+        };
+
+        // the sender has re-created or re-attached to the channel
+        let stats = match existing {
+            Some(conn) => conn.stats.clone(),
+            None => data
+                .pending
+                .lock()
+                .unwrap()
+                .remove(&path)
+                .map(|(stats, _)| stats)
+                .unwrap_or_default(),
+        };
+        if existing.is_some() || start.is_some() {
+            stats.retry();
+            data.metrics.retry();
+        }
+
+        // a re-attaching sender has passed the secret the channel has
+        let secret = match existing {
+            Some(conn) if !created => conn.secret.clone(),
+            _ => data.presented_secret(&path, req.headers()),
+        };
+
+        // the queue stays sized for the packets first declared
+        let packet_size = match existing {
+            Some(conn) if !created => conn.packet_size,
+            _ => packet_size,
+        };
+
+        let mut conn = Conn::attach(
+            worker_num,
+            queue,
+            filters,
+            retention.is_some(),
+            stats,
+            encoding,
+        );
+        conn.secret = secret;
+        conn.packet_size = packet_size;
+        conn.queue.expect_sender_heartbeats(heartbeat);
+
+        let mut resp = HttpResponse::Ok();
+        resp.header(headers::INDEX, conn.queue.next_index())
+            .header(headers::SESSION, conn.queue.session())
+            .header(headers::CREDIT, conn.queue.credit())
+            .header(headers::LIMITS, data.limits.to_header());
+        hint_rate(&mut resp, &conn.queue);
+        endpoints.insert(path, conn);
+
+        return Ok(resp.finish());
+    }
+
+    // a sender with nothing to send tells its receivers it is still there
+    if req.headers().get(headers::HEARTBEAT).is_some() {
+        let queue = if let Some(conn) = data.endpoints.lock().unwrap().get(&path) {
+            conn.queue.clone()
+        } else {
+            return Err(data.precondition_failed(&path, "sender not available"));
+        };
+        check_aborted(&queue)?;
+        if let Some(resp) = stale_session(&queue, req.headers()) {
+            return Ok(resp);
+        }
+        queue.heard_from_sender();
+        if let Some(resp) = receiver_gone(&queue) {
+            return Ok(resp);
+        }
         return Ok(HttpResponse::Ok().finish());
     }
 
-    debug!("PUT {:?}", path);
+    if req.headers().get(headers::CREDIT).is_some() {
+        let queue = if let Some(conn) = data.endpoints.lock().unwrap().get(&path) {
+            conn.queue.clone()
+        } else {
+            return Err(data.precondition_failed(&path, "sender not available"));
+        };
+        check_aborted(&queue)?;
+        if let Some(resp) = stale_session(&queue, req.headers()) {
+            return Ok(resp);
+        }
+        queue.heard_from_sender();
+        if let Some(resp) = receiver_gone(&queue) {
+            return Ok(resp);
+        }
 
-    let worker_index: usize = parse_from_header(&req, headers::WORKER)?;
-    let data_index = parse_from_header(&req, headers::INDEX)?;
+        // a primary relay tells its mirror what its receivers have taken
+        if let Some(committed) = req.headers().get(headers::COMMITTED) {
+            queue.remove_before(committed.to_str()?.parse()?);
+        }
 
-    let mut sender = if let Some(conn) = data.endpoints.lock().unwrap().get(&path) {
-        conn.senders[worker_index].clone()
-    } else {
-        return Err(ErrorPreconditionFailed("sender not available").into());
+        // wait a while for the receiver to free some space
+        let credit = tokio::time::timeout(CREDIT_WAIT, queue.wait_credit())
+            .await
+            .unwrap_or(0);
+        check_aborted(&queue)?;
+        if let Some(resp) = receiver_gone(&queue) {
+            return Ok(resp);
+        }
+
+        let mut resp = HttpResponse::Ok();
+        resp.header(headers::COMMITTED, queue.first_index())
+            .header(headers::CREDIT, credit);
+        hint_rate(&mut resp, &queue);
+        return Ok(resp.finish());
+    }
+
+    put_packet(&data, path, req.headers(), body).await
+}
+
+/// Passes a packet sent by a worker on to the channel.
+async fn put_packet<S, E>(
+    data: &AppState,
+    path: ChannelKey,
+    req_headers: &HeaderMap,
+    mut body: S,
+) -> ControllerResult<HttpResponse>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: fmt::Display,
+{
+    // once for each packet, too many to follow short of tracing
+    log_request(Level::Trace, "PUT", &path, req_headers);
+
+    let worker_index: usize = parse_from_header(req_headers, headers::WORKER)?;
+    let data_index = parse_from_header(req_headers, headers::INDEX)?;
+
+    let (sender, queue, filtered, parts, packet_size) =
+        if let Some(conn) = data.endpoints.lock().unwrap().get(&path) {
+            let filtered = !conn.filters.lock().unwrap().is_empty();
+            (
+                conn.senders.get(worker_index).cloned(),
+                conn.queue.clone(),
+                filtered,
+                conn.parts.clone(),
+                conn.packet_size,
+            )
+        } else {
+            return Err(data.precondition_failed(&path, "sender not available"));
+        };
+    check_aborted(&queue)?;
+    if let Some(resp) = stale_session(&queue, req_headers) {
+        return Ok(resp);
+    }
+    queue.touch();
+    queue.heard_from_sender();
+    if let Some(resp) = receiver_gone(&queue) {
+        return Ok(resp);
+    }
+
+    // the channel may have been re-created with fewer workers
+    let mut sender =
+        sender.ok_or_else(|| ControllerError::from(ErrorBadRequest("unknown worker")))?;
+
+    if let Some(committed) = req_headers.get(headers::COMMITTED) {
+        queue.remove_before(committed.to_str()?.parse()?);
+    }
+
+    // receivers get it with the end of the stream, so it has to be there
+    // before that is
+    if let Some(trailer) = req_headers.get(headers::TRAILER) {
+        queue.set_trailer(trailer.to_str()?.to_owned());
+    }
+
+    // packets may not be larger than the sender said when the queue was
+    // sized for them
+    let max_size = data
+        .max_packet_size
+        .unwrap_or(usize::MAX)
+        .min(packet_size.unwrap_or(usize::MAX));
+    let len = match req_headers.get(CONTENT_LENGTH) {
+        Some(len) => Some(len.to_str()?.parse::<usize>()?),
+        None => None,
     };
+    if len > Some(max_size) {
+        return Err(ErrorPayloadTooLarge("packet too large").into());
+    }
 
-    let mut bytes = BytesMut::new();
+    // packets enter the queue whole: checksums, parts, the spool, the store
+    // and plugins all work on complete packets, and receivers must not get
+    // the start of one whose sender dies halfway. Until then, chunks are
+    // kept as they arrive and only put together once all have, so that no
+    // more is ever held than has been received, whatever length the
+    // request claims
+    let mut chunks = Vec::new();
+    let mut received = 0;
     while let Some(chunk) = body.next().await {
-        bytes.extend_from_slice(&(chunk.map_err(|e| anyhow!("payload error: {}", e))?));
+        let chunk = chunk.map_err(|e| anyhow!("payload error: {}", e))?;
+        // chunked bodies are only known to be too large once read
+        received += chunk.len();
+        if received > max_size {
+            return Err(ErrorPayloadTooLarge("packet too large").into());
+        }
+        data.bandwidth.throttle(chunk.len() as u64).await;
+        data.metrics.received(chunk.len());
+        chunks.push(chunk);
+    }
+    let bytes = match chunks.len() {
+        0 => Bytes::new(),
+        1 => chunks.remove(0),
+        _ => {
+            let mut bytes = BytesMut::with_capacity(received);
+            for chunk in chunks {
+                bytes.extend_from_slice(&chunk);
+            }
+            bytes.freeze()
+        }
+    };
+
+    // a sender that died mid-request must not leave a partial packet behind
+    if len.is_some() && len != Some(bytes.len()) {
+        return Err(ErrorBadRequest("incomplete packet").into());
     }
 
-    sender
+    // a corrupted packet fails the transfer on both ends
+    if let Some(expected) = req_headers.get(headers::CHECKSUM) {
+        if !checksum::verify(expected.to_str()?, &bytes).map_err(ErrorBadRequest)? {
+            warn!("packet {} of channel {} is corrupted", data_index, path);
+            queue.abort(format!(
+                "packet {} was corrupted on its way to the server",
+                data_index
+            ));
+            check_aborted(&queue)?;
+        }
+    }
+
+    // packets too large for a proxy in front of the relay arrive in parts
+    let bytes = match req_headers.get(headers::PART) {
+        Some(part) => match parts
+            .add(data_index, part.to_str()?, bytes, max_size)
+            .await?
+        {
+            Some(bytes) => bytes,
+            None => {
+                let mut resp = HttpResponse::Ok();
+                resp.header(headers::COMMITTED, queue.first_index())
+                    .header(headers::CREDIT, queue.credit());
+                hint_rate(&mut resp, &queue);
+                return Ok(resp.finish());
+            }
+        },
+        None => bytes,
+    };
+
+    let is_eof = bytes.is_empty();
+    if sender
         .send(Packet {
             index: data_index,
-            data: bytes.freeze(),
+            data: bytes,
         })
         .await
-        .map_err(|_| anyhow!("failed to send packet to the channel"))?;
+        .is_err()
+    {
+        // the channel may have been closed while the packet waited for room
+        check_aborted(&queue)?;
+        return Err(anyhow!("failed to send packet to the channel").into());
+    }
+
+    // plugins may still reject the transfer at its end, which the sender
+    // must not miss
+    if is_eof && filtered {
+        while queue.aborted().is_none() && queue.next_index() <= data_index {
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        check_aborted(&queue)?;
+    }
 
-    debug!("PUT {:?} ended", path);
+    trace!("PUT {:?} ended", path);
 
-    Ok(HttpResponse::Ok().finish())
+    let mut resp = HttpResponse::Ok();
+    resp.header(headers::COMMITTED, queue.first_index())
+        .header(headers::CREDIT, queue.credit());
+    hint_rate(&mut resp, &queue);
+    Ok(resp.finish())
+}
+
+/// Passes the rate receivers declared on to the sender, which keeps to it
+/// instead of filling the queue only to wait for them.
+fn hint_rate(resp: &mut HttpResponseBuilder, queue: &Queue) {
+    if let Some(rate) = queue.max_rate() {
+        resp.header(headers::MAX_RATE, rate);
+    }
 }
 
-#[get("/{id}")]
+#[get("/{id:.+}")]
 async fn send(
     data: web::Data<AppState>,
     path: web::Path<String>,
     req: HttpRequest,
 ) -> ControllerResult<HttpResponse> {
-    let path = path.into_inner();
+    let path = data.channel_key(&req, path.into_inner())?;
+    // the status is public, as for any channel on the relay
+    if req.headers().get(headers::STATUS).is_none() {
+        data.check_access(&req, false)?;
+
+        let holder = match req.headers().get(headers::PAUSE) {
+            Some(_) => Holder::Either,
+            None => Holder::Receiver,
+        };
+        data.check_claim(&path, req.headers(), holder)?;
+    }
+
+    // receivers of a broadcast channel are told apart by their transfer
+    let receiver = req
+        .headers()
+        .get(headers::TRANSFER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
 
     if let Some(_) = req.headers().get(headers::RESET) {
-        data.endpoints.lock().unwrap().remove(&path);
-        debug!("FIN {:?}", path);
+        let mut endpoints = data.endpoints.lock().unwrap();
+        if let Some(conn) = endpoints.get(&path) {
+            if let Some(resp) = stale_session(&conn.queue, req.headers()) {
+                return Ok(resp);
+            }
+        }
+        // the channel stays until the last receiver of a broadcast is done
+        let finished = match endpoints.get(&path) {
+            Some(conn) => conn.queue.finish(receiver),
+            None => false,
+        };
+        if finished {
+            if let Some(conn) = endpoints.remove(&path) {
+                conn.queue.remove_stored();
+                data.retire(path.clone(), &conn);
+            }
+        }
+        log_request(Level::Debug, "FIN", &path, req.headers());
         return Ok(HttpResponse::Ok().finish());
     }
 
-    debug!("GET {:?}", path);
+    if req.headers().get(headers::STATUS).is_some() {
+        let mut found = data.status(&path);
+        // a channel in the object store is there for its receivers
+        if found.0 == Status::Unknown && data.restore(&path).await?.is_some() {
+            found = data.status(&path);
+        }
+        let (status, reason, stats) = found;
 
-    let queue = if let Some(conn) = data.endpoints.lock().unwrap().get(&path) {
-        conn.queue.clone()
-    } else {
-        return Err(ErrorPreconditionFailed("queue not available").into());
+        let mut resp = HttpResponse::Ok();
+        resp.header(headers::STATUS, status.to_string());
+        resp.header(headers::LIMITS, data.limits.to_header());
+        // receivers use as many workers as the sender unless told otherwise
+        if let Some(conn) = data.endpoints.lock().unwrap().get(&path) {
+            resp.header(headers::WORKERS, conn.senders.len());
+        }
+        if let Some(stats) = stats {
+            resp.header(headers::STATS, stats.to_header());
+        }
+        return Ok(resp.body(reason.unwrap_or_default()));
+    }
+
+    if let Some(pause) = req.headers().get(headers::PAUSE) {
+        let paused = pause.to_str()? != "0";
+        let queue = if let Some(conn) = data.endpoints.lock().unwrap().get(&path) {
+            conn.queue.clone()
+        } else {
+            return Err(data.precondition_failed(&path, "queue not available"));
+        };
+
+        queue.set_paused(paused);
+        debug!("{} {:?}", if paused { "PAUSE" } else { "RESUME" }, path);
+        return Ok(HttpResponse::Ok().finish());
+    }
+
+    get_packet(&data, path, req.headers()).await
+}
+
+/// Hands a packet out to a worker of a receiver.
+async fn get_packet(
+    data: &AppState,
+    path: ChannelKey,
+    req_headers: &HeaderMap,
+) -> ControllerResult<HttpResponse> {
+    // receivers of a broadcast channel are told apart by their transfer
+    let receiver = req_headers
+        .get(headers::TRANSFER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    log_request(Level::Trace, "GET", &path, req_headers);
+
+    let found = data.endpoints.lock().unwrap().get(&path).map(|conn| {
+        (
+            conn.queue.clone(),
+            conn.stream,
+            conn.stats.clone(),
+            conn.encoding.clone(),
+        )
+    });
+    let (queue, stream, stats, encoding) = match found {
+        Some(found) => found,
+        None => match data.restore(&path).await? {
+            // its secret was not known until now
+            Some(found) => {
+                data.check_claim(&path, req_headers, Holder::Receiver)?;
+                found
+            }
+            None => return Err(data.precondition_failed(&path, "queue not available")),
+        },
     };
+    check_aborted(&queue)?;
+    if let Some(resp) = stale_session(&queue, req_headers) {
+        return Ok(resp);
+    }
+    queue.touch();
+
+    // a receiver busy writing out what it has taken tells the sender it is
+    // still there
+    if req_headers.get(headers::HEARTBEAT).is_some() {
+        queue.heard_from_receiver(heartbeat(req_headers)?);
+        return Ok(HttpResponse::Ok().finish());
+    }
+    queue.heard_from_receiver(None);
 
-    if let Some(ack_num) = req.headers().get(headers::ACK) {
+    // a finished channel can be downloaded in several parallel segments
+    if req_headers.get(headers::COMPLETE).is_some() {
+        return match queue.completed() {
+            Some((first, end)) => {
+                let mut resp = HttpResponse::Ok();
+                resp.header(headers::OLDEST, first)
+                    .header(headers::COMPLETE, end);
+                if let Some(trailer) = queue.trailer() {
+                    resp.header(headers::TRAILER, trailer);
+                }
+                Ok(resp.finish())
+            }
+            None => Err(ErrorConflict("channel is not complete").into()),
+        };
+    }
+
+    // a finished channel can be verified without downloading it
+    if let Some(algorithm) = req_headers.get(headers::DIGEST) {
+        if !algorithm.to_str()?.eq_ignore_ascii_case("sha256") {
+            return Err(ErrorBadRequest("only sha256 digests are supported").into());
+        }
+        if encoding.is_opaque() {
+            return Err(ErrorConflict("channel is encoded, verify it on the client").into());
+        }
+        let (first, end) = match queue.completed() {
+            Some(range) => range,
+            None => return Err(ErrorConflict("channel is not complete").into()),
+        };
+
+        let mut digest = Sha256::new();
+        for index in first..end {
+            match queue.get(index).await {
+                Some(packet) => digest.update(&packet.data),
+                None => return Err(ErrorGone("data not avaiable").into()),
+            }
+        }
+        return Ok(HttpResponse::Ok()
+            .header(headers::OLDEST, first)
+            .header(headers::DIGEST, hex::encode(digest.finalize()))
+            .finish());
+    }
+
+    if let Some(range) = req_headers.get(headers::RANGE) {
+        let mut range = range.to_str()?.splitn(2, '-');
+        let (start, end) = match (range.next(), range.next()) {
+            (Some(start), Some(end)) => (start.parse()?, end.parse()?),
+            _ => return Err(ErrorBadRequest("invalid range").into()),
+        };
+
+        let packets = match queue.range(start, end).await {
+            Some(packets) => packets,
+            None => {
+                stats.gone();
+                data.metrics.gone();
+                return Err(ErrorGone("data not avaiable").into());
+            }
+        };
+
+        let mut bytes = BytesMut::new();
+        for packet in packets {
+            if let Some(wait) = queue.fetched(packet.index) {
+                stats.queued(wait);
+            }
+            bytes.extend_from_slice(&packet.data);
+        }
+
+        data.metrics.sent(bytes.len());
+        queue.sent(bytes.len());
+        let mut resp = HttpResponse::Ok();
+        encoding.describe(&mut resp, &bytes);
+        return Ok(resp.body(bytes.freeze()));
+    }
+
+    if !queue.admit(receiver) {
+        return Err(ErrorConflict("channel has all its receivers").into());
+    }
+
+    if let Some(ack_num) = req_headers.get(headers::ACK) {
         let ack_num = ack_num.to_str()?.parse()?;
         queue.remove(ack_num);
     }
 
-    let data_index = parse_from_header(&req, headers::INDEX)?;
+    if let Some(committed) = req_headers.get(headers::COMMITTED) {
+        queue.commit(receiver, committed.to_str()?.parse()?);
+    }
+
+    if let Some(rate) = req_headers.get(headers::MAX_RATE) {
+        queue.hint_rate(receiver, rate.to_str()?.parse().map_err(ErrorBadRequest)?);
+    }
+
+    let data_index = parse_from_header(req_headers, headers::INDEX)?;
+
+    trace!("GET {:?} ended", path);
+
+    let packet = match queue.get_from_live_sender(data_index).await {
+        Ok(packet) => packet,
+        // the receiver would otherwise wait for a sender that is gone
+        Err(silence) => {
+            return Ok(HttpResponse::GatewayTimeout()
+                .header(headers::SENDER_SILENCE, silence.as_secs())
+                .body("the sender has gone silent"))
+        }
+    };
+    check_aborted(&queue)?;
 
-    debug!("GET {:?} ended", path);
+    if packet.is_none() {
+        stats.gone();
+        data.metrics.gone();
+    }
+    if let Some(wait) = queue.fetched(data_index) {
+        stats.queued(wait);
+    }
 
-    let data = if let Some(pkt) = queue.get(data_index).await {
+    let metrics = data.metrics.clone();
+    let data = if let Some(pkt) = packet {
         pkt.data.clone()
+    } else if stream {
+        // let the receiver skip over packets dropped from the stream, or
+        // stop if it is past its end
+        let mut resp = HttpResponse::Gone();
+        resp.header(headers::OLDEST, queue.first_index());
+        if let Some(end) = queue.end_index() {
+            resp.header(headers::COMPLETE, end);
+        }
+        return Ok(resp.body("data not avaiable"));
     } else {
         return Err(ErrorGone("data not avaiable").into());
     };
 
-    Ok(HttpResponse::Ok().body(data))
+    let mut resp = HttpResponse::Ok();
+    resp.header(headers::CREDIT, queue.credit())
+        .header(headers::SESSION, queue.session());
+    if let (true, Some(trailer)) = (data.is_empty(), queue.trailer()) {
+        resp.header(headers::TRAILER, trailer);
+    }
+
+    let batch: usize = match req_headers.get(headers::PACKETS) {
+        Some(batch) => batch.to_str()?.parse().map_err(ErrorBadRequest)?,
+        None => 1,
+    };
+    metrics.sent(data.len());
+    queue.sent(data.len());
+    if batch <= 1 {
+        encoding.describe(&mut resp, &data);
+        return Ok(resp.body(data));
+    }
+
+    // the packets the receiver would ask for next follow in the same
+    // response as long as they come in time
+    let stride: usize = match req_headers.get(headers::STRIDE) {
+        Some(stride) => stride.to_str()?.parse().map_err(ErrorBadRequest)?,
+        None => 1,
+    };
+    let stride = stride.max(1);
+    let checksum = encoding.checksum;
+    let first = frame_packet(data_index, &checksum.checksum(&data), &data);
+    let sent = if data.is_empty() { batch } else { 1 };
+
+    let rest = stream::unfold((queue, data_index, sent), move |(queue, index, sent)| {
+        let (stats, metrics) = (stats.clone(), metrics.clone());
+        async move {
+            let index = index + stride;
+            // packets past the end of the queue only come once the receiver
+            // has committed the ones before, which it cannot do while waiting
+            if sent >= batch || index >= queue.first_index() + queue.capacity() {
+                return None;
+            }
+
+            let packet = tokio::time::timeout(BATCH_WAIT, queue.get(index))
+                .await
+                .ok()??;
+            // the end of the stream comes in a response of its own, whose
+            // headers carry the trailer
+            if packet.data.is_empty() && queue.trailer().is_some() {
+                return None;
+            }
+            if let Some(wait) = queue.fetched(index) {
+                stats.queued(wait);
+            }
+            metrics.sent(packet.data.len());
+            queue.sent(packet.data.len());
+            let frame = frame_packet(index, &checksum.checksum(&packet.data), &packet.data);
+            let sent = if packet.data.is_empty() {
+                batch
+            } else {
+                sent + 1
+            };
+
+            Some((Ok(Bytes::from(frame)), (queue, index, sent)))
+        }
+    });
+
+    encoding.describe_stream(&mut resp);
+    Ok(resp.header(headers::PACKETS, batch).streaming(
+        stream::once(future::ready(Ok::<_, actix_web::Error>(Bytes::from(first))))
+            .chain(Box::pin(rest)),
+    ))
+}
+
+#[delete("/{id:.+}")]
+async fn abort(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    req: HttpRequest,
+) -> ControllerResult<HttpResponse> {
+    let path = data.channel_key(&req, path.into_inner())?;
+    data.check_full_access(&req)?;
+    data.check_claim(&path, req.headers(), Holder::Either)?;
+
+    let reason = match req.headers().get(headers::ABORT_REASON) {
+        Some(reason) => reason.to_str()?,
+        None => "channel aborted by an operator",
+    };
+    if !close(&data, path, reason) {
+        return Err(ErrorNotFound("channel not found").into());
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Deserialize)]
+struct ReserveQuery {
+    /// In seconds
+    timeout: Option<u64>,
+}
+
+/// Sets a channel aside for the holders of the claims returned, one for
+/// each end, until neither has used it for the timeout.
+#[post("/{id:.+}")]
+async fn reserve(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    req: HttpRequest,
+    query: web::Query<ReserveQuery>,
+) -> ControllerResult<HttpResponse> {
+    let path = data.channel_key(&req, path.into_inner())?;
+    data.check_full_access(&req)?;
+    if data.is_draining() {
+        return Err(ShuttingDown.into());
+    }
+    data.names.check(&path.id).map_err(ErrorForbidden)?;
+
+    let timeout = query
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(RESERVATION_TIMEOUT);
+
+    let endpoints = data.endpoints.lock().unwrap();
+    let open = |key: &ChannelKey| endpoints.contains_key(key);
+    match data.reservations.reserve(path, timeout, open) {
+        Some(claims) => Ok(HttpResponse::Ok().json(claims)),
+        None => Err(ErrorConflict("channel is already in use or reserved").into()),
+    }
+}
+
+/// Fails the transfer over a channel on both ends, and removes the channel
+/// once they have had a chance to find out. Returns whether it existed.
+fn close(data: &web::Data<AppState>, path: ChannelKey, reason: &str) -> bool {
+    let queue = match data.endpoints.lock().unwrap().get(&path) {
+        Some(conn) => conn.queue.clone(),
+        None => return false,
+    };
+
+    warn!("channel {} aborted", path);
+    queue.abort(reason.to_owned());
+
+    let data = data.clone();
+    tokio::spawn(async move {
+        tokio::time::delay_for(ABORT_GRACE).await;
+
+        let mut endpoints = data.endpoints.lock().unwrap();
+        if let Some(conn) = endpoints.get(&path) {
+            if Arc::ptr_eq(&conn.queue, &queue) {
+                if let Some(conn) = endpoints.remove(&path) {
+                    data.retire(path, &conn);
+                }
+            }
+        }
+    });
+
+    true
 }
 
-pub async fn main(addr: String) -> anyhow::Result<()> {
+#[cfg(unix)]
+async fn terminated() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::terminate())?.recv().await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn terminated() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// Once told to terminate, stops taking new channels and waits up to
+/// `timeout` for those open to finish, then aborts any left and stops the
+/// server.
+async fn shut_down(
+    data: web::Data<AppState>,
+    server: Server,
+    timeout: Duration,
+) -> std::io::Result<()> {
+    terminated().await?;
+
+    data.draining.store(true, Ordering::SeqCst);
+    let deadline = Instant::now() + timeout;
+    info!(
+        "shutting down once {} channels have drained",
+        data.endpoints.lock().unwrap().len()
+    );
+
+    while !data.endpoints.lock().unwrap().is_empty() {
+        if Instant::now() >= deadline {
+            let endpoints = data.endpoints.lock().unwrap();
+            warn!("shutting down with {} channels still open", endpoints.len());
+            for conn in endpoints.values() {
+                conn.queue
+                    .abort("server shut down before the transfer completed".into());
+            }
+            break;
+        }
+
+        tokio::time::delay_for(DRAIN_INTERVAL).await;
+    }
+
+    // requests woken by the aborts are answered before stopping
+    server.stop(true).await;
+    Ok(())
+}
+
+pub async fn main(addr: Option<String>, secret: Option<String>, opts: Opts) -> anyhow::Result<()> {
+    let listener = Listener::new(addr)?;
+
+    if let Some(bits) = opts.proof_of_work.filter(|&bits| bits > pow::MAX_BITS) {
+        bail!(
+            "cannot require {} bits of proof of work, at most {}",
+            bits,
+            pow::MAX_BITS
+        );
+    }
+
+    let tls = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::config(cert, key)?),
+        _ => None,
+    };
+
+    let cluster = match (&opts.cluster, &opts.cluster_secret) {
+        (Some(url), Some(secret)) => Some(Cluster::new(url, &opts.cluster_peers, secret)),
+        _ => None,
+    };
+
+    let spool = match opts.spool_dir {
+        Some(dir) => Some(Arc::new(Spool::new(dir, opts.spool_dir_limit.0)?)),
+        None => None,
+    };
+
+    let store = match &opts.object_store {
+        Some(url) => Some(Arc::new(ObjectStore::new(url, &opts.object_store_region)?)),
+        None => None,
+    };
+
     let local = tokio::task::LocalSet::new();
     let sys = actix_rt::System::run_in_tokio("server", &local);
 
+    let limits = Limits {
+        max_workers: Some(MAX_WORKERS as u64),
+        max_packet_size: opts.max_packet_size.map(|size| size.0),
+        queue_depth: Some(MAX_QUEUE_CAPACITY as u64),
+        channel_bytes: Some(QUEUE_BYTES as u64),
+        max_retention: Some(opts.max_retention as u64),
+        memory_limit: opts.memory_limit.map(|size| size.0),
+    };
+
     let app_state = web::Data::new(AppState {
         endpoints: Mutex::new(HashMap::new()),
+        retired: Mutex::new(HashMap::new()),
+        pending: Mutex::new(HashMap::new()),
+        reservations: Reservations::default(),
+        vhosts: VirtualHosts::new(opts.vhosts),
+        trust_proxy: opts.trust_proxy,
+        max_retention: opts.max_retention,
+        max_packet_size: opts.max_packet_size.map(|size| size.0 as usize),
+        budget: Arc::new(MemoryBudget::new(opts.memory_limit.map(|size| size.0))),
+        spool,
+        store,
+        bandwidth: Bandwidth::new(opts.bandwidth),
+        plugins: opts.plugins,
+        channel_rate: opts.channel_rate.map(ChannelRate::new),
+        challenges: opts.proof_of_work.map(Challenges::new),
+        limits,
+        metrics: Arc::default(),
+        draining: AtomicBool::new(false),
+        names: NamePolicy::new(
+            opts.channel_patterns,
+            opts.min_channel_length,
+            opts.min_channel_entropy,
+        ),
+        require_channel_secret: opts.require_channel_secret,
+        mirror: match (&opts.mirror, &opts.mirror_secret) {
+            (Some(url), Some(mirror_secret)) => Some(Mirror::new(
+                url,
+                secret.as_deref().map(Signer::new),
+                mirror_secret,
+            )),
+            _ => None,
+        },
+        primary: opts.mirror_secret.as_deref().map(Primary::new),
+        cluster: cluster.clone(),
     });
 
-    HttpServer::new(move || {
+    if let Some(cluster) = cluster.clone() {
+        tokio::spawn(cluster.watch());
+    }
+
+    if opts.idle_timeout > 0 {
+        let timeout = Duration::from_secs(opts.idle_timeout);
+        let data = app_state.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(timeout.min(REAP_INTERVAL)).await;
+                data.reap(timeout);
+            }
+        });
+    }
+
+    let drained = app_state.clone();
+    let drain_timeout = Duration::from_secs(opts.drain_timeout);
+    let nonces = Arc::new(NonceCache::new(opts.signature_window));
+    let token = opts.require_token;
+    let expose_metrics = opts.metrics;
+    let admin_token = opts.admin_token;
+    let throttle = Throttle::new(
+        Limit {
+            requests: opts.ip_requests,
+            bandwidth: opts.ip_bandwidth.map(|size| size.0),
+        },
+        Limit {
+            requests: opts.channel_requests,
+            bandwidth: opts.channel_bandwidth.map(|size| size.0),
+        },
+    );
+
+    let server = HttpServer::new(move || {
         App::new()
+            .wrap_fn({
+                let metrics = app_state.metrics.clone();
+                move |req, srv| {
+                    let (method, started) = (req.method().to_string(), Instant::now());
+                    let metrics = metrics.clone();
+                    srv.call(req).map(move |resp| {
+                        metrics.observe(&method, started.elapsed());
+                        resp
+                    })
+                }
+            })
             .app_data(app_state.clone())
-            .service(recv)
-            .service(send)
-    })
-    .bind(&addr)?
-    .run()
-    .await?;
+            // ahead of the channels, which would take its paths
+            .configure(|cfg| {
+                if let Some(token) = &admin_token {
+                    cfg.service(
+                        web::scope("/admin")
+                            .wrap(RequireToken::new(token))
+                            .service(admin::channels)
+                            .service(admin::close_channel),
+                    )
+                    .service(
+                        web::resource("/{id:.+}/events")
+                            .guard(guard::fn_guard(admin::accepts_events))
+                            .wrap(RequireToken::new(token))
+                            .route(web::get().to(admin::events)),
+                    );
+                }
+            })
+            .service(
+                web::scope("")
+                    .wrap(Condition::new(
+                        secret.is_some(),
+                        VerifySignature::new(
+                            Signer::new(secret.as_deref().unwrap_or_default()),
+                            nonces.clone(),
+                        ),
+                    ))
+                    .wrap(Condition::new(
+                        token.is_some(),
+                        RequireToken::new(token.as_deref().unwrap_or_default()),
+                    ))
+                    // ahead of any other check, so that a client cannot
+                    // flood the relay with requests it turns down
+                    .wrap(Condition::new(throttle.is_enabled(), throttle.clone()))
+                    .configure(|cfg| {
+                        if expose_metrics {
+                            cfg.service(metrics::metrics);
+                        }
+                    })
+                    .service(
+                        web::scope("")
+                            .wrap(Forward::new(cluster.clone()))
+                            .service(recv)
+                            .service(tunnel::upgrade)
+                            .service(send)
+                            .service(abort)
+                            .service(reserve),
+                    ),
+            )
+    });
+
+    // SIGTERM is handled by draining the channels first
+    let server = match (listener, tls) {
+        (Listener::Addr(addr), Some(config)) => server.bind_rustls(addr, config)?,
+        (Listener::Addr(addr), None) => server.bind(addr)?,
+        (Listener::Tcp(listener), Some(config)) => server.listen_rustls(listener, config)?,
+        (Listener::Tcp(listener), None) => server.listen(listener)?,
+        #[cfg(unix)]
+        (Listener::Unix(listener), None) => server.listen_uds(listener)?,
+        #[cfg(unix)]
+        (Listener::Unix(_), Some(_)) => bail!("cannot serve TLS over a Unix socket"),
+    }
+    .disable_signals()
+    .system_exit();
 
-    sys.await?;
+    // the server takes commands, stopping it among them, on `local` once
+    // the system is running
+    local
+        .run_until(async move {
+            let server = server.run();
+            let handle = server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = shut_down(drained, handle, drain_timeout).await {
+                    warn!("cannot shut down gracefully: {}", e);
+                }
+            });
+            future::try_join(server, sys).await
+        })
+        .await?;
 
     Ok(())
 }
@@ -0,0 +1,114 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use actix_web::error::{ErrorBadRequest, ErrorPayloadTooLarge, ErrorServiceUnavailable};
+use bytes::{Bytes, BytesMut};
+
+use super::{queue::Queue, ControllerResult};
+
+/// Parts a packet may be split into for proxies limiting request sizes
+const MAX_PARTS: usize = 1024;
+
+/// Parts received so far of packets sent in several requests, by index.
+/// They wait outside the queue for the rest of their packet, and are taken
+/// from the memory budget all the same.
+pub struct Parts {
+    queue: Arc<Queue>,
+    packets: Mutex<HashMap<usize, Vec<Option<Bytes>>>>,
+    /// Packets that may be partly received at once, each worker sending
+    /// one packet at a time
+    max_packets: usize,
+}
+
+impl Parts {
+    pub fn new(queue: Arc<Queue>, workers: usize) -> Self {
+        Parts {
+            queue,
+            packets: Mutex::new(HashMap::new()),
+            max_packets: workers * 2,
+        }
+    }
+
+    /// Adds part `K/N` of a packet, returning the whole packet once all
+    /// parts have arrived.
+    pub async fn add(
+        &self,
+        index: usize,
+        part: &str,
+        data: Bytes,
+        max_size: usize,
+    ) -> ControllerResult<Option<Bytes>> {
+        let mut kn = part.splitn(2, '/');
+        let (k, n): (usize, usize) = match (kn.next(), kn.next()) {
+            (Some(k), Some(n)) => (k.parse()?, n.parse()?),
+            _ => return Err(ErrorBadRequest("invalid part").into()),
+        };
+        if k >= n || n > MAX_PARTS || data.is_empty() {
+            return Err(ErrorBadRequest("invalid part").into());
+        }
+
+        self.queue.reserve_memory(data.len()).await;
+        let mut packets = self.packets.lock().unwrap();
+
+        // parts of packets the queue already has are of no use any more
+        let next = self.queue.next_index();
+        let stale: Vec<usize> = packets.keys().filter(|&&i| i < next).copied().collect();
+        for i in stale {
+            self.release(packets.remove(&i));
+        }
+
+        if !packets.contains_key(&index) && packets.len() >= self.max_packets {
+            self.queue.release_memory(data.len());
+            return Err(ErrorServiceUnavailable("too many packets partly received").into());
+        }
+
+        let received = packets.entry(index).or_default();
+        // the sender has split the packet differently
+        if received.len() != n {
+            let old = std::mem::replace(received, vec![None; n]);
+            self.release(Some(old));
+        }
+        if let Some(old) = received[k].replace(data) {
+            self.queue.release_memory(old.len());
+        }
+
+        // a packet put back together may be no larger than one sent whole
+        let size = size_of(received);
+        if size > max_size {
+            self.release(packets.remove(&index));
+            return Err(ErrorPayloadTooLarge("packet too large").into());
+        }
+
+        if received.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let mut packet = BytesMut::with_capacity(size);
+        for part in packets.remove(&index).into_iter().flatten().flatten() {
+            packet.extend_from_slice(&part);
+        }
+        // the queue takes the whole packet from the budget again
+        self.queue.release_memory(size);
+        Ok(Some(packet.freeze()))
+    }
+
+    fn release(&self, parts: Option<Vec<Option<Bytes>>>) {
+        if let Some(parts) = parts {
+            self.queue.release_memory(size_of(&parts));
+        }
+    }
+}
+
+impl Drop for Parts {
+    fn drop(&mut self) {
+        for (_, parts) in self.packets.get_mut().unwrap().drain() {
+            self.queue.release_memory(size_of(&parts));
+        }
+    }
+}
+
+fn size_of(parts: &[Option<Bytes>]) -> usize {
+    parts.iter().flatten().map(Bytes::len).sum()
+}
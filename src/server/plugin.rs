@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use anyhow::bail;
+use bytes::Bytes;
+use globset::{Glob, GlobMatcher};
+use once_cell::sync::Lazy;
+
+use crate::common::Size;
+
+/// Server-side hook inspecting or transforming the packets of a channel.
+pub trait Plugin: Send + Sync {
+    /// Creates the state kept for a new channel.
+    fn filter(&self) -> Box<dyn Filter>;
+}
+
+/// A plugin's state for one channel. Packets are passed in order, and an
+/// error aborts the transfer.
+pub trait Filter: Send {
+    /// Returns the data to be passed on in place of `data`, which must not
+    /// be empty as that marks the end of the transfer.
+    fn packet(&mut self, data: Bytes) -> anyhow::Result<Bytes>;
+
+    /// Called once the sender has finished.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Rejects transfers larger than the given size.
+struct MaxSize(Size);
+
+struct MaxSizeFilter {
+    limit: Size,
+    total: u64,
+}
+
+impl Plugin for MaxSize {
+    fn filter(&self) -> Box<dyn Filter> {
+        Box::new(MaxSizeFilter {
+            limit: self.0,
+            total: 0,
+        })
+    }
+}
+
+impl Filter for MaxSizeFilter {
+    fn packet(&mut self, data: Bytes) -> anyhow::Result<Bytes> {
+        self.total += data.len() as u64;
+        if self.total > self.limit.0 {
+            bail!("transfer exceeds the maximum size of {}", self.limit);
+        }
+
+        Ok(data)
+    }
+}
+
+/// Rejects transfers containing the given byte sequence.
+struct Deny(Vec<u8>);
+
+struct DenyFilter {
+    pattern: Vec<u8>,
+    /// End of the previous packet, for matches spanning packets
+    tail: Vec<u8>,
+}
+
+impl Plugin for Deny {
+    fn filter(&self) -> Box<dyn Filter> {
+        Box::new(DenyFilter {
+            pattern: self.0.clone(),
+            tail: Vec::new(),
+        })
+    }
+}
+
+impl Filter for DenyFilter {
+    fn packet(&mut self, data: Bytes) -> anyhow::Result<Bytes> {
+        let mut window = std::mem::take(&mut self.tail);
+        window.extend_from_slice(&data);
+
+        if window
+            .windows(self.pattern.len())
+            .any(|w| w == &self.pattern[..])
+        {
+            bail!("transfer rejected by content policy");
+        }
+
+        let keep = (self.pattern.len() - 1).min(window.len());
+        self.tail = window.split_off(window.len() - keep);
+
+        Ok(data)
+    }
+}
+
+/// Creates a plugin from the argument it is enabled with, e.g. the size in
+/// `max-size=10G`, or an empty one if there is none.
+pub type Constructor = Box<dyn Fn(&str) -> anyhow::Result<Arc<dyn Plugin>> + Send + Sync>;
+
+/// The plugins `--plugin` enables by name, built in or registered.
+static PLUGINS: Lazy<RwLock<HashMap<String, Constructor>>> = Lazy::new(|| {
+    let mut plugins: HashMap<String, Constructor> = HashMap::new();
+    plugins.insert(
+        "max-size".into(),
+        Box::new(|size| Ok(Arc::new(MaxSize(size.parse()?)))),
+    );
+    plugins.insert(
+        "deny".into(),
+        Box::new(|pattern| {
+            if pattern.is_empty() {
+                bail!("deny needs a pattern");
+            }
+            Ok(Arc::new(Deny(pattern.as_bytes().to_vec())))
+        }),
+    );
+    RwLock::new(plugins)
+});
+
+/// Makes a plugin available to `--plugin` by its name, in place of any
+/// plugin of the same name. Registered before the options are parsed.
+pub fn register(name: &str, constructor: Constructor) {
+    PLUGINS.write().unwrap().insert(name.into(), constructor);
+}
+
+/// A plugin enabled with `NAME=ARG[,channels=GLOB]`, for all channels or
+/// only those matching the pattern.
+#[derive(Clone)]
+pub struct PluginSpec {
+    plugin: Arc<dyn Plugin>,
+    channels: Option<GlobMatcher>,
+}
+
+impl FromStr for PluginSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parts = s.split(',');
+        let mut kv = parts.next().unwrap_or_default().splitn(2, '=');
+
+        let (name, arg) = (kv.next().unwrap_or_default(), kv.next().unwrap_or_default());
+        let plugin = match PLUGINS.read().unwrap().get(name) {
+            Some(constructor) => constructor(arg)?,
+            None => bail!("unknown plugin: {}", s),
+        };
+
+        let mut spec = PluginSpec {
+            plugin,
+            channels: None,
+        };
+
+        for option in parts {
+            let mut kv = option.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("channels"), Some(glob)) => {
+                    spec.channels = Some(Glob::new(glob)?.compile_matcher())
+                }
+                _ => bail!("unknown plugin option: {}", option),
+            }
+        }
+
+        Ok(spec)
+    }
+}
+
+/// Creates the filters of all plugins enabled for a channel.
+pub(crate) fn filters(specs: &[PluginSpec], channel: &str) -> Vec<Box<dyn Filter>> {
+    specs
+        .iter()
+        .filter(|spec| match &spec.channels {
+            Some(glob) => glob.is_match(channel),
+            None => true,
+        })
+        .map(|spec| spec.plugin.filter())
+        .collect()
+}
+
+/// Passes a packet through all filters, or tells them about the end of
+/// the transfer on an empty one.
+pub(crate) fn apply(filters: &mut [Box<dyn Filter>], mut data: Bytes) -> anyhow::Result<Bytes> {
+    if data.is_empty() {
+        for filter in filters {
+            filter.finish()?;
+        }
+        return Ok(data);
+    }
+
+    for filter in filters {
+        data = filter.packet(data)?;
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,76 @@
+use std::str::FromStr;
+
+use regex::Regex;
+
+/// A pattern a channel name must match as a whole.
+#[derive(Clone, Debug)]
+pub struct NamePattern(Regex);
+
+impl FromStr for NamePattern {
+    type Err = regex::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(NamePattern(Regex::new(&format!("^(?:{})$", s))?))
+    }
+}
+
+/// Which names channels may be created or reserved under, so that strangers
+/// cannot guess a name like `test` and take over someone else's transfer.
+#[derive(Default)]
+pub struct NamePolicy {
+    patterns: Vec<NamePattern>,
+    min_length: Option<usize>,
+    min_entropy: Option<u32>,
+}
+
+impl NamePolicy {
+    pub fn new(
+        patterns: Vec<NamePattern>,
+        min_length: Option<usize>,
+        min_entropy: Option<u32>,
+    ) -> Self {
+        NamePolicy {
+            patterns,
+            min_length,
+            min_entropy,
+        }
+    }
+
+    pub fn check(&self, name: &str) -> Result<(), &'static str> {
+        if !self.patterns.is_empty() && !self.patterns.iter().any(|p| p.0.is_match(name)) {
+            return Err("channel name is not allowed on this relay");
+        }
+        if let Some(min_length) = self.min_length {
+            if name.chars().count() < min_length {
+                return Err("channel name is too short");
+            }
+        }
+        if let Some(min_entropy) = self.min_entropy {
+            if entropy(name) < f64::from(min_entropy) {
+                return Err("channel name is too easy to guess");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bits of a name, as if each character had been picked at random among
+/// the classes of characters it uses: lowercase letters, uppercase letters,
+/// digits and punctuation.
+fn entropy(name: &str) -> f64 {
+    let uses = |f: fn(&char) -> bool| name.chars().any(|c| f(&c));
+    let mut pool = 0;
+    if uses(char::is_ascii_lowercase) {
+        pool += 26;
+    }
+    if uses(char::is_ascii_uppercase) {
+        pool += 26;
+    }
+    if uses(char::is_ascii_digit) {
+        pool += 10;
+    }
+    if uses(|c| !c.is_ascii_alphanumeric()) {
+        pool += 33;
+    }
+    name.chars().count() as f64 * f64::from(pool.max(1)).log2()
+}
@@ -1,32 +1,444 @@
 use std::{
-    collections::VecDeque,
-    sync::{Arc, Mutex},
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll, Waker},
+    time::{Duration, Instant},
 };
 
+use bytes::Bytes;
 use futures::future;
+use log::warn;
 
-use crate::common::Packet;
+use super::budget::MemoryBudget;
+use super::spool::{Spool, SpooledPacket};
+use super::store::{StoredChannel, StoredPacket};
+use crate::common::{Packet, Priority};
+
+/// How long the rate declared by a receiver holds without it being renewed
+const RATE_HINT_TTL: Duration = Duration::from_secs(30);
+/// Heartbeats a sender may miss before it is taken for dead
+const MISSED_HEARTBEATS: u32 = 3;
+
+/// The receivers of a broadcast channel, each of which must take every
+/// packet before it is dropped.
+struct Broadcast {
+    receivers: usize,
+    /// Index of the next packet each receiver will consume, by transfer ID
+    committed: HashMap<String, usize>,
+}
+
+/// A buffered packet, kept in memory, spilled to disk or persisted to the
+/// object store.
+#[derive(Clone)]
+enum Entry {
+    Memory(Packet),
+    Spooled(usize, Arc<SpooledPacket>),
+    Stored(usize, Arc<StoredPacket>),
+}
+
+impl Entry {
+    fn index(&self) -> usize {
+        match self {
+            Entry::Memory(packet) => packet.index,
+            Entry::Spooled(index, _) | Entry::Stored(index, _) => *index,
+        }
+    }
+
+    /// The end-of-stream packet is always kept in memory.
+    fn is_end(&self) -> bool {
+        match self {
+            Entry::Memory(packet) => packet.data.is_empty(),
+            Entry::Spooled(..) | Entry::Stored(..) => false,
+        }
+    }
+
+    async fn load(self) -> Option<Packet> {
+        match self {
+            Entry::Memory(packet) => Some(packet),
+            Entry::Spooled(index, spooled) => match spooled.read().await {
+                Ok(data) => Some(Packet { index, data }),
+                Err(e) => {
+                    warn!("failed to read spooled packet {}: {}", index, e);
+                    None
+                }
+            },
+            Entry::Stored(index, stored) => match stored.read().await {
+                Ok(data) => Some(Packet { index, data }),
+                Err(e) => {
+                    warn!("failed to read stored packet {}: {}", index, e);
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// When one end of the channel was last heard from, and how often it said
+/// it would be if it did.
+#[derive(Clone, Copy)]
+struct Liveness {
+    seen: Instant,
+    heartbeat: Option<Duration>,
+}
+
+impl Liveness {
+    fn new(heartbeat: Option<Duration>) -> Self {
+        Liveness {
+            seen: Instant::now(),
+            heartbeat,
+        }
+    }
+
+    /// How long it has been silent, once it has missed several heartbeats.
+    fn silence(&self) -> Option<Duration> {
+        let silence = self.seen.elapsed();
+        match self.heartbeat {
+            Some(heartbeat) if silence > MISSED_HEARTBEATS * heartbeat => Some(silence),
+            _ => None,
+        }
+    }
+}
+
+/// Limits on what a stream keeps for receivers lagging behind, beyond the
+/// number of packets.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_bytes: Option<u64>,
+}
 
 pub struct Queue {
-    q: Arc<Mutex<VecDeque<Option<Packet>>>>,
+    q: Arc<Mutex<VecDeque<Option<Entry>>>>,
     capacity: usize,
+    drop_oldest: bool,
+    next: AtomicUsize,
+    paused: AtomicBool,
+    finished: AtomicBool,
+    aborted: Mutex<Option<String>>,
+    budget: Option<(Arc<MemoryBudget>, Priority)>,
+    broadcast: Option<Mutex<Broadcast>>,
+    spool: Option<Arc<Spool>>,
+    store: Option<StoredChannel>,
+    /// Bytes per second each receiver consumes at most, and when it said so
+    rate_hints: Mutex<HashMap<String, (u64, Instant)>>,
+    /// When buffered packets were pushed, until a receiver first fetches them
+    pushed: Mutex<HashMap<usize, Instant>>,
+    /// How long and how much of a stream is kept besides its packet count
+    retention: Retention,
+    /// When the packets of a stream retained by age were pushed, oldest first
+    ages: Mutex<VecDeque<(usize, Instant)>>,
+    /// Bytes pushed by the sender and handed out to receivers
+    received: AtomicU64,
+    sent: AtomicU64,
+    /// When the channel was last requested, or data went in or out
+    active: Mutex<Instant>,
+    sender: Mutex<Liveness>,
+    /// Any of the receivers
+    receiver: Mutex<Liveness>,
+    /// Sent by the sender along with the end of the stream, passed on to
+    /// receivers as is
+    trailer: Mutex<Option<String>>,
+    /// Tells the channel apart from others of the same name before and
+    /// after it, whose senders and receivers may still be retrying
+    session: String,
 
     write_wakers: Arc<Mutex<VecDeque<Waker>>>,
     read_wakers: Arc<Mutex<VecDeque<Waker>>>,
+    credit_wakers: Arc<Mutex<Vec<Waker>>>,
 }
 
 impl Queue {
-    pub fn new(capacity: usize) -> Self {
+    pub fn new(capacity: usize, start: usize) -> Self {
+        Self::with_policy(capacity, start, false)
+    }
+
+    /// A queue that never blocks writers and discards its oldest packets
+    /// instead once `capacity` packets are retained.
+    pub fn dropping_oldest(capacity: usize, start: usize) -> Self {
+        Self::with_policy(capacity.max(1), start, true)
+    }
+
+    fn with_policy(capacity: usize, start: usize, drop_oldest: bool) -> Self {
         Queue {
             capacity,
+            drop_oldest,
+            next: AtomicUsize::new(start),
+            paused: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            aborted: Mutex::new(None),
+            budget: None,
+            broadcast: None,
+            spool: None,
+            store: None,
+            rate_hints: Mutex::new(HashMap::new()),
+            pushed: Mutex::new(HashMap::new()),
+            retention: Retention::default(),
+            ages: Mutex::new(VecDeque::new()),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            active: Mutex::new(Instant::now()),
+            sender: Mutex::new(Liveness::new(None)),
+            receiver: Mutex::new(Liveness::new(None)),
+            trailer: Mutex::new(None),
+            session: hex::encode(rand::random::<[u8; 16]>()),
             q: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
 
             write_wakers: Arc::new(Mutex::new(VecDeque::new())),
             read_wakers: Arc::new(Mutex::new(VecDeque::new())),
+            credit_wakers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Also discards the oldest packets of a stream once older or larger in
+    /// total than `retention` allows.
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Accounts the buffered packets in the server-wide memory budget.
+    pub fn with_budget(mut self, budget: Arc<MemoryBudget>, priority: Priority) -> Self {
+        self.budget = Some((budget, priority));
+        self
+    }
+
+    /// Takes memory held for the channel outside the queue from the
+    /// budget, waiting for room as packets pushed do.
+    pub async fn reserve_memory(&self, size: usize) {
+        if let Some((budget, priority)) = &self.budget {
+            budget.reserve(size as u64, *priority).await;
+        }
+    }
+
+    pub fn release_memory(&self, size: usize) {
+        if let Some((budget, _)) = &self.budget {
+            budget.release(size as u64);
+        }
+    }
+
+    /// Writes packets that do not fit in memory to `spool` instead of
+    /// blocking the sender.
+    pub fn with_spool(mut self, spool: Arc<Spool>) -> Self {
+        self.spool = Some(spool);
+        self
+    }
+
+    /// Persists all packets to the object store, and keeps none in memory.
+    pub fn with_store(mut self, store: StoredChannel) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Deletes what is left of the channel in the object store once all
+    /// receivers are done with it.
+    pub fn remove_stored(&self) {
+        if let Some(store) = &self.store {
+            let mut q = self.q.lock().unwrap();
+            self.release(q.drain(..).flatten());
+            store.remove();
         }
     }
 
+    /// A channel the sender had finished, read back from the object store
+    /// after a restart.
+    pub fn restored(capacity: usize, store: StoredChannel, end: usize) -> Self {
+        let queue = Self::new(capacity, end + 1).with_store(store.clone());
+        {
+            let mut q = queue.q.lock().unwrap();
+            for index in 0..end {
+                q.push_back(Some(Entry::Stored(index, Arc::new(store.packet(index)))));
+            }
+            q.push_back(Some(Entry::Memory(Packet {
+                index: end,
+                data: Bytes::new(),
+            })));
+        }
+        queue.finished.store(true, Ordering::SeqCst);
+        queue
+    }
+
+    /// Continues `session` rather than starting a new one, for a channel
+    /// re-created after a restart, or replicated to a mirror.
+    pub fn with_session(mut self, session: Option<String>) -> Self {
+        if let Some(session) = session {
+            self.session = session;
+        }
+        self
+    }
+
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// Holds packets until all of `receivers` receivers have taken them.
+    pub fn with_receivers(mut self, receivers: usize) -> Self {
+        self.broadcast = Some(Mutex::new(Broadcast {
+            receivers,
+            committed: HashMap::new(),
+        }));
+        self
+    }
+
+    /// The number of receivers of a broadcast channel.
+    pub fn receivers(&self) -> Option<usize> {
+        self.broadcast
+            .as_ref()
+            .map(|broadcast| broadcast.lock().unwrap().receivers)
+    }
+
+    /// Records that `receiver` consumes at most `rate` bytes per second.
+    pub fn hint_rate(&self, receiver: &str, rate: u64) {
+        let mut hints = self.rate_hints.lock().unwrap();
+        hints.retain(|_, (_, at)| at.elapsed() < RATE_HINT_TTL);
+        hints.insert(receiver.to_owned(), (rate, Instant::now()));
+    }
+
+    /// The rate the sender should keep to, so that packets do not pile up
+    /// in front of the slowest receiver.
+    pub fn max_rate(&self) -> Option<u64> {
+        let hints = self.rate_hints.lock().unwrap();
+        hints
+            .values()
+            .filter(|(_, at)| at.elapsed() < RATE_HINT_TTL)
+            .map(|&(rate, _)| rate)
+            .min()
+    }
+
+    /// Whether `receiver` may read from the channel: any receiver may, but a
+    /// broadcast channel only takes as many as it was opened for.
+    pub fn admit(&self, receiver: &str) -> bool {
+        let mut broadcast = match &self.broadcast {
+            Some(broadcast) => broadcast.lock().unwrap(),
+            None => return true,
+        };
+
+        if broadcast.committed.contains_key(receiver) {
+            return true;
+        }
+        if broadcast.committed.len() >= broadcast.receivers {
+            return false;
+        }
+
+        let first = self.first_index();
+        broadcast.committed.insert(receiver.to_owned(), first);
+        true
+    }
+
+    /// Records that `receiver` has consumed all packets before `index`,
+    /// removing those all receivers have.
+    pub fn commit(&self, receiver: &str, index: usize) {
+        let index = match &self.broadcast {
+            Some(broadcast) => {
+                let mut broadcast = broadcast.lock().unwrap();
+                if let Some(committed) = broadcast.committed.get_mut(receiver) {
+                    *committed = index.max(*committed);
+                }
+
+                if broadcast.committed.len() < broadcast.receivers {
+                    return;
+                }
+                match broadcast.committed.values().min() {
+                    Some(&index) => index,
+                    None => return,
+                }
+            }
+            None => index,
+        };
+
+        self.remove_before(index);
+    }
+
+    /// Records that `receiver` has taken the whole stream, returning whether
+    /// all receivers have.
+    pub fn finish(&self, receiver: &str) -> bool {
+        if self.broadcast.is_none() {
+            return true;
+        }
+
+        self.commit(receiver, usize::MAX);
+
+        let broadcast = self.broadcast.as_ref().unwrap().lock().unwrap();
+        broadcast.committed.len() >= broadcast.receivers
+            && broadcast
+                .committed
+                .values()
+                .all(|&index| index == usize::MAX)
+    }
+
+    /// Gives back the memory of removed packets, spooled ones being deleted
+    /// once no longer read and stored ones right away.
+    fn release(&self, entries: impl Iterator<Item = Entry>) {
+        let mut pushed = self.pushed.lock().unwrap();
+        let size = entries
+            .inspect(|entry| {
+                pushed.remove(&entry.index());
+            })
+            .map(|entry| match entry {
+                Entry::Memory(packet) => packet.data.len() as u64,
+                Entry::Spooled(..) => 0,
+                Entry::Stored(_, stored) => {
+                    stored.remove();
+                    0
+                }
+            })
+            .sum();
+        if let Some((budget, _)) = &self.budget {
+            budget.release(size);
+        }
+    }
+
+    /// Discards the oldest packets of a stream past its retention by age or
+    /// size. The newest packet is kept whatever its size.
+    fn expire(&self, q: &mut VecDeque<Option<Entry>>) {
+        let first_index = match q.front() {
+            Some(Some(entry)) => entry.index(),
+            _ => return,
+        };
+
+        let mut n = 0;
+        if let Some(max_age) = self.retention.max_age {
+            let mut ages = self.ages.lock().unwrap();
+            while let Some(&(index, pushed)) = ages.front() {
+                if pushed.elapsed() < max_age {
+                    break;
+                }
+                ages.pop_front();
+                n = n.max((index + 1).saturating_sub(first_index));
+            }
+        }
+        if let Some(max_bytes) = self.retention.max_bytes {
+            let mut size = 0;
+            for (i, entry) in q.iter().enumerate().rev() {
+                if let Some(Entry::Memory(packet)) = entry {
+                    size += packet.data.len() as u64;
+                }
+                if size > max_bytes && i + 1 < q.len() {
+                    n = n.max(i + 1);
+                    break;
+                }
+            }
+        }
+
+        let n = n.min(q.len());
+        if n > 0 {
+            self.release(q.drain(..n).flatten());
+            while let Some(None) = q.front() {
+                q.pop_front();
+            }
+        }
+    }
+
+    /// Slots taken in memory. The gaps acknowledged packets leave take none,
+    /// as a receiver may only commit them along with the packet it waits for.
+    fn in_memory(&self, q: &VecDeque<Option<Entry>>) -> usize {
+        q.iter()
+            .filter(|entry| matches!(entry, Some(Entry::Memory(_))))
+            .count()
+    }
+
     fn register_reader(&self, waker: Waker) {
         self.read_wakers.lock().unwrap().push_back(waker);
     }
@@ -35,40 +447,148 @@ impl Queue {
         self.write_wakers.lock().unwrap().push_back(waker);
     }
 
+    /// Readers wait for packets of their own, and some have given up
+    /// waiting, so all of them are woken to look again.
     fn wakeup_reader(&self) {
-        if let Some(w) = self.read_wakers.lock().unwrap().pop_front() {
+        for w in self.read_wakers.lock().unwrap().drain(..) {
             w.wake();
         }
     }
 
     fn wakeup_writer(&self) {
-        if let Some(w) = self.write_wakers.lock().unwrap().pop_front() {
+        for w in self.write_wakers.lock().unwrap().drain(..) {
             w.wake();
         }
     }
 
-    fn poll_push(&self, cx: &mut Context, e: Packet) -> Poll<()> {
+    fn wakeup_credit(&self) {
+        for w in self.credit_wakers.lock().unwrap().drain(..) {
+            w.wake();
+        }
+    }
+
+    fn free_slots(&self, q: &VecDeque<Option<Entry>>) -> usize {
+        let spooling = match &self.spool {
+            Some(spool) => spool.has_room(),
+            None => self.store.is_some(),
+        };
+        if self.paused.load(Ordering::SeqCst) {
+            0
+        } else if self.drop_oldest || spooling {
+            self.capacity
+        } else {
+            self.capacity.saturating_sub(self.in_memory(q))
+        }
+    }
+
+    fn poll_credit(&self, cx: &mut Context) -> Poll<usize> {
+        let q = self.q.lock().unwrap();
+        if self.aborted.lock().unwrap().is_some() {
+            return Poll::Ready(0);
+        }
+
+        let credit = self.free_slots(&q);
+        if credit > 0 {
+            Poll::Ready(credit)
+        } else {
+            self.credit_wakers.lock().unwrap().push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn poll_push(&self, cx: &mut Context, e: Entry) -> Poll<bool> {
         let mut q = self.q.lock().unwrap();
-        if q.len() < self.capacity {
+
+        if self.aborted.lock().unwrap().is_some() {
+            return Poll::Ready(false);
+        }
+
+        if self.paused.load(Ordering::SeqCst) {
+            self.register_writer(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        if self.drop_oldest {
+            let next = self.next.load(Ordering::SeqCst);
+            if e.index() < next {
+                return Poll::Ready(false);
+            }
+
+            // packets are looked up by their offset from the first one
+            if e.index() > next {
+                self.release(q.drain(..).flatten());
+            }
+
+            if q.len() >= self.capacity {
+                self.release(q.pop_front().flatten().into_iter());
+                while let Some(None) = q.front() {
+                    q.pop_front();
+                }
+            }
+        }
+
+        let spooled = matches!(e, Entry::Spooled(..) | Entry::Stored(..));
+        if spooled || self.in_memory(&q) < self.capacity {
+            if e.is_end() {
+                self.finished.store(true, Ordering::SeqCst);
+            }
+
+            self.next.store(e.index() + 1, Ordering::SeqCst);
+            if !e.is_end() {
+                self.pushed
+                    .lock()
+                    .unwrap()
+                    .insert(e.index(), Instant::now());
+            }
+            if self.drop_oldest && self.retention.max_age.is_some() && !e.is_end() {
+                self.ages
+                    .lock()
+                    .unwrap()
+                    .push_back((e.index(), Instant::now()));
+            }
             q.push_back(Some(e));
+            if self.drop_oldest {
+                self.expire(&mut q);
+            }
 
             self.wakeup_reader();
 
-            Poll::Ready(())
+            Poll::Ready(true)
         } else {
             self.register_writer(cx.waker().clone());
             Poll::Pending
         }
     }
 
-    fn poll_get(&self, cx: &mut Context, index: usize) -> Poll<Option<Packet>> {
-        let q = self.q.lock().unwrap();
+    fn poll_get(&self, cx: &mut Context, index: usize) -> Poll<Option<Entry>> {
+        let mut q = self.q.lock().unwrap();
+        if self.aborted.lock().unwrap().is_some() {
+            return Poll::Ready(None);
+        }
+
+        if self.drop_oldest {
+            self.expire(&mut q);
+        }
+
+        // nothing follows the end of a stream, which may be all that is left
+        // of it for receivers that skipped dropped packets
+        if self.drop_oldest
+            && self.finished.load(Ordering::SeqCst)
+            && index >= self.next.load(Ordering::SeqCst)
+        {
+            return Poll::Ready(None);
+        }
+
         if q.is_empty() {
+            if self.drop_oldest && index < self.next.load(Ordering::SeqCst) {
+                return Poll::Ready(None);
+            }
+
             self.register_reader(cx.waker().clone());
             return Poll::Pending;
         }
 
-        let first_index = q[0].as_ref().unwrap().index;
+        let first_index = q[0].as_ref().unwrap().index();
 
         if index < first_index {
             return Poll::Ready(None);
@@ -82,29 +602,369 @@ impl Queue {
         Poll::Pending
     }
 
-    pub async fn push(&self, e: Packet) {
-        future::poll_fn(|cx| self.poll_push(cx, e.clone())).await
+    /// Buffers `e`, returning how long it had to wait for room.
+    pub async fn push(&self, e: Packet) -> Duration {
+        let len = e.data.len() as u64;
+        let mut waited = Duration::default();
+        let entry = if let Some(stored) = self.persist(&e).await {
+            Entry::Stored(e.index, Arc::new(stored))
+        } else if let Some(spooled) = self.spill(&e).await {
+            Entry::Spooled(e.index, Arc::new(spooled))
+        } else {
+            if let Some((budget, priority)) = &self.budget {
+                let started = Instant::now();
+                budget.reserve(e.data.len() as u64, *priority).await;
+                waited += started.elapsed();
+            }
+            Entry::Memory(e)
+        };
+
+        let started = Instant::now();
+        let accepted = future::poll_fn(|cx| self.poll_push(cx, entry.clone())).await;
+        if accepted {
+            self.received.fetch_add(len, Ordering::Relaxed);
+            *self.active.lock().unwrap() = Instant::now();
+        } else {
+            self.release(std::iter::once(entry));
+        }
+        waited + started.elapsed()
+    }
+
+    /// Counts data handed out to a receiver.
+    pub fn sent(&self, len: usize) {
+        self.sent.fetch_add(len as u64, Ordering::Relaxed);
+        *self.active.lock().unwrap() = Instant::now();
+    }
+
+    /// Bytes pushed by the sender and handed out to receivers so far.
+    pub fn traffic(&self) -> (u64, u64) {
+        (
+            self.received.load(Ordering::Relaxed),
+            self.sent.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Notes a request from a sender or receiver.
+    pub fn touch(&self) {
+        *self.active.lock().unwrap() = Instant::now();
+    }
+
+    /// How long the channel has not been used.
+    pub fn idle(&self) -> Duration {
+        self.active.lock().unwrap().elapsed()
+    }
+
+    /// Notes a request from the sender.
+    pub fn heard_from_sender(&self) {
+        self.sender.lock().unwrap().seen = Instant::now();
+    }
+
+    /// Notes a sender (re-)attaching, which sends heartbeats every
+    /// `heartbeat` if it does.
+    pub fn expect_sender_heartbeats(&self, heartbeat: Option<Duration>) {
+        *self.sender.lock().unwrap() = Liveness::new(heartbeat);
+    }
+
+    /// Notes a request from a receiver, which sends heartbeats every
+    /// `heartbeat` if given.
+    pub fn heard_from_receiver(&self, heartbeat: Option<Duration>) {
+        let mut receiver = self.receiver.lock().unwrap();
+        receiver.seen = Instant::now();
+        if heartbeat.is_some() {
+            receiver.heartbeat = heartbeat;
+        }
+    }
+
+    /// How long the sender has been silent, once it has missed several
+    /// heartbeats while the transfer is unfinished.
+    pub fn sender_silence(&self) -> Option<Duration> {
+        if self.is_finished() {
+            return None;
+        }
+        self.sender.lock().unwrap().silence()
+    }
+
+    /// How long the receivers have been silent, once they have missed
+    /// several heartbeats. Receivers of a stream may come and go.
+    pub fn receiver_silence(&self) -> Option<Duration> {
+        if self.drop_oldest {
+            return None;
+        }
+        self.receiver.lock().unwrap().silence()
+    }
+
+    /// Number of packets buffered.
+    pub fn depth(&self) -> usize {
+        let q = self.q.lock().unwrap();
+        q.iter().flatten().count()
+    }
+
+    /// How long the oldest packet no receiver has fetched yet has been
+    /// buffered.
+    pub fn oldest_pending(&self) -> Option<Duration> {
+        let pushed = self.pushed.lock().unwrap();
+        pushed.values().min().map(Instant::elapsed)
+    }
+
+    /// How long the packet at `index` had been buffered, the first time a
+    /// receiver fetches it.
+    pub fn fetched(&self, index: usize) -> Option<Duration> {
+        let pushed = self.pushed.lock().unwrap().remove(&index)?;
+        Some(pushed.elapsed())
+    }
+
+    /// Writes `e` to the object store, or returns `None` to keep it here.
+    /// The end-of-stream packet is kept, but completes the stored channel.
+    async fn persist(&self, e: &Packet) -> Option<StoredPacket> {
+        let store = self.store.as_ref()?;
+        let result = if e.data.is_empty() {
+            store.finish(e.index).await.map(|_| None)
+        } else {
+            store.put(e.index, e.data.clone()).await.map(Some)
+        };
+
+        match result {
+            Ok(stored) => stored,
+            Err(err) => {
+                warn!("failed to persist packet {}: {}", e.index, err);
+                None
+            }
+        }
+    }
+
+    /// Writes `e` to the spool if it would otherwise wait for memory, or
+    /// returns `None` to keep it in memory.
+    async fn spill(&self, e: &Packet) -> Option<SpooledPacket> {
+        let spool = self.spool.as_ref()?;
+        if e.data.is_empty() {
+            return None;
+        }
+
+        let slots = self.in_memory(&self.q.lock().unwrap()) < self.capacity;
+        let budget = match &self.budget {
+            Some((budget, priority)) => budget.has_room(e.data.len() as u64, *priority),
+            None => true,
+        };
+        if slots && budget {
+            return None;
+        }
+
+        match spool.write(&e.data).await {
+            Ok(spooled) => spooled,
+            Err(err) => {
+                warn!("failed to spool packet {}: {}", e.index, err);
+                None
+            }
+        }
     }
 
     pub async fn get(&self, index: usize) -> Option<Packet> {
-        future::poll_fn(|cx| self.poll_get(cx, index)).await
+        future::poll_fn(|cx| self.poll_get(cx, index))
+            .await?
+            .load()
+            .await
+    }
+
+    /// Like `get`, but gives up with how long the sender has been silent
+    /// once it seems to have died.
+    pub async fn get_from_live_sender(&self, index: usize) -> Result<Option<Packet>, Duration> {
+        loop {
+            let heartbeat = self.sender.lock().unwrap().heartbeat;
+            let interval = match heartbeat {
+                Some(heartbeat) => heartbeat,
+                None => return Ok(self.get(index).await),
+            };
+
+            let entry = future::poll_fn(|cx| self.poll_get(cx, index));
+            match tokio::time::timeout(interval, entry).await {
+                Ok(Some(entry)) => return Ok(entry.load().await),
+                Ok(None) => return Ok(None),
+                Err(_) => {
+                    if let Some(silence) = self.sender_silence() {
+                        return Err(silence);
+                    }
+                }
+            }
+        }
     }
 
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of packets that can be pushed without blocking.
+    pub fn credit(&self) -> usize {
+        self.free_slots(&self.q.lock().unwrap())
+    }
+
+    /// Waits until at least one packet can be pushed without blocking.
+    pub async fn wait_credit(&self) -> usize {
+        future::poll_fn(|cx| self.poll_credit(cx)).await
+    }
+
+    /// While paused, writers are blocked and no credit is given out.
+    pub fn set_paused(&self, paused: bool) {
+        let _q = self.q.lock().unwrap();
+        self.paused.store(paused, Ordering::SeqCst);
+
+        if !paused {
+            self.wakeup_writer();
+            self.wakeup_credit();
+        }
+    }
+
+    /// Drops all packets and fails any further reads and writes.
+    pub fn abort(&self, reason: String) {
+        let mut q = self.q.lock().unwrap();
+        *self.aborted.lock().unwrap() = Some(reason);
+        self.release(q.drain(..).flatten());
+
+        for w in self.read_wakers.lock().unwrap().drain(..) {
+            w.wake();
+        }
+        for w in self.write_wakers.lock().unwrap().drain(..) {
+            w.wake();
+        }
+        self.wakeup_credit();
+    }
+
+    pub fn set_trailer(&self, trailer: String) {
+        *self.trailer.lock().unwrap() = Some(trailer);
+    }
+
+    pub fn trailer(&self) -> Option<String> {
+        self.trailer.lock().unwrap().clone()
+    }
+
+    /// Whether the sender's end-of-stream packet has been pushed.
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    /// Why the transfer has been aborted, if it has.
+    pub fn aborted(&self) -> Option<String> {
+        self.aborted.lock().unwrap().clone()
+    }
+
+    /// Index of the next packet to be pushed.
+    pub fn next_index(&self) -> usize {
+        self.next.load(Ordering::SeqCst)
+    }
+
+    /// Index of the end-of-stream packet, once pushed.
+    pub fn end_index(&self) -> Option<usize> {
+        if self.is_finished() {
+            Some(self.next_index() - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Index of the oldest packet still available.
+    pub fn first_index(&self) -> usize {
+        let q = self.q.lock().unwrap();
+        match q.front() {
+            Some(Some(entry)) => entry.index(),
+            _ => self.next_index(),
+        }
+    }
+
+    /// Index of the first packet and of the end-of-stream packet, if the
+    /// sender has finished and all packets in between are still buffered.
+    pub fn completed(&self) -> Option<(usize, usize)> {
+        let q = self.q.lock().unwrap();
+        let first = q.front()?.as_ref()?.index();
+        let last = q.back()?.as_ref()?;
+
+        if !last.is_end() || q.iter().any(Option::is_none) {
+            return None;
+        }
+
+        Some((first, last.index()))
+    }
+
+    /// Packets from `start` to `end` (exclusive), if all of them are buffered.
+    pub async fn range(&self, start: usize, end: usize) -> Option<Vec<Packet>> {
+        let entries: Vec<_> = {
+            let q = self.q.lock().unwrap();
+            let first = q.front()?.as_ref()?.index();
+            if start < first {
+                return None;
+            }
+
+            (start..end)
+                .map(|i| q.get(i - first).cloned().flatten())
+                .collect::<Option<_>>()?
+        };
+
+        let mut packets = Vec::with_capacity(entries.len());
+        for entry in entries {
+            packets.push(entry.load().await?);
+        }
+        Some(packets)
+    }
+
+    /// Removes all packets before `index`, which the receiver has consumed.
+    pub fn remove_before(&self, index: usize) {
+        let mut q = self.q.lock().unwrap();
+
+        let first_index = match q.front() {
+            Some(Some(entry)) => entry.index(),
+            _ => return,
+        };
+
+        if index <= first_index {
+            return;
+        }
+
+        let n = (index - first_index).min(q.len());
+        self.release(q.drain(..n).flatten());
+
+        while let Some(None) = q.front() {
+            q.pop_front().unwrap();
+        }
+
+        self.wakeup_writer();
+        self.wakeup_credit();
+    }
+
+    /// Removes a packet a receiver has taken, unless other receivers have
+    /// yet to take it.
     pub fn remove(&self, index: usize) {
+        if self.broadcast.is_some() {
+            return;
+        }
+
         let mut q = self.q.lock().unwrap();
 
-        if q.is_empty() || q[0].as_ref().unwrap().index > index {
+        if q.is_empty() || q[0].as_ref().unwrap().index() > index {
             return;
         }
 
-        let first_index = q[0].as_ref().unwrap().index;
+        let first_index = q[0].as_ref().unwrap().index();
 
-        q[index - first_index] = None;
+        if let Some(entry) = q.get_mut(index - first_index).and_then(Option::take) {
+            self.release(std::iter::once(entry));
+        }
 
         while let Some(None) = q.front() {
             q.pop_front().unwrap();
         }
 
         self.wakeup_writer();
+        self.wakeup_credit();
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        // Stored packets stay in the object store for the channel to be
+        // restored from
+        let mut q = self.q.lock().unwrap();
+        self.release(
+            q.drain(..)
+                .flatten()
+                .filter(|entry| !matches!(entry, Entry::Stored(..))),
+        );
     }
 }
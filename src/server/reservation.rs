@@ -0,0 +1,131 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::http::HeaderMap;
+use log::info;
+use serde::Serialize;
+
+use super::{auth::equals, vhost::ChannelKey};
+use crate::common::headers;
+
+/// Which end of a channel a request comes from, and so which claim it must
+/// carry.
+#[derive(Clone, Copy)]
+pub enum Holder {
+    Sender,
+    Receiver,
+    /// Control requests, which either end may make
+    Either,
+}
+
+/// The tokens handed out for a reserved channel, one for each end.
+#[derive(Clone, Serialize)]
+pub struct Claims {
+    pub sender: String,
+    pub receiver: String,
+    /// Seconds the reservation lasts without either end using it
+    pub timeout: u64,
+}
+
+struct Reservation {
+    claims: Claims,
+    used: Instant,
+}
+
+impl Reservation {
+    fn expired(&self) -> bool {
+        self.used.elapsed() >= Duration::from_secs(self.claims.timeout)
+    }
+}
+
+/// Channels set aside for whoever holds their claims, so that two pipelines
+/// picking the same name cannot get in each other's way.
+#[derive(Default)]
+pub struct Reservations {
+    reservations: Mutex<HashMap<ChannelKey, Reservation>>,
+}
+
+impl Reservations {
+    /// Reserves the channel at `key`, unless it already is, or a sender has
+    /// created it. `open` tells which channels have been.
+    pub fn reserve<F>(&self, key: ChannelKey, timeout: Duration, open: F) -> Option<Claims>
+    where
+        F: Fn(&ChannelKey) -> bool,
+    {
+        let mut reservations = self.reservations.lock().unwrap();
+        reservations.retain(|key, r| {
+            let released = r.expired() && !open(key);
+            if released {
+                info!(
+                    "reservation of channel {} released, as it was not used",
+                    key
+                );
+            }
+            !released
+        });
+        if open(&key) || reservations.contains_key(&key) {
+            return None;
+        }
+
+        let claims = Claims {
+            sender: hex::encode(rand::random::<[u8; 16]>()),
+            receiver: hex::encode(rand::random::<[u8; 16]>()),
+            timeout: timeout.as_secs(),
+        };
+        info!("channel {} reserved", key);
+        reservations.insert(
+            key,
+            Reservation {
+                claims: claims.clone(),
+                used: Instant::now(),
+            },
+        );
+        Some(claims)
+    }
+
+    /// Whether a request may use the channel at `key`, which is `open` if
+    /// its sender has created it. Reservations are kept while the channel
+    /// is open, and otherwise released once unused for their timeout.
+    pub fn check(&self, key: &ChannelKey, headers: &HeaderMap, holder: Holder, open: bool) -> bool {
+        let mut reservations = self.reservations.lock().unwrap();
+        let reservation = match reservations.get_mut(key) {
+            Some(reservation) => reservation,
+            None => return true,
+        };
+
+        if !open && reservation.expired() {
+            info!(
+                "reservation of channel {} released, as it was not used",
+                key
+            );
+            reservations.remove(key);
+            return true;
+        }
+
+        let claim = match headers.get(headers::CLAIM) {
+            Some(claim) => claim.as_bytes(),
+            None => return false,
+        };
+        let claims = &reservation.claims;
+        let valid = match holder {
+            Holder::Sender => equals(claim, claims.sender.as_bytes()),
+            Holder::Receiver => equals(claim, claims.receiver.as_bytes()),
+            Holder::Either => {
+                equals(claim, claims.sender.as_bytes()) || equals(claim, claims.receiver.as_bytes())
+            }
+        };
+
+        if valid {
+            reservation.used = Instant::now();
+        }
+        valid
+    }
+
+    /// Frees the channel at `key` once its transfer is over.
+    pub fn release(&self, key: &ChannelKey) {
+        self.reservations.lock().unwrap().remove(key);
+    }
+}
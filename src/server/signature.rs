@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{Error, ErrorUnauthorized};
+use futures::future::{self, Either, Ready};
+
+use crate::common::{
+    headers,
+    signature::{self, Signer},
+};
+
+/// Nonces of recently accepted requests, remembered until their timestamp
+/// falls out of the accepted window so that a captured request cannot be
+/// replayed.
+pub struct NonceCache {
+    window: u64,
+    seen: Mutex<HashMap<String, u64>>,
+    last_pruned: Mutex<u64>,
+}
+
+impl NonceCache {
+    pub fn new(window: u64) -> Self {
+        NonceCache {
+            window,
+            seen: Mutex::new(HashMap::new()),
+            last_pruned: Mutex::new(0),
+        }
+    }
+
+    fn check(&self, nonce: &str, timestamp: u64) -> bool {
+        let now = signature::timestamp();
+        if now.max(timestamp) - now.min(timestamp) > self.window {
+            return false;
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+
+        let mut last_pruned = self.last_pruned.lock().unwrap();
+        if *last_pruned != now {
+            let window = self.window;
+            seen.retain(|_, t| *t + window >= now);
+            *last_pruned = now;
+        }
+
+        if seen.contains_key(nonce) {
+            return false;
+        }
+
+        seen.insert(nonce.into(), timestamp);
+        true
+    }
+}
+
+/// Middleware rejecting requests whose HMAC signature does not match the
+/// shared secret, or which are stale or replayed.
+pub struct VerifySignature {
+    signer: Signer,
+    nonces: Arc<NonceCache>,
+}
+
+impl VerifySignature {
+    pub fn new(signer: Signer, nonces: Arc<NonceCache>) -> Self {
+        VerifySignature { signer, nonces }
+    }
+}
+
+impl<S, B> Transform<S> for VerifySignature
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = VerifySignatureMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(VerifySignatureMiddleware {
+            service,
+            signer: self.signer.clone(),
+            nonces: self.nonces.clone(),
+        })
+    }
+}
+
+pub struct VerifySignatureMiddleware<S> {
+    service: S,
+    signer: Signer,
+    nonces: Arc<NonceCache>,
+}
+
+impl<S> VerifySignatureMiddleware<S> {
+    fn verify(&self, req: &ServiceRequest) -> bool {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+        };
+
+        let timestamp = header(headers::TIMESTAMP);
+        let nonce = header(headers::NONCE);
+
+        if !self.signer.verify(
+            req.method().as_str(),
+            req.path(),
+            header(headers::INDEX),
+            timestamp,
+            nonce,
+            header(headers::SIGNATURE),
+        ) {
+            return false;
+        }
+
+        match timestamp.parse() {
+            Ok(timestamp) => self.nonces.check(nonce, timestamp),
+            Err(_) => false,
+        }
+    }
+}
+
+impl<S, B> Service for VerifySignatureMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if self.verify(&req) {
+            Either::Left(self.service.call(req))
+        } else {
+            Either::Right(future::err(ErrorUnauthorized("invalid request signature")))
+        }
+    }
+}
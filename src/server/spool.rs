@@ -0,0 +1,104 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::Bytes;
+use tempfile::TempDir;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+/// Disk space shared by all channels of the server, which packets spill
+/// over to once a channel has no room for them in memory.
+pub struct Spool {
+    /// Only readable by the server, and removed along with the spool
+    dir: TempDir,
+    limit: u64,
+    used: Mutex<u64>,
+    next: AtomicUsize,
+}
+
+/// A packet written to the spool, deleted once dropped.
+pub struct SpooledPacket {
+    spool: Arc<Spool>,
+    path: PathBuf,
+    len: u64,
+}
+
+impl Spool {
+    pub fn new(dir: PathBuf, limit: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let dir = tempfile::Builder::new()
+            .prefix("http-pipe-")
+            .tempdir_in(dir)?;
+        #[cfg(unix)]
+        std::fs::set_permissions(
+            dir.path(),
+            std::os::unix::fs::PermissionsExt::from_mode(0o700),
+        )?;
+
+        Ok(Spool {
+            dir,
+            limit,
+            used: Mutex::new(0),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Whether there is any room left.
+    pub fn has_room(&self) -> bool {
+        *self.used.lock().unwrap() < self.limit
+    }
+
+    /// Writes `data` to a file of its own, or returns `None` if the spool
+    /// is full.
+    pub async fn write(self: &Arc<Self>, data: &[u8]) -> io::Result<Option<SpooledPacket>> {
+        let len = data.len() as u64;
+        {
+            let mut used = self.used.lock().unwrap();
+            if *used + len > self.limit {
+                return Ok(None);
+            }
+            *used += len;
+        }
+
+        let name = format!(
+            "{}-{}.packet",
+            std::process::id(),
+            self.next.fetch_add(1, Ordering::SeqCst)
+        );
+        let packet = SpooledPacket {
+            spool: self.clone(),
+            path: self.dir.path().join(name),
+            len,
+        };
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = OpenOptions::from(options).open(&packet.path).await?;
+        file.write_all(data).await?;
+        file.flush().await?;
+
+        Ok(Some(packet))
+    }
+}
+
+impl SpooledPacket {
+    pub async fn read(&self) -> io::Result<Bytes> {
+        Ok(tokio::fs::read(&self.path).await?.into())
+    }
+}
+
+impl Drop for SpooledPacket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+
+        let mut used = self.spool.used.lock().unwrap();
+        *used = used.saturating_sub(self.len);
+    }
+}
@@ -0,0 +1,308 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use hmac::{Hmac, Mac, NewMac};
+use log::warn;
+use reqwest::{Client, Method, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::vhost::ChannelKey;
+use crate::client::net;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// An S3-compatible bucket channels are persisted to, keyed by channel and
+/// packet index, so that receivers can still fetch them after the relay
+/// restarts.
+pub struct ObjectStore {
+    client: Client,
+    /// The bucket and prefix, addressed path-style, e.g.
+    /// `https://s3.eu-west-1.amazonaws.com/bucket/prefix`
+    base: Url,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+/// What is needed to serve a channel again: how its packets are encoded,
+/// and where they end once the sender has finished.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub checksum: String,
+    pub compression: Option<String>,
+    pub encryption: Option<String>,
+    pub records: bool,
+    /// Index of the end-of-stream packet
+    pub end: Option<usize>,
+    /// Digest of the channel secret, in hex
+    pub secret: Option<String>,
+}
+
+/// The objects of a single channel.
+#[derive(Clone)]
+pub struct StoredChannel {
+    store: Arc<ObjectStore>,
+    prefix: String,
+    manifest: Arc<Mutex<Manifest>>,
+    /// Whether the manifest has been written since the channel was opened
+    saved: Arc<AtomicBool>,
+}
+
+/// A packet in the store, read back when a receiver asks for it.
+pub struct StoredPacket {
+    channel: StoredChannel,
+    index: usize,
+}
+
+/// Percent-encodes everything but unreserved characters and slashes, as
+/// object keys are in signed requests.
+fn encode_key(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for b in key.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl ObjectStore {
+    /// Credentials are taken from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`
+    /// and, for temporary ones, `AWS_SESSION_TOKEN`.
+    pub fn new(url: &str, region: &str) -> anyhow::Result<Self> {
+        let base = Url::parse(url.trim_end_matches('/'))?;
+        if base.path().trim_matches('/').is_empty() {
+            bail!("the object store URL must name a bucket: {}", url);
+        }
+
+        let env = |name| std::env::var(name).map_err(|_| anyhow!("{} must be set", name));
+        Ok(ObjectStore {
+            client: net::client(),
+            base,
+            region: region.to_owned(),
+            access_key: env("AWS_ACCESS_KEY_ID")?,
+            secret_key: env("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    /// The objects of the channel at `key`, which is described by
+    /// `manifest` once its first packet is persisted.
+    pub fn channel(self: &Arc<Self>, key: &ChannelKey, manifest: Manifest) -> StoredChannel {
+        let prefix = if key.host.is_empty() {
+            key.id.clone()
+        } else {
+            format!("{}/{}", key.host, key.id)
+        };
+
+        StoredChannel {
+            store: self.clone(),
+            prefix,
+            manifest: Arc::new(Mutex::new(manifest)),
+            saved: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Sends a request signed with AWS Signature Version 4.
+    async fn request(&self, method: Method, key: &str, body: Bytes) -> anyhow::Result<Bytes> {
+        let mut url = self.base.clone();
+        url.set_path(&format!(
+            "{}/{}",
+            self.base.path().trim_end_matches('/'),
+            encode_key(key)
+        ));
+
+        let now = chrono::Utc::now();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload = hex::encode(Sha256::digest(&body));
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method,
+            url.path(),
+            headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            signed,
+            payload
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex::encode(Sha256::digest(canonical.as_bytes()))
+        );
+        let mut key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        for part in &[self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part);
+        }
+        let signature = hex::encode(hmac(&key, &string_to_sign));
+
+        let mut req = self.client.request(method, url).header(
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, signed, signature
+            ),
+        );
+        for (name, value) in headers.into_iter().skip(1) {
+            req = req.header(name, value);
+        }
+
+        let resp = req.body(body).send().await?;
+        let status = resp.status();
+        if status == StatusCode::NOT_FOUND {
+            bail!(NotFound);
+        }
+        if !status.is_success() {
+            bail!("object store returned failure status: {:?}", status);
+        }
+
+        Ok(resp.bytes().await?)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("object not found")]
+struct NotFound;
+
+impl StoredChannel {
+    fn manifest_key(&self) -> String {
+        format!("{}/.channel", self.prefix)
+    }
+
+    fn packet_key(&self, index: usize) -> String {
+        format!("{}/.packets/{:020}", self.prefix, index)
+    }
+
+    async fn save_manifest(&self) -> anyhow::Result<()> {
+        let body = serde_json::to_vec(&*self.manifest.lock().unwrap())?;
+        self.store
+            .request(Method::PUT, &self.manifest_key(), body.into())
+            .await?;
+        self.saved.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Reads the manifest of a channel persisted earlier, returning whether
+    /// there is one.
+    pub async fn load_manifest(&self) -> anyhow::Result<bool> {
+        let key = self.manifest_key();
+        let manifest = match self.store.request(Method::GET, &key, Bytes::new()).await {
+            Ok(body) => serde_json::from_slice(&body)?,
+            Err(e) if e.is::<NotFound>() => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        *self.manifest.lock().unwrap() = manifest;
+        self.saved.store(true, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        self.manifest.lock().unwrap().clone()
+    }
+
+    pub async fn put(&self, index: usize, data: Bytes) -> anyhow::Result<StoredPacket> {
+        if !self.saved.load(Ordering::SeqCst) {
+            self.save_manifest().await?;
+        }
+
+        self.store
+            .request(Method::PUT, &self.packet_key(index), data)
+            .await?;
+        Ok(self.packet(index))
+    }
+
+    /// Records that the sender has finished with the end-of-stream packet at
+    /// `index`, after which the channel can be restored.
+    pub async fn finish(&self, index: usize) -> anyhow::Result<()> {
+        self.manifest.lock().unwrap().end = Some(index);
+        self.save_manifest().await
+    }
+
+    /// A packet persisted earlier.
+    pub fn packet(&self, index: usize) -> StoredPacket {
+        StoredPacket {
+            channel: self.clone(),
+            index,
+        }
+    }
+
+    /// Deletes the manifest once the channel is done with. Any packets left
+    /// are deleted along with their entries in the queue.
+    pub fn remove(&self) {
+        let channel = self.clone();
+        tokio::spawn(async move {
+            let key = channel.manifest_key();
+            if let Err(e) = channel
+                .store
+                .request(Method::DELETE, &key, Bytes::new())
+                .await
+            {
+                warn!("failed to delete {} from the object store: {}", key, e);
+            }
+        });
+    }
+}
+
+impl StoredPacket {
+    pub async fn read(&self) -> anyhow::Result<Bytes> {
+        let key = self.channel.packet_key(self.index);
+        self.channel
+            .store
+            .request(Method::GET, &key, Bytes::new())
+            .await
+    }
+
+    /// Deletes the packet once the receiver has taken it.
+    pub fn remove(&self) {
+        let channel = self.channel.clone();
+        let key = channel.packet_key(self.index);
+        tokio::spawn(async move {
+            if let Err(e) = channel
+                .store
+                .request(Method::DELETE, &key, Bytes::new())
+                .await
+            {
+                warn!("failed to delete {} from the object store: {}", key, e);
+            }
+        });
+    }
+}
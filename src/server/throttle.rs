@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use actix_web::dev::{
+    Body, BodySize, MessageBody, Payload, ResponseBody, Service, ServiceRequest, ServiceResponse,
+    Transform,
+};
+use actix_web::{error::Error, http::header::RETRY_AFTER, web, HttpMessage, HttpResponse};
+use bytes::Bytes;
+use futures::{
+    future::{self, Either, Ready},
+    ready, StreamExt,
+};
+
+use super::AppState;
+use crate::common::TokenBucket;
+
+/// How long the state of a client or channel is kept once it stops making
+/// requests
+const FORGET_AFTER: Duration = Duration::from_secs(60);
+
+/// The rate of requests and of data allowed for each client address, or
+/// for each channel.
+#[derive(Clone, Copy, Default)]
+pub struct Limit {
+    /// Requests per second, allowing bursts of as many
+    pub requests: Option<u32>,
+    /// Bytes per second, uploads and downloads together
+    pub bandwidth: Option<u64>,
+}
+
+impl Limit {
+    fn is_set(&self) -> bool {
+        self.requests.is_some() || self.bandwidth.is_some()
+    }
+}
+
+struct State {
+    tokens: f64,
+    last: Instant,
+    bucket: Arc<TokenBucket>,
+}
+
+/// Data passing to or from a client or channel with a bandwidth limit.
+#[derive(Clone)]
+struct Shaper {
+    bucket: Arc<TokenBucket>,
+    rate: u64,
+}
+
+struct Limiter<K> {
+    limit: Limit,
+    states: Mutex<HashMap<K, State>>,
+}
+
+impl<K: Hash + Eq> Limiter<K> {
+    fn new(limit: Limit) -> Self {
+        Limiter {
+            limit,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request from `key`, returning whether it is within the
+    /// request rate, and how its data is to be shaped.
+    fn admit(&self, key: K) -> (bool, Option<Shaper>) {
+        if !self.limit.is_set() {
+            return (true, None);
+        }
+
+        let burst = f64::from(self.limit.requests.unwrap_or(0));
+        let mut states = self.states.lock().unwrap();
+        if !states.contains_key(&key) {
+            states.retain(|_, state| {
+                state.last.elapsed() < FORGET_AFTER || Arc::strong_count(&state.bucket) > 1
+            });
+        }
+
+        let state = states.entry(key).or_insert_with(|| State {
+            tokens: burst,
+            last: Instant::now(),
+            bucket: Arc::new(TokenBucket::new()),
+        });
+        let shaper = self.limit.bandwidth.map(|rate| Shaper {
+            bucket: state.bucket.clone(),
+            rate,
+        });
+
+        let allowed = match self.limit.requests {
+            Some(per_second) => {
+                let elapsed = state.last.elapsed().as_secs_f64();
+                state.tokens = (state.tokens + elapsed * f64::from(per_second)).min(burst);
+                state.last = Instant::now();
+                if state.tokens >= 1.0 {
+                    state.tokens -= 1.0;
+                    true
+                } else {
+                    false
+                }
+            }
+            None => {
+                state.last = Instant::now();
+                true
+            }
+        };
+        (allowed, shaper)
+    }
+}
+
+/// Middleware keeping each client address and each channel to a rate of
+/// requests, turning down those beyond it with 429, and of data, slowing
+/// down uploads and downloads, so that no one can take over the relay.
+#[derive(Clone)]
+pub struct Throttle {
+    clients: Arc<Limiter<IpAddr>>,
+    channels: Arc<Limiter<String>>,
+}
+
+impl Throttle {
+    pub fn new(per_client: Limit, per_channel: Limit) -> Self {
+        Throttle {
+            clients: Arc::new(Limiter::new(per_client)),
+            channels: Arc::new(Limiter::new(per_channel)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.clients.limit.is_set() || self.channels.limit.is_set()
+    }
+}
+
+impl<S> Transform<S> for Throttle
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ThrottleMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(ThrottleMiddleware {
+            service,
+            clients: self.clients.clone(),
+            channels: self.channels.clone(),
+        })
+    }
+}
+
+pub struct ThrottleMiddleware<S> {
+    service: S,
+    clients: Arc<Limiter<IpAddr>>,
+    channels: Arc<Limiter<String>>,
+}
+
+/// The channel a request is for, the same whether or not it is tunneled.
+fn channel(req: &ServiceRequest) -> String {
+    let path = req.path();
+    let path = if req.head().upgrade() && path.ends_with("/ws") {
+        &path[..path.len() - "/ws".len()]
+    } else {
+        path
+    };
+    // keyed the way channels are, by the namespace of their host
+    let id = path.trim_start_matches('/').to_owned();
+    req.app_data::<web::Data<AppState>>()
+        .and_then(|data| data.channel_key_of(req.head(), id).ok())
+        .map_or_else(|| path.to_owned(), |key| key.to_string())
+}
+
+/// Whether another node of the cluster forwarded `req`.
+fn forwarded(req: &ServiceRequest) -> bool {
+    req.app_data::<web::Data<AppState>>()
+        .and_then(|data| data.cluster.as_ref())
+        .is_some_and(|cluster| cluster.is_forwarded(req))
+}
+
+fn too_many(req: ServiceRequest, reason: &str) -> ServiceResponse {
+    req.into_response(
+        HttpResponse::TooManyRequests()
+            .header(RETRY_AFTER, "1")
+            .body(reason.to_owned()),
+    )
+}
+
+impl<S> Service for ThrottleMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = Either<
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>,
+        Ready<Result<Self::Response, Self::Error>>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        // the node a request was forwarded by has limited its client, and
+        // would otherwise share one limit with all the others it forwards
+        let (client_allowed, client_shaper) = match req.peer_addr() {
+            _ if forwarded(&req) => (true, None),
+            Some(addr) => self.clients.admit(addr.ip()),
+            None => (true, None),
+        };
+        if !client_allowed {
+            return Either::Right(future::ok(too_many(
+                req,
+                "too many requests from this address",
+            )));
+        }
+        let (channel_allowed, channel_shaper) = self.channels.admit(channel(&req));
+        if !channel_allowed {
+            return Either::Right(future::ok(too_many(
+                req,
+                "too many requests on this channel",
+            )));
+        }
+
+        let shapers: Vec<Shaper> = client_shaper.into_iter().chain(channel_shaper).collect();
+        if shapers.is_empty() {
+            return Either::Left(Box::pin(self.service.call(req)));
+        }
+
+        let upload = shapers.clone();
+        let payload = req.take_payload().then(move |chunk| {
+            let shapers = upload.clone();
+            async move {
+                if let Ok(chunk) = &chunk {
+                    for shaper in &shapers {
+                        shaper.bucket.take(chunk.len() as u64, shaper.rate).await;
+                    }
+                }
+                chunk
+            }
+        });
+        req.set_payload(Payload::Stream(Box::pin(payload)));
+
+        let resp = self.service.call(req);
+        Either::Left(Box::pin(async move {
+            let resp = resp.await?;
+            Ok(resp.map_body(|_, body| {
+                ResponseBody::Other(Body::Message(Box::new(Shaped {
+                    body,
+                    shapers,
+                    waiting: None,
+                })))
+            }))
+        }))
+    }
+}
+
+/// Waiting for the shapers to let a chunk through
+type Delay = Pin<Box<dyn Future<Output = ()>>>;
+
+/// A response body sent no faster than its shapers allow.
+struct Shaped {
+    body: ResponseBody<Body>,
+    shapers: Vec<Shaper>,
+    /// A chunk held back until the shapers let it through
+    waiting: Option<(Bytes, Delay)>,
+}
+
+impl MessageBody for Shaped {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Error>>> {
+        loop {
+            if let Some((_, delay)) = &mut self.waiting {
+                ready!(delay.as_mut().poll(cx));
+                let (chunk, _) = self.waiting.take().unwrap();
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+
+            let chunk = match ready!(Pin::new(&mut self.body).poll_next(cx)) {
+                Some(Ok(chunk)) => chunk,
+                other => return Poll::Ready(other),
+            };
+            let (shapers, n) = (self.shapers.clone(), chunk.len() as u64);
+            let delay = Box::pin(async move {
+                for shaper in &shapers {
+                    shaper.bucket.take(n, shaper.rate).await;
+                }
+            });
+            self.waiting = Some((chunk, delay));
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::{anyhow, bail};
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    NoClientAuth, ServerConfig,
+};
+
+/// A TLS configuration serving the PEM encoded certificate chain in `cert`
+/// with the PKCS #8 or RSA private key in `key`.
+pub fn config(cert: &Path, key: &Path) -> anyhow::Result<ServerConfig> {
+    let chain = certs(&mut BufReader::new(File::open(cert)?))
+        .map_err(|_| anyhow!("invalid certificate: {}", cert.display()))?;
+    if chain.is_empty() {
+        bail!("no certificate found in {}", cert.display());
+    }
+
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+        .map_err(|_| anyhow!("invalid private key: {}", key.display()))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key)?))
+            .map_err(|_| anyhow!("invalid private key: {}", key.display()))?;
+    }
+
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => bail!("no private key found in {}", key.display()),
+    };
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key)?;
+    Ok(config)
+}
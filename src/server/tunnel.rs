@@ -0,0 +1,246 @@
+use std::convert::Infallible;
+use std::str::FromStr;
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, Codec, Frame, Item, Message};
+use actix_web::dev::{Body, RequestHead, ResponseBody};
+use actix_web::error::ErrorBadGateway;
+use actix_web::http::{header, HeaderMap, HeaderName, HeaderValue, Method};
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use anyhow::{anyhow, bail};
+use bytes::{Bytes, BytesMut};
+use futures::future;
+use futures::stream::{self, StreamExt};
+use log::debug;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use super::{
+    cluster::{self, Cluster},
+    get_packet, origin, put_packet,
+    reservation::Holder,
+    AppState, ChannelKey, ControllerResult,
+};
+use crate::common::headers;
+use crate::common::tunnel::{self, ResponseHead};
+
+/// Largest message accepted, a packet along with its headers
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+fn is_upgrade(head: &RequestHead) -> bool {
+    head.upgrade()
+}
+
+/// Upgrades to a WebSocket over which a worker sends all its requests for
+/// the channel, instead of making a new one for each packet.
+#[get("/{id:.+}/ws", guard = "is_upgrade")]
+pub async fn upgrade(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    req: HttpRequest,
+    body: web::Payload,
+) -> ControllerResult<HttpResponse> {
+    let path = data.channel_key(&req, path.into_inner())?;
+    let mut resp = ws::handshake(req.head()).map_err(Error::from)?;
+    debug!("WS {:?} ({})", path, origin(req.headers()));
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let serve = Box::pin(async move { serve(&data, &req, path, body, tx).await });
+    // served by the task writing the response, which the payload of the
+    // request is only reliably read by
+    let serve = stream::once(serve).filter_map(|result| {
+        if let Err(e) = result {
+            debug!("WebSocket closed: {}", e);
+        }
+        future::ready(None)
+    });
+
+    Ok(resp.streaming(stream::select(rx, serve)))
+}
+
+fn reply(
+    tx: &UnboundedSender<Result<Bytes, Error>>,
+    codec: &mut Codec,
+    message: Message,
+) -> anyhow::Result<()> {
+    let mut frame = BytesMut::new();
+    codec
+        .encode(message, &mut frame)
+        .map_err(|e| anyhow!("failed to encode frame: {}", e))?;
+    tx.send(Ok(frame.freeze()))
+        .map_err(|_| anyhow!("connection closed"))
+}
+
+/// Adds a fragment to a message, which may be no larger than one sent in a
+/// single frame.
+fn append(message: &mut BytesMut, fragment: &[u8]) -> anyhow::Result<()> {
+    if message.len() + fragment.len() > MAX_MESSAGE_SIZE {
+        bail!("fragmented message too large");
+    }
+    message.extend_from_slice(fragment);
+    Ok(())
+}
+
+/// Answers the requests sent over the WebSocket one after the other, as a
+/// worker only sends its next request once it has the reply.
+async fn serve(
+    data: &AppState,
+    req: &HttpRequest,
+    path: ChannelKey,
+    mut body: web::Payload,
+    tx: UnboundedSender<Result<Bytes, Error>>,
+) -> anyhow::Result<()> {
+    let mut codec = Codec::new().max_size(MAX_MESSAGE_SIZE);
+    let mut buf = BytesMut::new();
+    let mut fragments = BytesMut::new();
+
+    loop {
+        let frame = codec
+            .decode(&mut buf)
+            .map_err(|e| anyhow!("invalid frame: {}", e))?;
+        let frame = match frame {
+            Some(frame) => frame,
+            None => match body.next().await {
+                Some(chunk) => {
+                    let chunk = chunk.map_err(|e| anyhow!("payload error: {}", e))?;
+                    buf.extend_from_slice(&chunk);
+                    continue;
+                }
+                None => return Ok(()),
+            },
+        };
+
+        let message = match frame {
+            Frame::Binary(message) => message,
+            Frame::Continuation(Item::FirstBinary(fragment))
+            | Frame::Continuation(Item::Continue(fragment)) => {
+                append(&mut fragments, &fragment)?;
+                continue;
+            }
+            Frame::Continuation(Item::Last(fragment)) => {
+                append(&mut fragments, &fragment)?;
+                fragments.split().freeze()
+            }
+            Frame::Ping(ping) => {
+                reply(&tx, &mut codec, Message::Pong(ping))?;
+                continue;
+            }
+            Frame::Pong(_) => continue,
+            Frame::Close(reason) => {
+                reply(&tx, &mut codec, Message::Close(reason))?;
+                return Ok(());
+            }
+            _ => bail!("unexpected text frame"),
+        };
+
+        let response = handle(data, req, path.clone(), message).await?;
+        reply(&tx, &mut codec, Message::Binary(response.into()))?;
+    }
+}
+
+/// Answers a tunneled request as the same request made over HTTP.
+async fn handle(
+    data: &AppState,
+    req: &HttpRequest,
+    path: ChannelKey,
+    message: Bytes,
+) -> anyhow::Result<Vec<u8>> {
+    let (head, body): (tunnel::RequestHead, _) = tunnel::decode(message)?;
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in &head.headers {
+        headers.append(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+    }
+    // replies are whole messages, with a single packet each
+    headers.remove(headers::PACKETS);
+
+    // only packets go through the tunnel, anything else is sent over HTTP
+    let upload = match head.method.as_str() {
+        "PUT" => true,
+        "GET" => false,
+        method => bail!("cannot tunnel {} requests", method),
+    };
+
+    let holder = if upload {
+        Holder::Sender
+    } else {
+        Holder::Receiver
+    };
+    let checked = data
+        .check_access(req, upload)
+        .and_then(|()| data.check_claim(&path, req.headers(), holder));
+
+    let owner = data
+        .cluster
+        .as_ref()
+        .and_then(|cluster| Some((cluster, cluster.owner(&path)?)));
+    let result = match (checked, owner) {
+        (Err(e), _) => Err(e),
+        (Ok(()), Some((cluster, owner))) => {
+            forward(cluster, owner, req, upload, &headers, body).await
+        }
+        (Ok(()), None) if upload => {
+            let body = stream::iter(Some(Ok::<_, Infallible>(body)));
+            put_packet(data, path, &headers, body).await
+        }
+        (Ok(()), None) => get_packet(data, path, &headers).await,
+    };
+    let resp = result.unwrap_or_else(|e| HttpResponse::from(Error::from(e)));
+
+    let head = ResponseHead {
+        status: resp.status().as_u16(),
+        headers: resp
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect(),
+    };
+    let body = match resp.body() {
+        ResponseBody::Body(Body::Bytes(bytes)) | ResponseBody::Other(Body::Bytes(bytes)) => {
+            bytes.clone()
+        }
+        _ => Bytes::new(),
+    };
+
+    tunnel::encode(&head, &body)
+}
+
+/// Makes a tunneled request on the node of the cluster its channel belongs
+/// to, over HTTP.
+async fn forward(
+    cluster: &Cluster,
+    owner: &str,
+    req: &HttpRequest,
+    upload: bool,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> ControllerResult<HttpResponse> {
+    let path = req.path();
+    let path = &path[..path.len() - "/ws".len()];
+    let method = if upload { Method::PUT } else { Method::GET };
+
+    // the client sends these once, with the upgrade request, and the owner
+    // checks them and files the channel under its host all the same
+    let mut headers = headers.clone();
+    let sent_once = [
+        header::AUTHORIZATION.as_str(),
+        header::HOST.as_str(),
+        headers::CLAIM,
+        headers::CHANNEL_SECRET,
+    ];
+    for (name, value) in req.headers() {
+        if sent_once
+            .iter()
+            .any(|n| name.as_str().eq_ignore_ascii_case(n))
+        {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+
+    let resp = cluster
+        .forward(owner, &method, path, &headers, body)
+        .await
+        .map_err(ErrorBadGateway)?;
+    let mut builder = cluster::response_builder(&resp);
+    let body = resp.bytes().await.map_err(ErrorBadGateway)?;
+    Ok(builder.body(body))
+}
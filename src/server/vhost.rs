@@ -0,0 +1,159 @@
+use std::fmt;
+use std::str::FromStr;
+
+use actix_web::dev::{AppConfig, ConnectionInfo, RequestHead};
+use actix_web::http::header;
+use anyhow::bail;
+
+/// Which ends of transfers a host serves, e.g. only senders on a drop box
+/// for untrusted devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    ReadWrite,
+    WriteOnly,
+    ReadOnly,
+}
+
+impl Access {
+    pub fn allows_upload(self) -> bool {
+        self != Access::ReadOnly
+    }
+
+    pub fn allows_download(self) -> bool {
+        self != Access::WriteOnly
+    }
+}
+
+impl FromStr for Access {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "read-write" => Access::ReadWrite,
+            "write-only" => Access::WriteOnly,
+            "read-only" => Access::ReadOnly,
+            _ => bail!("unknown access mode: {}", s),
+        })
+    }
+}
+
+/// A hostname the relay answers for, along with limits applied to the
+/// channels created under it. The name `*` matches any other host. Hosts
+/// share channels when given the same namespace.
+#[derive(Clone, Debug)]
+pub struct VirtualHost {
+    pub name: String,
+    pub max_channels: Option<usize>,
+    pub access: Access,
+    pub namespace: Option<String>,
+}
+
+impl FromStr for VirtualHost {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parts = s.split(',');
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+        if name.is_empty() {
+            bail!("virtual host name must not be empty");
+        }
+
+        let mut vhost = VirtualHost {
+            name,
+            max_channels: None,
+            access: Access::ReadWrite,
+            namespace: None,
+        };
+
+        for option in parts {
+            let mut kv = option.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("max-channels"), Some(value)) => vhost.max_channels = Some(value.parse()?),
+                (Some("access"), Some(value)) => vhost.access = value.parse()?,
+                (Some("namespace"), Some(value)) if !value.is_empty() => {
+                    vhost.namespace = Some(value.to_owned())
+                }
+                _ => bail!("unknown virtual host option: {}", option),
+            }
+        }
+
+        Ok(vhost)
+    }
+}
+
+/// Identifies a channel within the namespace of the host it was addressed to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ChannelKey {
+    /// The host's namespace, its name unless configured otherwise
+    pub host: String,
+    pub id: String,
+}
+
+impl fmt::Display for ChannelKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.is_empty() {
+            write!(f, "{}", self.id)
+        } else {
+            write!(f, "{}/{}", self.host, self.id)
+        }
+    }
+}
+
+pub struct VirtualHosts {
+    hosts: Vec<VirtualHost>,
+}
+
+impl VirtualHosts {
+    pub fn new(hosts: Vec<VirtualHost>) -> Self {
+        VirtualHosts { hosts }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    pub fn lookup(&self, host: &str) -> Option<&VirtualHost> {
+        self.hosts
+            .iter()
+            .find(|vhost| vhost.name == host)
+            .or_else(|| self.hosts.iter().find(|vhost| vhost.name == "*"))
+    }
+
+    /// The virtual host channels in `namespace` were created under, that
+    /// given the namespace or else the one its name looks up.
+    pub fn owner(&self, namespace: &str) -> Option<&VirtualHost> {
+        self.hosts
+            .iter()
+            .find(|vhost| vhost.namespace.as_deref() == Some(namespace))
+            .or_else(|| self.lookup(namespace))
+    }
+}
+
+/// The host a request is for, without its port, as named by its `Host`
+/// header or its URI. Forwarding headers, which any client can set, only
+/// count when `trust_proxy`, behind a proxy that sets them itself.
+pub fn host_of(head: &RequestHead, trust_proxy: bool) -> String {
+    let info;
+    let host = if trust_proxy {
+        info = ConnectionInfo::get(head, &AppConfig::default());
+        info.host()
+    } else {
+        head.headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| head.uri.authority().map(|authority| authority.as_str()))
+            .unwrap_or_default()
+    };
+    let host = host.to_ascii_lowercase();
+
+    let end = if host.starts_with('[') {
+        host.find(']').map(|i| i + 1)
+    } else {
+        host.find(':')
+    };
+
+    match end {
+        Some(end) => host[..end].to_owned(),
+        None => host,
+    }
+}